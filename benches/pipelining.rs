@@ -0,0 +1,86 @@
+//! Pipelined requests: the server's loop, which flushes once per batch of requests, against one that
+//! flushes after every reply (as the server used to).
+//!
+//! Run with `cargo bench --bench pipelining`.
+
+use std::{net::SocketAddr,
+          time::{Duration, Instant}};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use my_redis::{cmd::Registry, connection::Connection, db::Db, server};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+            runtime::Runtime};
+
+/// Requests per pipeline.
+const DEPTHS: [usize; 3] = [1, 16, 128];
+
+/// Serve connections one reply, one flush at a time.
+async fn flush_per_reply(listener: TcpListener, db: Db) {
+    let registry = Registry::new();
+    loop {
+        let (socket, _) = listener.accept().await.expect("Socket acquired.");
+        let (db, registry) = (db.clone(), registry.clone());
+        tokio::spawn(async move {
+            socket.set_nodelay(true).expect("Nodelay set.");
+            let mut connection = Connection::new(socket);
+            while let Ok(Some(frame)) = connection.read_frame().await {
+                let reply = match registry.parse(frame) {
+                    Ok(command) => command.execute(&db).await,
+                    Err(err) => Err(err),
+                };
+                let reply = reply.unwrap_or_else(|err| err.to_frame());
+                connection.write_frame(&reply).await.expect("Reply sent.");
+            }
+        });
+    }
+}
+
+/// Start a server on a port of its own; `batched` picks the real loop.
+fn start(runtime: &Runtime, batched: bool) -> SocketAddr {
+    runtime.block_on(async {
+               let listener = TcpListener::bind("127.0.0.1:0").await
+                                                              .expect("Listener binds.");
+               let addr = listener.local_addr().expect("Listener has an address.");
+               match batched {
+                   true => drop(tokio::spawn(server::run(listener, Db::new(), Registry::new()))),
+                   false => drop(tokio::spawn(flush_per_reply(listener, Db::new()))),
+               }
+               addr
+           })
+}
+
+/// Send `iters` pipelines of `depth` SETs, reading back every `+OK` before the next pipeline.
+async fn pipelines(addr: SocketAddr, depth: usize, iters: u64) -> Duration {
+    let mut client = TcpStream::connect(addr).await.expect("Client connects.");
+    client.set_nodelay(true).expect("Nodelay set.");
+    let request = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n".repeat(depth);
+    let mut replies = vec![0; b"+OK\r\n".len() * depth];
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        client.write_all(&request).await.expect("Requests sent.");
+        client.read_exact(&mut replies)
+              .await
+              .expect("Replies received.");
+    }
+    start.elapsed()
+}
+
+fn pipelining(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("Runtime starts.");
+    let mut group = c.benchmark_group("pipelining");
+    for (name, batched) in [("flush_per_reply", false), ("flush_per_batch", true)] {
+        let addr = start(&runtime, batched);
+        for depth in DEPTHS {
+            group.throughput(Throughput::Elements(depth as u64));
+            group.bench_with_input(BenchmarkId::new(name, depth), &depth, |b, &depth| {
+                     b.iter_custom(|iters| runtime.block_on(pipelines(addr, depth, iters)))
+                 });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipelining);
+criterion_main!(benches);
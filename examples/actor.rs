@@ -20,7 +20,7 @@ async fn main() -> Result<()> {
 
         // use tokio spawn to run MyActor and have it wait to receive messages
         // we don't take a yorrick_handle here; using our mpsc & oneshots solely
-        let _ = tokio::spawn(async move {
+        let _handle = tokio::spawn(async move {
             while let Some(msg) = yorrick.receiver.recv().await {
                 tracing::debug!("Received message: {:?}", msg);
                 yorrick.handle_message(msg)
@@ -40,7 +40,7 @@ async fn main() -> Result<()> {
     // overly complicated `new()` 2, with generated channels:
     {
         tracing::info!("Starting actor example 2 -- where actor and actorhandle are generated with tx & rx embedded within.");
-        let (Some(alas), mut yorrick) = MyActor::new(None) else {
+        let (Some(alas), yorrick) = MyActor::new(None) else {
             panic!("Failed to create actor-handle.");
         };
 
@@ -0,0 +1,39 @@
+//! A server with a command of our own: `GETLEN key` replies with the key's value and its length.
+//!
+//! Try it with `redis-cli GETLEN hello`, or `redis-cli COMMAND INFO getlen`.
+
+use bytes::Bytes;
+use my_redis::{boilerplate,
+               cmd::{CommandFlags, CommandHandler, CommandSpec, Registry},
+               db::Db,
+               error::CommandError,
+               frame::Frame,
+               parse::Parse,
+               server};
+use tokio::net::TcpListener;
+
+use crate::boilerplate::{tracing_subscribe_boilerplate, SubKind};
+
+#[derive(Debug)]
+struct GetLen;
+
+impl CommandHandler for GetLen {
+    fn call(&self, db: &Db, args: &mut Parse) -> Result<Frame, CommandError> {
+        let key = args.next_string()
+                      .map_err(|err| err.for_command("getlen"))?;
+        let value = db.get(&key)?.unwrap_or_else(Bytes::new);
+        Ok(Frame::Array(vec![Frame::Integer(value.len() as i64), Frame::Bulk(value)]))
+    }
+}
+
+#[tokio::main]
+async fn main() -> my_redis::error::Result<()> {
+    tracing_subscribe_boilerplate(SubKind::Tracing(String::from("info")));
+
+    let mut registry = Registry::new();
+    registry.register(CommandSpec::new("getlen", 2, CommandFlags::READONLY).with_keys(1, 1, 1),
+                      GetLen);
+
+    let listener = TcpListener::bind("127.0.0.1:6379").await?;
+    server::run(listener, Db::new(), registry).await
+}
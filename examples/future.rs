@@ -9,7 +9,6 @@ use std::{future::Future,
 use futures::task::{self, ArcWake};
 use my_redis::{boilerplate, error::Result};
 use tokio::{net::TcpStream, sync::Notify};
-use tracing;
 
 #[tokio::main]
 async fn main() -> Result<()> {
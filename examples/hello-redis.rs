@@ -1,5 +1,4 @@
-use mini_redis::{client, Result};
-use my_redis::boilerplate;
+use my_redis::{boilerplate, client, error::Result};
 
 use crate::boilerplate::{tracing_subscribe_boilerplate, SubKind};
 
@@ -8,7 +7,7 @@ async fn main() -> Result<()> {
     tracing_subscribe_boilerplate(SubKind::Tracing(String::from("debug")));
     // tracing_subscribe_boilerplate(SubKind::Console);
 
-    // open connection to mini-redis address
+    // open connection to the server
     let mut client = client::connect("127.0.0.1:6379").await?;
 
     // set key: "hello" with value: "world"
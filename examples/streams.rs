@@ -1,12 +1,12 @@
 //! Streams, async
 
-use mini_redis::client;
-use my_redis::boilerplate;
+use my_redis::{boilerplate, client, error::Result};
+use tokio::sync::oneshot;
 use tokio_stream::StreamExt;
 
 const SOCKET_STR: &str = "127.0.0.1:6379";
 #[tokio::main]
-async fn main() -> mini_redis::Result<()> {
+async fn main() -> Result<()> {
     boilerplate::tracing_subscribe_boilerplate(boilerplate::SubKind::Tracing(String::from("trace")));
 
     // /////////////////
@@ -17,22 +17,29 @@ async fn main() -> mini_redis::Result<()> {
     }
 
     // //////////////////
-    // NOTE: this requires running the **MINI**-Redis Server
-    // (my-redis server does not implement the required funcgtionality)
+    // NOTE: this requires a running server (`just serve`)
 
-    tokio::spawn(async { publish().await });
-    subscribe().await?;
+    // messages published before the subscriber registers are dropped, not queued: hold the publisher
+    // back until the subscription is confirmed
+    let (subscribed, ready) = oneshot::channel();
+    tokio::spawn(async { publish(ready).await });
+    subscribe(subscribed).await?;
 
     println!("DONE");
     Ok(())
 }
 
-#[tracing::instrument]
-async fn publish() -> mini_redis::Result<()> {
+#[tracing::instrument(skip(ready))]
+async fn publish(ready: oneshot::Receiver<()>) -> Result<()> {
     tracing::info!("starting client (publisher)");
     tracing::info!(SOCKET_STR, "connecting to");
     let mut client = client::connect(SOCKET_STR).await?;
 
+    if ready.await.is_err() {
+        tracing::warn!("subscriber gave up before subscribing");
+        return Ok(());
+    }
+
     // publishes to the "number" channel
     client.publish("numbers", "lost to time".into()).await?;
     client.publish("numbers", "1".into()).await?;
@@ -44,19 +51,18 @@ async fn publish() -> mini_redis::Result<()> {
     Ok(())
 }
 
-#[tracing::instrument]
-async fn subscribe() -> mini_redis::Result<()> {
+#[tracing::instrument(skip(subscribed))]
+async fn subscribe(subscribed: oneshot::Sender<()>) -> Result<()> {
     tracing::info!("starting subscriber");
     tracing::info!(SOCKET_STR, "connecting to");
     let client = client::connect(SOCKET_STR).await?;
 
     // ¿defines "numbers" channel to listen to
     let subscriber = client.subscribe(vec!["numbers".to_string()]).await?;
+    // the server has confirmed the subscription, so nothing published from here on is missed
+    let _ = subscribed.send(());
     let messages = subscriber.into_stream()
-                             .filter(|msg| match msg {
-                                 Ok(msg) if msg.content.len() == 1 => true,
-                                 _ => false,
-                             })
+                             .filter(|msg| matches!(msg, Ok(msg) if msg.content.len() == 1))
                              .map(|msg| msg.expect("content extraction").content)
                              .take(3);
 
@@ -1,12 +1,12 @@
 //! Client
 
-use mini_redis::client;
-use my_redis::boilerplate::{tracing_subscribe_boilerplate, SubKind};
+use my_redis::{boilerplate::{tracing_subscribe_boilerplate, SubKind},
+               client};
 use tokio::sync::{mpsc, oneshot};
 
 // I did not choose this name: "Responder" is type of "sender" half of channel
 // to be given as a defacto address to receive a response at
-type Responder<T> = oneshot::Sender<mini_redis::Result<T>>;
+type Responder<T> = oneshot::Sender<my_redis::error::Result<T>>;
 
 #[tokio::main]
 async fn main() {
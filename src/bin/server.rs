@@ -1,16 +1,38 @@
-use std::{collections::HashMap,
-          sync::{Arc, Mutex}};
+use std::time::Duration;
 
 use boilerplate::{tracing_subscribe_boilerplate, SubKind};
-use bytes::Bytes;
-use mini_redis::Frame;
-use my_redis::boilerplate;
-use tokio::net::{TcpListener, TcpStream};
+use clap::Parser;
+use my_redis::{boilerplate, cmd::Registry, db::Db, frame::Limits, server,
+               shard_hash::DEFAULT_SHARDS};
+use tokio::{net::TcpListener, time};
 
-type Db = Arc<Mutex<HashMap<String, Bytes>>>;
+#[derive(Parser, Debug)]
+#[command(version, about)]
+/// my-redis server
+struct Args {
+    /// Number of independently locked shards to split the keyspace over
+    #[arg(long, default_value_t = DEFAULT_SHARDS)]
+    shards:                    usize,
+    /// Log per-shard lock contention every this many seconds (0: never)
+    #[arg(long, default_value_t = 0)]
+    shard_stats:               u64,
+    /// Longest bulk string a client may send, in bytes
+    #[arg(long, default_value_t = Limits::default().max_bulk_len)]
+    proto_max_bulk_len:        usize,
+    /// Most entries in one array a client may send
+    #[arg(long, default_value_t = Limits::default().max_multibulk_len)]
+    max_multibulk_len:         usize,
+    /// How deep a client's arrays may nest
+    #[arg(long, default_value_t = Limits::default().max_depth)]
+    max_depth:                 usize,
+    /// Most bytes held for a client while waiting for the rest of a request
+    #[arg(long, default_value_t = Limits::default().max_buffer)]
+    client_query_buffer_limit: usize,
+}
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
     tracing_subscribe_boilerplate(SubKind::Tracing(String::from("debug")));
     // tracing_subscribe_boilerplate(SubKind::Console);
     tracing::info!("Tracing Subscriber active.");
@@ -21,56 +43,27 @@ async fn main() {
                                                       .expect("Listener binds.");
     tracing::debug!("listener bound.");
 
-    let db = Arc::new(Mutex::new(HashMap::new()));
-
-    loop {
-        // The Second item contains the IP and port of the new connection.
-        // -- presumably "accept" is "accept if asked, wait otherwise"
-        tracing::debug!("Awaiting socket receipt...");
-        let (socket, _) = listener.accept().await.expect("Socket acquired.");
-        tracing::debug!("'Cloning' Arc.");
-        let db = db.clone();
-        tracing::debug!("Socket accepted; Spawning thread to process...");
-        tokio::spawn(async move {
-            tracing::debug!("Thread for socket processing spawned.");
-            tracing::debug!("Processing socket...");
-            process(socket, db).await;
-            tracing::debug!("Socket processed.");
-        });
+    let db = Db::with_shards(args.shards);
+    if args.shard_stats > 0 {
+        tokio::spawn(log_shard_stats(db.clone(), Duration::from_secs(args.shard_stats)));
     }
-}
 
-/// Process commands from a TcpStream, translate into 'frames', and manage comms with database.
-async fn process(socket: TcpStream, db: Db) {
-    use mini_redis::Command::{self, Get, Set};
-
-    // "mini_redis specific" Read&Write "frames" instead of working with byte streams
-    let mut connection = mini_redis::Connection::new(socket);
+    let limits = Limits { max_bulk_len:      args.proto_max_bulk_len,
+                          max_multibulk_len: args.max_multibulk_len,
+                          max_depth:         args.max_depth,
+                          max_buffer:        args.client_query_buffer_limit, };
+    server::run_with_limits(listener, db, Registry::new(), limits).await
+                                                                  .expect("Listener accepts connections.");
+}
 
-    while let Some(frame) = connection.read_frame().await.expect("frame read") {
-        tracing::info!("GOT: {:?}", frame);
-        let response = match Command::from_frame(frame).expect("Unpoisoned mutex.") {
-            Set(cmd) => {
-                // value stored as Vec<u8>
-                tracing::debug!("Acquiring mutex lock...");
-                let mut db = db.lock().unwrap();
-                tracing::debug!("Mutex lock acquired.");
-                db.insert(cmd.key().to_string(), cmd.value().clone());
-                Frame::Simple("OK".to_string())
-            }
-            Get(cmd) => {
-                let db = db.lock().expect("Unpoisoned mutex.");
-                if let Some(value) = db.get(cmd.key()) {
-                    Frame::Bulk(value.clone())
-                } else {
-                    Frame::Null
-                }
-            }
-            cmd => unimplemented!("{:?}", cmd),
-        };
-        // write response to client
-        connection.write_frame(&response)
-                  .await
-                  .expect("Write to client");
+/// Periodically report how often each shard's lock had to be waited for.
+async fn log_shard_stats(db: Db, every: Duration) {
+    let mut interval = time::interval(every);
+    loop {
+        interval.tick().await;
+        let stats = db.shard_stats();
+        let acquisitions: u64 = stats.iter().map(|s| s.acquisitions).sum();
+        let contended: u64 = stats.iter().map(|s| s.contended).sum();
+        tracing::info!(acquisitions, contended, per_shard = ?stats, "Shard lock contention.");
     }
 }
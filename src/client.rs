@@ -0,0 +1,352 @@
+//! A client for the server: one request at a time, over our own `Connection`
+//!
+//! `connect` gives a `Client` for the usual request/reply commands.  Subscribing hands the connection
+//! over to a `Subscriber`, which receives published messages until it's dropped.
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::{connection::Connection, error::Result, frame::Frame};
+
+/// An open connection to the server.
+#[derive(Debug)]
+pub struct Client {
+    connection: Connection,
+}
+
+/// A connection subscribed to channels, waiting for messages.
+#[derive(Debug)]
+pub struct Subscriber {
+    client:   Client,
+    channels: Vec<String>,
+}
+
+/// A message published to a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub channel: String,
+    pub content: Bytes,
+}
+
+/// Connect to the server at `addr`.
+pub async fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+    let socket = TcpStream::connect(addr).await?;
+    Ok(Client { connection: Connection::new(socket), })
+}
+
+impl Client {
+    /// The value at `key`, if there is one.
+    pub async fn get(&mut self, key: &str) -> Result<Option<Bytes>> {
+        match self.request(&["GET".as_bytes(), key.as_bytes()]).await? {
+            Frame::Bulk(value) => Ok(Some(value)),
+            Frame::Null => Ok(None),
+            frame => Err(unexpected(frame)),
+        }
+    }
+
+    /// Set `key` to `value`, with no expiry.
+    pub async fn set(&mut self, key: &str, value: Bytes) -> Result<()> {
+        match self.request(&["SET".as_bytes(), key.as_bytes(), &value])
+                  .await?
+        {
+            Frame::Simple(reply) if reply == "OK" => Ok(()),
+            frame => Err(unexpected(frame)),
+        }
+    }
+
+    /// Publish `message` on `channel`.  Returns how many subscribers got it.
+    pub async fn publish(&mut self, channel: &str, message: Bytes) -> Result<u64> {
+        match self.request(&["PUBLISH".as_bytes(), channel.as_bytes(), &message])
+                  .await?
+        {
+            Frame::Integer(count) => Ok(count as u64),
+            frame => Err(unexpected(frame)),
+        }
+    }
+
+    /// Subscribe to `channels`, turning the connection over to receiving their messages.
+    pub async fn subscribe(mut self, channels: Vec<String>) -> Result<Subscriber> {
+        let request = std::iter::once("SUBSCRIBE").chain(channels.iter().map(String::as_str))
+                                                  .map(Frame::bulk)
+                                                  .collect();
+        self.connection.write_frame(&Frame::Array(request)).await?;
+        // one acknowledgement per channel: ["subscribe", channel, count]
+        for channel in &channels {
+            let ack = self.read_reply().await?;
+            let expected = [Frame::bulk("subscribe"), Frame::bulk(channel)];
+            match &ack {
+                Frame::Array(parts) | Frame::Push(parts) if parts.starts_with(&expected) => {}
+                _ => return Err(unexpected(ack)),
+            }
+        }
+        Ok(Subscriber { client: self,
+                        channels })
+    }
+
+    /// Send a command, and wait for the reply.  An error reply becomes an `Err`.
+    async fn request(&mut self, args: &[&[u8]]) -> Result<Frame> {
+        let request = Frame::Array(args.iter().map(Frame::bulk).collect());
+        self.connection.write_frame(&request).await?;
+        self.read_reply().await
+    }
+
+    async fn read_reply(&mut self) -> Result<Frame> {
+        match self.connection.read_frame().await? {
+            Some(Frame::Error(msg)) => Err(msg.into()),
+            Some(frame) => Ok(frame),
+            None => Err("connection reset by server".into()),
+        }
+    }
+}
+
+impl Subscriber {
+    /// The channels subscribed to.
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
+
+    /// Wait for the next message.  `None` once the server hangs up.
+    pub async fn next_message(&mut self) -> Result<Option<Message>> {
+        let Some(frame) = self.client.connection.read_frame().await? else {
+            return Ok(None);
+        };
+        match frame {
+            Frame::Array(parts) | Frame::Push(parts) => match <[Frame; 3]>::try_from(parts) {
+                Ok([kind, Frame::Bulk(channel), Frame::Bulk(content)])
+                    if kind == Frame::bulk("message") =>
+                {
+                    let channel = String::from_utf8(channel.to_vec())?;
+                    Ok(Some(Message { channel, content }))
+                }
+                Ok(parts) => Err(unexpected(Frame::Array(parts.into()))),
+                Err(parts) => Err(unexpected(Frame::Array(parts))),
+            },
+            frame => Err(unexpected(frame)),
+        }
+    }
+
+    /// The messages, as a stream.  It ends when the server hangs up, or after an error.
+    pub fn into_stream(self) -> impl Stream<Item=Result<Message>> {
+        futures::stream::unfold(Some(self), |subscriber| async move {
+            let mut subscriber = subscriber?;
+            match subscriber.next_message().await {
+                Ok(Some(message)) => Some((Ok(message), Some(subscriber))),
+                Ok(None) => None,
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+    }
+}
+
+fn unexpected(frame: Frame) -> crate::error::Error {
+    format!("unexpected reply: {:?}", frame).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{cmd::Registry, db::Db, frame::Limits, server};
+
+    /// Address of a server of our own.
+    async fn serve() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server::run(listener, Db::new(), Registry::new()));
+        addr
+    }
+
+    /// Send a request without waiting for its reply.
+    async fn send(connection: &mut Connection, args: &[&str]) {
+        let request = Frame::Array(args.iter().map(Frame::bulk).collect());
+        connection.write_frame(&request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn talks_to_the_server() {
+        let addr = serve().await;
+        let mut client = connect(addr).await.unwrap();
+        assert_eq!(client.get("k").await.unwrap(), None);
+        client.set("k", Bytes::from("v")).await.unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Some(Bytes::from("v")));
+        assert_eq!(client.request(&[b"LPUSH", b"k", b"x"])
+                         .await
+                         .unwrap_err()
+                         .to_string(),
+                   "WRONGTYPE Operation against a key holding the wrong kind of value");
+
+        let subscriber = connect(addr).await
+                                      .unwrap()
+                                      .subscribe(vec!["a".into(), "b".into()])
+                                      .await
+                                      .unwrap();
+        assert_eq!(subscriber.channels(), ["a", "b"]);
+        assert_eq!(client.publish("b", Bytes::from("hi")).await.unwrap(), 1);
+        let mut messages = Box::pin(subscriber.into_stream());
+        assert_eq!(messages.next().await.unwrap().unwrap(),
+                   Message { channel: "b".into(),
+                             content: Bytes::from("hi"), });
+    }
+
+    #[tokio::test]
+    async fn subscription_replies_carry_running_counts() {
+        let addr = serve().await;
+        let mut publisher = connect(addr).await.unwrap();
+        let mut subscriber = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let ack = |kind: &str, channel: &str, count| {
+            Frame::Array(vec![Frame::bulk(kind),
+                              Frame::bulk(channel),
+                              Frame::Integer(count)])
+        };
+
+        send(&mut subscriber, &["SUBSCRIBE", "a", "b"]).await;
+        send(&mut subscriber, &["SUBSCRIBE", "b", "c"]).await;
+        // a channel subscribed to twice is counted once
+        for expected in [ack("subscribe", "a", 1),
+                         ack("subscribe", "b", 2),
+                         ack("subscribe", "b", 2),
+                         ack("subscribe", "c", 3)]
+        {
+            assert_eq!(subscriber.read_frame().await.unwrap(), Some(expected));
+        }
+
+        // PUBLISH counts the connections the message goes to
+        let other = connect(addr).await
+                                 .unwrap()
+                                 .subscribe(vec!["b".into()])
+                                 .await
+                                 .unwrap();
+        assert_eq!(publisher.publish("b", Bytes::from("hi")).await.unwrap(), 2);
+        assert_eq!(publisher.publish("nobody", Bytes::from("hi"))
+                            .await
+                            .unwrap(),
+                   0);
+        drop(other);
+        let message = Frame::Array(vec![Frame::bulk("message"),
+                                        Frame::bulk("b"),
+                                        Frame::bulk("hi")]);
+        assert_eq!(subscriber.read_frame().await.unwrap(), Some(message));
+
+        send(&mut subscriber, &["UNSUBSCRIBE", "a"]).await;
+        send(&mut subscriber, &["UNSUBSCRIBE", "c", "b"]).await;
+        for expected in [ack("unsubscribe", "a", 2),
+                         ack("unsubscribe", "c", 1),
+                         ack("unsubscribe", "b", 0)]
+        {
+            assert_eq!(subscriber.read_frame().await.unwrap(), Some(expected));
+        }
+        // out of subscribe mode, and nobody left listening
+        send(&mut subscriber, &["PING"]).await;
+        assert_eq!(subscriber.read_frame().await.unwrap(),
+                   Some(Frame::Simple("PONG".to_string())));
+        assert_eq!(publisher.publish("b", Bytes::from("hi")).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn server_ignores_empty_requests() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut socket = TcpStream::connect(serve().await).await.unwrap();
+        socket.write_all(b"*0\r\n*-1\r\nPING\r\n*0\r\n").await.unwrap();
+        socket.shutdown().await.unwrap();
+        let mut replies = String::new();
+        socket.read_to_string(&mut replies).await.unwrap();
+        assert_eq!(replies, "+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn pipelined_replies_arrive_whole_and_in_order() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut socket = TcpStream::connect(serve().await).await.unwrap();
+        let mut requests = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\n0\r\nLPUSH l a b\r\n".to_vec();
+        let mut expected = "+OK\r\n:2\r\n".to_string();
+        for n in 1..=500 {
+            requests.extend_from_slice(b"INCR k\r\n");
+            expected += &format!(":{n}\r\n");
+        }
+        requests.extend_from_slice(b"LRANGE l 0 -1\r\nGET k\r\nPING\r\n");
+        expected += "*2\r\n$1\r\nb\r\n$1\r\na\r\n$3\r\n500\r\n+PONG\r\n";
+
+        // all in one write; the server reads them in however many pieces it likes
+        socket.write_all(&requests).await.unwrap();
+        socket.shutdown().await.unwrap();
+        let mut replies = String::new();
+        socket.read_to_string(&mut replies).await.unwrap();
+        assert_eq!(replies, expected);
+    }
+
+    #[tokio::test]
+    async fn blocking_command_sends_earlier_replies_before_waiting() {
+        use std::time::Duration;
+
+        use tokio::{io::{AsyncReadExt, AsyncWriteExt},
+                    time::timeout};
+
+        let addr = serve().await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"SET k v\r\nGET k\r\nBLPOP q 0\r\nGET k\r\n")
+              .await
+              .unwrap();
+
+        // the replies ahead of BLPOP come while it waits
+        let mut before = [0; 12];
+        timeout(Duration::from_secs(5), socket.read_exact(&mut before)).await
+                                                                       .expect("replies held back")
+                                                                       .unwrap();
+        assert_eq!(&before, b"+OK\r\n$1\r\nv\r\n");
+
+        // then the rest, once there's something to pop
+        let mut pusher = connect(addr).await.unwrap();
+        pusher.request(&[b"RPUSH", b"q", b"x"]).await.unwrap();
+        let expected = b"*2\r\n$1\r\nq\r\n$1\r\nx\r\n$1\r\nv\r\n";
+        let mut after = [0; 25];
+        timeout(Duration::from_secs(5), socket.read_exact(&mut after)).await
+                                                                      .unwrap()
+                                                                      .unwrap();
+        assert_eq!(&after, expected);
+    }
+
+    #[tokio::test]
+    async fn server_hangs_up_on_hostile_frames() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = Limits { max_bulk_len:      1024,
+                              max_multibulk_len: 8,
+                              max_depth:         2,
+                              max_buffer:        4096, };
+        tokio::spawn(server::run_with_limits(listener, Db::new(), Registry::new(), limits));
+
+        for (hostile, err) in [(&b"*2\r\n$3\r\nGET\r\n$1025\r\n"[..], "invalid bulk length"),
+                               (b"*9\r\n", "invalid multibulk length"),
+                               (b"*1\r\n*1\r\n*1\r\n", "too many nested aggregates"),
+                               // just over: all read by the time it's refused, as bytes left
+                               // unread would turn the hang-up into a reset
+                               (&[b'x'; 4097], "request exceeds the input buffer limit")]
+        {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            // a well-behaved request first: its reply still arrives
+            socket.write_all(b"PING\r\n").await.unwrap();
+            socket.write_all(hostile).await.unwrap();
+            let mut replies = String::new();
+            socket.read_to_string(&mut replies).await.unwrap();
+            assert_eq!(replies,
+                       format!("+PONG\r\n-ERR Protocol error: {}\r\n", err));
+        }
+
+        // the limit holds while a blocking command waits, too
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"BLPOP list 0\r\n").await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        socket.write_all(&[b'x'; 4097]).await.unwrap();
+        let mut replies = String::new();
+        socket.read_to_string(&mut replies).await.unwrap();
+        assert_eq!(replies,
+                   "-ERR Protocol error: request exceeds the input buffer limit\r\n");
+    }
+}
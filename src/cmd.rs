@@ -0,0 +1,2308 @@
+//! Commands understood by the server, parsed out of client frames
+
+use std::{sync::Arc,
+          time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::{db::{self, BitFieldOp, BitFieldType, BitRange, BitUnit, ClaimOptions, Db, End,
+                 ExpireCondition, GroupEntry, LexBound, Lifetime, Overflow, ScoreBound,
+                 ScoreCondition, SetCondition, SetOp, StreamEntry, StreamId, Trim, XAddId,
+                 ZAddOptions, ZRange},
+            error::CommandError,
+            frame::Frame,
+            parse::{parse_float, parse_int, parse_score, Parse, ParseError}};
+
+mod registry;
+mod transaction;
+
+pub use registry::{CommandFlags, CommandHandler, CommandQuery, CommandSpec, Registry};
+pub use transaction::Transaction;
+
+/// Keys a SCAN step looks at when the client doesn't say.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A request from a client.
+#[derive(Debug)]
+pub enum Command {
+    Get {
+        key: String,
+    },
+    Set {
+        key:       String,
+        value:     Bytes,
+        lifetime:  TtlChange,
+        condition: Option<SetCondition>,
+        /// Reply with the old value, rather than OK.
+        get:       bool,
+    },
+    GetDel {
+        key: String,
+    },
+    MGet {
+        keys: Vec<String>,
+    },
+    /// MSET, or (with `nx`) MSETNX
+    MSet {
+        pairs: Vec<(String, Bytes)>,
+        nx:    bool,
+    },
+    GetEx {
+        key:      String,
+        lifetime: TtlChange,
+    },
+    Append {
+        key:   String,
+        value: Bytes,
+    },
+    Strlen {
+        key: String,
+    },
+    GetRange {
+        key:   String,
+        start: i64,
+        end:   i64,
+    },
+    SetRange {
+        key:    String,
+        offset: usize,
+        value:  Bytes,
+    },
+    /// EXPIRE, PEXPIRE, EXPIREAT & PEXPIREAT
+    Expire {
+        key:       String,
+        when:      Expiry,
+        condition: Option<ExpireCondition>,
+    },
+    /// TTL, or (with `millis`) PTTL
+    Ttl {
+        key:    String,
+        millis: bool,
+    },
+    Persist {
+        key: String,
+    },
+    /// INCR, DECR, INCRBY & DECRBY
+    IncrBy {
+        key:   String,
+        delta: i64,
+    },
+    IncrByFloat {
+        key:   String,
+        delta: f64,
+    },
+    SetBit {
+        key:    String,
+        offset: u64,
+        on:     bool,
+    },
+    GetBit {
+        key:    String,
+        offset: u64,
+    },
+    BitCount {
+        key:   String,
+        range: Option<BitRange>,
+    },
+    BitPos {
+        key:   String,
+        bit:   bool,
+        range: Option<BitRange>,
+    },
+    BitOp {
+        op:          db::BitOp,
+        destination: String,
+        keys:        Vec<String>,
+    },
+    /// BITFIELD, or (with `read_only`) BITFIELD_RO
+    BitField {
+        key:       String,
+        ops:       Vec<BitFieldOp>,
+        read_only: bool,
+    },
+    /// LPUSH & RPUSH
+    Push {
+        key:      String,
+        elements: Vec<Bytes>,
+        end:      End,
+    },
+    /// LPOP & RPOP.  Without a `count`, replies with a single element rather than an array.
+    Pop {
+        key:   String,
+        end:   End,
+        count: Option<usize>,
+    },
+    /// BLPOP & BRPOP.  A `timeout` of `None` waits for ever.
+    BPop {
+        keys:    Vec<String>,
+        end:     End,
+        timeout: Option<Duration>,
+    },
+    /// LMOVE, or (with a `timeout`) BLMOVE
+    LMove {
+        source:      String,
+        destination: String,
+        from:        End,
+        to:          End,
+        timeout:     Option<Option<Duration>>,
+    },
+    LRange {
+        key:   String,
+        start: i64,
+        stop:  i64,
+    },
+    LLen {
+        key: String,
+    },
+    LIndex {
+        key:   String,
+        index: i64,
+    },
+    /// HSET, or (with `hmset`) HMSET, which replies OK rather than a count
+    HSet {
+        key:   String,
+        pairs: Vec<(Bytes, Bytes)>,
+        hmset: bool,
+    },
+    HSetNx {
+        key:   String,
+        field: Bytes,
+        value: Bytes,
+    },
+    HGet {
+        key:   String,
+        field: Bytes,
+    },
+    HMGet {
+        key:    String,
+        fields: Vec<Bytes>,
+    },
+    HGetAll {
+        key: String,
+    },
+    HKeys {
+        key: String,
+    },
+    HVals {
+        key: String,
+    },
+    HDel {
+        key:    String,
+        fields: Vec<Bytes>,
+    },
+    HExists {
+        key:   String,
+        field: Bytes,
+    },
+    HLen {
+        key: String,
+    },
+    HStrlen {
+        key:   String,
+        field: Bytes,
+    },
+    HIncrBy {
+        key:   String,
+        field: Bytes,
+        delta: i64,
+    },
+    HIncrByFloat {
+        key:   String,
+        field: Bytes,
+        delta: f64,
+    },
+    HScan {
+        key:      String,
+        cursor:   u64,
+        pattern:  Option<Bytes>,
+        count:    usize,
+        /// Reply with field names only.
+        novalues: bool,
+    },
+    SAdd {
+        key:     String,
+        members: Vec<Bytes>,
+    },
+    SRem {
+        key:     String,
+        members: Vec<Bytes>,
+    },
+    SMembers {
+        key: String,
+    },
+    SIsMember {
+        key:    String,
+        member: Bytes,
+    },
+    SMIsMember {
+        key:     String,
+        members: Vec<Bytes>,
+    },
+    SCard {
+        key: String,
+    },
+    /// SINTER, SUNION & SDIFF
+    SCombine {
+        op:   SetOp,
+        keys: Vec<String>,
+    },
+    /// SINTERSTORE, SUNIONSTORE & SDIFFSTORE
+    SCombineStore {
+        op:          SetOp,
+        destination: String,
+        keys:        Vec<String>,
+    },
+    SScan {
+        key:     String,
+        cursor:  u64,
+        pattern: Option<Bytes>,
+        count:   usize,
+    },
+    ZAdd {
+        key:     String,
+        members: Vec<(f64, Bytes)>,
+        options: ZAddOptions,
+        /// Add the (single) score to the member's, replying with the result.
+        incr:    bool,
+    },
+    ZIncrBy {
+        key:    String,
+        delta:  f64,
+        member: Bytes,
+    },
+    ZRange {
+        key:        String,
+        range:      ZRange,
+        rev:        bool,
+        offset:     usize,
+        count:      Option<usize>,
+        withscores: bool,
+    },
+    /// ZRANK & ZREVRANK
+    ZRank {
+        key:       String,
+        member:    Bytes,
+        rev:       bool,
+        withscore: bool,
+    },
+    ZScore {
+        key:    String,
+        member: Bytes,
+    },
+    ZCard {
+        key: String,
+    },
+    ZRem {
+        key:     String,
+        members: Vec<Bytes>,
+    },
+    /// ZPOPMIN & ZPOPMAX
+    ZPop {
+        key:   String,
+        rev:   bool,
+        /// Reply with a flat member-score array either way; `None` pops one.
+        count: Option<usize>,
+    },
+    XAdd {
+        key:    String,
+        id:     XAddId,
+        fields: Vec<(Bytes, Bytes)>,
+        trim:   Option<Trim>,
+        /// Create the stream if it doesn't exist (no NOMKSTREAM).
+        create: bool,
+    },
+    /// XRANGE & XREVRANGE.  Both ends are inclusive.
+    XRange {
+        key:   String,
+        start: StreamId,
+        end:   StreamId,
+        rev:   bool,
+        count: Option<usize>,
+    },
+    XLen {
+        key: String,
+    },
+    XTrim {
+        key:  String,
+        trim: Trim,
+    },
+    XRead {
+        /// Each key with the ID to read past; `None` for `$`.
+        streams: Vec<(String, Option<StreamId>)>,
+        count:   Option<usize>,
+        /// `Some` to block: with a timeout, or for ever.
+        block:   Option<Option<Duration>>,
+    },
+    /// XGROUP CREATE
+    XGroupCreate {
+        key:      String,
+        group:    Bytes,
+        /// `None` for `$`
+        id:       Option<StreamId>,
+        mkstream: bool,
+    },
+    /// XGROUP DESTROY
+    XGroupDestroy {
+        key:   String,
+        group: Bytes,
+    },
+    /// XGROUP SETID
+    XGroupSetId {
+        key:   String,
+        group: Bytes,
+        id:    Option<StreamId>,
+    },
+    /// XGROUP CREATECONSUMER
+    XGroupCreateConsumer {
+        key:      String,
+        group:    Bytes,
+        consumer: Bytes,
+    },
+    /// XGROUP DELCONSUMER
+    XGroupDelConsumer {
+        key:      String,
+        group:    Bytes,
+        consumer: Bytes,
+    },
+    XReadGroup {
+        group:    Bytes,
+        consumer: Bytes,
+        /// Each key with the ID to read the consumer's pending entries past; `None` for `>`.
+        streams:  Vec<(String, Option<StreamId>)>,
+        count:    Option<usize>,
+        block:    Option<Option<Duration>>,
+        noack:    bool,
+    },
+    XAck {
+        key:   String,
+        group: Bytes,
+        ids:   Vec<StreamId>,
+    },
+    /// XPENDING without a range
+    XPendingSummary {
+        key:   String,
+        group: Bytes,
+    },
+    XPending {
+        key:      String,
+        group:    Bytes,
+        start:    StreamId,
+        end:      StreamId,
+        count:    usize,
+        consumer: Option<Bytes>,
+        min_idle: u64,
+    },
+    XClaim {
+        key:      String,
+        group:    Bytes,
+        consumer: Bytes,
+        ids:      Vec<StreamId>,
+        options:  ClaimOptions,
+    },
+    PfAdd {
+        key:      String,
+        elements: Vec<Bytes>,
+    },
+    PfCount {
+        keys: Vec<String>,
+    },
+    PfMerge {
+        destination: String,
+        sources:     Vec<String>,
+    },
+    Del {
+        keys: Vec<String>,
+    },
+    Exists {
+        keys: Vec<String>,
+    },
+    Type {
+        key: String,
+    },
+    Keys {
+        pattern: Bytes,
+    },
+    Scan {
+        cursor:  u64,
+        pattern: Option<Bytes>,
+        count:   usize,
+        kind:    Option<String>,
+    },
+    RandomKey,
+    Publish {
+        channel: String,
+        message: Bytes,
+    },
+    Subscribe {
+        channels: Vec<String>,
+    },
+    /// An empty `channels` means "all of them".
+    Unsubscribe {
+        channels: Vec<String>,
+    },
+    Ping {
+        message: Option<Bytes>,
+    },
+    Multi,
+    Exec,
+    Discard,
+    Watch {
+        keys: Vec<String>,
+    },
+    Unwatch,
+    /// Switch protocol version (if one is given) and say hello.  `auth` is a username and password.
+    Hello {
+        protocol:    Option<i64>,
+        auth:        Option<(String, String)>,
+        client_name: Option<String>,
+    },
+    /// COMMAND & co.: answered by the connection loop, which has the `Registry`.
+    Describe(CommandQuery),
+    /// A command added to a `Registry` from outside this crate.
+    Custom {
+        spec:    Arc<CommandSpec>,
+        handler: Arc<dyn CommandHandler>,
+        args:    Vec<Bytes>,
+    },
+}
+
+/// When an EXPIRE-family command wants the key gone, as given on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// Relative, in seconds (EXPIRE)
+    Seconds(i64),
+    /// Relative, in milliseconds (PEXPIRE)
+    Millis(i64),
+    /// Absolute unix time, in seconds (EXPIREAT)
+    UnixSeconds(i64),
+    /// Absolute unix time, in milliseconds (PEXPIREAT)
+    UnixMillis(i64),
+}
+
+/// What SET or GETEX asks to be done with the key's deadline, as given on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtlChange {
+    /// Drop any deadline (plain SET, or GETEX PERSIST).
+    Persist,
+    /// EX, PX, EXAT or PXAT.
+    Expire(Expiry),
+    /// Leave the deadline alone (SET KEEPTTL, or GETEX without options).
+    Keep,
+}
+
+impl Command {
+    /// Parse a command out of a frame.
+    ///
+    /// The frame must be an array whose first entry is the command name (case insensitive).
+    pub fn from_frame(frame: Frame) -> Result<Command, CommandError> {
+        let (name, parse) = Command::split_name(frame)?;
+        Command::parse_named(name, parse)
+    }
+
+    /// Take the (lowercased) command name off the front of a frame, leaving the arguments.
+    fn split_name(frame: Frame) -> Result<(String, Parse), CommandError> {
+        let mut parse = Parse::new(frame).map_err(|err| err.for_command(""))?;
+        match parse.next_string() {
+            Ok(name) => Ok((name.to_lowercase(), parse)),
+            // the server skips these before they get here, as redis does
+            Err(ParseError::EndOfStream) => Err(CommandError::Other("empty command".to_string())),
+            Err(err) => Err(err.for_command("")),
+        }
+    }
+
+    /// Parse the arguments of built in command `name`, insisting on using them all.
+    fn parse_named(name: String, mut parse: Parse) -> Result<Command, CommandError> {
+        Command::parse_args(&name, &mut parse).and_then(|command| parse.finish().map(|_| command))
+                                              .map_err(|err| match err {
+                                                  ParseError::UnknownCommand => {
+                                                      Command::unknown(name, &mut parse)
+                                                  }
+                                                  err => err.for_command(&name),
+                                              })
+    }
+
+    /// The reply to a command nobody knows, quoting its arguments.
+    fn unknown(name: String, parse: &mut Parse) -> CommandError {
+        let args = std::iter::from_fn(|| parse.next_bytes().ok())
+            .map(|arg| String::from_utf8_lossy(&arg).into_owned())
+            .collect();
+        CommandError::UnknownCommand { name, args }
+    }
+
+    /// Parse the arguments of command `name`, leaving any surplus for the caller to complain about.
+    fn parse_args(name: &str, parse: &mut Parse) -> Result<Command, ParseError> {
+        let command = match name {
+            "get" => Command::Get { key: parse.next_string()?, },
+            "set" => parse_set(parse)?,
+            "getdel" => Command::GetDel { key: parse.next_string()?, },
+            "mget" => Command::MGet { keys: at_least_one(remaining_strings(parse)?)?, },
+            "mset" | "msetnx" => {
+                let mut pairs = Vec::new();
+                while !parse.is_empty() {
+                    pairs.push((parse.next_string()?, parse.next_bytes()?));
+                }
+                Command::MSet { pairs: at_least_one(pairs)?,
+                                nx:    name == "msetnx", }
+            }
+            "getex" => parse_getex(parse)?,
+            "append" => Command::Append { key:   parse.next_string()?,
+                                          value: parse.next_bytes()?, },
+            "strlen" => Command::Strlen { key: parse.next_string()?, },
+            "getrange" | "substr" => Command::GetRange { key:   parse.next_string()?,
+                                                         start: parse.next_int()?,
+                                                         end:   parse.next_int()?, },
+            "setrange" => {
+                let key = parse.next_string()?;
+                let offset =
+                    usize::try_from(parse.next_int()?).map_err(|_| {
+                                                          ParseError::from("offset is out of range")
+                                                      })?;
+                Command::SetRange { key,
+                                    offset,
+                                    value: parse.next_bytes()? }
+            }
+            "expire" | "pexpire" | "expireat" | "pexpireat" => {
+                let key = parse.next_string()?;
+                let n = parse.next_int()?;
+                let when = match name {
+                    "expire" => Expiry::Seconds(n),
+                    "pexpire" => Expiry::Millis(n),
+                    "expireat" => Expiry::UnixSeconds(n),
+                    _ => Expiry::UnixMillis(n),
+                };
+                let condition = match parse.is_empty() {
+                    true => None,
+                    false => Some(parse_expire_condition(&parse.next_string()?)?),
+                };
+                Command::Expire { key,
+                                  when,
+                                  condition }
+            }
+            "ttl" => Command::Ttl { key:    parse.next_string()?,
+                                    millis: false, },
+            "pttl" => Command::Ttl { key:    parse.next_string()?,
+                                     millis: true, },
+            "persist" => Command::Persist { key: parse.next_string()?, },
+            "incr" => Command::IncrBy { key:   parse.next_string()?,
+                                        delta: 1, },
+            "decr" => Command::IncrBy { key:   parse.next_string()?,
+                                        delta: -1, },
+            "incrby" => Command::IncrBy { key:   parse.next_string()?,
+                                          delta: parse.next_int()?, },
+            "decrby" => {
+                let key = parse.next_string()?;
+                let delta = parse.next_int()?
+                                 .checked_neg()
+                                 .ok_or_else(|| ParseError::from("decrement would overflow"))?;
+                Command::IncrBy { key, delta }
+            }
+            "incrbyfloat" => Command::IncrByFloat { key:   parse.next_string()?,
+                                                    delta: parse.next_float()?, },
+            "setbit" => {
+                let (key, offset) = (parse.next_string()?, parse_bit_offset(&parse.next_bytes()?)?);
+                let on = match &parse.next_bytes()?[..] {
+                    b"0" => false,
+                    b"1" => true,
+                    _ => return Err("bit is not an integer or out of range".into()),
+                };
+                Command::SetBit { key, offset, on }
+            }
+            "getbit" => Command::GetBit { key:    parse.next_string()?,
+                                          offset: parse_bit_offset(&parse.next_bytes()?)?, },
+            "bitcount" => Command::BitCount { key:   parse.next_string()?,
+                                              range: parse_bit_range(parse, true)?, },
+            "bitpos" => {
+                let key = parse.next_string()?;
+                let bit = match parse.next_int()? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err("The bit argument must be 1 or 0.".into()),
+                };
+                Command::BitPos { key,
+                                  bit,
+                                  range: parse_bit_range(parse, false)? }
+            }
+            "bitop" => parse_bitop(parse)?,
+            "bitfield" | "bitfield_ro" => parse_bitfield(parse, name == "bitfield_ro")?,
+            "lpush" | "rpush" => Command::Push { key:      parse.next_string()?,
+                                                 elements: at_least_one(remaining_bytes(parse)?)?,
+                                                 end:      list_end(name), },
+            "lpop" | "rpop" => {
+                let key = parse.next_string()?;
+                let count = match parse.is_empty() {
+                    true => None,
+                    false => Some(usize::try_from(parse.next_int()?).map_err(|_| {
+                                      ParseError::from("value is out of range, must be positive")
+                                  })?),
+                };
+                Command::Pop { key,
+                               end: list_end(name),
+                               count }
+            }
+            "blpop" | "brpop" => {
+                let mut keys = remaining_strings(parse)?;
+                let timeout = keys.pop().ok_or(ParseError::EndOfStream)?;
+                Command::BPop { keys:    at_least_one(keys)?,
+                                end:     list_end(&name[1..]),
+                                timeout: parse_timeout(timeout.as_bytes())?, }
+            }
+            "lmove" | "blmove" => {
+                Command::LMove { source:      parse.next_string()?,
+                                 destination: parse.next_string()?,
+                                 from:        parse_end(&parse.next_string()?)?,
+                                 to:          parse_end(&parse.next_string()?)?,
+                                 timeout:     match name {
+                                     "blmove" => Some(parse_timeout(&parse.next_bytes()?)?),
+                                     _ => None,
+                                 }, }
+            }
+            "lrange" => Command::LRange { key:   parse.next_string()?,
+                                          start: parse.next_int()?,
+                                          stop:  parse.next_int()?, },
+            "llen" => Command::LLen { key: parse.next_string()?, },
+            "lindex" => Command::LIndex { key:   parse.next_string()?,
+                                          index: parse.next_int()?, },
+            "hset" | "hmset" => {
+                let key = parse.next_string()?;
+                let mut pairs = Vec::new();
+                while !parse.is_empty() {
+                    pairs.push((parse.next_bytes()?, parse.next_bytes()?));
+                }
+                Command::HSet { key,
+                                pairs: at_least_one(pairs)?,
+                                hmset: name == "hmset" }
+            }
+            "hsetnx" => Command::HSetNx { key:   parse.next_string()?,
+                                          field: parse.next_bytes()?,
+                                          value: parse.next_bytes()?, },
+            "hget" => Command::HGet { key:   parse.next_string()?,
+                                      field: parse.next_bytes()?, },
+            "hmget" => Command::HMGet { key:    parse.next_string()?,
+                                        fields: at_least_one(remaining_bytes(parse)?)?, },
+            "hgetall" => Command::HGetAll { key: parse.next_string()?, },
+            "hkeys" => Command::HKeys { key: parse.next_string()?, },
+            "hvals" => Command::HVals { key: parse.next_string()?, },
+            "hdel" => Command::HDel { key:    parse.next_string()?,
+                                      fields: at_least_one(remaining_bytes(parse)?)?, },
+            "hexists" => Command::HExists { key:   parse.next_string()?,
+                                            field: parse.next_bytes()?, },
+            "hlen" => Command::HLen { key: parse.next_string()?, },
+            "hstrlen" => Command::HStrlen { key:   parse.next_string()?,
+                                            field: parse.next_bytes()?, },
+            "hincrby" => Command::HIncrBy { key:   parse.next_string()?,
+                                            field: parse.next_bytes()?,
+                                            delta: parse.next_int()?, },
+            "hincrbyfloat" => Command::HIncrByFloat { key:   parse.next_string()?,
+                                                      field: parse.next_bytes()?,
+                                                      delta: parse.next_float()?, },
+            "hscan" => parse_hscan(parse)?,
+            "sadd" => Command::SAdd { key:     parse.next_string()?,
+                                      members: at_least_one(remaining_bytes(parse)?)?, },
+            "srem" => Command::SRem { key:     parse.next_string()?,
+                                      members: at_least_one(remaining_bytes(parse)?)?, },
+            "smembers" => Command::SMembers { key: parse.next_string()?, },
+            "sismember" => Command::SIsMember { key:    parse.next_string()?,
+                                                member: parse.next_bytes()?, },
+            "smismember" => Command::SMIsMember { key:     parse.next_string()?,
+                                                  members: at_least_one(remaining_bytes(parse)?)?, },
+            "scard" => Command::SCard { key: parse.next_string()?, },
+            "sinter" | "sunion" | "sdiff" => {
+                Command::SCombine { op:   set_op(name),
+                                    keys: at_least_one(remaining_strings(parse)?)?, }
+            }
+            "sinterstore" | "sunionstore" | "sdiffstore" => {
+                Command::SCombineStore { op:          set_op(name),
+                                         destination: parse.next_string()?,
+                                         keys:        at_least_one(remaining_strings(parse)?)?, }
+            }
+            "sscan" => {
+                let key = parse.next_string()?;
+                let (cursor, pattern, count) =
+                    parse_scan_args(parse, |_, _| Err(ParseError::Syntax))?;
+                Command::SScan { key,
+                                 cursor,
+                                 pattern,
+                                 count }
+            }
+            "zadd" => parse_zadd(parse)?,
+            "zincrby" => Command::ZIncrBy { key:    parse.next_string()?,
+                                            delta:  parse.next_score()?,
+                                            member: parse.next_bytes()?, },
+            "zrange" => parse_zrange(parse)?,
+            "zrank" | "zrevrank" => {
+                let (key, member) = (parse.next_string()?, parse.next_bytes()?);
+                let withscore = match parse.is_empty() {
+                    true => false,
+                    false if parse.next_string()?.eq_ignore_ascii_case("WITHSCORE") => true,
+                    false => return Err(ParseError::Syntax),
+                };
+                Command::ZRank { key,
+                                 member,
+                                 rev: name == "zrevrank",
+                                 withscore }
+            }
+            "zscore" => Command::ZScore { key:    parse.next_string()?,
+                                          member: parse.next_bytes()?, },
+            "zcard" => Command::ZCard { key: parse.next_string()?, },
+            "zrem" => Command::ZRem { key:     parse.next_string()?,
+                                      members: at_least_one(remaining_bytes(parse)?)?, },
+            "zpopmin" | "zpopmax" => {
+                let key = parse.next_string()?;
+                let count = (!parse.is_empty()).then(|| parse.next_int())
+                                               .transpose()?
+                                               .map(|count| {
+                                                   usize::try_from(count).map_err(|_| {
+                                                       ParseError::from("value is out of range, must be positive")
+                                                   })
+                                               })
+                                               .transpose()?;
+                Command::ZPop { key,
+                                rev: name == "zpopmax",
+                                count }
+            }
+            "xadd" => parse_xadd(parse)?,
+            "xrange" | "xrevrange" => {
+                let key = parse.next_string()?;
+                let (first, second) = (parse.next_bytes()?, parse.next_bytes()?);
+                let (start, end) = match name {
+                    "xrange" => (first, second),
+                    _ => (second, first),
+                };
+                let count = match parse.is_empty() {
+                    true => None,
+                    false if parse.next_string()?.eq_ignore_ascii_case("COUNT") => {
+                        // a negative count reads nothing
+                        Some(usize::try_from(parse.next_int()?).unwrap_or(0))
+                    }
+                    false => return Err(ParseError::Syntax),
+                };
+                Command::XRange { key,
+                                  start: parse_range_id(&start, true)?,
+                                  end: parse_range_id(&end, false)?,
+                                  rev: name == "xrevrange",
+                                  count }
+            }
+            "xlen" => Command::XLen { key: parse.next_string()?, },
+            "xtrim" => {
+                let key = parse.next_string()?;
+                let strategy = parse.next_string()?.to_uppercase();
+                Command::XTrim { key,
+                                 trim: parse_trim(&strategy, parse)?.ok_or(ParseError::Syntax)? }
+            }
+            "xread" => parse_xread(parse, false)?,
+            "xreadgroup" => parse_xread(parse, true)?,
+            "xgroup" => parse_xgroup(parse)?,
+            "xack" => {
+                let (key, group) = (parse.next_string()?, parse.next_bytes()?);
+                let ids = at_least_one(remaining_bytes(parse)?)?;
+                Command::XAck { key,
+                                group,
+                                ids: ids.iter()
+                                        .map(|id| parse_stream_id(id, 0))
+                                        .collect::<Result<_, _>>()? }
+            }
+            "xpending" => parse_xpending(parse)?,
+            "xclaim" => parse_xclaim(parse)?,
+            "pfadd" => Command::PfAdd { key:      parse.next_string()?,
+                                        elements: remaining_bytes(parse)?, },
+            "pfcount" => Command::PfCount { keys: at_least_one(remaining_strings(parse)?)?, },
+            "pfmerge" => Command::PfMerge { destination: parse.next_string()?,
+                                            sources:     remaining_strings(parse)?, },
+            "del" => Command::Del { keys: at_least_one(remaining_strings(parse)?)?, },
+            "exists" => Command::Exists { keys: at_least_one(remaining_strings(parse)?)?, },
+            "type" => Command::Type { key: parse.next_string()?, },
+            "keys" => Command::Keys { pattern: parse.next_bytes()?, },
+            "scan" => parse_scan(parse)?,
+            "randomkey" => Command::RandomKey,
+            "publish" => Command::Publish { channel: parse.next_string()?,
+                                            message: parse.next_bytes()?, },
+            "subscribe" => {
+                Command::Subscribe { channels: at_least_one(remaining_strings(parse)?)?, }
+            }
+            "unsubscribe" => Command::Unsubscribe { channels: remaining_strings(parse)?, },
+            "ping" => Command::Ping { message: (!parse.is_empty()).then(|| parse.next_bytes())
+                                                                  .transpose()?, },
+            "multi" => Command::Multi,
+            "exec" => Command::Exec,
+            "discard" => Command::Discard,
+            "watch" => Command::Watch { keys: at_least_one(remaining_strings(parse)?)?, },
+            "unwatch" => Command::Unwatch,
+            "hello" => parse_hello(parse)?,
+            "command" => Command::Describe(registry::parse_command_query(parse)?),
+            _ => return Err(ParseError::UnknownCommand),
+        };
+
+        Ok(command)
+    }
+
+    /// Carry out the command against the store, producing the reply for the client.
+    ///
+    /// Commands that change what the connection is doing (SUBSCRIBE, MULTI & co.) are the connection
+    /// loop's business; handed one of those, we refuse it.
+    pub fn apply(self, db: &Db) -> Result<Frame, CommandError> {
+        db.with_shared_access(|| self.apply_within(db))
+    }
+
+    /// `apply`, for a caller that already has the access to the store it needs: as an ordinary command,
+    /// as part of a transaction, or from a `CommandHandler`.  Marks the keys the command writes as
+    /// changed, for WATCH -- unless it turned out to change nothing.
+    pub fn apply_within(self, db: &Db) -> Result<Frame, CommandError> {
+        let written = self.written_keys();
+        let unchanged = self.unchanged_reply();
+        let custom = matches!(self, Command::Custom { .. });
+        let reply = self.reply(db);
+        // our own commands check everything before changing anything, so one that failed changed
+        // nothing; a custom one might have got halfway
+        let changed = match &reply {
+            Ok(frame) => unchanged.as_ref() != Some(frame),
+            Err(_) => custom,
+        };
+        if changed {
+            db.touch(written.iter().map(String::as_str));
+        }
+        reply
+    }
+
+    fn reply(self, db: &Db) -> Result<Frame, CommandError> {
+        use Command::*;
+
+        let reply = match self {
+            Get { key } => match db.get(&key)? {
+                Some(value) => Frame::Bulk(value),
+                None => Frame::Null,
+            },
+            Set { key,
+                  value,
+                  lifetime,
+                  condition,
+                  get, } => {
+                let lifetime = lifetime.resolve("set")?;
+                let (written, previous) = db.set_with(key, value, lifetime, condition, get)?;
+                match (get, written) {
+                    (true, _) => previous.map_or(Frame::Null, Frame::Bulk),
+                    (false, true) => Frame::ok(),
+                    (false, false) => Frame::Null,
+                }
+            }
+            MGet { keys } => Frame::Array(db.mget(&keys)
+                                             .into_iter()
+                                             .map(|value| value.map_or(Frame::Null, Frame::Bulk))
+                                             .collect()),
+            MSet { pairs, nx: false } => {
+                db.mset(pairs);
+                Frame::ok()
+            }
+            MSet { pairs, nx: true } => Frame::Integer(db.msetnx(pairs) as i64),
+            GetDel { key } => db.get_del(&key)?.map_or(Frame::Null, Frame::Bulk),
+            GetEx { key, lifetime } => {
+                let lifetime = lifetime.resolve("getex")?;
+                db.get_ex(&key, lifetime)?.map_or(Frame::Null, Frame::Bulk)
+            }
+            Append { key, value } => Frame::Integer(db.append(&key, &value)? as i64),
+            Strlen { key } => Frame::Integer(db.strlen(&key)? as i64),
+            GetRange { key, start, end } => Frame::Bulk(db.get_range(&key, start, end)?),
+            SetRange { key, offset, value } => {
+                Frame::Integer(db.set_range(&key, offset, &value)? as i64)
+            }
+            Expire { key,
+                     when,
+                     condition, } => {
+                let deadline = when.deadline().ok_or_else(|| {
+                                                   CommandError::Other(format!("invalid expire time in '{}' command",
+                                                                               when.command_name()))
+                                               })?;
+                Frame::Integer(db.expire_at(&key, deadline, condition) as i64)
+            }
+            Ttl { key, millis } => match db.ttl(&key) {
+                db::Ttl::Missing => Frame::Integer(-2),
+                db::Ttl::Persistent => Frame::Integer(-1),
+                db::Ttl::Expires(left) if millis => Frame::Integer(left.as_millis() as i64),
+                // rounded to the nearest second, as redis does
+                db::Ttl::Expires(left) => Frame::Integer(((left.as_millis() + 500) / 1000) as i64),
+            },
+            Persist { key } => Frame::Integer(db.persist(&key) as i64),
+            IncrBy { key, delta } => Frame::Integer(db.incr_by(&key, delta)?),
+            IncrByFloat { key, delta } => Frame::Bulk(db.incr_by_float(&key, delta)?),
+            SetBit { key, offset, on } => Frame::Integer(db.setbit(&key, offset, on)? as i64),
+            GetBit { key, offset } => Frame::Integer(db.getbit(&key, offset)? as i64),
+            BitCount { key, range } => Frame::Integer(db.bitcount(&key, range)? as i64),
+            BitPos { key, bit, range } => Frame::Integer(db.bitpos(&key, bit, range)?),
+            BitOp { op,
+                    destination,
+                    keys, } => Frame::Integer(db.bitop(op, &destination, &keys)? as i64),
+            BitField { key, ops, .. } => Frame::Array(db.bitfield(&key, &ops)?
+                                                        .into_iter()
+                                                        .map(|reply| reply.map_or(Frame::Null, Frame::Integer))
+                                                        .collect()),
+            Push { key, elements, end } => Frame::Integer(db.push(&key, elements, end)? as i64),
+            Pop { key,
+                  end,
+                  count: None, } => match db.pop(&key, end, 1)? {
+                Some(mut popped) => popped.pop().map_or(Frame::Null, Frame::Bulk),
+                None => Frame::Null,
+            },
+            Pop { key,
+                  end,
+                  count: Some(count), } => match db.pop(&key, end, count)? {
+                Some(popped) => bulk_array(popped),
+                None => Frame::Null,
+            },
+            // Applied directly (as inside a transaction), a blocking command doesn't wait.
+            BPop { keys, end, .. } => {
+                for key in keys {
+                    if let Some(mut popped) = db.pop(&key, end, 1)? {
+                        let element = popped.pop().expect("A list holds at least one element.");
+                        return Ok(Frame::Array(vec![Frame::bulk(key), Frame::Bulk(element)]));
+                    }
+                }
+                Frame::Null
+            }
+            LMove { source,
+                    destination,
+                    from,
+                    to,
+                    .. } => db.lmove(&source, &destination, from, to)?
+                              .map_or(Frame::Null, Frame::Bulk),
+            LRange { key, start, stop } => bulk_array(db.lrange(&key, start, stop)?),
+            LLen { key } => Frame::Integer(db.llen(&key)? as i64),
+            LIndex { key, index } => db.lindex(&key, index)?.map_or(Frame::Null, Frame::Bulk),
+            HSet { key, pairs, hmset } => {
+                let added = db.hset(&key, pairs)?;
+                match hmset {
+                    true => Frame::ok(),
+                    false => Frame::Integer(added as i64),
+                }
+            }
+            HSetNx { key, field, value } => Frame::Integer(db.hsetnx(&key, field, value)? as i64),
+            HGet { key, field } => first(db.hmget(&key, &[field])?).map_or(Frame::Null, Frame::Bulk),
+            HMGet { key, fields } => Frame::Array(db.hmget(&key, &fields)?
+                                                    .into_iter()
+                                                    .map(|value| value.map_or(Frame::Null, Frame::Bulk))
+                                                    .collect()),
+            HGetAll { key } => Frame::Map(db.hgetall(&key)?
+                                            .into_iter()
+                                            .map(|(field, value)| (Frame::Bulk(field), Frame::Bulk(value)))
+                                            .collect()),
+            HKeys { key } => bulk_array(db.hgetall(&key)?.into_iter().map(|(field, _)| field)),
+            HVals { key } => bulk_array(db.hgetall(&key)?.into_iter().map(|(_, value)| value)),
+            HDel { key, fields } => Frame::Integer(db.hdel(&key, &fields)? as i64),
+            HExists { key, field } => Frame::Integer(first(db.hmget(&key, &[field])?).is_some() as i64),
+            HLen { key } => Frame::Integer(db.hlen(&key)? as i64),
+            HStrlen { key, field } => {
+                Frame::Integer(first(db.hmget(&key, &[field])?).map_or(0, |value| value.len()) as i64)
+            }
+            HIncrBy { key, field, delta } => Frame::Integer(db.hincr_by(&key, field, delta)?),
+            HIncrByFloat { key, field, delta } => Frame::Bulk(db.hincr_by_float(&key, field, delta)?),
+            HScan { key,
+                    cursor,
+                    pattern,
+                    count,
+                    novalues, } => {
+                let (next, fields) = db.hscan(&key, cursor, count, pattern.as_deref())?;
+                let fields = fields.into_iter().flat_map(|(field, value)| {
+                                                   std::iter::once(field).chain((!novalues).then_some(value))
+                                               });
+                Frame::Array(vec![Frame::bulk(next.to_string()), bulk_array(fields)])
+            }
+            SAdd { key, members } => Frame::Integer(db.sadd(&key, members)? as i64),
+            SRem { key, members } => Frame::Integer(db.srem(&key, &members)? as i64),
+            SMembers { key } => bulk_set(db.smembers(&key)?),
+            SIsMember { key, member } => Frame::Integer(first(db.smismember(&key, &[member])?) as i64),
+            SMIsMember { key, members } => Frame::Array(db.smismember(&key, &members)?
+                                                          .into_iter()
+                                                          .map(|is_member| Frame::Integer(is_member as i64))
+                                                          .collect()),
+            SCard { key } => Frame::Integer(db.scard(&key)? as i64),
+            SCombine { op, keys } => bulk_set(db.set_op(op, &keys)?),
+            SCombineStore { op,
+                         destination,
+                         keys, } => Frame::Integer(db.set_op_store(op, &destination, &keys)? as i64),
+            SScan { key,
+                    cursor,
+                    pattern,
+                    count, } => {
+                let (next, members) = db.sscan(&key, cursor, count, pattern.as_deref())?;
+                Frame::Array(vec![Frame::bulk(next.to_string()), bulk_array(members)])
+            }
+            ZAdd { key,
+                   members,
+                   options,
+                   incr: false, } => Frame::Integer(db.zadd(&key, members, options)? as i64),
+            ZAdd { key,
+                   members,
+                   options,
+                   incr: true, } => {
+                let (delta, member) = first(members);
+                db.zincr_by(&key, member, delta, options)?
+                  .map_or(Frame::Null, score_frame)
+            }
+            ZIncrBy { key, delta, member } => {
+                score_frame(db.zincr_by(&key, member, delta, ZAddOptions::default())?
+                              .expect("No options to refuse an increment."))
+            }
+            ZRange { key,
+                     range,
+                     rev,
+                     offset,
+                     count,
+                     withscores, } => scored_array(db.zrange(&key, &range, rev, offset, count)?, withscores),
+            ZRank { key,
+                    member,
+                    rev,
+                    withscore, } => match db.zrank(&key, &member, rev)? {
+                None => Frame::Null,
+                Some((rank, _)) if !withscore => Frame::Integer(rank as i64),
+                Some((rank, score)) => Frame::Array(vec![Frame::Integer(rank as i64), score_frame(score)]),
+            },
+            ZScore { key, member } => db.zscore(&key, &member)?.map_or(Frame::Null, score_frame),
+            ZCard { key } => Frame::Integer(db.zcard(&key)? as i64),
+            ZRem { key, members } => Frame::Integer(db.zrem(&key, &members)? as i64),
+            ZPop { key, rev, count } => scored_array(db.zpop(&key, count.unwrap_or(1), rev)?, true),
+            XAdd { key,
+                   id,
+                   fields,
+                   trim,
+                   create, } => db.xadd(&key, id, fields, trim, create)?
+                                  .map_or(Frame::Null, |id| Frame::bulk(id.to_string())),
+            XRange { key,
+                     start,
+                     end,
+                     rev,
+                     count, } => Frame::Array(db.xrange(&key, start, end, rev, count)?.into_iter().map(stream_entry).collect()),
+            XLen { key } => Frame::Integer(db.xlen(&key)? as i64),
+            XTrim { key, trim } => Frame::Integer(db.xtrim(&key, trim)? as i64),
+            XRead { streams, count, .. } => streams_read(db.xread(&streams, count)?, stream_entry),
+            XGroupCreate { key,
+                           group,
+                           id,
+                           mkstream, } => {
+                db.xgroup_create(&key, group, id, mkstream)?;
+                Frame::ok()
+            }
+            XGroupDestroy { key, group } => Frame::Integer(db.xgroup_destroy(&key, &group)? as i64),
+            XGroupSetId { key, group, id } => {
+                db.xgroup_set_id(&key, &group, id)?;
+                Frame::ok()
+            }
+            XGroupCreateConsumer { key,
+                                   group,
+                                   consumer, } => {
+                Frame::Integer(db.xgroup_create_consumer(&key, &group, consumer)? as i64)
+            }
+            XGroupDelConsumer { key,
+                                group,
+                                consumer, } => {
+                Frame::Integer(db.xgroup_del_consumer(&key, &group, &consumer)? as i64)
+            }
+            XReadGroup { group,
+                         consumer,
+                         streams,
+                         count,
+                         noack,
+                         .. } => {
+                streams_read(db.xreadgroup(&group, &consumer, &streams, count, noack)?,
+                             group_entry)
+            }
+            XAck { key, group, ids } => Frame::Integer(db.xack(&key, &group, &ids)? as i64),
+            XPendingSummary { key, group } => {
+                let summary = db.xpending_summary(&key, &group)?;
+                let (first, last) = match summary.range {
+                    Some((first, last)) => (Frame::bulk(first.to_string()), Frame::bulk(last.to_string())),
+                    None => (Frame::Null, Frame::Null),
+                };
+                let consumers = match summary.consumers.is_empty() {
+                    true => Frame::Null,
+                    false => Frame::Array(summary.consumers
+                                                 .into_iter()
+                                                 .map(|(name, count)| {
+                                                     bulk_array([name, Bytes::from(count.to_string())])
+                                                 })
+                                                 .collect()),
+                };
+                Frame::Array(vec![Frame::Integer(summary.count as i64), first, last, consumers])
+            }
+            XPending { key,
+                       group,
+                       start,
+                       end,
+                       count,
+                       consumer,
+                       min_idle, } => {
+                let pending = db.xpending(&key, &group, start, end, count, consumer.as_deref(), min_idle)?;
+                Frame::Array(pending.into_iter()
+                                    .map(|info| {
+                                        Frame::Array(vec![Frame::bulk(info.id.to_string()),
+                                                          Frame::Bulk(info.consumer),
+                                                          Frame::Integer(info.idle as i64),
+                                                          Frame::Integer(info.deliveries as i64)])
+                                    })
+                                    .collect())
+            }
+            XClaim { key,
+                     group,
+                     consumer,
+                     ids,
+                     options, } => {
+                let claimed = db.xclaim(&key, &group, consumer, &ids, options)?;
+                match options.just_id {
+                    true => bulk_array(claimed.into_iter().map(|(id, _)| Bytes::from(id.to_string()))),
+                    false => Frame::Array(claimed.into_iter().map(stream_entry).collect()),
+                }
+            }
+            PfAdd { key, elements } => Frame::Integer(db.pfadd(&key, &elements)? as i64),
+            PfCount { keys } => Frame::Integer(db.pfcount(&keys)? as i64),
+            PfMerge { destination, sources } => {
+                db.pfmerge(&destination, &sources)?;
+                Frame::ok()
+            }
+            Del { keys } => Frame::Integer(db.del(&keys) as i64),
+            Exists { keys } => Frame::Integer(db.exists(&keys) as i64),
+            Type { key } => Frame::Simple(db.key_type(&key).unwrap_or("none").to_string()),
+            Keys { pattern } => Frame::Array(db.keys(&pattern).into_iter().map(Frame::bulk).collect()),
+            Scan { cursor,
+                   pattern,
+                   count,
+                   kind, } => {
+                let (next, keys) = db.scan(cursor, count, pattern.as_deref(), kind.as_deref());
+                Frame::Array(vec![Frame::bulk(next.to_string()),
+                                  Frame::Array(keys.into_iter().map(Frame::bulk).collect())])
+            }
+            RandomKey => db.random_key().map_or(Frame::Null, Frame::bulk),
+            Publish { channel, message } => Frame::Integer(db.publish(&channel, message) as i64),
+            Ping { message: None } => Frame::Simple("PONG".to_string()),
+            Ping { message: Some(msg) } => Frame::Bulk(msg),
+            Custom { handler, args, .. } => {
+                let args = Frame::Array(args.into_iter().map(Frame::Bulk).collect());
+                let mut args = Parse::new(args).expect("Arguments make an array.");
+                handler.call(db, &mut args)?
+            }
+            cmd @ (Subscribe { .. }
+                   | Unsubscribe { .. }
+                   | Multi
+                   | Exec
+                   | Discard
+                   | Watch { .. }
+                   | Unwatch
+                   | Hello { .. }
+                   | Describe(_)) => {
+                return Err(CommandError::Other(format!("'{}' is handled by the connection, not the store",
+                                                       cmd.name())))
+            }
+        };
+        Ok(reply)
+    }
+
+    /// Carry out the command, waiting as long as it takes if it's one that blocks (BLPOP & co.).
+    ///
+    /// The future may be dropped at any point -- when the client hangs up, say -- without losing data.
+    pub async fn execute(self, db: &Db) -> Result<Frame, CommandError> {
+        use Command::*;
+
+        let reply = match self {
+            BPop { keys, end, timeout } => match db.blocking_pop(&keys, end, timeout).await? {
+                Some((key, element)) => Frame::Array(vec![Frame::bulk(key), Frame::Bulk(element)]),
+                None => Frame::Null,
+            },
+            LMove { source,
+                    destination,
+                    from,
+                    to,
+                    timeout: Some(timeout), } => {
+                db.blocking_move(&source, &destination, from, to, timeout)
+                  .await?
+                  .map_or(Frame::Null, Frame::Bulk)
+            }
+            XRead { streams,
+                    count,
+                    block: Some(timeout), } => {
+                streams_read(db.blocking_xread(&streams, count, timeout).await?,
+                             stream_entry)
+            }
+            XReadGroup { group,
+                         consumer,
+                         streams,
+                         count,
+                         block: Some(timeout),
+                         noack, } => streams_read(db.blocking_xreadgroup(&group, &consumer,
+                                                                         &streams, count, noack,
+                                                                         timeout)
+                                                    .await?,
+                                                  group_entry),
+            cmd => return cmd.apply(db),
+        };
+        Ok(reply)
+    }
+
+    /// Lowercase name of the command, as it is spelled on the wire.
+    pub fn name(&self) -> &str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Set { .. } => "set",
+            Command::GetDel { .. } => "getdel",
+            Command::MGet { .. } => "mget",
+            Command::MSet { nx: false, .. } => "mset",
+            Command::MSet { nx: true, .. } => "msetnx",
+            Command::GetEx { .. } => "getex",
+            Command::Append { .. } => "append",
+            Command::Strlen { .. } => "strlen",
+            Command::GetRange { .. } => "getrange",
+            Command::SetRange { .. } => "setrange",
+            Command::Expire { when, .. } => when.command_name(),
+            Command::Ttl { millis: false, .. } => "ttl",
+            Command::Ttl { millis: true, .. } => "pttl",
+            Command::Persist { .. } => "persist",
+            Command::IncrBy { .. } => "incrby",
+            Command::IncrByFloat { .. } => "incrbyfloat",
+            Command::SetBit { .. } => "setbit",
+            Command::GetBit { .. } => "getbit",
+            Command::BitCount { .. } => "bitcount",
+            Command::BitPos { .. } => "bitpos",
+            Command::BitOp { .. } => "bitop",
+            Command::BitField { read_only: false, .. } => "bitfield",
+            Command::BitField { read_only: true, .. } => "bitfield_ro",
+            Command::Push { end: End::Left, .. } => "lpush",
+            Command::Push { end: End::Right, .. } => "rpush",
+            Command::Pop { end: End::Left, .. } => "lpop",
+            Command::Pop { end: End::Right, .. } => "rpop",
+            Command::BPop { end: End::Left, .. } => "blpop",
+            Command::BPop { end: End::Right, .. } => "brpop",
+            Command::LMove { timeout: None, .. } => "lmove",
+            Command::LMove { timeout: Some(_), .. } => "blmove",
+            Command::LRange { .. } => "lrange",
+            Command::LLen { .. } => "llen",
+            Command::LIndex { .. } => "lindex",
+            Command::HSet { hmset: false, .. } => "hset",
+            Command::HSet { hmset: true, .. } => "hmset",
+            Command::HSetNx { .. } => "hsetnx",
+            Command::HGet { .. } => "hget",
+            Command::HMGet { .. } => "hmget",
+            Command::HGetAll { .. } => "hgetall",
+            Command::HKeys { .. } => "hkeys",
+            Command::HVals { .. } => "hvals",
+            Command::HDel { .. } => "hdel",
+            Command::HExists { .. } => "hexists",
+            Command::HLen { .. } => "hlen",
+            Command::HStrlen { .. } => "hstrlen",
+            Command::HIncrBy { .. } => "hincrby",
+            Command::HIncrByFloat { .. } => "hincrbyfloat",
+            Command::HScan { .. } => "hscan",
+            Command::SAdd { .. } => "sadd",
+            Command::SRem { .. } => "srem",
+            Command::SMembers { .. } => "smembers",
+            Command::SIsMember { .. } => "sismember",
+            Command::SMIsMember { .. } => "smismember",
+            Command::SCard { .. } => "scard",
+            Command::SCombine { op: SetOp::Inter, .. } => "sinter",
+            Command::SCombine { op: SetOp::Union, .. } => "sunion",
+            Command::SCombine { op: SetOp::Diff, .. } => "sdiff",
+            Command::SCombineStore { op: SetOp::Inter, .. } => "sinterstore",
+            Command::SCombineStore { op: SetOp::Union, .. } => "sunionstore",
+            Command::SCombineStore { op: SetOp::Diff, .. } => "sdiffstore",
+            Command::SScan { .. } => "sscan",
+            Command::ZAdd { .. } => "zadd",
+            Command::ZIncrBy { .. } => "zincrby",
+            Command::ZRange { .. } => "zrange",
+            Command::ZRank { rev: false, .. } => "zrank",
+            Command::ZRank { rev: true, .. } => "zrevrank",
+            Command::ZScore { .. } => "zscore",
+            Command::ZCard { .. } => "zcard",
+            Command::ZRem { .. } => "zrem",
+            Command::ZPop { rev: false, .. } => "zpopmin",
+            Command::ZPop { rev: true, .. } => "zpopmax",
+            Command::XAdd { .. } => "xadd",
+            Command::XRange { rev: false, .. } => "xrange",
+            Command::XRange { rev: true, .. } => "xrevrange",
+            Command::XLen { .. } => "xlen",
+            Command::XTrim { .. } => "xtrim",
+            Command::XRead { .. } => "xread",
+            Command::XGroupCreate { .. }
+            | Command::XGroupDestroy { .. }
+            | Command::XGroupSetId { .. }
+            | Command::XGroupCreateConsumer { .. }
+            | Command::XGroupDelConsumer { .. } => "xgroup",
+            Command::XReadGroup { .. } => "xreadgroup",
+            Command::XAck { .. } => "xack",
+            Command::XPendingSummary { .. } | Command::XPending { .. } => "xpending",
+            Command::XClaim { .. } => "xclaim",
+            Command::PfAdd { .. } => "pfadd",
+            Command::PfCount { .. } => "pfcount",
+            Command::PfMerge { .. } => "pfmerge",
+            Command::Del { .. } => "del",
+            Command::Exists { .. } => "exists",
+            Command::Type { .. } => "type",
+            Command::Keys { .. } => "keys",
+            Command::Scan { .. } => "scan",
+            Command::RandomKey => "randomkey",
+            Command::Publish { .. } => "publish",
+            Command::Subscribe { .. } => "subscribe",
+            Command::Unsubscribe { .. } => "unsubscribe",
+            Command::Ping { .. } => "ping",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch { .. } => "watch",
+            Command::Unwatch => "unwatch",
+            Command::Hello { .. } => "hello",
+            Command::Describe(_) => "command",
+            Command::Custom { spec, .. } => &spec.name,
+        }
+    }
+
+    /// The reply meaning that the command changed nothing (SET NX finding the key taken, LPOP finding
+    /// no list, DEL finding no keys...), for those whose reply can tell.
+    fn unchanged_reply(&self) -> Option<Frame> {
+        use Command::*;
+
+        match self {
+            Set { get: false, .. }
+            | GetDel { .. }
+            | GetEx { .. }
+            | Pop { .. }
+            | BPop { .. }
+            | LMove { .. }
+            | ZAdd { incr: true, .. }
+            | XAdd { .. } => Some(Frame::Null),
+            MSet { nx: true, .. }
+            | Expire { .. }
+            | Persist { .. }
+            | HSetNx { .. }
+            | HDel { .. }
+            | SAdd { .. }
+            | SRem { .. }
+            | ZRem { .. }
+            | XTrim { .. }
+            | XGroupDestroy { .. }
+            | XGroupCreateConsumer { .. }
+            | XAck { .. }
+            | PfAdd { .. }
+            | Del { .. } => Some(Frame::Integer(0)),
+            ZPop { .. } => Some(Frame::Array(Vec::new())),
+            _ => None,
+        }
+    }
+
+    /// Keys the command may change, so that connections WATCHing them can tell.
+    fn written_keys(&self) -> Vec<String> {
+        use Command::*;
+
+        match self {
+            Set { key, .. }
+            | GetDel { key }
+            | GetEx { key, .. }
+            | Append { key, .. }
+            | SetRange { key, .. }
+            | Expire { key, .. }
+            | Persist { key }
+            | IncrBy { key, .. }
+            | IncrByFloat { key, .. }
+            | SetBit { key, .. }
+            | BitField { key,
+                       read_only: false,
+                       .. }
+            | Push { key, .. }
+            | Pop { key, .. }
+            | HSet { key, .. }
+            | HSetNx { key, .. }
+            | HDel { key, .. }
+            | HIncrBy { key, .. }
+            | HIncrByFloat { key, .. }
+            | SAdd { key, .. }
+            | SRem { key, .. }
+            | ZAdd { key, .. }
+            | ZIncrBy { key, .. }
+            | ZRem { key, .. }
+            | ZPop { key, .. }
+            | XAdd { key, .. }
+            | XTrim { key, .. }
+            | XGroupCreate { key, .. }
+            | XGroupDestroy { key, .. }
+            | XGroupSetId { key, .. }
+            | XGroupCreateConsumer { key, .. }
+            | XGroupDelConsumer { key, .. }
+            | XAck { key, .. }
+            | XClaim { key, .. }
+            | PfAdd { key, .. } => vec![key.clone()],
+            BitOp { destination, .. }
+            | SCombineStore { destination, .. }
+            | PfMerge { destination, .. } => vec![destination.clone()],
+            MSet { pairs, .. } => pairs.iter().map(|(key, _)| key.clone()).collect(),
+            BPop { keys, .. } | Del { keys } => keys.clone(),
+            LMove { source,
+                    destination,
+                    .. } => vec![source.clone(), destination.clone()],
+            XReadGroup { streams, .. } => streams.iter().map(|(key, _)| key.clone()).collect(),
+            Custom { spec, args, .. } if spec.flags.contains(CommandFlags::WRITE) => {
+                spec.keys(args)
+                    .map(|key| String::from_utf8_lossy(key).into_owned())
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Expiry {
+    /// The command that spells a deadline this way.
+    pub fn command_name(&self) -> &'static str {
+        match self {
+            Expiry::Seconds(_) => "expire",
+            Expiry::Millis(_) => "pexpire",
+            Expiry::UnixSeconds(_) => "expireat",
+            Expiry::UnixMillis(_) => "pexpireat",
+        }
+    }
+
+    /// The deadline as an `Instant`.  (Deadlines already past come out as "now".)
+    ///
+    /// `None` if the number given doesn't fit in a millisecond timestamp.
+    pub fn deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let relative_ms = match *self {
+            Expiry::Seconds(secs) => secs.checked_mul(1000)?,
+            Expiry::Millis(ms) => ms,
+            Expiry::UnixSeconds(secs) => secs.checked_mul(1000)?.checked_sub(unix_millis_now())?,
+            Expiry::UnixMillis(ms) => ms.checked_sub(unix_millis_now())?,
+        };
+        match u64::try_from(relative_ms) {
+            Ok(ms) => now.checked_add(Duration::from_millis(ms)),
+            Err(_) => Some(now),
+        }
+    }
+}
+
+impl TtlChange {
+    /// What to do with the key's deadline, now.  Fails if an expire time overflows.
+    fn resolve(self, command: &str) -> Result<Lifetime, CommandError> {
+        match self {
+            TtlChange::Persist => Ok(Lifetime::Persistent),
+            TtlChange::Keep => Ok(Lifetime::Keep),
+            TtlChange::Expire(expiry) => expiry.deadline().map(Lifetime::Until).ok_or_else(|| {
+                CommandError::Other(format!("invalid expire time in '{}' command", command))
+            }),
+        }
+    }
+}
+
+/// Current unix time, in milliseconds.
+fn unix_millis_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+                     .map(|d| d.as_millis() as i64)
+                     .unwrap_or(0)
+}
+
+/// `SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+/// PXAT unix-time-milliseconds | KEEPTTL]`
+fn parse_set(parse: &mut Parse) -> Result<Command, ParseError> {
+    let key = parse.next_string()?;
+    let value = parse.next_bytes()?;
+
+    let (mut lifetime, mut condition, mut get) = (None, None, false);
+    while !parse.is_empty() {
+        let option = parse.next_string()?.to_uppercase();
+        match &option[..] {
+            "NX" | "XX" if condition.is_none() => {
+                condition = Some(match &option[..] {
+                    "NX" => SetCondition::Nx,
+                    _ => SetCondition::Xx,
+                })
+            }
+            "GET" => get = true,
+            "KEEPTTL" if lifetime.is_none() => lifetime = Some(TtlChange::Keep),
+            _ if lifetime.is_none() => {
+                lifetime = Some(TtlChange::Expire(parse_expire_option(&option, parse, "set")?))
+            }
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+
+    Ok(Command::Set { key,
+                      value,
+                      lifetime: lifetime.unwrap_or(TtlChange::Persist),
+                      condition,
+                      get })
+}
+
+/// `GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds | PXAT unix-time-milliseconds |
+/// PERSIST]`
+fn parse_getex(parse: &mut Parse) -> Result<Command, ParseError> {
+    let key = parse.next_string()?;
+    let lifetime = match parse.is_empty() {
+        true => TtlChange::Keep,
+        false => match &parse.next_string()?.to_uppercase()[..] {
+            "PERSIST" => TtlChange::Persist,
+            option => TtlChange::Expire(parse_expire_option(option, parse, "getex")?),
+        },
+    };
+    if !parse.is_empty() {
+        return Err(ParseError::Syntax);
+    }
+    Ok(Command::GetEx { key, lifetime })
+}
+
+/// The time following an EX, PX, EXAT or PXAT option (already uppercased).
+fn parse_expire_option(option: &str,
+                       parse: &mut Parse,
+                       command: &str)
+                       -> Result<Expiry, ParseError> {
+    let unit: fn(i64) -> Expiry = match option {
+        "EX" => Expiry::Seconds,
+        "PX" => Expiry::Millis,
+        "EXAT" => Expiry::UnixSeconds,
+        "PXAT" => Expiry::UnixMillis,
+        _ => return Err(ParseError::Syntax),
+    };
+    if parse.is_empty() {
+        return Err(ParseError::Syntax);
+    }
+    match parse.next_int()? {
+        n if n > 0 => Ok(unit(n)),
+        _ => Err(format!("invalid expire time in '{}' command", command).into()),
+    }
+}
+
+/// A bit offset, which must fall within the longest string allowed.
+fn parse_bit_offset(data: &[u8]) -> Result<u64, ParseError> {
+    bit_offset(parse_int(data))
+}
+
+/// A BITFIELD offset: in bits or, after a `#`, in fields of type `ty`.
+fn parse_bitfield_offset(data: &[u8], ty: BitFieldType) -> Result<u64, ParseError> {
+    match data.strip_prefix(b"#") {
+        Some(index) => {
+            bit_offset(parse_int(index).and_then(|index| index.checked_mul(ty.bits as i64)))
+        }
+        None => parse_bit_offset(data),
+    }
+}
+
+fn bit_offset(offset: Option<i64>) -> Result<u64, ParseError> {
+    offset.and_then(|offset| u64::try_from(offset).ok())
+          .filter(|offset| offset / 8 < db::MAX_STRING_LEN as u64)
+          .ok_or_else(|| "bit offset is not an integer or out of range".into())
+}
+
+/// `[start [end [BYTE | BIT]]]`, as BITCOUNT (where `end` must come with `start`) and BITPOS take it.
+fn parse_bit_range(parse: &mut Parse, end_required: bool) -> Result<Option<BitRange>, ParseError> {
+    if parse.is_empty() {
+        return Ok(None);
+    }
+    let start = parse.next_int()?;
+    let end = match parse.is_empty() {
+        true if end_required => return Err(ParseError::Syntax),
+        true => None,
+        false => Some(parse.next_int()?),
+    };
+    let unit = match parse.is_empty() {
+        true => BitUnit::Byte,
+        false => match &parse.next_string()?.to_uppercase()[..] {
+            "BYTE" => BitUnit::Byte,
+            "BIT" => BitUnit::Bit,
+            _ => return Err(ParseError::Syntax),
+        },
+    };
+    if !parse.is_empty() {
+        return Err(ParseError::Syntax);
+    }
+    Ok(Some(BitRange { start, end, unit }))
+}
+
+/// `BITOP AND | OR | XOR | NOT destkey key [key ...]`
+fn parse_bitop(parse: &mut Parse) -> Result<Command, ParseError> {
+    let op = match &parse.next_string()?.to_uppercase()[..] {
+        "AND" => db::BitOp::And,
+        "OR" => db::BitOp::Or,
+        "XOR" => db::BitOp::Xor,
+        "NOT" => db::BitOp::Not,
+        _ => return Err(ParseError::Syntax),
+    };
+    let destination = parse.next_string()?;
+    let keys = at_least_one(remaining_strings(parse)?)?;
+    if op == db::BitOp::Not && keys.len() != 1 {
+        return Err("BITOP NOT must be called with a single source key.".into());
+    }
+    Ok(Command::BitOp { op,
+                        destination,
+                        keys })
+}
+
+/// `BITFIELD key [GET type offset | [OVERFLOW WRAP | SAT | FAIL] SET type offset value |
+/// [OVERFLOW WRAP | SAT | FAIL] INCRBY type offset increment ...]`, or BITFIELD_RO with only GETs
+fn parse_bitfield(parse: &mut Parse, read_only: bool) -> Result<Command, ParseError> {
+    let key = parse.next_string()?;
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::default();
+    while !parse.is_empty() {
+        let subcommand = parse.next_string()?.to_uppercase();
+        if read_only && subcommand != "GET" {
+            return Err("BITFIELD_RO only supports the GET subcommand".into());
+        }
+        if subcommand == "OVERFLOW" {
+            overflow = match &parse.next_string()?.to_uppercase()[..] {
+                "WRAP" => Overflow::Wrap,
+                "SAT" => Overflow::Sat,
+                "FAIL" => Overflow::Fail,
+                _ => return Err("Invalid OVERFLOW type specified".into()),
+            };
+            continue;
+        }
+        let ty = parse_bitfield_type(&parse.next_bytes()?)?;
+        let offset = parse_bitfield_offset(&parse.next_bytes()?, ty)?;
+        ops.push(match &subcommand[..] {
+               "GET" => BitFieldOp::Get { ty, offset },
+               "SET" => BitFieldOp::Set { ty,
+                                          offset,
+                                          value: parse.next_int()?,
+                                          overflow },
+               "INCRBY" => BitFieldOp::IncrBy { ty,
+                                                offset,
+                                                delta: parse.next_int()?,
+                                                overflow },
+               _ => return Err(ParseError::Syntax),
+           });
+    }
+    Ok(Command::BitField { key,
+                           ops,
+                           read_only })
+}
+
+/// A BITFIELD type: `i1` to `i64`, or `u1` to `u63`.
+fn parse_bitfield_type(data: &[u8]) -> Result<BitFieldType, ParseError> {
+    let ty = match data.split_first() {
+        Some((b'i' | b'I', bits)) => parse_int(bits).map(|bits| (true, bits)),
+        Some((b'u' | b'U', bits)) => parse_int(bits).map(|bits| (false, bits)),
+        _ => None,
+    };
+    match ty {
+        Some((signed, bits @ 1..=64)) if signed || bits < 64 => Ok(BitFieldType { signed,
+                                                                                  bits: bits
+                                                                                        as u32 }),
+        _ => {
+            let message = "Invalid bitfield type. Use something like i16 u8. Note that u64 is not \
+                           supported but i64 is.";
+            Err(message.into())
+        }
+    }
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+fn parse_hello(parse: &mut Parse) -> Result<Command, ParseError> {
+    if parse.is_empty() {
+        return Ok(Command::Hello { protocol:    None,
+                                   auth:        None,
+                                   client_name: None, });
+    }
+    let protocol = parse.next_int()
+                        .map_err(|_| "Protocol version is not an integer or out of range")?;
+    let (mut auth, mut client_name) = (None, None);
+    while !parse.is_empty() {
+        let option = parse.next_string()?;
+        match &option.to_uppercase()[..] {
+            "AUTH" => auth = Some((parse.next_string()?, parse.next_string()?)),
+            "SETNAME" => client_name = Some(parse.next_string()?),
+            _ => return Err(format!("Syntax error in HELLO option '{}'", option).into()),
+        }
+    }
+    Ok(Command::Hello { protocol: Some(protocol),
+                        auth,
+                        client_name })
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`
+fn parse_scan(parse: &mut Parse) -> Result<Command, ParseError> {
+    let mut kind = None;
+    let (cursor, pattern, count) = parse_scan_args(parse, |option, parse| match option {
+        "TYPE" => {
+            kind = Some(parse.next_string()?);
+            Ok(())
+        }
+        _ => Err(ParseError::Syntax),
+    })?;
+    Ok(Command::Scan { cursor,
+                       pattern,
+                       count,
+                       kind })
+}
+
+/// `HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]`
+fn parse_hscan(parse: &mut Parse) -> Result<Command, ParseError> {
+    let key = parse.next_string()?;
+    let mut novalues = false;
+    let (cursor, pattern, count) = parse_scan_args(parse, |option, _| match option {
+        "NOVALUES" => {
+            novalues = true;
+            Ok(())
+        }
+        _ => Err(ParseError::Syntax),
+    })?;
+    Ok(Command::HScan { key,
+                        cursor,
+                        pattern,
+                        count,
+                        novalues })
+}
+
+/// The arguments every SCAN flavour takes: `cursor [MATCH pattern] [COUNT count]`.
+///
+/// Any other option is passed (uppercased) to `other`, to deal with or refuse.
+fn parse_scan_args(parse: &mut Parse,
+                   mut other: impl FnMut(&str, &mut Parse) -> Result<(), ParseError>)
+                   -> Result<(u64, Option<Bytes>, usize), ParseError> {
+    let cursor = parse.next_string()?
+                      .parse()
+                      .map_err(|_| ParseError::from("invalid cursor"))?;
+    let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+    while !parse.is_empty() {
+        match &parse.next_string()?.to_uppercase()[..] {
+            "MATCH" => pattern = Some(parse.next_bytes()?),
+            "COUNT" => {
+                count = usize::try_from(parse.next_int()?).ok()
+                                                          .filter(|count| *count > 0)
+                                                          .ok_or(ParseError::Syntax)?
+            }
+            option => other(option, parse)?,
+        }
+    }
+    Ok((cursor, pattern, count))
+}
+
+/// `ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]`
+fn parse_zadd(parse: &mut Parse) -> Result<Command, ParseError> {
+    let key = parse.next_string()?;
+    let args = remaining_bytes(parse)?;
+    if args.len() < 2 {
+        return Err(ParseError::EndOfStream);
+    }
+
+    let (mut nx, mut xx, mut gt, mut lt, mut changed, mut incr) = Default::default();
+    let mut scores_from = 0;
+    for arg in &args {
+        match &arg.to_ascii_uppercase()[..] {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"GT" => gt = true,
+            b"LT" => lt = true,
+            b"CH" => changed = true,
+            b"INCR" => incr = true,
+            _ => break,
+        }
+        scores_from += 1;
+    }
+    if nx && xx {
+        return Err("XX and NX options at the same time are not compatible".into());
+    }
+    if [nx, gt, lt].into_iter().filter(|flag| *flag).count() > 1 {
+        return Err("GT, LT, and/or NX options at the same time are not compatible".into());
+    }
+
+    let pairs = &args[scores_from..];
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(ParseError::Syntax);
+    }
+    if incr && pairs.len() != 2 {
+        return Err("INCR option supports a single increment-element pair".into());
+    }
+    let members =
+        pairs.chunks(2)
+             .map(|pair| Ok((parse_score(&pair[0]).ok_or(ParseError::NotFloat)?, pair[1].clone())))
+             .collect::<Result<_, ParseError>>()?;
+    let options = ZAddOptions { condition: match (nx, xx) {
+                                    (true, _) => Some(SetCondition::Nx),
+                                    (_, true) => Some(SetCondition::Xx),
+                                    _ => None,
+                                },
+                                compare: match (gt, lt) {
+                                    (true, _) => Some(ScoreCondition::Gt),
+                                    (_, true) => Some(ScoreCondition::Lt),
+                                    _ => None,
+                                },
+                                changed };
+    Ok(Command::ZAdd { key,
+                       members,
+                       options,
+                       incr })
+}
+
+/// `ZRANGE key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count] [WITHSCORES]`
+fn parse_zrange(parse: &mut Parse) -> Result<Command, ParseError> {
+    let key = parse.next_string()?;
+    let (start, stop) = (parse.next_bytes()?, parse.next_bytes()?);
+
+    let (mut by_score, mut by_lex, mut rev, mut limit, mut withscores) = Default::default();
+    while !parse.is_empty() {
+        match &parse.next_string()?.to_uppercase()[..] {
+            "BYSCORE" => by_score = true,
+            "BYLEX" => by_lex = true,
+            "REV" => rev = true,
+            "LIMIT" => limit = Some((parse.next_int()?, parse.next_int()?)),
+            "WITHSCORES" => withscores = true,
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    if by_score && by_lex {
+        return Err(ParseError::Syntax);
+    }
+    if limit.is_some() && !by_score && !by_lex {
+        return Err("syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".into());
+    }
+    if withscores && by_lex {
+        return Err("syntax error, WITHSCORES not supported in combination with BYLEX".into());
+    }
+
+    // with REV, a score or lex range is given highest first
+    let (min, max) = match rev && (by_score || by_lex) {
+        true => (stop, start),
+        false => (start, stop),
+    };
+    let range = if by_score {
+        ZRange::Score(parse_score_bound(&min)?, parse_score_bound(&max)?)
+    } else if by_lex {
+        ZRange::Lex(parse_lex_bound(min)?, parse_lex_bound(max)?)
+    } else {
+        ZRange::Rank(parse_int(&min).ok_or(ParseError::NotInteger)?,
+                     parse_int(&max).ok_or(ParseError::NotInteger)?)
+    };
+    let (offset, count) = limit.unwrap_or((0, -1));
+    Ok(Command::ZRange { key,
+                         range,
+                         rev,
+                         // a negative offset skips everything; a negative count takes everything
+                         offset: usize::try_from(offset).unwrap_or(usize::MAX),
+                         count: usize::try_from(count).ok(),
+                         withscores })
+}
+
+/// A BYSCORE bound: a score, exclusive if it follows a `(`.
+fn parse_score_bound(data: &[u8]) -> Result<ScoreBound, ParseError> {
+    let (exclusive, score) = match data.split_first() {
+        Some((b'(', rest)) => (true, rest),
+        _ => (false, data),
+    };
+    let score = parse_score(score).ok_or("min or max is not a float")?;
+    Ok(match exclusive {
+        true => ScoreBound::Exclusive(score),
+        false => ScoreBound::Inclusive(score),
+    })
+}
+
+/// A BYLEX bound: `-`, `+`, or a member following `[` (inclusive) or `(` (exclusive).
+fn parse_lex_bound(data: Bytes) -> Result<LexBound, ParseError> {
+    match data.first() {
+        Some(b'-') if data.len() == 1 => Ok(LexBound::Least),
+        Some(b'+') if data.len() == 1 => Ok(LexBound::Greatest),
+        Some(b'[') => Ok(LexBound::Inclusive(data.slice(1..))),
+        Some(b'(') => Ok(LexBound::Exclusive(data.slice(1..))),
+        _ => Err("min or max not valid string range item".into()),
+    }
+}
+
+/// `XADD key [NOMKSTREAM] [MAXLEN | MINID [= | ~] threshold] <* | id> field value [field value ...]`
+fn parse_xadd(parse: &mut Parse) -> Result<Command, ParseError> {
+    let key = parse.next_string()?;
+    let (mut create, mut trim) = (true, None);
+    let id = loop {
+        let arg = parse.next_string()?;
+        match &arg.to_uppercase()[..] {
+            "NOMKSTREAM" => create = false,
+            strategy => match parse_trim(strategy, parse)? {
+                Some(threshold) => trim = Some(threshold),
+                None => break parse_xadd_id(&arg)?,
+            },
+        }
+    };
+
+    let mut fields = Vec::new();
+    while !parse.is_empty() {
+        fields.push((parse.next_bytes()?, parse.next_bytes()?));
+    }
+    Ok(Command::XAdd { key,
+                       id,
+                       fields: at_least_one(fields)?,
+                       trim,
+                       create })
+}
+
+/// XADD's ID: `*`, `<ms>-*` or an ID proper.
+fn parse_xadd_id(arg: &str) -> Result<XAddId, ParseError> {
+    match arg.strip_suffix("-*") {
+        _ if arg == "*" => Ok(XAddId::Auto),
+        Some(ms) => ms.parse()
+                      .map(XAddId::AutoSeq)
+                      .map_err(|_| invalid_stream_id()),
+        None => Ok(XAddId::Explicit(parse_stream_id(arg.as_bytes(), 0)?)),
+    }
+}
+
+/// `MAXLEN [= | ~] threshold` or `MINID [= | ~] threshold`, given the first word (uppercased).
+/// `None` if the word is neither.  Approximate trimming (`~`) is done exactly.
+fn parse_trim(strategy: &str, parse: &mut Parse) -> Result<Option<Trim>, ParseError> {
+    if strategy != "MAXLEN" && strategy != "MINID" {
+        return Ok(None);
+    }
+    let mut threshold = parse.next_bytes()?;
+    if &threshold[..] == b"=" || &threshold[..] == b"~" {
+        threshold = parse.next_bytes()?;
+    }
+    Ok(Some(match strategy {
+        "MAXLEN" => {
+            let max = parse_int(&threshold).ok_or(ParseError::NotInteger)?;
+            Trim::MaxLen(usize::try_from(max).map_err(|_| "The MAXLEN argument must be >= 0.")?)
+        }
+        _ => Trim::MinId(parse_stream_id(&threshold, 0)?),
+    }))
+}
+
+/// `XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]`, or with `group`,
+/// `XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key [key ...]
+/// id [id ...]`
+fn parse_xread(parse: &mut Parse, group: bool) -> Result<Command, ParseError> {
+    let reader = match group {
+        true => match &parse.next_string()?.to_uppercase()[..] {
+            "GROUP" => Some((parse.next_bytes()?, parse.next_bytes()?)),
+            _ => return Err(ParseError::Syntax),
+        },
+        false => None,
+    };
+    let (mut count, mut block, mut noack) = (None, None, false);
+    loop {
+        match &parse.next_string()?.to_uppercase()[..] {
+            // zero or less: no limit
+            "COUNT" => {
+                count = usize::try_from(parse.next_int()?).ok()
+                                                          .filter(|count| *count > 0)
+            }
+            "BLOCK" => {
+                block = match parse.next_int()? {
+                    0 => Some(None),
+                    ms => Some(Some(Duration::from_millis(u64::try_from(ms).map_err(|_| {
+                                                                               "timeout is negative"
+                                                                           })?))),
+                }
+            }
+            "NOACK" if group => noack = true,
+            "STREAMS" => break,
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+
+    let mut args = at_least_one(remaining_bytes(parse)?)?;
+    if args.len() % 2 != 0 {
+        let (command, special) = if group {
+            ("xreadgroup", ">")
+        } else {
+            ("xread", "$")
+        };
+        return Err(format!("Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be \
+                            specified.",
+                           command, special).into());
+    }
+    let ids = args.split_off(args.len() / 2);
+    let streams = args.into_iter()
+                      .zip(ids)
+                      .map(|(key, id)| {
+                          let id = match (&id[..], group) {
+                              (b"$", false) | (b">", true) => None,
+                              (b"$", true) => {
+                                  return Err("The $ ID is meaningless in the context of XREADGROUP: you \
+                                              want to read the history of this consumer by specifying a \
+                                              proper ID, or use the > ID to get new messages. The $ ID \
+                                              would just return an empty result set."
+                                                                                     .into())
+                              }
+                              (id, _) => Some(parse_stream_id(id, 0)?),
+                          };
+                          Ok((String::from_utf8_lossy(&key).into_owned(), id))
+                      })
+                      .collect::<Result<_, ParseError>>()?;
+    Ok(match reader {
+        Some((group, consumer)) => Command::XReadGroup { group,
+                                                         consumer,
+                                                         streams,
+                                                         count,
+                                                         block,
+                                                         noack },
+        None => Command::XRead { streams,
+                                 count,
+                                 block },
+    })
+}
+
+/// `XGROUP CREATE key group <id | $> [MKSTREAM]`, `XGROUP DESTROY key group`, `XGROUP SETID key group
+/// <id | $>`, `XGROUP CREATECONSUMER key group consumer` or `XGROUP DELCONSUMER key group consumer`
+fn parse_xgroup(parse: &mut Parse) -> Result<Command, ParseError> {
+    let subcommand = parse.next_string()?;
+    let group_id = |id: Bytes| match &id[..] {
+        b"$" => Ok(None),
+        id => parse_stream_id(id, 0).map(Some),
+    };
+    let command = match &subcommand.to_uppercase()[..] {
+        "CREATE" => {
+            let (key, group, id) = (parse.next_string()?, parse.next_bytes()?, parse.next_bytes()?);
+            let mkstream = match parse.is_empty() {
+                true => false,
+                false if parse.next_string()?.eq_ignore_ascii_case("MKSTREAM") => true,
+                false => return Err(ParseError::Syntax),
+            };
+            Command::XGroupCreate { key,
+                                    group,
+                                    id: group_id(id)?,
+                                    mkstream }
+        }
+        "DESTROY" => Command::XGroupDestroy { key:   parse.next_string()?,
+                                              group: parse.next_bytes()?, },
+        "SETID" => Command::XGroupSetId { key:   parse.next_string()?,
+                                          group: parse.next_bytes()?,
+                                          id:    group_id(parse.next_bytes()?)?, },
+        "CREATECONSUMER" => Command::XGroupCreateConsumer { key:      parse.next_string()?,
+                                                            group:    parse.next_bytes()?,
+                                                            consumer: parse.next_bytes()?, },
+        "DELCONSUMER" => Command::XGroupDelConsumer { key:      parse.next_string()?,
+                                                      group:    parse.next_bytes()?,
+                                                      consumer: parse.next_bytes()?, },
+        _ => return Err(format!("unknown subcommand '{}'. Try XGROUP HELP.", subcommand).into()),
+    };
+    Ok(command)
+}
+
+/// `XPENDING key group [[IDLE min-idle-time] start end count [consumer]]`
+fn parse_xpending(parse: &mut Parse) -> Result<Command, ParseError> {
+    let (key, group) = (parse.next_string()?, parse.next_bytes()?);
+    if parse.is_empty() {
+        return Ok(Command::XPendingSummary { key, group });
+    }
+    let mut start = parse.next_bytes()?;
+    let mut min_idle = 0;
+    if start.eq_ignore_ascii_case(b"IDLE") {
+        // negative: no minimum
+        min_idle = u64::try_from(parse.next_int()?).unwrap_or(0);
+        start = parse.next_bytes()?;
+    }
+    Ok(Command::XPending { key,
+                           group,
+                           start: parse_range_id(&start, true)?,
+                           end: parse_range_id(&parse.next_bytes()?, false)?,
+                           count: usize::try_from(parse.next_int()?).unwrap_or(0),
+                           consumer: (!parse.is_empty()).then(|| parse.next_bytes())
+                                                        .transpose()?,
+                           min_idle })
+}
+
+/// `XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME unix-time-milliseconds]
+/// [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID lastid]`
+fn parse_xclaim(parse: &mut Parse) -> Result<Command, ParseError> {
+    let (key, group, consumer) = (parse.next_string()?, parse.next_bytes()?, parse.next_bytes()?);
+    let mut options = ClaimOptions { min_idle: u64::try_from(parse.next_int()?).unwrap_or(0),
+                                     ..Default::default() };
+
+    // IDs run up to the first argument that isn't one; options follow
+    let mut args = remaining_bytes(parse)?.into_iter().peekable();
+    let mut ids = Vec::new();
+    while let Some(id) = args.peek().and_then(|arg| parse_stream_id(arg, 0).ok()) {
+        ids.push(id);
+        args.next();
+    }
+    let ids = at_least_one(ids)?;
+    let number = |args: &mut dyn Iterator<Item=Bytes>| -> Result<u64, ParseError> {
+        let value =
+            parse_int(&args.next().ok_or(ParseError::Syntax)?).ok_or(ParseError::NotInteger)?;
+        // negative: as good as zero
+        Ok(u64::try_from(value).unwrap_or(0))
+    };
+    while let Some(option) = args.next() {
+        match &option.to_ascii_uppercase()[..] {
+            b"IDLE" => {
+                let idle = number(&mut args)?;
+                options.delivered = Some(u64::try_from(unix_millis_now()).unwrap_or(0)
+                                                                         .saturating_sub(idle));
+            }
+            b"TIME" => options.delivered = Some(number(&mut args)?),
+            b"RETRYCOUNT" => options.retry_count = Some(number(&mut args)?),
+            b"FORCE" => options.force = true,
+            b"JUSTID" => options.just_id = true,
+            b"LASTID" => {
+                options.last_id = Some(parse_stream_id(&args.next().ok_or(ParseError::Syntax)?, 0)?)
+            }
+            _ => {
+                return Err(format!("Unrecognized XCLAIM option '{}'",
+                                   String::from_utf8_lossy(&option)).into())
+            }
+        }
+    }
+    Ok(Command::XClaim { key,
+                         group,
+                         consumer,
+                         ids,
+                         options })
+}
+
+/// `<ms>-<seq>`, or just `<ms>` with `seq` filled in.
+fn parse_stream_id(data: &[u8], seq: u64) -> Result<StreamId, ParseError> {
+    let number = |digits: &[u8]| -> Result<u64, ParseError> {
+        std::str::from_utf8(digits).ok()
+                                   .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+                                   .and_then(|digits| digits.parse().ok())
+                                   .ok_or_else(invalid_stream_id)
+    };
+    match data.iter().position(|&b| b == b'-') {
+        Some(dash) => Ok(StreamId { ms:  number(&data[..dash])?,
+                                    seq: number(&data[dash + 1..])?, }),
+        None => Ok(StreamId { ms: number(data)?,
+                              seq }),
+    }
+}
+
+/// One end of an XRANGE: `-`, `+`, an ID (missing sequence numbers reaching as far as they can), or
+/// an ID after `(` to leave it out.
+fn parse_range_id(data: &[u8], start: bool) -> Result<StreamId, ParseError> {
+    match data {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        [b'(', id @ ..] => {
+            let id = parse_stream_id(id, if start { 0 } else { u64::MAX })?;
+            match start {
+                true => id.next()
+                          .ok_or_else(|| "invalid start ID for the interval".into()),
+                false => id.prev()
+                           .ok_or_else(|| "invalid end ID for the interval".into()),
+            }
+        }
+        id => parse_stream_id(id, if start { 0 } else { u64::MAX }),
+    }
+}
+
+fn invalid_stream_id() -> ParseError {
+    ParseError::from("Invalid stream ID specified as stream command argument")
+}
+
+fn parse_expire_condition(flag: &str) -> Result<ExpireCondition, ParseError> {
+    match &flag.to_uppercase()[..] {
+        "NX" => Ok(ExpireCondition::Nx),
+        "XX" => Ok(ExpireCondition::Xx),
+        "GT" => Ok(ExpireCondition::Gt),
+        "LT" => Ok(ExpireCondition::Lt),
+        other => Err(format!("Unsupported option {}", other).into()),
+    }
+}
+
+/// LEFT or RIGHT, going by the first letter of a list command's name.
+fn list_end(name: &str) -> End {
+    match name.starts_with('l') {
+        true => End::Left,
+        false => End::Right,
+    }
+}
+
+/// The set operation a SINTER/SUNION/SDIFF(STORE) command's name asks for.
+fn set_op(name: &str) -> SetOp {
+    match &name[1..] {
+        op if op.starts_with("inter") => SetOp::Inter,
+        op if op.starts_with("union") => SetOp::Union,
+        _ => SetOp::Diff,
+    }
+}
+
+/// `LEFT` or `RIGHT`, as LMOVE spells them.
+fn parse_end(word: &str) -> Result<End, ParseError> {
+    match &word.to_uppercase()[..] {
+        "LEFT" => Ok(End::Left),
+        "RIGHT" => Ok(End::Right),
+        _ => Err(ParseError::Syntax),
+    }
+}
+
+/// A blocking command's timeout: seconds, possibly fractional.  Zero means "for ever".
+fn parse_timeout(data: &[u8]) -> Result<Option<Duration>, ParseError> {
+    let secs = parse_float(data).ok_or("timeout is not a float or out of range")?;
+    if secs < 0.0 {
+        return Err("timeout is negative".into());
+    }
+    match secs {
+        0.0 => Ok(None),
+        secs => Duration::try_from_secs_f64(secs).map(Some)
+                                                 .map_err(|_| "timeout is out of range".into()),
+    }
+}
+
+/// A score as Redis replies with it.
+fn score_frame(score: f64) -> Frame {
+    Frame::Double(score)
+}
+
+/// Sorted-set members, each followed by its score if `withscores`.
+fn scored_array(members: Vec<(Bytes, f64)>, withscores: bool) -> Frame {
+    Frame::Array(members.into_iter()
+                        .flat_map(|(member, score)| {
+                            std::iter::once(Frame::Bulk(member)).chain(withscores.then(|| score_frame(score)))
+                        })
+                        .collect())
+}
+
+/// A stream entry: its ID and a flat array of its fields and values.
+fn stream_entry((id, fields): StreamEntry) -> Frame {
+    group_entry((id, Some(fields)))
+}
+
+/// A stream entry read back from a pending entries list: as `stream_entry`, with nil for the fields
+/// of one since deleted.
+fn group_entry((id, fields): GroupEntry) -> Frame {
+    let fields = fields.map_or(Frame::Null, |fields| {
+                           bulk_array(fields.into_iter().flat_map(|(field, value)| [field, value]))
+                       });
+    Frame::Array(vec![Frame::bulk(id.to_string()), fields])
+}
+
+/// XREAD's reply: each stream read from, with its entries.  Nil if there are none.
+fn streams_read<E>(streams: Vec<(String, Vec<E>)>, entry: fn(E) -> Frame) -> Frame {
+    if streams.is_empty() {
+        return Frame::Null;
+    }
+    Frame::Array(streams.into_iter()
+                        .map(|(key, entries)| {
+                            Frame::Array(vec![Frame::bulk(key),
+                                              Frame::Array(entries.into_iter()
+                                                                  .map(entry)
+                                                                  .collect())])
+                        })
+                        .collect())
+}
+
+/// Array reply of bulk strings.
+fn bulk_array(items: impl IntoIterator<Item=Bytes>) -> Frame {
+    Frame::Array(items.into_iter().map(Frame::Bulk).collect())
+}
+
+/// The members of a set, as a (RESP3) set.
+fn bulk_set(items: impl IntoIterator<Item=Bytes>) -> Frame {
+    Frame::Set(items.into_iter().map(Frame::Bulk).collect())
+}
+
+/// The one value asked for.
+fn first<T>(values: Vec<T>) -> T {
+    values.into_iter().next().expect("One value per argument.")
+}
+
+/// Refuse an empty argument list, for commands that need at least one.
+fn at_least_one<T>(args: Vec<T>) -> Result<Vec<T>, ParseError> {
+    match args.is_empty() {
+        true => Err(ParseError::EndOfStream),
+        false => Ok(args),
+    }
+}
+
+/// Collect every argument left in the frame, as is.
+fn remaining_bytes(parse: &mut Parse) -> Result<Vec<Bytes>, ParseError> {
+    let mut out = Vec::new();
+    while !parse.is_empty() {
+        out.push(parse.next_bytes()?);
+    }
+    Ok(out)
+}
+
+/// Collect every argument left in the frame as strings.
+fn remaining_strings(parse: &mut Parse) -> Result<Vec<String>, ParseError> {
+    let mut out = Vec::new();
+    while !parse.is_empty() {
+        out.push(parse.next_string()?);
+    }
+    Ok(out)
+}
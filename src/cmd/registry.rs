@@ -0,0 +1,539 @@
+//! Which commands the server knows, and what it knows about them
+//!
+//! A `Registry` maps command names to their `CommandSpec`: arity, flags and where the keys are, as
+//! COMMAND INFO reports them.  Besides the server's own commands, it can hold commands added from
+//! outside this crate, each carried out by a `CommandHandler`.
+
+use std::{collections::HashMap, fmt, ops::BitOr, sync::Arc};
+
+use bytes::Bytes;
+
+use super::{remaining_bytes, Command};
+use crate::{db::Db,
+            error::CommandError,
+            frame::Frame,
+            parse::{Parse, ParseError}};
+
+/// Carries out a command added to a `Registry`.
+pub trait CommandHandler: fmt::Debug+Send+Sync {
+    /// Produce the reply to the command.  `args` holds its arguments (the name not included), in a
+    /// number the command's arity allows; what's left once the handler returns is ignored.
+    ///
+    /// Runs like any other command: never in the middle of a transaction, and in order within one.
+    /// That is, with shared access to `db` already held -- so a handler carrying out other commands
+    /// must use `Command::apply_within`, never `apply` or `execute`: asking for shared access again
+    /// can deadlock, should an EXEC be waiting for exclusive access.
+    fn call(&self, db: &Db, args: &mut Parse) -> Result<Frame, CommandError>;
+}
+
+/// What a command is like, as far as COMMAND INFO (and the server) cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CommandFlags(u8);
+
+impl CommandFlags {
+    /// May wait for other clients before replying.
+    pub const BLOCKING: CommandFlags = CommandFlags(1 << 2);
+    const NAMES: [(CommandFlags, &'static str); 4] = [(CommandFlags::WRITE, "write"),
+                                                      (CommandFlags::READONLY, "readonly"),
+                                                      (CommandFlags::BLOCKING, "blocking"),
+                                                      (CommandFlags::PUBSUB, "pubsub")];
+    /// Publishes or subscribes.
+    pub const PUBSUB: CommandFlags = CommandFlags(1 << 3);
+    /// Only reads the store.
+    pub const READONLY: CommandFlags = CommandFlags(1 << 1);
+    /// May change the store.
+    pub const WRITE: CommandFlags = CommandFlags(1);
+
+    /// No flags at all.
+    pub const fn empty() -> CommandFlags {
+        CommandFlags(0)
+    }
+
+    /// Both sets of flags (`|`, usable in constants).
+    pub const fn union(self, other: CommandFlags) -> CommandFlags {
+        CommandFlags(self.0 | other.0)
+    }
+
+    /// Whether every flag in `other` is set.
+    pub fn contains(self, other: CommandFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Names of the flags set, as COMMAND INFO spells them.
+    pub fn names(self) -> impl Iterator<Item=&'static str> {
+        CommandFlags::NAMES.into_iter()
+                           .filter(move |(flag, _)| self.contains(*flag))
+                           .map(|(_, name)| name)
+    }
+}
+
+impl BitOr for CommandFlags {
+    type Output = CommandFlags;
+
+    fn bitor(self, other: CommandFlags) -> CommandFlags {
+        self.union(other)
+    }
+}
+
+/// A command's name, arity, flags and key positions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSpec {
+    /// Lowercase name.
+    pub name:      String,
+    /// Number of parts to the command, name included; negative for "at least this many".
+    pub arity:     i64,
+    pub flags:     CommandFlags,
+    /// Position of the first key (the name being at 0); 0 if the command takes no keys.
+    pub first_key: i64,
+    /// Position of the last key; negative counts back from the end (-1 being the last argument).
+    pub last_key:  i64,
+    /// Distance between keys.
+    pub key_step:  i64,
+}
+
+impl CommandSpec {
+    /// A command that takes no keys.  `name` is case insensitive.
+    pub fn new(name: &str, arity: i64, flags: CommandFlags) -> CommandSpec {
+        CommandSpec { name: name.to_lowercase(),
+                      arity,
+                      flags,
+                      first_key: 0,
+                      last_key: 0,
+                      key_step: 0 }
+    }
+
+    /// The same, with keys at positions `first`, `first + step`, ... up to `last`.
+    pub fn with_keys(self, first: i64, last: i64, step: i64) -> CommandSpec {
+        CommandSpec { first_key: first,
+                      last_key: last,
+                      key_step: step,
+                      ..self }
+    }
+
+    /// Whether `count` parts (name included) is a number the command accepts.
+    pub fn accepts(&self, count: usize) -> bool {
+        let count = count as i64;
+        match self.arity < 0 {
+            true => count >= -self.arity,
+            false => count == self.arity,
+        }
+    }
+
+    /// The keys among `args` (the arguments after the name).
+    pub fn keys<'a>(&self, args: &'a [Bytes]) -> impl Iterator<Item=&'a Bytes> {
+        let last = match self.last_key < 0 {
+            true => args.len() as i64 + 1 + self.last_key,
+            false => self.last_key,
+        };
+        // no keys: first and last both 0, an empty range
+        let positions = (self.first_key.max(1)..=last).step_by(self.key_step.max(1) as usize);
+        positions.filter_map(|position| args.get(position as usize - 1))
+    }
+
+    /// The reply to COMMAND INFO for this command.
+    fn info(&self) -> Frame {
+        let flags = self.flags
+                        .names()
+                        .map(|flag| Frame::Simple(flag.to_string()))
+                        .collect();
+        Frame::Array(vec![Frame::bulk(&self.name),
+                          Frame::Integer(self.arity),
+                          Frame::Array(flags),
+                          Frame::Integer(self.first_key),
+                          Frame::Integer(self.last_key),
+                          Frame::Integer(self.key_step),
+                          // ACL categories, tips, key specs and subcommands: none here
+                          Frame::Array(Vec::new()),
+                          Frame::Array(Vec::new()),
+                          Frame::Array(Vec::new()),
+                          Frame::Array(Vec::new())])
+    }
+}
+
+/// What COMMAND asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandQuery {
+    /// Details of every command (plain COMMAND).
+    All,
+    /// How many commands there are.
+    Count,
+    /// Every command's name.
+    List,
+    /// Details of the commands named (nil for unknown ones).
+    Info(Vec<String>),
+}
+
+/// How a registered command gets carried out.
+#[derive(Debug, Clone)]
+enum Handler {
+    /// Parsed into one of `Command`'s own variants
+    Builtin,
+    Custom(Arc<dyn CommandHandler>),
+}
+
+/// The commands a server understands, by name.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    commands: HashMap<String, (Arc<CommandSpec>, Handler)>,
+}
+
+impl Registry {
+    /// The server's own commands.
+    pub fn new() -> Registry {
+        let commands = BUILTINS.iter()
+                               .map(|&(name, arity, flags, [first, last, step])| {
+                                   let spec = CommandSpec::new(name, arity, flags).with_keys(first,
+                                                                                             last,
+                                                                                             step);
+                                   (name.to_string(), (Arc::new(spec), Handler::Builtin))
+                               })
+                               .collect();
+        Registry { commands }
+    }
+
+    /// Add a command, replacing any (built in or not) of the same name.
+    pub fn register(&mut self, spec: CommandSpec, handler: impl CommandHandler+'static) {
+        let handler = Handler::Custom(Arc::new(handler));
+        self.commands
+            .insert(spec.name.clone(), (Arc::new(spec), handler));
+    }
+
+    /// The spec of the command called `name` (case insensitive), if there is one.
+    pub fn get(&self, name: &str) -> Option<&CommandSpec> {
+        self.commands
+            .get(&name.to_lowercase())
+            .map(|(spec, _)| &**spec)
+    }
+
+    /// Every command's spec, in no particular order.
+    pub fn specs(&self) -> impl Iterator<Item=&CommandSpec> {
+        self.commands.values().map(|(spec, _)| &**spec)
+    }
+
+    /// Parse a command out of a frame, checking its arity before looking at its arguments.
+    ///
+    /// The frame must be an array whose first entry is the command name (case insensitive).
+    pub fn parse(&self, frame: Frame) -> Result<Command, CommandError> {
+        let (name, mut parse) = Command::split_name(frame)?;
+        let Some((spec, handler)) = self.commands.get(&name) else {
+            return Err(Command::unknown(name, &mut parse));
+        };
+        if !spec.accepts(parse.remaining() + 1) {
+            return Err(CommandError::WrongArity(name));
+        }
+        match handler {
+            Handler::Builtin => Command::parse_named(name, parse),
+            Handler::Custom(handler) => {
+                let args = remaining_bytes(&mut parse).map_err(|err| err.for_command(&name))?;
+                Ok(Command::Custom { spec: spec.clone(),
+                                     handler: handler.clone(),
+                                     args })
+            }
+        }
+    }
+
+    /// The reply to COMMAND.
+    pub fn describe(&self, query: &CommandQuery) -> Frame {
+        match query {
+            CommandQuery::All => Frame::Array(self.specs().map(CommandSpec::info).collect()),
+            CommandQuery::Count => Frame::Integer(self.commands.len() as i64),
+            CommandQuery::List => Frame::Array(self.commands.keys().map(Frame::bulk).collect()),
+            CommandQuery::Info(names) => {
+                Frame::Array(names.iter()
+                                  .map(|name| self.get(name).map_or(Frame::Null, CommandSpec::info))
+                                  .collect())
+            }
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+/// Parse COMMAND's arguments.
+pub(super) fn parse_command_query(parse: &mut Parse) -> Result<CommandQuery, ParseError> {
+    if parse.is_empty() {
+        return Ok(CommandQuery::All);
+    }
+    let subcommand = parse.next_string()?;
+    let query = match &subcommand.to_uppercase()[..] {
+        "COUNT" => CommandQuery::Count,
+        "LIST" => CommandQuery::List,
+        "INFO" => CommandQuery::Info(super::remaining_strings(parse)?),
+        _ => return Err(format!("unknown subcommand '{}'. Try COMMAND HELP.", subcommand).into()),
+    };
+    Ok(query)
+}
+
+const NONE: CommandFlags = CommandFlags::empty();
+const READONLY: CommandFlags = CommandFlags::READONLY;
+const WRITE: CommandFlags = CommandFlags::WRITE;
+const PUBSUB: CommandFlags = CommandFlags::PUBSUB;
+const BLOCKING_WRITE: CommandFlags = CommandFlags::WRITE.union(CommandFlags::BLOCKING);
+const BLOCKING_READ: CommandFlags = CommandFlags::READONLY.union(CommandFlags::BLOCKING);
+
+/// One key, right after the name.
+const KEY: [i64; 3] = [1, 1, 1];
+/// Every argument a key.
+const KEYS: [i64; 3] = [1, -1, 1];
+/// Keys found by looking at the arguments (or none at all).
+const NO_KEYS: [i64; 3] = [0, 0, 0];
+
+/// Name, arity, flags and key positions (first, last, step) of the commands `Command` parses, as
+/// Redis has them.
+#[rustfmt::skip]
+const BUILTINS: &[(&str, i64, CommandFlags, [i64; 3])] = &[
+    ("get", 2, READONLY, KEY),
+    ("set", -3, WRITE, KEY),
+    ("getdel", 2, WRITE, KEY),
+    ("mget", -2, READONLY, KEYS),
+    ("mset", -3, WRITE, [1, -1, 2]),
+    ("msetnx", -3, WRITE, [1, -1, 2]),
+    ("getex", -2, WRITE, KEY),
+    ("append", 3, WRITE, KEY),
+    ("strlen", 2, READONLY, KEY),
+    ("getrange", 4, READONLY, KEY),
+    ("substr", 4, READONLY, KEY),
+    ("setrange", 4, WRITE, KEY),
+    ("expire", -3, WRITE, KEY),
+    ("pexpire", -3, WRITE, KEY),
+    ("expireat", -3, WRITE, KEY),
+    ("pexpireat", -3, WRITE, KEY),
+    ("ttl", 2, READONLY, KEY),
+    ("pttl", 2, READONLY, KEY),
+    ("persist", 2, WRITE, KEY),
+    ("incr", 2, WRITE, KEY),
+    ("decr", 2, WRITE, KEY),
+    ("incrby", 3, WRITE, KEY),
+    ("decrby", 3, WRITE, KEY),
+    ("incrbyfloat", 3, WRITE, KEY),
+    ("setbit", 4, WRITE, KEY),
+    ("getbit", 3, READONLY, KEY),
+    ("bitcount", -2, READONLY, KEY),
+    ("bitpos", -3, READONLY, KEY),
+    ("bitop", -4, WRITE, [2, -1, 1]),
+    ("bitfield", -2, WRITE, KEY),
+    ("bitfield_ro", -2, READONLY, KEY),
+    ("lpush", -3, WRITE, KEY),
+    ("rpush", -3, WRITE, KEY),
+    ("lpop", -2, WRITE, KEY),
+    ("rpop", -2, WRITE, KEY),
+    ("blpop", -3, BLOCKING_WRITE, [1, -2, 1]),
+    ("brpop", -3, BLOCKING_WRITE, [1, -2, 1]),
+    ("lmove", 5, WRITE, [1, 2, 1]),
+    ("blmove", 6, BLOCKING_WRITE, [1, 2, 1]),
+    ("lrange", 4, READONLY, KEY),
+    ("llen", 2, READONLY, KEY),
+    ("lindex", 3, READONLY, KEY),
+    ("hset", -4, WRITE, KEY),
+    ("hmset", -4, WRITE, KEY),
+    ("hsetnx", 4, WRITE, KEY),
+    ("hget", 3, READONLY, KEY),
+    ("hmget", -3, READONLY, KEY),
+    ("hgetall", 2, READONLY, KEY),
+    ("hkeys", 2, READONLY, KEY),
+    ("hvals", 2, READONLY, KEY),
+    ("hdel", -3, WRITE, KEY),
+    ("hexists", 3, READONLY, KEY),
+    ("hlen", 2, READONLY, KEY),
+    ("hstrlen", 3, READONLY, KEY),
+    ("hincrby", 4, WRITE, KEY),
+    ("hincrbyfloat", 4, WRITE, KEY),
+    ("hscan", -3, READONLY, KEY),
+    ("sadd", -3, WRITE, KEY),
+    ("srem", -3, WRITE, KEY),
+    ("smembers", 2, READONLY, KEY),
+    ("sismember", 3, READONLY, KEY),
+    ("smismember", -3, READONLY, KEY),
+    ("scard", 2, READONLY, KEY),
+    ("sinter", -2, READONLY, KEYS),
+    ("sunion", -2, READONLY, KEYS),
+    ("sdiff", -2, READONLY, KEYS),
+    ("sinterstore", -3, WRITE, KEYS),
+    ("sunionstore", -3, WRITE, KEYS),
+    ("sdiffstore", -3, WRITE, KEYS),
+    ("sscan", -3, READONLY, KEY),
+    ("zadd", -4, WRITE, KEY),
+    ("zincrby", 4, WRITE, KEY),
+    ("zrange", -4, READONLY, KEY),
+    ("zrank", -3, READONLY, KEY),
+    ("zrevrank", -3, READONLY, KEY),
+    ("zscore", 3, READONLY, KEY),
+    ("zcard", 2, READONLY, KEY),
+    ("zrem", -3, WRITE, KEY),
+    ("zpopmin", -2, WRITE, KEY),
+    ("zpopmax", -2, WRITE, KEY),
+    ("xadd", -5, WRITE, KEY),
+    ("xrange", -4, READONLY, KEY),
+    ("xrevrange", -4, READONLY, KEY),
+    ("xlen", 2, READONLY, KEY),
+    ("xtrim", -4, WRITE, KEY),
+    ("xread", -4, BLOCKING_READ, NO_KEYS),
+    ("xreadgroup", -7, BLOCKING_WRITE, NO_KEYS),
+    ("xgroup", -2, WRITE, NO_KEYS),
+    ("xack", -4, WRITE, KEY),
+    ("xpending", -3, READONLY, KEY),
+    ("xclaim", -6, WRITE, KEY),
+    ("pfadd", -2, WRITE, KEY),
+    ("pfcount", -2, READONLY, KEYS),
+    ("pfmerge", -2, WRITE, KEYS),
+    ("del", -2, WRITE, KEYS),
+    ("exists", -2, READONLY, KEYS),
+    ("type", 2, READONLY, KEY),
+    ("keys", 2, READONLY, NO_KEYS),
+    ("scan", -2, READONLY, NO_KEYS),
+    ("randomkey", 1, READONLY, NO_KEYS),
+    ("publish", 3, PUBSUB, NO_KEYS),
+    ("subscribe", -2, PUBSUB, NO_KEYS),
+    ("unsubscribe", -1, PUBSUB, NO_KEYS),
+    ("ping", -1, NONE, NO_KEYS),
+    ("multi", 1, NONE, NO_KEYS),
+    ("exec", 1, NONE, NO_KEYS),
+    ("discard", 1, NONE, NO_KEYS),
+    ("watch", -2, NONE, KEYS),
+    ("unwatch", 1, NONE, NO_KEYS),
+    ("hello", -1, NONE, NO_KEYS),
+    ("command", -1, NONE, NO_KEYS),
+];
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+
+    fn command(args: &[&str]) -> Frame {
+        Frame::Array(args.iter().map(Frame::bulk).collect())
+    }
+
+    /// Replies with how many arguments it got, and stores that under its first one.
+    #[derive(Debug)]
+    struct Count;
+
+    impl CommandHandler for Count {
+        fn call(&self, db: &Db, args: &mut Parse) -> Result<Frame, CommandError> {
+            let key = args.next_string()
+                          .map_err(|err| err.for_command("count.args"))?;
+            let count = remaining_bytes(args).map_err(|err| err.for_command("count.args"))?
+                                             .len()
+                        + 1;
+            db.set(key, Bytes::from(count.to_string()), None);
+            Ok(Frame::Integer(count as i64))
+        }
+    }
+
+    /// Increments its key twice, with the built in INCR -- letting an EXEC queue up in between.
+    #[derive(Debug)]
+    struct IncrTwice;
+
+    impl CommandHandler for IncrTwice {
+        fn call(&self, db: &Db, args: &mut Parse) -> Result<Frame, CommandError> {
+            let key = args.next_bytes()
+                          .map_err(|err| err.for_command("incr.twice"))?;
+            let incr = || {
+                Command::from_frame(Frame::Array(vec![Frame::bulk("INCR"),
+                                                      Frame::Bulk(key.clone())]))
+            };
+            incr()?.apply_within(db)?;
+            let exec = db.clone();
+            std::thread::spawn(move || exec.with_exclusive_access(|| ()));
+            std::thread::sleep(Duration::from_millis(50));
+            incr()?.apply_within(db)
+        }
+    }
+
+    #[test]
+    fn builtins_parse_to_themselves() {
+        let registry = Registry::new();
+        for (name, ..) in BUILTINS {
+            // everything in the table is something `Command` knows
+            let err = registry.parse(command(&[name])).err();
+            assert!(!matches!(err, Some(CommandError::UnknownCommand { .. })),
+                    "{name}");
+        }
+        assert_eq!(registry.parse(command(&["GET"])).unwrap_err(),
+                   CommandError::WrongArity("get".to_string()));
+        assert!(matches!(registry.parse(command(&["GeT", "k"])),
+                         Ok(Command::Get { .. })));
+        assert!(matches!(registry.parse(command(&["nope", "k"])),
+                         Err(CommandError::UnknownCommand { .. })));
+    }
+
+    #[test]
+    fn specs_find_keys() {
+        let args = |args: &[&str]| {
+            args.iter()
+                .map(|arg| Bytes::copy_from_slice(arg.as_bytes()))
+                .collect::<Vec<_>>()
+        };
+        let keys =
+            |spec: &CommandSpec, given: &[Bytes]| spec.keys(given).cloned().collect::<Vec<_>>();
+        let registry = Registry::new();
+        let mset = registry.get("MSET").unwrap();
+        assert_eq!(keys(mset, &args(&["a", "1", "b", "2"])), args(&["a", "b"]));
+        let blpop = registry.get("blpop").unwrap();
+        assert_eq!(keys(blpop, &args(&["a", "b", "0"])), args(&["a", "b"]));
+        let keys_cmd = registry.get("keys").unwrap();
+        assert!(keys(keys_cmd, &args(&["*"])).is_empty());
+        assert!(blpop.flags
+                     .contains(CommandFlags::WRITE | CommandFlags::BLOCKING));
+        assert_eq!(blpop.flags.names().collect::<Vec<_>>(), ["write",
+                                                             "blocking"]);
+    }
+
+    #[tokio::test]
+    async fn custom_commands_run_and_describe_themselves() {
+        let mut registry = Registry::new();
+        registry.register(CommandSpec::new("COUNT.ARGS", -2, CommandFlags::WRITE).with_keys(1, 1,
+                                                                                            1),
+                          Count);
+        let db = Db::new();
+        let cmd = registry.parse(command(&["count.args", "k", "x", "y"]))
+                          .unwrap();
+        assert_eq!(cmd.written_keys(), ["k"]);
+        assert_eq!(cmd.apply(&db).unwrap(), Frame::Integer(3));
+        assert_eq!(db.get("k").unwrap(), Some(Bytes::from("3")));
+        assert_eq!(registry.parse(command(&["count.args"])).unwrap_err(),
+                   CommandError::WrongArity("count.args".to_string()));
+
+        let info = registry.describe(&CommandQuery::Info(vec!["count.args".into(), "nope".into()]));
+        let Frame::Array(info) = info else {
+            panic!("not an array: {info:?}")
+        };
+        assert_eq!(info[1], Frame::Null);
+        let Frame::Array(fields) = &info[0] else {
+            panic!("not an array: {:?}", info[0])
+        };
+        assert_eq!(fields[..6],
+                   [Frame::bulk("count.args"),
+                    Frame::Integer(-2),
+                    Frame::Array(vec![Frame::Simple("write".to_string())]),
+                    Frame::Integer(1),
+                    Frame::Integer(1),
+                    Frame::Integer(1)]);
+        assert_eq!(registry.describe(&CommandQuery::Count),
+                   Frame::Integer(BUILTINS.len() as i64 + 1));
+    }
+
+    #[tokio::test]
+    async fn handlers_run_commands_without_waiting_on_exec() {
+        let mut registry = Registry::new();
+        registry.register(CommandSpec::new("incr.twice", 2, CommandFlags::WRITE).with_keys(1, 1,
+                                                                                           1),
+                          IncrTwice);
+        let db = Db::new();
+        let version = db.watch("k");
+        let cmd = registry.parse(command(&["incr.twice", "k"])).unwrap();
+
+        let (done, replied) = mpsc::channel();
+        let handler_db = db.clone();
+        std::thread::spawn(move || done.send(cmd.apply(&handler_db)));
+        let reply = replied.recv_timeout(Duration::from_secs(5))
+                           .expect("Handler finishes while EXEC waits.");
+        assert_eq!(reply.unwrap(), Frame::Integer(2));
+        assert!(!db.with_exclusive_access(|| db.unchanged(&[("k".to_string(), version)])));
+    }
+}
@@ -0,0 +1,259 @@
+//! MULTI/EXEC transactions, and the WATCHes that make them optimistic
+//!
+//! Between MULTI and EXEC a connection's commands are checked and queued rather than run.  EXEC then
+//! runs the lot with the store to itself -- unless a watched key changed in the meantime, in which
+//! case it runs none of them and replies nil.
+
+use super::Command;
+use crate::{db::Db, error::CommandError, frame::Frame};
+
+/// A connection's transaction state.
+#[derive(Debug)]
+pub struct Transaction {
+    db:      Db,
+    /// Keys WATCHed, with the versions they had at the time.
+    watched: Vec<(String, u64)>,
+    /// Commands queued since MULTI; `None` outside a transaction.
+    queued:  Option<Vec<Command>>,
+    /// A command was refused while queuing: EXEC must discard the transaction.
+    doomed:  bool,
+}
+
+impl Transaction {
+    pub fn new(db: Db) -> Transaction {
+        Transaction { db,
+                      watched: Vec::new(),
+                      queued: None,
+                      doomed: false }
+    }
+
+    /// Whether `command` (or the failure to parse one) is for `handle` rather than to be run as usual:
+    /// MULTI and its kin -- or anything at all, once MULTI has started a transaction.
+    pub fn takes(&self, command: &Result<Command, CommandError>) -> bool {
+        use Command::*;
+
+        self.queued.is_some()
+        || matches!(command, Ok(Multi | Exec | Discard | Watch { .. } | Unwatch))
+    }
+
+    /// Deal with a command that `takes` said was ours, producing the reply.
+    pub fn handle(&mut self,
+                  command: Result<Command, CommandError>)
+                  -> Result<Frame, CommandError> {
+        let command = match command {
+            Ok(command) => command,
+            Err(err) => {
+                self.doomed = true;
+                return Err(err);
+            }
+        };
+        match (command, &mut self.queued) {
+            (Command::Multi, Some(_)) => {
+                Err(CommandError::Other("MULTI calls can not be nested".to_string()))
+            }
+            (Command::Multi, queued @ None) => {
+                *queued = Some(Vec::new());
+                Ok(Frame::ok())
+            }
+            (Command::Exec, None) => Err(CommandError::Other("EXEC without MULTI".to_string())),
+            (Command::Exec, Some(_)) => self.exec(),
+            (Command::Discard, None) => {
+                Err(CommandError::Other("DISCARD without MULTI".to_string()))
+            }
+            (Command::Discard, Some(_)) => {
+                self.reset();
+                Ok(Frame::ok())
+            }
+            (Command::Watch { .. }, Some(_)) => {
+                Err(CommandError::Other("WATCH inside MULTI is not allowed".to_string()))
+            }
+            (Command::Watch { keys }, None) => {
+                for key in keys {
+                    let version = self.db.watch(&key);
+                    self.watched.push((key, version));
+                }
+                Ok(Frame::ok())
+            }
+            (Command::Unwatch, None) => {
+                self.unwatch_all();
+                Ok(Frame::ok())
+            }
+            (command, Some(queued)) => {
+                queued.push(command);
+                Ok(Frame::Simple("QUEUED".to_string()))
+            }
+            (command, None) => unreachable!("'{}' isn't a transaction command.", command.name()),
+        }
+    }
+
+    /// Run the queued commands, all at once, if no watched key has changed.  Either way, the
+    /// transaction is over and nothing is watched any more.
+    fn exec(&mut self) -> Result<Frame, CommandError> {
+        let queued = self.queued.take().unwrap_or_default();
+        let doomed = std::mem::take(&mut self.doomed);
+        let reply = match doomed {
+            true => Err(CommandError::ExecAbort),
+            false => Ok(self.db.with_exclusive_access(|| {
+                                   if !self.db.unchanged(&self.watched) {
+                                       return Frame::Null;
+                                   }
+                                   Frame::Array(queued.into_iter()
+                                                      .map(|command| self.run(command))
+                                                      .collect())
+                               })),
+        };
+        self.unwatch_all();
+        reply
+    }
+
+    /// One queued command's reply.
+    fn run(&self, command: Command) -> Frame {
+        let reply = match command {
+            // EXEC unwatches everything anyway
+            Command::Unwatch => Ok(Frame::ok()),
+            command => command.apply_within(&self.db),
+        };
+        reply.unwrap_or_else(|err| err.to_frame())
+    }
+
+    /// End the transaction (if any) without running it.
+    fn reset(&mut self) {
+        self.queued = None;
+        self.doomed = false;
+        self.unwatch_all();
+    }
+
+    fn unwatch_all(&mut self) {
+        for (key, _) in self.watched.drain(..) {
+            self.db.unwatch(&key);
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.unwatch_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::parse::ParseError;
+
+    fn command(args: &[&str]) -> Result<Command, CommandError> {
+        let frame = Frame::Array(args.iter().map(Frame::bulk).collect());
+        Command::from_frame(frame)
+    }
+
+    /// Feed a command through `transaction` if it takes it, or run it as usual.
+    fn send(transaction: &mut Transaction, args: &[&str]) -> Frame {
+        let command = command(args);
+        let reply = match transaction.takes(&command) {
+            true => transaction.handle(command),
+            false => command.and_then(|command| command.apply(&transaction.db)),
+        };
+        reply.unwrap_or_else(|err| err.to_frame())
+    }
+
+    fn queued() -> Frame {
+        Frame::Simple("QUEUED".to_string())
+    }
+
+    #[tokio::test]
+    async fn queues_then_runs_everything() {
+        let mut client = Transaction::new(Db::new());
+        assert_eq!(send(&mut client, &["MULTI"]), Frame::ok());
+        assert_eq!(send(&mut client, &["SET", "k", "1"]), queued());
+        assert_eq!(send(&mut client, &["INCR", "k"]), queued());
+        assert_eq!(send(&mut client, &["LPUSH", "k", "x"]), queued());
+        assert_eq!(send(&mut client, &["GET", "k"]), queued());
+        assert_eq!(send(&mut client, &["EXEC"]),
+                   Frame::Array(vec![Frame::ok(),
+                                     Frame::Integer(2),
+                                     CommandError::WrongType.to_frame(),
+                                     Frame::bulk("2")]));
+        assert_eq!(send(&mut client, &["EXEC"]),
+                   CommandError::Other("EXEC without MULTI".to_string()).to_frame());
+    }
+
+    #[tokio::test]
+    async fn errors_while_queuing_abort() {
+        let mut client = Transaction::new(Db::new());
+        send(&mut client, &["MULTI"]);
+        send(&mut client, &["SET", "k", "1"]);
+        assert_eq!(send(&mut client, &["GET"]),
+                   ParseError::EndOfStream.for_command("get").to_frame());
+        assert_eq!(send(&mut client, &["MULTI"]),
+                   CommandError::Other("MULTI calls can not be nested".to_string()).to_frame());
+        assert_eq!(send(&mut client, &["EXEC"]),
+                   CommandError::ExecAbort.to_frame());
+        assert_eq!(client.db.get("k").unwrap(), None);
+
+        send(&mut client, &["MULTI"]);
+        send(&mut client, &["SET", "k", "1"]);
+        assert_eq!(send(&mut client, &["DISCARD"]), Frame::ok());
+        assert_eq!(client.db.get("k").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn watched_changes_abort() {
+        let db = Db::new();
+        let mut client = Transaction::new(db.clone());
+        let mut other = Transaction::new(db.clone());
+        assert_eq!(send(&mut client, &["WATCH", "k"]), Frame::ok());
+        send(&mut other, &["SET", "k", "theirs"]);
+        send(&mut client, &["MULTI"]);
+        send(&mut client, &["SET", "k", "ours"]);
+        assert_eq!(send(&mut client, &["EXEC"]), Frame::Null);
+        assert_eq!(db.get("k").unwrap(), Some(Bytes::from("theirs")));
+
+        // EXEC forgets the watch, aborted or not
+        send(&mut other, &["SET", "k", "again"]);
+        send(&mut client, &["MULTI"]);
+        send(&mut client, &["SET", "k", "ours"]);
+        assert_eq!(send(&mut client, &["EXEC"]),
+                   Frame::Array(vec![Frame::ok()]));
+
+        // reads don't count as changes; nor do writes to other keys
+        send(&mut client, &["WATCH", "k"]);
+        send(&mut other, &["GET", "k"]);
+        send(&mut other, &["SET", "j", "1"]);
+        send(&mut client, &["MULTI"]);
+        send(&mut client, &["DEL", "k"]);
+        assert_eq!(send(&mut client, &["EXEC"]),
+                   Frame::Array(vec![Frame::Integer(1)]));
+    }
+
+    #[tokio::test]
+    async fn writes_that_change_nothing_dont_abort() {
+        let db = Db::new();
+        let mut client = Transaction::new(db.clone());
+        let mut other = Transaction::new(db.clone());
+        send(&mut other, &["SET", "k", "one"]);
+        send(&mut client, &["WATCH", "k", "list"]);
+        assert_eq!(send(&mut other, &["SET", "k", "two", "NX"]), Frame::Null);
+        assert_eq!(send(&mut other, &["INCR", "k"]),
+                   CommandError::NotInteger.to_frame());
+        assert_eq!(send(&mut other, &["LPOP", "list"]), Frame::Null);
+        assert_eq!(send(&mut other, &["DEL", "list"]), Frame::Integer(0));
+        send(&mut client, &["MULTI"]);
+        send(&mut client, &["GET", "k"]);
+        assert_eq!(send(&mut client, &["EXEC"]),
+                   Frame::Array(vec![Frame::bulk("one")]));
+
+        // the same commands do count when they change something
+        send(&mut client, &["WATCH", "list"]);
+        send(&mut other, &["RPUSH", "list", "x", "y"]);
+        send(&mut client, &["MULTI"]);
+        send(&mut client, &["LLEN", "list"]);
+        assert_eq!(send(&mut client, &["EXEC"]), Frame::Null);
+        send(&mut client, &["WATCH", "list"]);
+        assert_eq!(send(&mut other, &["LPOP", "list"]), Frame::bulk("x"));
+        send(&mut client, &["MULTI"]);
+        send(&mut client, &["LLEN", "list"]);
+        assert_eq!(send(&mut client, &["EXEC"]), Frame::Null);
+    }
+}
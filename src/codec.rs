@@ -0,0 +1,261 @@
+//! RESP as a tokio-util codec
+//!
+//! Wrapping a socket in `Framed::new(socket, RespCodec::new())` gives a `Stream` of the frames
+//! arriving and a `Sink` for frames to send -- read and written just as `Connection` does, which
+//! decodes with a `RespCodec` too.
+
+use std::io::Cursor;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{error::Error,
+            frame::{self, Frame, Limits, Protocol}};
+
+/// Decodes RESP frames (and inline commands); encodes frames in the protocol version set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RespCodec {
+    /// How frames are encoded: RESP2 until the peer asks otherwise (with HELLO)
+    protocol: Protocol,
+    /// What's refused while decoding
+    limits:   Limits,
+}
+
+impl RespCodec {
+    pub fn new() -> RespCodec {
+        RespCodec::default()
+    }
+
+    /// A codec refusing frames beyond `limits`, rather than the defaults.
+    pub fn with_limits(limits: Limits) -> RespCodec {
+        RespCodec { limits,
+                    ..RespCodec::default() }
+    }
+
+    /// The version of the protocol frames are encoded in.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Encode frames in `protocol` from now on.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Refuse to hold `src` any longer if it's grown past the buffer limit.
+    pub fn check_buffer(&self, src: &BytesMut) -> Result<(), Error> {
+        if src.len() > self.limits.max_buffer {
+            return Err(frame::Error::from("request exceeds the input buffer limit").into());
+        }
+        Ok(())
+    }
+
+    /// The frame at the front of `src`, if a whole one is there -- without minding the buffer limit.
+    fn next_frame(&self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        while src.first()
+                 .is_some_and(|&first| Frame::starts_inline(first))
+        {
+            let mut buf = Cursor::new(&src[..]);
+            match Frame::parse_inline(&mut buf, &self.limits) {
+                Ok(frame) => {
+                    let len = buf.position() as usize;
+                    src.advance(len);
+                    if frame != Frame::Array(Vec::new()) {
+                        return Ok(Some(frame));
+                    }
+                }
+                Err(frame::Error::Incomplete) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Create `T: Buf` type
+        let mut buf = Cursor::new(&src[..]);
+        // Check if full frame available
+        match Frame::check(&mut buf, &self.limits) {
+            Ok(_) => {
+                //Get the byte length of frame
+                let len = buf.position() as usize;
+                // Reset internal cursor
+                buf.set_position(0);
+                // Parse the frame
+                let frame = Frame::parse(&mut buf)?;
+                // Discard the frame from the buffer
+                src.advance(len);
+
+                Ok(Some(frame))
+            }
+            Err(frame::Error::Incomplete) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Error = Error;
+    type Item = Frame;
+
+    /// Pull a frame off the front of `src`, if a whole one is there.
+    ///
+    /// Anything that doesn't start like a RESP frame is taken for an inline command, typed by hand;
+    /// blank lines in between are skipped.  Going over the limits is an error, as is holding more
+    /// than `max_buffer` bytes without a whole frame among them.
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        let frame = self.next_frame(src)?;
+        if frame.is_none() {
+            self.check_buffer(src)?;
+        }
+        Ok(frame)
+    }
+}
+
+impl Encoder<&Frame> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        frame.encode(self.protocol, dst);
+        Ok(())
+    }
+}
+
+impl Encoder<Frame> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        self.encode(&frame, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+
+    #[test]
+    fn decodes_frames_as_they_complete() {
+        let mut codec = RespCodec::new();
+        let mut src = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$1"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(b"\r\nk\r\n:1\r\n\r\nping\r\n");
+        assert_eq!(codec.decode(&mut src).unwrap(),
+                   Some(Frame::Array(vec![Frame::bulk("GET"), Frame::bulk("k")])));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Frame::Integer(1)));
+        assert_eq!(codec.decode(&mut src).unwrap(),
+                   Some(Frame::Array(vec![Frame::bulk("ping")])));
+        assert!(src.is_empty());
+
+        // a frame cut off by the end of the stream is an error
+        src.extend_from_slice(b"$5\r\nab");
+        assert!(codec.decode_eof(&mut src).is_err());
+    }
+
+    #[test]
+    fn refuses_to_buffer_past_the_limit() {
+        let limits = Limits { max_buffer: 16,
+                              ..Limits::default() };
+        // a bulk string within the other limits, or an inline command that never ends its line
+        for hostile in [&b"$100\r\n"[..], b"get "] {
+            let mut codec = RespCodec::with_limits(limits);
+            let mut src = BytesMut::from(hostile);
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+            src.extend_from_slice(&[b'a'; 16]);
+            assert_eq!(codec.decode(&mut src).unwrap_err().to_string(),
+                       "request exceeds the input buffer limit");
+        }
+
+        // an inline command has a limit of its own, well short of the buffer's, on its length
+        let mut codec = RespCodec::new();
+        let mut src = BytesMut::from(&b"set k "[..]);
+        src.extend_from_slice(&vec![b'a'; frame::MAX_INLINE_LEN - src.len()]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(b"a");
+        assert_eq!(codec.decode(&mut src).unwrap_err().to_string(),
+                   "too big inline request");
+        // and the multibulk limit applies to its words
+        let mut codec = RespCodec::with_limits(Limits { max_multibulk_len: 3,
+                                                        ..Limits::default() });
+        let mut src = BytesMut::from(&b"set k v\r\nset k v nx\r\n"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert_eq!(codec.decode(&mut src).unwrap_err().to_string(),
+                   "invalid multibulk length");
+
+        // a whole frame is taken off the front before the rest is measured
+        let mut codec = RespCodec::with_limits(limits);
+        let mut src = BytesMut::from(&b"$10\r\n0123456789\r\n$3\r\nab"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap(),
+                   Some(Frame::bulk("0123456789")));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_lines_past_the_inline_limit() {
+        // a header or simple line that never ends is refused once it's past the inline limit,
+        // well short of the buffer's
+        for kind in *b"*$+:" {
+            let mut codec = RespCodec::new();
+            let mut src = BytesMut::from(&[kind][..]);
+            src.extend_from_slice(&vec![b'1'; frame::MAX_INLINE_LEN]);
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+            src.extend_from_slice(b"11");
+            assert_eq!(codec.decode(&mut src).unwrap_err().to_string(),
+                       "too big line");
+        }
+
+        // inside an array too
+        let mut codec = RespCodec::new();
+        let mut src = BytesMut::from(&b"*2\r\n+"[..]);
+        src.extend_from_slice(&vec![b'a'; frame::MAX_INLINE_LEN + 2]);
+        assert_eq!(codec.decode(&mut src).unwrap_err().to_string(),
+                   "too big line");
+
+        // one right at the limit is fine
+        let mut codec = RespCodec::new();
+        let mut src = BytesMut::from(&b"+"[..]);
+        src.extend_from_slice(&vec![b'a'; frame::MAX_INLINE_LEN]);
+        src.extend_from_slice(b"\r\n");
+        let line = codec.decode(&mut src).unwrap();
+        assert!(matches!(line, Some(Frame::Simple(s)) if s.len() == frame::MAX_INLINE_LEN));
+    }
+
+    #[test]
+    fn encodes_in_the_protocol_set() {
+        let mut codec = RespCodec::new();
+        let mut dst = BytesMut::new();
+        codec.encode(Frame::Boolean(true), &mut dst).unwrap();
+        codec.set_protocol(Protocol::Resp3);
+        codec.encode(&Frame::Boolean(true), &mut dst).unwrap();
+        assert_eq!(&dst[..], b":1\r\n#t\r\n");
+    }
+
+    #[tokio::test]
+    async fn frames_a_stream() {
+        let (ours, theirs) = tokio::io::duplex(64);
+        let mut ours = Framed::new(ours, RespCodec::new());
+        let theirs = Framed::new(theirs, RespCodec::new());
+
+        // echo back every integer doubled, until the other end hangs up
+        fn double(frame: Result<Frame, Error>) -> Result<Frame, Error> {
+            match frame? {
+                Frame::Integer(n) => Ok(Frame::Integer(n * 2)),
+                frame => Ok(frame),
+            }
+        }
+        let echo = tokio::spawn(async move {
+            let (mut sink, stream) = futures::StreamExt::split::<Frame>(theirs);
+            sink.send_all(&mut stream.map(double)).await
+        });
+
+        for n in 0..100 {
+            ours.feed(Frame::Integer(n)).await.unwrap();
+        }
+        SinkExt::<Frame>::flush(&mut ours).await.unwrap();
+        let replies: Vec<Frame> = (&mut ours).take(100).map(Result::unwrap).collect().await;
+        assert_eq!(replies,
+                   (0..100).map(|n| Frame::Integer(n * 2)).collect::<Vec<_>>());
+        drop(ours);
+        echo.await.unwrap().unwrap();
+    }
+}
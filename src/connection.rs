@@ -1,104 +1,199 @@
-// //! Framing our bytestreams
-
-// use bytes::Buf;
-// use bytes::BytesMut;
-// use mini_redis::frame::Error::Incomplete;
-// use mini_redis::{Frame, Result};
-// use std::io::Cursor;
-// use tokio::io::AsyncReadExt;
-// use tokio::io::BufWriter;
-// use tokio::io::{self, AsyncWriteExt};
-// use tokio::net::TcpStream;
-
-// struct Connection {
-//     stream: TcpStream,
-//     buffer: BytesMut,
-// }
-
-// impl Connection {
-//     /// Generate new Connection from a TcpStream
-//     pub fn new(stream: TcpStream) -> Connection {
-//         Connection {
-//             stream: BufWriter::new(stream),
-//             // Allocate the buffer with 4kb of capacity.
-//             buffer: BytesMut::with_capacity(4096),
-//         }
-//     }
-
-//     /// ...
-//     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
-//         // Attempt frame from buffered data.  Return if possible.
-//         loop {
-//             if let Some(frame) = self.parse_frame()? {
-//                 return Ok(Some(frame));
-//             }
-//             // Try to get more data.
-//             if 0 == self.stream.read_buf(&mut self.buffer).await? {
-//                 // Remote closed the connection.  Check if incomplete frame in buffer.
-//                 if self.buffer.is_empty() {
-//                     return Ok(None);
-//                 } else {
-//                     return Err("connection reset by peer".into());
-//                 }
-//             }
-//         }
-//     }
-
-//     /// ...
-//     fn parse_frame(&mut self) -> Result<Option<Frame>> {
-//         // Create `T: Buf` type
-//         let mut buf = Cursor::new(&self.buffer[..]);
-//         // Check if full frame available
-//         match Frame::check(&mut buf) {
-//             Ok(_) => {
-//                 //Get the byte length of frame
-//                 let len = buf.position() as usize;
-//                 // Reset internal cursor
-//                 buf.set_position(0);
-//                 // Parse the frame
-//                 let frame = Frame::parse(&mut buf)?;
-//                 // Discard th frame from the buffer
-//                 self.buffer.advance(len);
-
-//                 Ok(Some(frame))
-//             }
-//             Err(Incomple) => Ok(None),
-//             Err(e) => Err(e.into()),
-//         }
-//     }
-
-//     /// ...
-//     async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
-//         match frame {
-//             Frame::Simple(val) => {
-//                 self.stream.write_u8(b'+').await?;
-//                 self.stream.write_all(val.as_bytes()).await?;
-//                 self.stream.write_all(b"\r\n").await?;
-//             }
-//             Frame::Error(val) => {
-//                 self.stream.write_u8(b'-').await?;
-//                 self.stream.write_all(val.as_bytes()).await?;
-//                 self.stream.write_all(b"\r\n").await?;
-//             }
-//             Frame::Integer(val) => {
-//                 self.stream.write_u8(b':').await?;
-//                 // self.write_decimal(*val).await?;
-//             }
-//             Frame::Null => {
-//                 self.stream.write_all(b"$-1\r\n").await?;
-//             }
-//             Frame::Bulk(val) => {
-//                 let len = val.len();
-
-//                 self.stream.write_u8(b'$').await?;
-//                 // self.write_decimal(len as u64).await?; // not implemented here
-//                 self.stream.write_all(val).await?;
-//                 self.stream.write_all(b"\r\n").await?;
-//             }
-//             Frame::Array(_val) => unimplemented!(),
-//         }
-
-//         self.stream.flush().await;
-//         Ok(())
-//     }
-// }
+//! Framing our bytestreams
+
+use bytes::BytesMut;
+use tokio::{io::{self, AsyncReadExt, AsyncWriteExt, BufWriter},
+            net::TcpStream};
+use tokio_util::codec::Decoder;
+
+use crate::{codec::RespCodec,
+            error::Result,
+            frame::{Frame, Limits, Protocol}};
+
+/// Read & write `Frame`s over a TcpStream.
+#[derive(Debug)]
+pub struct Connection {
+    stream:  BufWriter<TcpStream>,
+    buffer:  BytesMut,
+    /// Scratch space for encoding frames
+    encoded: BytesMut,
+    /// How frames are read and written
+    codec:   RespCodec,
+}
+
+impl Connection {
+    /// Generate new Connection from a TcpStream
+    pub fn new(stream: TcpStream) -> Connection {
+        Connection::with_limits(stream, Limits::default())
+    }
+
+    /// A Connection refusing frames beyond `limits`, rather than the defaults.
+    pub fn with_limits(stream: TcpStream, limits: Limits) -> Connection {
+        Connection { stream:  BufWriter::new(stream),
+                     // Allocate the buffer with 4kb of capacity.
+                     buffer:  BytesMut::with_capacity(4096),
+                     encoded: BytesMut::new(),
+                     codec:   RespCodec::with_limits(limits), }
+    }
+
+    /// The version of the protocol frames are written in.
+    pub fn protocol(&self) -> Protocol {
+        self.codec.protocol()
+    }
+
+    /// Write frames in `protocol` from now on.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.codec.set_protocol(protocol);
+    }
+
+    /// Read a single frame, waiting on the socket until a whole one has arrived.
+    ///
+    /// `None` means the peer closed the connection cleanly (between frames).
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        // Attempt frame from buffered data.  Return if possible.
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(Some(frame));
+            }
+            // Try to get more data.
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                // Remote closed the connection.  Check if incomplete frame in buffer.
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+
+    /// The next frame, if a whole one has already arrived; doesn't wait on the socket.
+    ///
+    /// For draining a pipeline: requests the client sent without waiting for replies to the earlier
+    /// ones can be answered all together.
+    pub fn buffered_frame(&mut self) -> Result<Option<Frame>> {
+        self.parse_frame()
+    }
+
+    /// Wait for the peer to hang up.
+    ///
+    /// Anything it sends meanwhile is kept for later calls to `read_frame` -- up to the buffer limit,
+    /// past which it's an error.  Cancel safe: for use in a `select!` alongside a command that may
+    /// take a while.
+    pub async fn closed(&mut self) -> Result<()> {
+        while 0 != self.stream.read_buf(&mut self.buffer).await? {
+            self.codec.check_buffer(&self.buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Pull a frame off the front of the buffer, if a whole one is there.
+    fn parse_frame(&mut self) -> Result<Option<Frame>> {
+        self.codec.decode(&mut self.buffer)
+    }
+
+    /// Write a frame to the socket, and flush it.
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.queue_frame(frame).await?;
+        self.flush().await
+    }
+
+    /// Write a frame, but leave it buffered until the next `flush` (or until the buffer fills).
+    pub async fn queue_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        frame.encode(self.codec.protocol(), &mut self.encoded);
+        let written = self.stream.write_all(&self.encoded).await;
+        self.encoded.clear();
+        written
+    }
+
+    /// Send everything queued so far.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Our end of a fresh connection, and the raw socket at the other.
+    async fn pair() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).await
+                                                                     .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        (Connection::new(socket), peer)
+    }
+
+    #[tokio::test]
+    async fn reads_frames_however_they_arrive() {
+        let (mut connection, mut peer) = pair().await;
+
+        // two frames in one write: the second waits in the buffer
+        peer.write_all(b"+OK\r\n:-12\r\n").await.unwrap();
+        assert_eq!(connection.read_frame().await.unwrap(), Some(Frame::ok()));
+        assert_eq!(connection.buffered_frame().unwrap(),
+                   Some(Frame::Integer(-12)));
+        assert_eq!(connection.buffered_frame().unwrap(), None);
+
+        // a frame split over several writes is only returned once it's all there
+        let reader = tokio::spawn(async move {
+            let frame = connection.read_frame().await.unwrap();
+            (connection, frame)
+        });
+        for part in [&b"*2\r\n$5\r\nhel"[..], b"lo\r\n*1", b"\r\n$0\r\n\r\n"] {
+            peer.write_all(part).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (mut connection, frame) = reader.await.unwrap();
+        assert_eq!(frame,
+                   Some(Frame::Array(vec![Frame::bulk("hello"),
+                                          Frame::Array(vec![Frame::bulk("")])])));
+
+        // hanging up between frames is a clean end; in the middle of one, it isn't
+        peer.write_all(b"$3\r\nab").await.unwrap();
+        drop(peer);
+        assert!(connection.read_frame().await.is_err());
+        let (mut connection, peer) = pair().await;
+        drop(peer);
+        assert_eq!(connection.read_frame().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn writes_nested_frames() {
+        let (mut connection, mut peer) = pair().await;
+        let frame = Frame::Array(vec![Frame::Simple("s".into()),
+                                      Frame::Error("ERR e".into()),
+                                      Frame::Integer(42),
+                                      Frame::Bulk(Bytes::from_static(b"a\r\nb")),
+                                      Frame::Null,
+                                      Frame::Array(vec![Frame::Array(Vec::new()),
+                                                        Frame::Integer(-1)])]);
+        connection.queue_frame(&frame).await.unwrap();
+        connection.write_frame(&Frame::ok()).await.unwrap();
+        drop(connection);
+
+        let mut written = Vec::new();
+        peer.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written,
+                   b"*6\r\n+s\r\n-ERR e\r\n:42\r\n$4\r\na\r\nb\r\n$-1\r\n*2\r\n*0\r\n:-1\r\n+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn reads_inline_commands() {
+        let (mut connection, mut peer) = pair().await;
+        peer.write_all(b"\r\nset k 'a b'\r\n*1\r\n$4\r\nPING\r\n")
+            .await
+            .unwrap();
+        assert_eq!(connection.read_frame().await.unwrap(),
+                   Some(Frame::Array(vec![Frame::bulk("set"),
+                                          Frame::bulk("k"),
+                                          Frame::bulk("a b")])));
+        assert_eq!(connection.read_frame().await.unwrap(),
+                   Some(Frame::Array(vec![Frame::bulk("PING")])));
+    }
+}
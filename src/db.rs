@@ -0,0 +1,1273 @@
+//! Shared server state: the key-value store and the pub/sub channel registry
+//!
+//! Keys are spread over the shards of a `ShardedDb`, so clients working on different keys rarely
+//! wait on the same lock.
+//!
+//! Keys may carry a deadline.  An expired key is removed lazily, the next time anyone touches it,
+//! and in any case by a background task that sleeps until the earliest deadline comes due.
+
+use std::{collections::{BTreeSet, HashMap, VecDeque},
+          sync::{Arc, Mutex, RwLock, Weak},
+          time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use rand::{distributions::{Distribution, WeightedIndex},
+           seq::SliceRandom};
+use tokio::{sync::{broadcast, Notify},
+            time::{self, Instant}};
+
+use crate::{error::CommandError,
+            glob::glob_match,
+            parse::{format_float, parse_float, parse_int},
+            scan_map::{ScanMap, ScanSet, Step},
+            shard_hash::{ShardStats, ShardedDb, DEFAULT_SHARDS}};
+
+mod bitmap;
+mod hash;
+mod hyperloglog;
+mod list;
+mod set;
+mod stream;
+mod transaction;
+mod zset;
+
+pub use bitmap::{BitFieldOp, BitFieldType, BitOp, BitRange, BitUnit, Overflow};
+pub use list::End;
+pub use set::SetOp;
+pub use stream::{ClaimOptions, GroupEntry, PendingInfo, PendingSummary, StreamEntry, StreamId,
+                 Trim, XAddId};
+pub use zset::{LexBound, ScoreBound, ScoreCondition, ZAddOptions, ZRange};
+
+/// Shards RANDOMKEY will try before concluding that every key it can see has expired.
+const RANDOM_KEY_ATTEMPTS: usize = 8;
+
+/// Longest string a value may grow to, as with Redis's default `proto-max-bulk-len`.
+pub const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// How many unread messages a channel holds for a slow subscriber before it starts dropping them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Handle to the server's state.  Cheap to clone; every clone refers to the same data.
+///
+/// Must be created inside a tokio runtime: it spawns the task that purges expired keys.
+#[derive(Debug, Clone)]
+pub struct Db {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    entries:     ShardedDb<String, Entry>,
+    /// Keys with a deadline, ordered soonest first.
+    ///
+    /// Lock order: a shard of `entries`, *then* this.  Never the other way round.
+    expirations: Mutex<BTreeSet<(Instant, String)>>,
+    /// One broadcast sender per channel with (or recently with) a subscriber.
+    pub_sub:     Mutex<HashMap<String, broadcast::Sender<Bytes>>>,
+    /// Clients blocked waiting for list elements.
+    ///
+    /// Lock order: shards of `entries`, then this, then `expirations`.
+    blocked:     Mutex<list::WaitQueues>,
+    /// Clients blocked in XREAD, by the stream they wait on.
+    ///
+    /// Lock order: shards of `entries`, then this.
+    readers:     Mutex<stream::Readers>,
+    /// Held for reading by each command as it runs, and for writing by EXEC, so that a transaction
+    /// happens all at once.
+    gate:        RwLock<()>,
+    /// Versions of the keys connections are WATCHing.
+    ///
+    /// Lock order: shards of `entries`, then this.
+    watched:     Mutex<transaction::Watched>,
+    /// Wakes the purge task: a new earliest deadline, or shutdown.
+    purge:       Arc<Notify>,
+}
+
+/// Contents of one shard.
+type Entries = ScanMap<String, Entry>;
+
+#[derive(Debug)]
+struct Entry {
+    value:      Value,
+    expires_at: Option<Instant>,
+}
+
+/// What a key holds.
+#[derive(Debug)]
+enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(ScanMap<Bytes, Bytes>),
+    Set(ScanSet<Bytes>),
+    SortedSet(zset::SortedSet),
+    Stream(stream::Stream),
+}
+
+/// Precondition on the key's current deadline for EXPIRE & co. to take effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpireCondition {
+    /// Only if the key has no deadline.
+    Nx,
+    /// Only if the key already has a deadline.
+    Xx,
+    /// Only if the new deadline is later than the current one.  (No deadline counts as infinitely late.)
+    Gt,
+    /// Only if the new deadline is sooner than the current one.
+    Lt,
+}
+
+/// What SET and GETEX do to a key's deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifetime {
+    /// No deadline.
+    Persistent,
+    /// Expire at the given moment.
+    Until(Instant),
+    /// Leave whatever deadline the key has (SET's KEEPTTL).
+    Keep,
+}
+
+/// Precondition on the key's existence for SET to take effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    /// Only if the key doesn't exist.
+    Nx,
+    /// Only if the key already exists.
+    Xx,
+}
+
+/// Remaining lifetime of a key, as reported by TTL/PTTL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    Missing,
+    Persistent,
+    Expires(Duration),
+}
+
+impl Db {
+    /// A store with the default number of shards.
+    pub fn new() -> Db {
+        Db::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// A store whose keys are spread over `num_shards` independently locked maps.
+    pub fn with_shards(num_shards: usize) -> Db {
+        let shared = Arc::new(Shared { entries:     ShardedDb::new(num_shards),
+                                       expirations: Mutex::default(),
+                                       pub_sub:     Mutex::default(),
+                                       blocked:     Mutex::default(),
+                                       readers:     Mutex::default(),
+                                       gate:        RwLock::default(),
+                                       watched:     Mutex::default(),
+                                       purge:       Arc::default(), });
+        tokio::spawn(purge_task(Arc::downgrade(&shared), shared.purge.clone()));
+        Db { shared }
+    }
+
+    /// Lock statistics for each shard; see `ShardedDb::stats`.
+    pub fn shard_stats(&self) -> Vec<ShardStats> {
+        self.shared.entries.stats()
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Bytes>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        self.shared.live_string(&mut entries, key)
+    }
+
+    /// Store `value`, replacing whatever was there -- including any deadline.
+    pub fn set(&self, key: String, value: Bytes, expire: Option<Duration>) {
+        let lifetime = match expire {
+            Some(ttl) => Lifetime::Until(Instant::now() + ttl),
+            None => Lifetime::Persistent,
+        };
+        // without GET, nothing to object to
+        let _ = self.set_with(key, value, lifetime, None, false);
+    }
+
+    /// SET in full: store `value` with the given `lifetime`, provided `condition` holds.
+    ///
+    /// Returns whether the value was stored, and (if `get`) the string it replaced or would have.
+    /// SET overwrites a value of any type -- but with `get`, the old one must be a string.
+    pub fn set_with(&self,
+                    key: String,
+                    value: Bytes,
+                    lifetime: Lifetime,
+                    condition: Option<SetCondition>,
+                    get: bool)
+                    -> Result<(bool, Option<Bytes>), CommandError> {
+        let mut entries = self.shared.entries.lock_key(&key);
+        let previous = self.shared.live(&mut entries, &key);
+        let old_value = match &previous {
+            Some(entry) if get => Some(entry.value.as_string()?.clone()),
+            _ => None,
+        };
+        let allowed = match condition {
+            None => true,
+            Some(SetCondition::Nx) => previous.is_none(),
+            Some(SetCondition::Xx) => previous.is_some(),
+        };
+        if !allowed {
+            return Ok((false, old_value));
+        }
+
+        let old_deadline = previous.and_then(|entry| entry.expires_at);
+        let expires_at = match lifetime {
+            Lifetime::Persistent => None,
+            Lifetime::Until(when) => Some(when),
+            Lifetime::Keep => old_deadline,
+        };
+        entries.insert(key.clone(), Entry { value: Value::String(value),
+                                            expires_at });
+        if old_deadline != expires_at {
+            if let Some(when) = old_deadline {
+                self.shared.unschedule(when, &key);
+            }
+            if let Some(when) = expires_at {
+                self.shared.schedule(when, key);
+            }
+        }
+        Ok((true, old_value))
+    }
+
+    /// Values at each of `keys`, all read at the same moment.  A key holding something other than a
+    /// string reads as missing.
+    pub fn mget(&self, keys: &[String]) -> Vec<Option<Bytes>> {
+        let mut shards = self.shared.entries.lock_keys(keys);
+        keys.iter()
+            .map(|key| {
+                self.shared
+                    .live_string(shards.map_for(key), key)
+                    .unwrap_or(None)
+            })
+            .collect()
+    }
+
+    /// Store every pair, as SET would, all at once: no reader sees some stored and others not.
+    ///
+    /// A key given twice ends up with the later value.
+    pub fn mset(&self, pairs: Vec<(String, Bytes)>) {
+        let mut shards = self.shared
+                             .entries
+                             .lock_keys(pairs.iter().map(|(key, _)| key));
+        for (key, value) in pairs {
+            self.shared.put_persistent(shards.map_for(&key), key, value);
+        }
+    }
+
+    /// Like `mset`, but only if none of the keys exist.  Returns whether the pairs were stored.
+    pub fn msetnx(&self, pairs: Vec<(String, Bytes)>) -> bool {
+        let mut shards = self.shared
+                             .entries
+                             .lock_keys(pairs.iter().map(|(key, _)| key));
+        if pairs.iter()
+                .any(|(key, _)| self.shared.live(shards.map_for(key), key).is_some())
+        {
+            return false;
+        }
+        for (key, value) in pairs {
+            self.shared.put_persistent(shards.map_for(&key), key, value);
+        }
+        true
+    }
+
+    /// Remove `key`, returning the value it held.
+    pub fn get_del(&self, key: &str) -> Result<Option<Bytes>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let value = self.shared.live_string(&mut entries, key)?;
+        if value.is_some() {
+            self.shared.remove(&mut entries, key);
+        }
+        Ok(value)
+    }
+
+    /// Value at `key`, changing its deadline on the way.  A deadline already past deletes the key.
+    pub fn get_ex(&self, key: &str, lifetime: Lifetime) -> Result<Option<Bytes>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(entry) = self.shared.live(&mut entries, key) else {
+            return Ok(None);
+        };
+        let value = Some(entry.value.as_string()?.clone());
+        let expires_at = match lifetime {
+            Lifetime::Keep => return Ok(value),
+            Lifetime::Persistent => None,
+            Lifetime::Until(when) if when <= Instant::now() => {
+                self.shared.remove(&mut entries, key);
+                return Ok(value);
+            }
+            Lifetime::Until(when) => Some(when),
+        };
+        if let Some(when) = std::mem::replace(&mut entry.expires_at, expires_at) {
+            self.shared.unschedule(when, key);
+        }
+        if let Some(when) = expires_at {
+            self.shared.schedule(when, key.to_string());
+        }
+        Ok(value)
+    }
+
+    /// Add `value` to the end of the string at `key` (creating it if need be).  Returns the new length.
+    pub fn append(&self, key: &str, value: &[u8]) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let current = self.shared
+                          .live_string(&mut entries, key)?
+                          .unwrap_or_default();
+        check_string_length(current.len() + value.len())?;
+        let mut data = BytesMut::with_capacity(current.len() + value.len());
+        data.extend_from_slice(&current);
+        data.extend_from_slice(value);
+        let len = data.len();
+        self.shared.put_value(&mut entries, key, data.freeze());
+        Ok(len)
+    }
+
+    /// Length of the string at `key`; `0` if there is none.
+    pub fn strlen(&self, key: &str) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_string(&mut entries, key)?
+               .map_or(0, |value| value.len()))
+    }
+
+    /// Bytes `start..=end` of the string at `key`.  Negative offsets count back from the end; offsets
+    /// past either end are clamped, as in Redis.
+    pub fn get_range(&self, key: &str, start: i64, end: i64) -> Result<Bytes, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(value) = self.shared.live_string(&mut entries, key)? else {
+            return Ok(Bytes::new());
+        };
+        let len = value.len() as i64;
+        if start < 0 && end < 0 && start > end {
+            return Ok(Bytes::new());
+        }
+        let start = if start < 0 { len + start } else { start }.max(0);
+        let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+        if len == 0 || start > end {
+            return Ok(Bytes::new());
+        }
+        Ok(value.slice(start as usize..=end as usize))
+    }
+
+    /// Overwrite the string at `key` with `value`, starting `offset` bytes in; any gap past the current
+    /// end is filled with zero bytes.  Returns the new length.
+    ///
+    /// An empty `value` changes nothing -- and, in particular, doesn't create the key.
+    pub fn set_range(&self, key: &str, offset: usize, value: &[u8]) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let current = self.shared
+                          .live_string(&mut entries, key)?
+                          .unwrap_or_default();
+        if value.is_empty() {
+            return Ok(current.len());
+        }
+        let end = offset.checked_add(value.len())
+                        .ok_or_else(string_too_long)?;
+        check_string_length(end)?;
+        let mut data = BytesMut::from(&current[..]);
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(value);
+        let len = data.len();
+        self.shared.put_value(&mut entries, key, data.freeze());
+        Ok(len)
+    }
+
+    /// Add `delta` to the integer stored at `key` (a missing key counts as `0`); returns the new value.
+    ///
+    /// Read, add and write all happen under the shard lock, so concurrent increments never lose updates.
+    /// The key keeps whatever deadline it had.
+    pub fn incr_by(&self, key: &str, delta: i64) -> Result<i64, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let current = match self.shared.live_string(&mut entries, key)? {
+            Some(value) => parse_int(&value).ok_or(CommandError::NotInteger)?,
+            None => 0,
+        };
+        let new = current.checked_add(delta)
+                         .ok_or_else(|| CommandError::Other("increment or decrement would overflow".to_string()))?;
+        self.shared
+            .put_value(&mut entries, key, Bytes::from(new.to_string()));
+        Ok(new)
+    }
+
+    /// Float flavour of `incr_by`.  Returns the new value, formatted as it was stored.
+    pub fn incr_by_float(&self, key: &str, delta: f64) -> Result<Bytes, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let current = match self.shared.live_string(&mut entries, key)? {
+            Some(value) => parse_float(&value).ok_or(CommandError::NotFloat)?,
+            None => 0.0,
+        };
+        let new = current + delta;
+        if !new.is_finite() {
+            return Err(CommandError::Other("increment would produce NaN or Infinity".to_string()));
+        }
+        let new = Bytes::from(format_float(new));
+        self.shared.put_value(&mut entries, key, new.clone());
+        Ok(new)
+    }
+
+    /// Give `key` a deadline, provided it exists and `condition` holds.
+    ///
+    /// A deadline that has already passed deletes the key on the spot.
+    /// Returns whether anything changed.
+    pub fn expire_at(&self, key: &str, when: Instant, condition: Option<ExpireCondition>) -> bool {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(entry) = self.shared.live(&mut entries, key) else {
+            return false;
+        };
+        let current = entry.expires_at;
+        let allowed = match condition {
+            None => true,
+            Some(ExpireCondition::Nx) => current.is_none(),
+            Some(ExpireCondition::Xx) => current.is_some(),
+            Some(ExpireCondition::Gt) => current.is_some_and(|current| when > current),
+            Some(ExpireCondition::Lt) => current.is_none_or(|current| when < current),
+        };
+        if !allowed {
+            return false;
+        }
+
+        if let Some(current) = current {
+            self.shared.unschedule(current, key);
+        }
+        if when <= Instant::now() {
+            entries.remove(key);
+        } else {
+            entry.expires_at = Some(when);
+            self.shared.schedule(when, key.to_string());
+        }
+        true
+    }
+
+    /// Time left before `key` expires.
+    pub fn ttl(&self, key: &str) -> Ttl {
+        let mut entries = self.shared.entries.lock_key(key);
+        match self.shared.live(&mut entries, key) {
+            None => Ttl::Missing,
+            Some(Entry { expires_at: None, .. }) => Ttl::Persistent,
+            Some(Entry { expires_at: Some(when),
+                         .. }) => Ttl::Expires(when.saturating_duration_since(Instant::now())),
+        }
+    }
+
+    /// Remove the deadline from `key`.  Returns whether it had one.
+    pub fn persist(&self, key: &str) -> bool {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(when) = self.shared
+                             .live(&mut entries, key)
+                             .and_then(|entry| entry.expires_at.take())
+        else {
+            return false;
+        };
+        self.shared.unschedule(when, key);
+        true
+    }
+
+    /// Remove each of `keys`, all at once; returns how many were there to remove.
+    pub fn del(&self, keys: &[String]) -> usize {
+        let mut shards = self.shared.entries.lock_keys(keys);
+        keys.iter()
+            .filter(|key| {
+                let entries = shards.map_for(key.as_str());
+                self.shared.live(entries, key).is_some() && self.shared.remove(entries, key)
+            })
+            .count()
+    }
+
+    /// How many of `keys` exist, all at the same moment.  (A key named twice counts twice.)
+    pub fn exists(&self, keys: &[String]) -> usize {
+        let mut shards = self.shared.entries.lock_keys(keys);
+        keys.iter()
+            .filter(|key| {
+                self.shared
+                    .live(shards.map_for(key.as_str()), key)
+                    .is_some()
+            })
+            .count()
+    }
+
+    /// Name of the type of value at `key`, or `None` if there is no such key.
+    pub fn key_type(&self, key: &str) -> Option<&'static str> {
+        let mut entries = self.shared.entries.lock_key(key);
+        self.shared
+            .live(&mut entries, key)
+            .map(|entry| entry.value.type_name())
+    }
+
+    /// Every live key matching the glob `pattern`.
+    pub fn keys(&self, pattern: &[u8]) -> Vec<String> {
+        let now = Instant::now();
+        let mut out = Vec::new();
+        for shard in 0..self.shared.entries.num_shards() {
+            let entries = self.shared.entries.lock(shard);
+            out.extend(entries.iter()
+                              .filter(|(key, entry)| {
+                                  !entry.is_expired(now) && glob_match(pattern, key.as_bytes())
+                              })
+                              .map(|(key, _)| key.clone()));
+        }
+        out
+    }
+
+    /// One step of an incremental walk over the keyspace.
+    ///
+    /// Keys are visited in order of their (fixed) hash, and `cursor` is the hash to resume from, so
+    /// the walk is unaffected by keys being added or removed, or by shards growing and rehashing, in
+    /// between calls: a key present from the first call to the last is returned exactly once.  Each
+    /// shard keeps its keys indexed by hash, so a step costs about `count` keys per shard, whatever
+    /// the size of the store.
+    ///
+    /// Takes the next `count` keys (plus any that share the last one's hash), *then* drops those that
+    /// fail the pattern or type filters, or have expired -- so a step may return fewer than `count`,
+    /// or none, without the walk being over.  Returns the cursor for the next step, which is `0` once
+    /// the walk is done.
+    pub fn scan(&self,
+                cursor: u64,
+                count: usize,
+                pattern: Option<&[u8]>,
+                kind: Option<&str>)
+                -> (u64, Vec<String>) {
+        let now = Instant::now();
+        let mut steps = Vec::new();
+        for shard in 0..self.shared.entries.num_shards() {
+            let entries = self.shared.entries.lock(shard);
+            let step = entries.step(cursor, count).map(|(key, entry)| {
+                                                      (key.clone(),
+                                                       entry.value.type_name(),
+                                                       entry.is_expired(now))
+                                                  });
+            steps.push(step);
+        }
+        let (next, taken) = Step::merge(steps, count).finish();
+
+        let keys =
+            taken.into_iter()
+                 .filter(|(_, _, expired)| !expired)
+                 .filter(|(key, ..)| {
+                     pattern.is_none_or(|pattern| glob_match(pattern, key.as_bytes()))
+                 })
+                 .filter(|(_, type_name, _)| {
+                     kind.is_none_or(|kind| kind.eq_ignore_ascii_case(type_name))
+                 })
+                 .map(|(key, ..)| key)
+                 .collect();
+        (next, keys)
+    }
+
+    /// A key picked at random, or `None` if the store is empty.
+    pub fn random_key(&self) -> Option<String> {
+        let mut rng = rand::thread_rng();
+        let now = Instant::now();
+        // pick a shard in proportion to its size, then a key within it
+        let sizes: Vec<usize> =
+            (0..self.shared.entries.num_shards()).map(|shard| {
+                                                     self.shared.entries.lock(shard).len()
+                                                 })
+                                                 .collect();
+        for _ in 0..RANDOM_KEY_ATTEMPTS {
+            let Ok(pick) = WeightedIndex::new(&sizes) else {
+                return None;
+            };
+            let shard = pick.sample(&mut rng);
+            let entries = self.shared.entries.lock(shard);
+            let live: Vec<&String> = entries.iter()
+                                            .filter(|(_, entry)| !entry.is_expired(now))
+                                            .map(|(key, _)| key)
+                                            .collect();
+            if let Some(key) = live.choose(&mut rng) {
+                return Some((*key).clone());
+            }
+        }
+        None
+    }
+
+    /// Get a receiver for `channel`, creating the channel if this is its first subscriber.
+    pub fn subscribe(&self, channel: String) -> broadcast::Receiver<Bytes> {
+        let mut pub_sub = self.shared.pub_sub.lock().expect("Unpoisoned mutex.");
+        pub_sub.entry(channel)
+               .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+               .subscribe()
+    }
+
+    /// Send `message` to everyone listening on `channel`.
+    ///
+    /// Returns the number of subscribers that will receive it.
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let pub_sub = self.shared.pub_sub.lock().expect("Unpoisoned mutex.");
+        pub_sub.get(channel)
+               // `send` errs only when there are no receivers -- i.e. nobody to count.
+               .map(|tx| tx.send(message).unwrap_or(0))
+               .unwrap_or(0)
+    }
+
+    /// Drop `channel` from the registry if its last subscriber has gone.
+    ///
+    /// Call after dropping a receiver, so that abandoned channels don't accumulate.
+    pub fn prune_channel(&self, channel: &str) {
+        let mut pub_sub = self.shared.pub_sub.lock().expect("Unpoisoned mutex.");
+        if pub_sub.get(channel)
+                  .is_some_and(|tx| tx.receiver_count() == 0)
+        {
+            pub_sub.remove(channel);
+        }
+    }
+}
+
+impl Default for Db {
+    fn default() -> Db {
+        Db::new()
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|when| when <= now)
+    }
+}
+
+impl Value {
+    /// Name of the type, as TYPE reports it.
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    fn as_string(&self) -> Result<&Bytes, CommandError> {
+        match self {
+            Value::String(data) => Ok(data),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+}
+
+impl Shared {
+    /// Replace the value at `key` in its (locked) shard with a string, keeping any deadline it has.
+    fn put_value(&self, entries: &mut Entries, key: &str, data: Bytes) {
+        match entries.get_mut(key) {
+            Some(entry) => entry.value = Value::String(data),
+            None => {
+                entries.insert(key.to_string(), Entry { value:      Value::String(data),
+                                                        expires_at: None, });
+            }
+        }
+    }
+
+    /// Store `data` at `key` in its (locked) shard, with no deadline.
+    fn put_persistent(&self, entries: &mut Entries, key: String, data: Bytes) {
+        let previous = entries.insert(key.clone(), Entry { value:      Value::String(data),
+                                                           expires_at: None, });
+        if let Some(when) = previous.and_then(|entry| entry.expires_at) {
+            self.unschedule(when, &key);
+        }
+    }
+
+    /// Remove a key from its (locked) shard, along with its deadline.  Returns whether it was there.
+    fn remove(&self, entries: &mut Entries, key: &str) -> bool {
+        match entries.remove(key) {
+            Some(entry) => {
+                if let Some(when) = entry.expires_at {
+                    self.unschedule(when, key);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Look up a key in its (locked) shard, treating -- and removing -- an expired one as absent.
+    fn live<'m>(&self, entries: &'m mut Entries, key: &str) -> Option<&'m mut Entry> {
+        let expires_at = entries.get(key)?.expires_at;
+        match expires_at {
+            Some(when) if when <= Instant::now() => {
+                entries.remove(key);
+                self.unschedule(when, key);
+                self.touch(key);
+                None
+            }
+            _ => entries.get_mut(key),
+        }
+    }
+
+    /// The string at `key` in its (locked) shard, if there is one.  Any other type is an error.
+    fn live_string(&self, entries: &mut Entries, key: &str) -> Result<Option<Bytes>, CommandError> {
+        self.live(entries, key)
+            .map(|entry| entry.value.as_string().cloned())
+            .transpose()
+    }
+
+    /// Record a deadline, waking the purge task if it is now the earliest one.
+    fn schedule(&self, when: Instant, key: String) {
+        let mut expirations = self.expirations.lock().expect("Unpoisoned mutex.");
+        let wake = expirations.first().is_none_or(|(next, _)| when < *next);
+        expirations.insert((when, key));
+        if wake {
+            self.purge.notify_one();
+        }
+    }
+
+    fn unschedule(&self, when: Instant, key: &str) {
+        let mut expirations = self.expirations.lock().expect("Unpoisoned mutex.");
+        expirations.remove(&(when, key.to_string()));
+    }
+
+    /// Remove every key whose deadline has passed.  Returns the next deadline, if any.
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        let now = Instant::now();
+        // Take the due keys first, then visit their shards: keeps to the shard-then-expirations lock order.
+        let (due, next) = {
+            let mut expirations = self.expirations.lock().expect("Unpoisoned mutex.");
+            let mut due = Vec::new();
+            while expirations.first().is_some_and(|(when, _)| *when <= now) {
+                due.push(expirations.pop_first().expect("just checked").1);
+            }
+            (due, expirations.first().map(|(when, _)| *when))
+        };
+        for key in due {
+            let mut entries = self.entries.lock_key(&key);
+            // The deadline may have been moved or cleared since we popped it; only go by what the entry says now.
+            if entries.get(&key)
+                      .and_then(|entry| entry.expires_at)
+                      .is_some_and(|when| when <= now)
+            {
+                entries.remove(&key);
+                self.touch(&key);
+            }
+        }
+        next
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // let the purge task notice there is nothing left to purge
+        self.purge.notify_one();
+    }
+}
+
+/// Refuse to grow a string past `MAX_STRING_LEN`.
+fn check_string_length(len: usize) -> Result<(), CommandError> {
+    match len > MAX_STRING_LEN {
+        true => Err(string_too_long()),
+        false => Ok(()),
+    }
+}
+
+fn string_too_long() -> CommandError {
+    CommandError::Other("string exceeds maximum allowed size (proto-max-bulk-len)".to_string())
+}
+
+/// Background task: purge expired keys, then sleep until the next deadline (or until woken early).
+///
+/// Holds only a weak reference, so it ends once every `Db` handle has been dropped.
+async fn purge_task(shared: Weak<Shared>, wake: Arc<Notify>) {
+    loop {
+        let next = {
+            let Some(shared) = shared.upgrade() else {
+                break;
+            };
+            shared.purge_expired_keys()
+        };
+        match next {
+            Some(when) => {
+                tokio::select! {
+                    _ = time::sleep_until(when) => {}
+                    _ = wake.notified() => {}
+                }
+            }
+            None => wake.notified().await,
+        }
+    }
+    tracing::debug!("Purge background task shut down.");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicBool, Ordering},
+                     Barrier},
+              thread};
+
+    use super::*;
+    use crate::{cmd::Command, frame::Frame};
+
+    fn keys(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("key:{i}")).collect()
+    }
+
+    fn pairs(keys: &[String], value: &str) -> Vec<(String, Bytes)> {
+        keys.iter()
+            .map(|key| (key.clone(), Bytes::from(value.to_string())))
+            .collect()
+    }
+
+    /// Run a command the way a client would, error replies included.
+    fn run(db: &Db, args: &[&str]) -> Frame {
+        let frame = Frame::Array(args.iter().map(Frame::bulk).collect());
+        Command::from_frame(frame).and_then(|command| command.apply(db))
+                                  .unwrap_or_else(|err| err.to_frame())
+    }
+
+    fn int(n: i64) -> Frame {
+        Frame::Integer(n)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_with_a_deadline_expires() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["SET", "ex", "v", "EX", "100"]), Frame::ok());
+        assert_eq!(run(&db, &["SET", "px", "v", "PX", "1500"]), Frame::ok());
+        assert_eq!(run(&db, &["TTL", "ex"]), int(100));
+        assert_eq!(run(&db, &["PTTL", "px"]), int(1500));
+        assert_eq!(run(&db, &["SET", "px", "v", "EX", "0"]),
+                   Frame::Error("ERR invalid expire time in 'set' command".to_string()));
+
+        time::advance(Duration::from_millis(1500)).await;
+        assert_eq!(db.get("px"), Ok(None));
+        assert_eq!(run(&db, &["TTL", "ex"]), int(99));
+        // a plain SET drops the deadline
+        assert_eq!(run(&db, &["SET", "ex", "v"]), Frame::ok());
+        assert_eq!(run(&db, &["TTL", "ex"]), int(-1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_reports_missing_persistent_and_rounded_lifetimes() {
+        let db = Db::new();
+        db.set("persistent".to_string(), Bytes::from("v"), None);
+        db.set("k".to_string(),
+               Bytes::from("v"),
+               Some(Duration::from_millis(2499)));
+        assert_eq!(run(&db, &["TTL", "missing"]), int(-2));
+        assert_eq!(run(&db, &["PTTL", "missing"]), int(-2));
+        assert_eq!(run(&db, &["TTL", "persistent"]), int(-1));
+        assert_eq!(run(&db, &["PTTL", "persistent"]), int(-1));
+
+        // TTL rounds to the nearest second; PTTL doesn't need to
+        assert_eq!(run(&db, &["TTL", "k"]), int(2));
+        time::advance(Duration::from_millis(999)).await;
+        assert_eq!(run(&db, &["PTTL", "k"]), int(1500));
+        assert_eq!(run(&db, &["TTL", "k"]), int(2));
+        time::advance(Duration::from_millis(1)).await;
+        assert_eq!(run(&db, &["TTL", "k"]), int(1));
+        assert_eq!(db.ttl("k"), Ttl::Expires(Duration::from_millis(1499)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expire_conditions() {
+        let db = Db::new();
+        db.set("k".to_string(), Bytes::from("v"), None);
+        // no deadline yet: XX and GT refuse, LT counts it as infinitely late
+        assert_eq!(run(&db, &["EXPIRE", "k", "100", "XX"]), int(0));
+        assert_eq!(run(&db, &["EXPIRE", "k", "100", "GT"]), int(0));
+        assert_eq!(run(&db, &["EXPIRE", "k", "100", "NX"]), int(1));
+        assert_eq!(run(&db, &["EXPIRE", "k", "200", "NX"]), int(0));
+        assert_eq!(run(&db, &["TTL", "k"]), int(100));
+
+        assert_eq!(run(&db, &["EXPIRE", "k", "50", "GT"]), int(0));
+        assert_eq!(run(&db, &["EXPIRE", "k", "150", "GT"]), int(1));
+        assert_eq!(run(&db, &["PEXPIRE", "k", "200000", "LT"]), int(0));
+        assert_eq!(run(&db, &["PEXPIRE", "k", "120500", "LT"]), int(1));
+        assert_eq!(run(&db, &["PTTL", "k"]), int(120500));
+        assert_eq!(run(&db, &["PEXPIRE", "k", "3000", "XX"]), int(1));
+        assert_eq!(run(&db, &["TTL", "k"]), int(3));
+
+        assert_eq!(run(&db, &["EXPIRE", "missing", "100"]), int(0));
+        db.persist("k");
+        assert_eq!(run(&db, &["EXPIRE", "k", "100", "LT"]), int(1));
+        // only the latest deadline is kept
+        assert_eq!(db.shared.expirations.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn persist_clears_the_deadline() {
+        let db = Db::new();
+        db.set("k".to_string(),
+               Bytes::from("v"),
+               Some(Duration::from_secs(10)));
+        db.set("persistent".to_string(), Bytes::from("v"), None);
+        assert!(db.persist("k"));
+        assert!(!db.persist("k"));
+        assert!(!db.persist("persistent"));
+        assert!(!db.persist("missing"));
+        assert_eq!(db.ttl("k"), Ttl::Persistent);
+        assert!(db.shared.expirations.lock().unwrap().is_empty());
+
+        time::advance(Duration::from_secs(20)).await;
+        assert_eq!(db.get("k"), Ok(Some(Bytes::from("v"))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_past_deadline_deletes_the_key() {
+        let db = Db::new();
+        db.set("k".to_string(),
+               Bytes::from("v"),
+               Some(Duration::from_secs(10)));
+        assert!(db.expire_at("k", Instant::now(), None));
+        assert_eq!(db.ttl("k"), Ttl::Missing);
+        assert!(!db.shared.entries.lock_key("k").contains_key("k"));
+        assert!(db.shared.expirations.lock().unwrap().is_empty());
+
+        db.set("k".to_string(), Bytes::from("v"), None);
+        assert_eq!(run(&db, &["EXPIRE", "k", "-1"]), int(1));
+        assert_eq!(run(&db, &["EXISTS", "k"]), int(0));
+        // nothing left to expire
+        assert!(!db.expire_at("k", Instant::now(), None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn purge_wakes_early_for_a_nearer_deadline() {
+        let db = Db::new();
+        db.set("late".to_string(),
+               Bytes::from("v"),
+               Some(Duration::from_secs(100)));
+        // let the purge task go to sleep until the late deadline
+        time::sleep(Duration::from_millis(10)).await;
+        db.set("soon".to_string(),
+               Bytes::from("v"),
+               Some(Duration::from_secs(1)));
+        time::sleep(Duration::from_secs(2)).await;
+
+        // gone from the map itself, not merely hidden by a lazy check
+        assert!(!db.shared.entries.lock_key("soon").contains_key("soon"));
+        assert!(db.shared.entries.lock_key("late").contains_key("late"));
+        time::sleep(Duration::from_secs(100)).await;
+        assert!(!db.shared.entries.lock_key("late").contains_key("late"));
+        assert!(db.shared.expirations.lock().unwrap().is_empty());
+    }
+
+    fn bulk(data: &str) -> Frame {
+        Frame::Bulk(Bytes::from(data.to_string()))
+    }
+
+    /// Seconds since the epoch, give or take.
+    fn unix_now() -> u64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+                                    .unwrap()
+                                    .as_secs()
+    }
+
+    #[tokio::test]
+    async fn setrange_pads_with_zeros_and_respects_the_limit() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["SETRANGE", "k", "3", "ab"]), int(5));
+        assert_eq!(db.get("k"), Ok(Some(Bytes::from(&b"\0\0\0ab"[..]))));
+        assert_eq!(run(&db, &["SETRANGE", "k", "1", "X"]), int(5));
+        assert_eq!(db.get("k"), Ok(Some(Bytes::from(&b"\0X\0ab"[..]))));
+        // an empty value changes nothing, and creates nothing
+        assert_eq!(run(&db, &["SETRANGE", "k", "100", ""]), int(5));
+        assert_eq!(run(&db, &["SETRANGE", "missing", "100", ""]), int(0));
+        assert_eq!(run(&db, &["EXISTS", "missing"]), int(0));
+
+        let too_long =
+            Frame::Error("ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string());
+        let last = (MAX_STRING_LEN - 1).to_string();
+        assert_eq!(run(&db, &["SETRANGE", "k", &last, "ab"]), too_long);
+        assert_eq!(run(&db, &["SETRANGE", "k", "-1", "ab"]),
+                   Frame::Error("ERR offset is out of range".to_string()));
+        assert_eq!(db.strlen("k"), Ok(5));
+    }
+
+    #[tokio::test]
+    async fn getrange_clamps_its_offsets() {
+        let db = Db::new();
+        db.set("k".to_string(), Bytes::from("Hello World"), None);
+        for (start, end, expected) in [("0", "4", "Hello"),
+                                       ("-5", "-1", "World"),
+                                       ("-100", "4", "Hello"),
+                                       ("6", "100", "World"),
+                                       ("-1", "-5", ""),
+                                       ("5", "3", ""),
+                                       ("100", "200", ""),
+                                       // an end too far back clamps to the first byte, as in Redis
+                                       ("0", "-100", "H")]
+        {
+            assert_eq!(run(&db, &["GETRANGE", "k", start, end]),
+                       bulk(expected),
+                       "{start} {end}");
+        }
+        assert_eq!(run(&db, &["GETRANGE", "missing", "0", "-1"]), bulk(""));
+    }
+
+    #[tokio::test]
+    async fn append_and_strlen_on_missing_keys() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["STRLEN", "k"]), int(0));
+        assert_eq!(run(&db, &["APPEND", "k", "Hello"]), int(5));
+        assert_eq!(run(&db, &["APPEND", "k", " World"]), int(11));
+        assert_eq!(run(&db, &["STRLEN", "k"]), int(11));
+        assert_eq!(run(&db, &["GET", "k"]), bulk("Hello World"));
+
+        db.push("list", vec![Bytes::from("a")], End::Left).unwrap();
+        assert_eq!(run(&db, &["APPEND", "list", "x"]),
+                   CommandError::WrongType.to_frame());
+        assert_eq!(run(&db, &["STRLEN", "list"]),
+                   CommandError::WrongType.to_frame());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn getex_and_getdel() {
+        let db = Db::new();
+        db.set("k".to_string(), Bytes::from("v"), None);
+        assert_eq!(run(&db, &["GETEX", "k", "EX", "100"]), bulk("v"));
+        assert_eq!(run(&db, &["TTL", "k"]), int(100));
+        // no option leaves the deadline alone
+        assert_eq!(run(&db, &["GETEX", "k"]), bulk("v"));
+        assert_eq!(run(&db, &["TTL", "k"]), int(100));
+        assert_eq!(run(&db, &["GETEX", "k", "PERSIST"]), bulk("v"));
+        assert_eq!(run(&db, &["TTL", "k"]), int(-1));
+        assert_eq!(run(&db, &["GETEX", "missing", "EX", "100"]), Frame::Null);
+
+        assert_eq!(run(&db, &["GETDEL", "k"]), bulk("v"));
+        assert_eq!(run(&db, &["GETDEL", "k"]), Frame::Null);
+        assert_eq!(run(&db, &["EXISTS", "k"]), int(0));
+
+        // neither touches anything that isn't a string
+        db.push("list", vec![Bytes::from("a")], End::Left).unwrap();
+        assert_eq!(run(&db, &["GETEX", "list", "EX", "100"]),
+                   CommandError::WrongType.to_frame());
+        assert_eq!(run(&db, &["GETDEL", "list"]),
+                   CommandError::WrongType.to_frame());
+        assert_eq!(run(&db, &["TTL", "list"]), int(-1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_options_combine() {
+        let db = Db::new();
+        // NX & XX go by whether the key exists; GET replies with what was there either way
+        assert_eq!(run(&db, &["SET", "k", "1", "XX"]), Frame::Null);
+        assert_eq!(run(&db, &["SET", "k", "1", "NX", "GET"]), Frame::Null);
+        assert_eq!(run(&db, &["SET", "k", "2", "NX", "GET"]), bulk("1"));
+        assert_eq!(run(&db, &["GET", "k"]), bulk("1"));
+        assert_eq!(run(&db, &["SET", "k", "3", "XX", "GET"]), bulk("1"));
+        assert_eq!(run(&db, &["SET", "k", "4", "NX"]), Frame::Null);
+        assert_eq!(run(&db, &["GET", "k"]), bulk("3"));
+        assert_eq!(run(&db, &["SET", "k", "1", "NX", "XX"]),
+                   CommandError::Syntax.to_frame());
+
+        // KEEPTTL keeps the deadline; anything else replaces it
+        assert_eq!(run(&db, &["SET", "k", "v", "EX", "100"]), Frame::ok());
+        assert_eq!(run(&db, &["SET", "k", "w", "KEEPTTL"]), Frame::ok());
+        assert_eq!(run(&db, &["TTL", "k"]), int(100));
+        assert_eq!(run(&db, &["SET", "k", "v", "EX", "10", "KEEPTTL"]),
+                   CommandError::Syntax.to_frame());
+
+        let at = (unix_now() + 100).to_string();
+        assert_eq!(run(&db, &["SET", "k", "v", "EXAT", &at]), Frame::ok());
+        assert!(matches!(run(&db, &["TTL", "k"]), Frame::Integer(99..=100)));
+        let at = ((unix_now() + 100) * 1000).to_string();
+        assert_eq!(run(&db, &["SET", "k", "v", "PXAT", &at, "GET"]), bulk("v"));
+        assert!(matches!(run(&db, &["TTL", "k"]), Frame::Integer(99..=100)));
+        // a moment already past stores nothing that lasts
+        assert_eq!(run(&db, &["SET", "k", "v", "PXAT", "1"]), Frame::ok());
+        assert_eq!(run(&db, &["EXISTS", "k"]), int(0));
+
+        // GET wants the old value to be a string, and then changes nothing
+        db.push("list", vec![Bytes::from("a")], End::Left).unwrap();
+        assert_eq!(run(&db, &["SET", "list", "v", "GET"]),
+                   CommandError::WrongType.to_frame());
+        assert_eq!(run(&db, &["TYPE", "list"]),
+                   Frame::Simple("list".to_string()));
+        assert_eq!(run(&db, &["SET", "list", "v"]), Frame::ok());
+    }
+
+    #[tokio::test]
+    async fn mset_replaces_values_and_deadlines() {
+        let db = Db::with_shards(4);
+        db.set("key:0".to_string(),
+               Bytes::from("old"),
+               Some(Duration::from_secs(100)));
+        db.mset(pairs(&keys(3), "new"));
+        assert_eq!(db.mget(&keys(4)), vec![Some(Bytes::from("new")),
+                                           Some(Bytes::from("new")),
+                                           Some(Bytes::from("new")),
+                                           None]);
+        assert_eq!(db.ttl("key:0"), Ttl::Persistent);
+        assert!(db.shared.expirations.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn scan_returns_every_key_once_in_small_steps() {
+        let db = Db::with_shards(8);
+        db.mset(pairs(&keys(2000), "v"));
+
+        let mut seen = HashMap::new();
+        let mut cursor = 0;
+        let mut steps = 0;
+        loop {
+            let (next, keys) = db.scan(cursor, 7, None, None);
+            assert!(keys.len() <= 7, "{} keys in one step", keys.len());
+            for key in keys {
+                *seen.entry(key).or_insert(0) += 1;
+            }
+            steps += 1;
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 2000);
+        assert!(seen.values().all(|&n| n == 1));
+        assert!(steps >= 2000 / 7);
+    }
+
+    #[tokio::test]
+    async fn msetnx_stores_nothing_if_any_key_exists() {
+        let db = Db::with_shards(4);
+        db.set("key:2".to_string(), Bytes::from("there"), None);
+        assert!(!db.msetnx(pairs(&keys(4), "new")));
+        assert_eq!(db.mget(&keys(4)), vec![None,
+                                           None,
+                                           Some(Bytes::from("there")),
+                                           None]);
+        assert!(db.msetnx(pairs(&keys(2), "new")));
+        assert_eq!(db.get("key:1"), Ok(Some(Bytes::from("new"))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mset_is_never_seen_half_applied() {
+        let db = Db::with_shards(8);
+        let keys = keys(32);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                     for round in 0..2000 {
+                         db.mset(pairs(&keys, &round.to_string()));
+                     }
+                     done.store(true, Ordering::Release);
+                 });
+            for _ in 0..3 {
+                scope.spawn(|| {
+                         while !done.load(Ordering::Acquire) {
+                             let values = db.mget(&keys);
+                             assert!(values.iter().all(|value| *value == values[0]),
+                                     "saw a partial MSET: {values:?}");
+                         }
+                     });
+            }
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn del_is_never_seen_half_applied() {
+        let db = Db::with_shards(8);
+        let keys = keys(32);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                     for _ in 0..2000 {
+                         db.mset(pairs(&keys, "v"));
+                         assert_eq!(db.del(&keys), keys.len());
+                     }
+                     done.store(true, Ordering::Release);
+                 });
+            for _ in 0..3 {
+                scope.spawn(|| {
+                         while !done.load(Ordering::Acquire) {
+                             let existing = db.exists(&keys);
+                             assert!(existing == 0 || existing == keys.len(),
+                                     "saw a partial DEL: {existing} keys left");
+                         }
+                     });
+            }
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn racing_msetnx_has_one_winner() {
+        let db = Db::with_shards(8);
+        let keys = keys(16);
+        let start = Barrier::new(4);
+
+        let wins: usize = thread::scope(|scope| {
+            let racers: Vec<_> = (0..4).map(|n| {
+                                           let (db, keys, start) = (&db, &keys, &start);
+                                           scope.spawn(move || {
+                                                    // overlapping, differently ordered, key sets
+                                                    let mut mine = keys[n..].to_vec();
+                                                    mine.rotate_left(n);
+                                                    start.wait();
+                                                    db.msetnx(pairs(&mine, &n.to_string()))
+                                                })
+                                       })
+                                       .collect();
+            racers.into_iter()
+                  .map(|racer| racer.join().unwrap() as usize)
+                  .sum()
+        });
+        assert_eq!(wins, 1);
+        let values: Vec<_> = db.mget(&keys[3..]);
+        assert!(values.iter()
+                      .all(|value| value.is_some() && *value == values[0]));
+    }
+
+    #[tokio::test]
+    async fn incr_refuses_what_isnt_an_integer() {
+        let db = Db::with_shards(4);
+        assert_eq!(db.incr_by("n", 1), Ok(1));
+        assert_eq!(db.incr_by("n", -5), Ok(-4));
+        for value in ["abc", "1.5", " 1", "+1", ""] {
+            db.set("s".to_string(), Bytes::from(value), None);
+            assert_eq!(db.incr_by("s", 1),
+                       Err(CommandError::NotInteger),
+                       "{value:?}");
+        }
+        db.push("list", vec![Bytes::from("a")], End::Left).unwrap();
+        assert_eq!(db.incr_by("list", 1), Err(CommandError::WrongType));
+    }
+
+    #[tokio::test]
+    async fn incr_past_the_limits_is_an_error() {
+        let db = Db::with_shards(4);
+        let overflow =
+            Err(CommandError::Other("increment or decrement would overflow".to_string()));
+        db.set("max".to_string(), Bytes::from(i64::MAX.to_string()), None);
+        assert_eq!(db.incr_by("max", 1), overflow);
+        db.set("min".to_string(), Bytes::from(i64::MIN.to_string()), None);
+        assert_eq!(db.incr_by("min", -1), overflow);
+        // and nothing changed
+        assert_eq!(db.get("max"), Ok(Some(Bytes::from(i64::MAX.to_string()))));
+    }
+
+    #[tokio::test]
+    async fn incr_by_float_formats_like_redis() {
+        let db = Db::with_shards(4);
+        assert_eq!(db.incr_by_float("f", 0.1), Ok(Bytes::from("0.1")));
+        assert_eq!(db.incr_by_float("f", 0.2), Ok(Bytes::from("0.3")));
+        assert_eq!(db.get("f"), Ok(Some(Bytes::from("0.3"))));
+
+        for (start, delta, expected) in [("10.50", 0.1, "10.6"),
+                                         ("5.0e3", 0.0, "5000"),
+                                         ("3", 1.0, "4"),
+                                         ("0", 0.00001, "0.00001"),
+                                         ("-1", -0.5, "-1.5"),
+                                         ("1e20", 1.0, "100000000000000000000"),
+                                         ("1", 1.0 / 3.0, "1.33333333333333")]
+        {
+            db.set("f".to_string(), Bytes::from(start), None);
+            assert_eq!(db.incr_by_float("f", delta),
+                       Ok(Bytes::from(expected)),
+                       "{start} + {delta}");
+        }
+    }
+
+    #[tokio::test]
+    async fn incr_by_float_refuses_inf_and_nan() {
+        let db = Db::with_shards(4);
+        let refused =
+            Err(CommandError::Other("increment would produce NaN or Infinity".to_string()));
+        db.set("f".to_string(), Bytes::from("1.7e308"), None);
+        assert_eq!(db.incr_by_float("f", 1.7e308), refused);
+        assert_eq!(db.incr_by_float("f", f64::NAN), refused);
+        assert_eq!(db.get("f"), Ok(Some(Bytes::from("1.7e308"))));
+
+        db.set("s".to_string(), Bytes::from("abc"), None);
+        assert_eq!(db.incr_by_float("s", 1.0), Err(CommandError::NotFloat));
+        db.set("s".to_string(), Bytes::from("inf"), None);
+        assert_eq!(db.incr_by_float("s", 1.0), Err(CommandError::NotFloat));
+    }
+}
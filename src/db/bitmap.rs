@@ -0,0 +1,479 @@
+//! Bitmaps: strings addressed bit by bit
+//!
+//! Bit 0 is the most significant bit of the first byte, as in Redis.  Writes past the end grow the
+//! string with zero bytes; reads past it see zeros.
+
+use bytes::{Bytes, BytesMut};
+
+use super::{check_string_length, Db, Entry, Shared};
+use crate::{error::CommandError, shard_hash::ShardGuards};
+
+/// How BITOP combines its sources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    /// Complement of the (single) source
+    Not,
+}
+
+/// What the indices of a BITCOUNT or BITPOS range count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitUnit {
+    Byte,
+    Bit,
+}
+
+/// Part of a bitmap to look at.  Negative indices count back from the end; both ends are inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitRange {
+    pub start: i64,
+    /// The end of the bitmap, if not given.
+    pub end:   Option<i64>,
+    pub unit:  BitUnit,
+}
+
+/// An integer field of a BITFIELD, e.g. `i8` or `u63`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitFieldType {
+    pub signed: bool,
+    /// 1-64 for signed fields, 1-63 for unsigned ones (so that every value fits an `i64` reply).
+    pub bits:   u32,
+}
+
+/// What a BITFIELD write does with a result that doesn't fit its field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Keep the low bits
+    #[default]
+    Wrap,
+    /// Clamp to the field's least or greatest value
+    Sat,
+    /// Leave the field be, and reply nil
+    Fail,
+}
+
+/// One operation of a BITFIELD, at a bit offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitFieldOp {
+    Get {
+        ty:     BitFieldType,
+        offset: u64,
+    },
+    /// Replies with the old value.
+    Set {
+        ty:       BitFieldType,
+        offset:   u64,
+        value:    i64,
+        overflow: Overflow,
+    },
+    /// Replies with the new value.
+    IncrBy {
+        ty:       BitFieldType,
+        offset:   u64,
+        delta:    i64,
+        overflow: Overflow,
+    },
+}
+
+impl Db {
+    /// Set or clear the bit at `offset` in the string at `key`, creating or growing it as need be.
+    /// Returns the bit's old value.
+    pub fn setbit(&self, key: &str, offset: u64, on: bool) -> Result<bool, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let current = self.shared
+                          .live_string(&mut entries, key)?
+                          .unwrap_or_default();
+        let mut data = grown(&current, offset + 1)?;
+        let old = get_bit(&data, offset);
+        set_bit(&mut data, offset, on);
+        self.shared.put_value(&mut entries, key, data.freeze());
+        Ok(old)
+    }
+
+    /// The bit at `offset` in the string at `key`.
+    pub fn getbit(&self, key: &str, offset: u64) -> Result<bool, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_string(&mut entries, key)?
+               .is_some_and(|data| get_bit(&data, offset)))
+    }
+
+    /// How many bits are set in (the given `range` of) the string at `key`.
+    pub fn bitcount(&self, key: &str, range: Option<BitRange>) -> Result<u64, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let data = self.shared
+                       .live_string(&mut entries, key)?
+                       .unwrap_or_default();
+        let Some((first, last)) = bit_span(data.len(), range) else {
+            return Ok(0);
+        };
+        let (first_byte, last_byte) = ((first / 8) as usize, (last / 8) as usize);
+        let whole: u64 = data[first_byte..=last_byte].iter()
+                                                     .map(|byte| byte.count_ones() as u64)
+                                                     .sum();
+        // take off the bits of the end bytes that fall outside the range
+        let before = data[first_byte].checked_shr(8 - (first % 8) as u32)
+                                     .unwrap_or(0);
+        let after = data[last_byte].checked_shl((last % 8) as u32 + 1)
+                                   .unwrap_or(0);
+        Ok(whole - before.count_ones() as u64 - after.count_ones() as u64)
+    }
+
+    /// Position of the first `bit` in (the given `range` of) the string at `key`, or -1.
+    ///
+    /// Looking for a clear bit with no end given, a string of set bits is taken to be followed by clear
+    /// ones, as in Redis: the answer is then the bit just past the end.
+    pub fn bitpos(&self,
+                  key: &str,
+                  bit: bool,
+                  range: Option<BitRange>)
+                  -> Result<i64, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(data) = self.shared.live_string(&mut entries, key)? else {
+            return Ok(if bit { -1 } else { 0 });
+        };
+        let Some((first, last)) = bit_span(data.len(), range) else {
+            return Ok(-1);
+        };
+        let skip = if bit { 0x00 } else { 0xff };
+        let mut offset = first;
+        while offset <= last {
+            // whole bytes that can't hold the bit are passed over in one go
+            if offset % 8 == 0 && offset + 7 <= last && data[(offset / 8) as usize] == skip {
+                offset += 8;
+                continue;
+            }
+            if get_bit(&data, offset) == bit {
+                return Ok(offset as i64);
+            }
+            offset += 1;
+        }
+        let unbounded = range.is_none_or(|range| range.end.is_none());
+        Ok(if !bit && unbounded {
+            last as i64 + 1
+        } else {
+            -1
+        })
+    }
+
+    /// Combine the strings at `keys` (a missing key being empty, and shorter strings padded with zero
+    /// bytes) and store the result at `destination`, replacing whatever was there.  Returns its length.
+    ///
+    /// An empty result deletes `destination`.
+    pub fn bitop(&self,
+                 op: BitOp,
+                 destination: &str,
+                 keys: &[String])
+                 -> Result<usize, CommandError> {
+        let mut shards = self.shared
+                             .entries
+                             .lock_keys(keys.iter().map(String::as_str).chain([destination]));
+        let sources = self.shared.strings(&mut shards, keys)?;
+        let len = sources.iter().map(Bytes::len).max().unwrap_or(0);
+        let byte = |source: &Bytes, i: usize| source.get(i).copied().unwrap_or(0);
+        let result: BytesMut = (0..len).map(|i| {
+                                           let mut bytes =
+                                               sources.iter().map(|source| byte(source, i));
+                                           let first = bytes.next().unwrap_or(0);
+                                           match op {
+                                               BitOp::And => bytes.fold(first, |acc, b| acc & b),
+                                               BitOp::Or => bytes.fold(first, |acc, b| acc | b),
+                                               BitOp::Xor => bytes.fold(first, |acc, b| acc ^ b),
+                                               BitOp::Not => !first,
+                                           }
+                                       })
+                                       .collect();
+        let entries = shards.map_for(destination);
+        match result.is_empty() {
+            true => {
+                self.shared.remove(entries, destination);
+            }
+            false => self.shared
+                         .put_persistent(entries, destination.to_string(), result.freeze()),
+        }
+        Ok(len)
+    }
+
+    /// Run `ops` in order against the string at `key`.  Returns a reply for each: `None` where a write
+    /// was refused under `Overflow::Fail`.
+    ///
+    /// If there are any writes the string is created, and grown to hold every field written, even if
+    /// none of them succeeds.
+    pub fn bitfield(&self,
+                    key: &str,
+                    ops: &[BitFieldOp])
+                    -> Result<Vec<Option<i64>>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let current = self.shared.live_string(&mut entries, key)?;
+        let end = ops.iter()
+                     .filter_map(|op| match *op {
+                         BitFieldOp::Get { .. } => None,
+                         BitFieldOp::Set { ty, offset, .. }
+                         | BitFieldOp::IncrBy { ty, offset, .. } => Some(offset + ty.bits as u64),
+                     })
+                     .max();
+        let Some(end) = end else {
+            let data = current.unwrap_or_default();
+            return Ok(ops.iter()
+                         .map(|op| match *op {
+                             BitFieldOp::Get { ty, offset } => Some(ty.read(&data, offset)),
+                             _ => unreachable!("No writes."),
+                         })
+                         .collect());
+        };
+
+        let mut data = grown(&current.unwrap_or_default(), end)?;
+        let replies = ops.iter()
+                         .map(|op| match *op {
+                             BitFieldOp::Get { ty, offset } => Some(ty.read(&data, offset)),
+                             BitFieldOp::Set { ty,
+                                               offset,
+                                               value,
+                                               overflow, } => {
+                                 let old = ty.read(&data, offset);
+                                 let target = match ty.signed {
+                                     true => value as i128,
+                                     // as Redis does: a negative value is a huge unsigned one
+                                     false => value as u64 as i128,
+                                 };
+                                 let new = ty.fit(target, overflow)?;
+                                 ty.write(&mut data, offset, new);
+                                 Some(old)
+                             }
+                             BitFieldOp::IncrBy { ty,
+                                                  offset,
+                                                  delta,
+                                                  overflow, } => {
+                                 let old = ty.read(&data, offset);
+                                 let new = ty.fit(old as i128 + delta as i128, overflow)?;
+                                 ty.write(&mut data, offset, new);
+                                 Some(new)
+                             }
+                         })
+                         .collect();
+        self.shared.put_value(&mut entries, key, data.freeze());
+        Ok(replies)
+    }
+}
+
+impl Shared {
+    /// The strings at `keys`, whose shards are all locked; a missing key is empty.  Any other type is
+    /// an error.
+    fn strings(&self,
+               shards: &mut ShardGuards<'_, String, Entry>,
+               keys: &[String])
+               -> Result<Vec<Bytes>, CommandError> {
+        keys.iter()
+            .map(|key| {
+                Ok(self.live_string(shards.map_for(key), key)?
+                       .unwrap_or_default())
+            })
+            .collect()
+    }
+}
+
+impl BitFieldType {
+    /// The field at `offset`; zero past the end of `data`.
+    fn read(self, data: &[u8], offset: u64) -> i64 {
+        let raw = (offset..offset + self.bits as u64).fold(0u64, |acc, bit| {
+                                                         acc << 1 | get_bit(data, bit) as u64
+                                                     });
+        match self.signed && self.bits < 64 {
+            // sign-extend
+            true => (raw << (64 - self.bits)) as i64 >> (64 - self.bits),
+            false => raw as i64,
+        }
+    }
+
+    /// Store the low `bits` bits of `value` at `offset`.  `data` must be long enough.
+    fn write(self, data: &mut [u8], offset: u64, value: i64) {
+        for i in 0..self.bits as u64 {
+            let shift = self.bits as u64 - 1 - i;
+            set_bit(data, offset + i, (value as u64 >> shift) & 1 == 1);
+        }
+    }
+
+    /// `target` as a value of this type, or what `overflow` makes of it if it doesn't fit.
+    fn fit(self, target: i128, overflow: Overflow) -> Option<i64> {
+        let (min, max) = match self.signed {
+            true => (-(1i128 << (self.bits - 1)), (1i128 << (self.bits - 1)) - 1),
+            false => (0, (1i128 << self.bits) - 1),
+        };
+        if (min..=max).contains(&target) {
+            return Some(target as i64);
+        }
+        match overflow {
+            Overflow::Wrap => Some(((target - min).rem_euclid(1 << self.bits) + min) as i64),
+            Overflow::Sat => Some(target.clamp(min, max) as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+/// A copy of `data`, padded with zero bytes to hold at least `bits` bits.
+fn grown(data: &[u8], bits: u64) -> Result<BytesMut, CommandError> {
+    let len = usize::try_from(bits.div_ceil(8)).unwrap_or(usize::MAX);
+    check_string_length(len)?;
+    let mut grown = BytesMut::from(data);
+    if grown.len() < len {
+        grown.resize(len, 0);
+    }
+    Ok(grown)
+}
+
+fn get_bit(data: &[u8], offset: u64) -> bool {
+    usize::try_from(offset / 8).ok()
+                               .and_then(|byte| data.get(byte))
+                               .is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+/// Set or clear a bit.  `data` must be long enough.
+fn set_bit(data: &mut [u8], offset: u64, on: bool) {
+    let byte = &mut data[(offset / 8) as usize];
+    let mask = 0x80 >> (offset % 8);
+    match on {
+        true => *byte |= mask,
+        false => *byte &= !mask,
+    }
+}
+
+/// The first and last bits (inclusive) of `range` in a string of `len` bytes, clamped to the string;
+/// `None` if that leaves nothing.  Indices are resolved as GETRANGE resolves them.
+fn bit_span(len: usize, range: Option<BitRange>) -> Option<(u64, u64)> {
+    let Some(range) = range else {
+        return (len > 0).then(|| (0, len as u64 * 8 - 1));
+    };
+    let scale = match range.unit {
+        BitUnit::Byte => 1,
+        BitUnit::Bit => 8,
+    };
+    let units = len as i64 * scale;
+    let end = range.end.unwrap_or(-1);
+    if range.start < 0 && end < 0 && range.start > end {
+        return None;
+    }
+    let resolve = |index: i64| if index < 0 { units + index } else { index }.max(0);
+    let (start, end) = (resolve(range.start), resolve(end).min(units - 1));
+    if units == 0 || start > end {
+        return None;
+    }
+    Some(match range.unit {
+        BitUnit::Byte => (start as u64 * 8, end as u64 * 8 + 7),
+        BitUnit::Bit => (start as u64, end as u64),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const I8: BitFieldType = BitFieldType { signed: true,
+                                            bits:   8, };
+    const U4: BitFieldType = BitFieldType { signed: false,
+                                            bits:   4, };
+
+    fn bytes_range(start: i64, end: i64) -> Option<BitRange> {
+        Some(BitRange { start,
+                        end: Some(end),
+                        unit: BitUnit::Byte })
+    }
+
+    #[tokio::test]
+    async fn sets_and_counts_bits() {
+        let db = Db::new();
+        assert!(!db.setbit("b", 7, true).unwrap());
+        assert!(db.setbit("b", 7, true).unwrap());
+        db.setbit("b", 17, true).unwrap();
+        assert_eq!(db.get("b").unwrap().unwrap(),
+                   Bytes::from_static(b"\x01\x00\x40"));
+        assert!(db.getbit("b", 17).unwrap());
+        assert!(!db.getbit("b", 1000).unwrap());
+
+        db.set("s".into(), Bytes::from("foobar"), None);
+        assert_eq!(db.bitcount("s", None).unwrap(), 26);
+        assert_eq!(db.bitcount("s", bytes_range(0, 0)).unwrap(), 4);
+        assert_eq!(db.bitcount("s", bytes_range(1, 1)).unwrap(), 6);
+        assert_eq!(db.bitcount("s", bytes_range(-2, -1)).unwrap(), 7);
+        let bits = Some(BitRange { start: 5,
+                                   end:   Some(30),
+                                   unit:  BitUnit::Bit, });
+        assert_eq!(db.bitcount("s", bits).unwrap(), 17);
+        assert_eq!(db.bitcount("nope", None).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn finds_bits() {
+        let db = Db::new();
+        db.set("k".into(), Bytes::from_static(b"\xff\xf0\x00"), None);
+        assert_eq!(db.bitpos("k", false, None).unwrap(), 12);
+        assert_eq!(db.bitpos("k", true, bytes_range(2, -1)).unwrap(), -1);
+        let from_bit = Some(BitRange { start: 7,
+                                       end:   Some(15),
+                                       unit:  BitUnit::Bit, });
+        assert_eq!(db.bitpos("k", true, from_bit).unwrap(), 7);
+
+        db.set("ones".into(), Bytes::from_static(b"\xff\xff"), None);
+        assert_eq!(db.bitpos("ones", false, None).unwrap(), 16);
+        assert_eq!(db.bitpos("ones", false, bytes_range(0, -1)).unwrap(), -1);
+        assert_eq!(db.bitpos("nope", false, None).unwrap(), 0);
+        assert_eq!(db.bitpos("nope", true, None).unwrap(), -1);
+    }
+
+    #[tokio::test]
+    async fn combines_strings() {
+        let db = Db::new();
+        db.set("a".into(), Bytes::from_static(b"\x0f\xff"), None);
+        db.set("b".into(), Bytes::from_static(b"\xf0"), None);
+        let keys = ["a".to_string(), "b".to_string()];
+        assert_eq!(db.bitop(BitOp::Or, "d", &keys).unwrap(), 2);
+        assert_eq!(db.get("d").unwrap().unwrap(),
+                   Bytes::from_static(b"\xff\xff"));
+        db.bitop(BitOp::And, "d", &keys).unwrap();
+        assert_eq!(db.get("d").unwrap().unwrap(),
+                   Bytes::from_static(b"\x00\x00"));
+        db.bitop(BitOp::Xor, "d", &keys).unwrap();
+        assert_eq!(db.get("d").unwrap().unwrap(),
+                   Bytes::from_static(b"\xff\xff"));
+        db.bitop(BitOp::Not, "d", &keys[..1]).unwrap();
+        assert_eq!(db.get("d").unwrap().unwrap(),
+                   Bytes::from_static(b"\xf0\x00"));
+        assert_eq!(db.bitop(BitOp::Or, "d", &["nope".to_string()]).unwrap(), 0);
+        assert_eq!(db.get("d").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn bitfields_overflow_as_asked() {
+        let db = Db::new();
+        let set = |offset, value, overflow| BitFieldOp::Set { ty: I8,
+                                                              offset,
+                                                              value,
+                                                              overflow };
+        let incr = |ty, delta, overflow| BitFieldOp::IncrBy { ty,
+                                                              offset: 0,
+                                                              delta,
+                                                              overflow };
+        assert_eq!(db.bitfield("f", &[set(0, 100, Overflow::Wrap)]).unwrap(),
+                   vec![Some(0)]);
+        assert_eq!(db.bitfield("f", &[incr(I8, 100, Overflow::Fail),
+                                      incr(I8, 100, Overflow::Wrap),
+                                      incr(I8, 100, Overflow::Sat),
+                                      incr(I8, -300, Overflow::Sat)])
+                     .unwrap(),
+                   vec![None, Some(-56), Some(44), Some(-128)]);
+        assert_eq!(db.bitfield("f", &[incr(U4, 20, Overflow::Wrap),
+                                      incr(U4, -10, Overflow::Sat),
+                                      set(8, -1, Overflow::Wrap)])
+                     .unwrap(),
+                   vec![Some(12), Some(2), Some(0)]);
+        let get = |ty, offset| BitFieldOp::Get { ty, offset };
+        assert_eq!(db.bitfield("f", &[get(I8, 8), get(U4, 4), get(I8, 100)])
+                     .unwrap(),
+                   vec![Some(-1), Some(0), Some(0)]);
+        assert_eq!(db.get("f").unwrap().unwrap(),
+                   Bytes::from_static(b"\x20\xff"));
+        assert_eq!(db.bitfield("g", &[get(U4, 0)]).unwrap(), vec![Some(0)]);
+        assert_eq!(db.get("g").unwrap(), None);
+    }
+}
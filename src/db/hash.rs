@@ -0,0 +1,292 @@
+//! Hashes: a map of fields to string values per key
+//!
+//! As with lists, a hash exists only while it has fields; deleting the last one removes the key.
+
+use bytes::Bytes;
+
+use super::{Db, Entries, Entry, Shared, Value};
+use crate::{error::CommandError,
+            glob::glob_match,
+            parse::{format_float, parse_float, parse_int},
+            scan_map::ScanMap};
+
+impl Db {
+    /// Set each field to its value, creating the hash if need be.  Returns how many fields are new.
+    pub fn hset(&self, key: &str, pairs: Vec<(Bytes, Bytes)>) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let hash = self.shared.hash_or_new(&mut entries, key)?;
+        Ok(pairs.into_iter()
+                .map(|(field, value)| hash.insert(field, value))
+                .filter(Option::is_none)
+                .count())
+    }
+
+    /// Set `field`, unless the hash already has it.  Returns whether it was set.
+    pub fn hsetnx(&self, key: &str, field: Bytes, value: Bytes) -> Result<bool, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let hash = self.shared.hash_or_new(&mut entries, key)?;
+        if hash.contains_key(&field) {
+            return Ok(false);
+        }
+        hash.insert(field, value);
+        Ok(true)
+    }
+
+    /// Value of each of `fields` in the hash at `key`.
+    pub fn hmget(&self, key: &str, fields: &[Bytes]) -> Result<Vec<Option<Bytes>>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(hash) = self.shared.live_hash(&mut entries, key)? else {
+            return Ok(vec![None; fields.len()]);
+        };
+        Ok(fields.iter()
+                 .map(|field| hash.get(field).cloned())
+                 .collect())
+    }
+
+    /// Every field of the hash at `key`, with its value.
+    pub fn hgetall(&self, key: &str) -> Result<Vec<(Bytes, Bytes)>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_hash(&mut entries, key)?
+               .map(|hash| {
+                   hash.iter()
+                       .map(|(field, value)| (field.clone(), value.clone()))
+                       .collect()
+               })
+               .unwrap_or_default())
+    }
+
+    /// Remove `fields` from the hash at `key`.  Returns how many were there to remove.
+    pub fn hdel(&self, key: &str, fields: &[Bytes]) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(hash) = self.shared.live_hash(&mut entries, key)? else {
+            return Ok(0);
+        };
+        let removed = fields.iter()
+                            .filter(|field| hash.remove(*field).is_some())
+                            .count();
+        if hash.is_empty() {
+            self.shared.remove(&mut entries, key);
+        }
+        Ok(removed)
+    }
+
+    /// Number of fields in the hash at `key`.
+    pub fn hlen(&self, key: &str) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_hash(&mut entries, key)?
+               .map_or(0, |hash| hash.len()))
+    }
+
+    /// Add `delta` to the integer in `field` (a missing field counts as `0`).  Returns the new value.
+    pub fn hincr_by(&self, key: &str, field: Bytes, delta: i64) -> Result<i64, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let current = match self.shared.hash_field(&mut entries, key, &field)? {
+            Some(value) => parse_int(&value).ok_or_else(|| {
+                               CommandError::Other("hash value is not an integer".to_string())
+                           })?,
+            None => 0,
+        };
+        let new = current.checked_add(delta)
+                         .ok_or_else(|| CommandError::Other("increment or decrement would overflow".to_string()))?;
+        self.shared
+            .hash_or_new(&mut entries, key)?
+            .insert(field, Bytes::from(new.to_string()));
+        Ok(new)
+    }
+
+    /// Float flavour of `hincr_by`.  Returns the new value, formatted as it was stored.
+    pub fn hincr_by_float(&self,
+                          key: &str,
+                          field: Bytes,
+                          delta: f64)
+                          -> Result<Bytes, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let current = match self.shared.hash_field(&mut entries, key, &field)? {
+            Some(value) => parse_float(&value).ok_or_else(|| {
+                               CommandError::Other("hash value is not a float".to_string())
+                           })?,
+            None => 0.0,
+        };
+        let new = current + delta;
+        if !new.is_finite() {
+            return Err(CommandError::Other("increment would produce NaN or Infinity".to_string()));
+        }
+        let new = Bytes::from(format_float(new));
+        self.shared
+            .hash_or_new(&mut entries, key)?
+            .insert(field, new.clone());
+        Ok(new)
+    }
+
+    /// One step of a walk over the fields of the hash at `key`; see `scan` for how the cursor works.
+    pub fn hscan(&self,
+                 key: &str,
+                 cursor: u64,
+                 count: usize,
+                 pattern: Option<&[u8]>)
+                 -> Result<(u64, Vec<(Bytes, Bytes)>), CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(hash) = self.shared.live_hash(&mut entries, key)? else {
+            return Ok((0, Vec::new()));
+        };
+        let (next, taken) = hash.step(cursor, count).finish();
+        let fields =
+            taken.into_iter()
+                 .filter(|(field, _)| pattern.is_none_or(|pattern| glob_match(pattern, field)))
+                 .map(|(field, value)| (field.clone(), value.clone()))
+                 .collect();
+        Ok((next, fields))
+    }
+}
+
+impl Shared {
+    /// The hash at `key` in its (locked) shard, if there is one.  Any other type is an error.
+    fn live_hash<'m>(&self,
+                     entries: &'m mut Entries,
+                     key: &str)
+                     -> Result<Option<&'m mut ScanMap<Bytes, Bytes>>, CommandError> {
+        match self.live(entries, key) {
+            None => Ok(None),
+            Some(Entry { value: Value::Hash(hash),
+                         .. }) => Ok(Some(hash)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// Value of `field` in the hash at `key`, if both exist.
+    fn hash_field(&self,
+                  entries: &mut Entries,
+                  key: &str,
+                  field: &[u8])
+                  -> Result<Option<Bytes>, CommandError> {
+        Ok(self.live_hash(entries, key)?
+               .and_then(|hash| hash.get(field).cloned()))
+    }
+
+    /// The hash at `key`, created empty if the key doesn't exist.  (The caller must fill it.)
+    fn hash_or_new<'m>(&self,
+                       entries: &'m mut Entries,
+                       key: &str)
+                       -> Result<&'m mut ScanMap<Bytes, Bytes>, CommandError> {
+        if self.live(entries, key).is_none() {
+            entries.insert(key.to_string(),
+                           Entry { value:      Value::Hash(ScanMap::new()),
+                                   expires_at: None, });
+        }
+        Ok(self.live_hash(entries, key)?.expect("Key just made live."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(Bytes, Bytes)> {
+        items.iter()
+             .map(|(field, value)| (Bytes::from(field.to_string()), Bytes::from(value.to_string())))
+             .collect()
+    }
+
+    fn fields(items: &[&str]) -> Vec<Bytes> {
+        items.iter()
+             .map(|item| Bytes::from(item.to_string()))
+             .collect()
+    }
+
+    #[tokio::test]
+    async fn set_get_and_delete_fields() {
+        let db = Db::with_shards(4);
+        assert_eq!(db.hset("h", pairs(&[("a", "1"), ("b", "2")])), Ok(2));
+        // only new fields count; existing ones are overwritten
+        assert_eq!(db.hset("h", pairs(&[("b", "3"), ("c", "4")])), Ok(1));
+        assert_eq!(db.hmget("h", &fields(&["a", "b", "missing"])),
+                   Ok(vec![Some(Bytes::from("1")), Some(Bytes::from("3")), None]));
+        assert_eq!(db.hmget("missing", &fields(&["a"])), Ok(vec![None]));
+        assert_eq!(db.hsetnx("h", Bytes::from("a"), Bytes::from("9")),
+                   Ok(false));
+        assert_eq!(db.hlen("h"), Ok(3));
+
+        assert_eq!(db.hdel("h", &fields(&["a", "missing"])), Ok(1));
+        assert_eq!(db.hdel("h", &fields(&["b", "c"])), Ok(2));
+        // the last field took the key with it
+        assert_eq!(db.key_type("h"), None);
+        assert_eq!(db.hdel("h", &fields(&["a"])), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn increments_parse_the_field() {
+        let db = Db::with_shards(4);
+        assert_eq!(db.hincr_by("h", Bytes::from("n"), 5), Ok(5));
+        assert_eq!(db.hincr_by("h", Bytes::from("n"), -7), Ok(-2));
+        assert_eq!(db.hincr_by_float("h", Bytes::from("n"), 0.5),
+                   Ok(Bytes::from("-1.5")));
+        assert_eq!(db.hmget("h", &fields(&["n"])),
+                   Ok(vec![Some(Bytes::from("-1.5"))]));
+        db.hincr_by_float("h", Bytes::from("f"), 0.1).unwrap();
+        assert_eq!(db.hincr_by_float("h", Bytes::from("f"), 0.2),
+                   Ok(Bytes::from("0.3")));
+
+        assert_eq!(db.hincr_by("h", Bytes::from("n"), 1),
+                   Err(CommandError::Other("hash value is not an integer".to_string())));
+        db.hset("h",
+                pairs(&[("word", "abc"), ("max", &i64::MAX.to_string())]))
+          .unwrap();
+        assert_eq!(db.hincr_by_float("h", Bytes::from("word"), 1.0),
+                   Err(CommandError::Other("hash value is not a float".to_string())));
+        assert_eq!(db.hincr_by("h", Bytes::from("max"), 1),
+                   Err(CommandError::Other("increment or decrement would overflow".to_string())));
+        assert_eq!(db.hincr_by_float("h", Bytes::from("n"), f64::INFINITY),
+                   Err(CommandError::Other("increment would produce NaN or Infinity".to_string())));
+        // a failed increment leaves the field as it was
+        assert_eq!(db.hmget("h", &fields(&["n"])),
+                   Ok(vec![Some(Bytes::from("-1.5"))]));
+    }
+
+    #[tokio::test]
+    async fn wrong_type_is_an_error() {
+        let db = Db::with_shards(4);
+        db.set("s".to_string(), Bytes::from("string"), None);
+        assert_eq!(db.hset("s", pairs(&[("a", "1")])),
+                   Err(CommandError::WrongType));
+        assert_eq!(db.hmget("s", &fields(&["a"])), Err(CommandError::WrongType));
+        assert_eq!(db.hdel("s", &fields(&["a"])), Err(CommandError::WrongType));
+        assert_eq!(db.hincr_by("s", Bytes::from("a"), 1),
+                   Err(CommandError::WrongType));
+        assert_eq!(db.hincr_by_float("s", Bytes::from("a"), 1.0),
+                   Err(CommandError::WrongType));
+        assert_eq!(db.hscan("s", 0, 10, None), Err(CommandError::WrongType));
+        assert_eq!(db.get("s"), Ok(Some(Bytes::from("string"))));
+    }
+
+    #[tokio::test]
+    async fn scan_returns_every_field_once_in_small_steps() {
+        let db = Db::with_shards(4);
+        let all: Vec<(String, String)> = (0..500).map(|i| (format!("field:{i}"), i.to_string()))
+                                                 .collect();
+        let all: Vec<(&str, &str)> = all.iter().map(|(f, v)| (f.as_str(), v.as_str())).collect();
+        db.hset("h", pairs(&all)).unwrap();
+
+        let mut seen = HashMap::new();
+        let mut cursor = 0;
+        loop {
+            let (next, step) = db.hscan("h", cursor, 7, None).unwrap();
+            assert!(step.len() <= 7, "{} fields in one step", step.len());
+            for (field, value) in step {
+                assert!(seen.insert(field, value).is_none(), "field returned twice");
+            }
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 500);
+        assert_eq!(seen[&Bytes::from("field:42")], Bytes::from("42"));
+
+        let (_, matching) = db.hscan("h", 0, 1000, Some(b"field:4?")).unwrap();
+        assert_eq!(matching.len(), 10);
+    }
+}
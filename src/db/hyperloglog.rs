@@ -0,0 +1,119 @@
+//! HyperLogLogs: strings in Redis's HLL format, read and rewritten in place
+//!
+//! Nothing marks a key as holding one other than its bytes, so a SET can replace it and a GET reads
+//! it back out, as in Redis.
+
+use bytes::Bytes;
+
+use super::{Db, Entries, Shared};
+use crate::{error::CommandError, hyperloglog::HyperLogLog};
+
+impl Db {
+    /// Count `elements` in the HyperLogLog at `key`, creating it if need be.  Returns whether its
+    /// estimate may have changed (always, if it was created).
+    pub fn pfadd(&self, key: &str, elements: &[Bytes]) -> Result<bool, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let (mut hll, mut changed) = match self.shared.live_hll(&mut entries, key)? {
+            Some(hll) => (hll, false),
+            None => (HyperLogLog::new(), true),
+        };
+        for element in elements {
+            changed |= hll.add(element);
+        }
+        if changed {
+            self.shared.put_value(&mut entries, key, hll.encode());
+        }
+        Ok(changed)
+    }
+
+    /// Estimated number of distinct elements counted by the union of the HyperLogLogs at `keys`
+    /// (a missing key counting none).
+    pub fn pfcount(&self, keys: &[String]) -> Result<u64, CommandError> {
+        if let [key] = keys {
+            // a lone key keeps its estimate cached, to save recomputing it next time
+            let mut entries = self.shared.entries.lock_key(key);
+            let Some(mut hll) = self.shared.live_hll(&mut entries, key)? else {
+                return Ok(0);
+            };
+            let fresh = hll.is_cached();
+            let count = hll.count();
+            if !fresh {
+                self.shared.put_value(&mut entries, key, hll.encode());
+            }
+            return Ok(count);
+        }
+        let mut shards = self.shared.entries.lock_keys(keys);
+        let mut union = HyperLogLog::new();
+        for key in keys {
+            if let Some(hll) = self.shared.live_hll(shards.map_for(key), key)? {
+                union.merge(&hll);
+            }
+        }
+        Ok(union.count())
+    }
+
+    /// Store at `destination` the union of its HyperLogLog (if any) and those at `sources`.
+    pub fn pfmerge(&self, destination: &str, sources: &[String]) -> Result<(), CommandError> {
+        let mut shards = self.shared
+                             .entries
+                             .lock_keys(sources.iter().map(String::as_str).chain([destination]));
+        let mut union = self.shared
+                            .live_hll(shards.map_for(destination), destination)?
+                            .unwrap_or_default();
+        for key in sources {
+            if let Some(hll) = self.shared.live_hll(shards.map_for(key), key)? {
+                union.merge(&hll);
+            }
+        }
+        self.shared
+            .put_value(shards.map_for(destination), destination, union.encode());
+        Ok(())
+    }
+}
+
+impl Shared {
+    /// The HyperLogLog at `key` in its (locked) shard, if there is one.  Any other value is an error.
+    fn live_hll(&self,
+                entries: &mut Entries,
+                key: &str)
+                -> Result<Option<HyperLogLog>, CommandError> {
+        self.live_string(entries, key)?
+            .map(|data| HyperLogLog::decode(&data).ok_or(CommandError::InvalidHll))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_and_merges() {
+        let db = Db::new();
+        let elements = |range: std::ops::Range<u32>| -> Vec<Bytes> {
+            range.map(|n| Bytes::from(n.to_string())).collect()
+        };
+        assert!(db.pfadd("a", &[]).unwrap());
+        assert!(!db.pfadd("a", &[]).unwrap());
+        assert!(db.pfadd("a", &elements(0..100)).unwrap());
+        assert!(!db.pfadd("a", &elements(0..100)).unwrap());
+        assert_eq!(db.pfcount(&["a".into()]).unwrap(), 100);
+
+        db.pfadd("b", &elements(50..150)).unwrap();
+        let union = db.pfcount(&["a".into(), "b".into(), "nope".into()])
+                      .unwrap();
+        assert!(union.abs_diff(150) <= 3, "estimate {union}");
+        db.pfmerge("c", &["a".into(), "b".into()]).unwrap();
+        assert_eq!(db.pfcount(&["c".into()]).unwrap(), union);
+
+        // it's just a string
+        let stored = db.get("c").unwrap().unwrap();
+        db.set("d".into(), stored, None);
+        assert_eq!(db.pfcount(&["d".into()]).unwrap(), union);
+        db.set("e".into(), Bytes::from("not a sketch"), None);
+        assert_eq!(db.pfadd("e", &elements(0..1)),
+                   Err(CommandError::InvalidHll));
+        assert_eq!(db.pfcount(&["a".into(), "e".into()]),
+                   Err(CommandError::InvalidHll));
+    }
+}
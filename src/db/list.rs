@@ -0,0 +1,537 @@
+//! Lists: a deque of strings per key
+//!
+//! A list exists only while it has elements; popping the last one removes the key.
+//!
+//! Clients blocked in BLPOP & co. wait in a FIFO queue per key.  Whatever adds elements to a list
+//! hands them straight to that key's waiters, oldest first, before anyone else can pop them.
+
+use std::{collections::{HashMap, VecDeque},
+          sync::{Arc, Mutex},
+          time::Duration};
+
+use bytes::Bytes;
+use tokio::{sync::oneshot, time};
+
+use super::{Db, Entries, Entry, Shared, Value};
+use crate::error::CommandError;
+
+/// Clients waiting for an element, by the key they wait on.
+pub(super) type WaitQueues = HashMap<String, VecDeque<Arc<Waiter>>>;
+
+/// A client blocked on one or more lists.  Queued under each of its keys; served at most once.
+#[derive(Debug)]
+pub(super) struct Waiter {
+    /// Which end of the list it pops from.
+    end:    End,
+    /// Where to hand over the element (and the key it came from).  Taken by whoever serves it first --
+    /// or by the waiter itself, when it gives up.
+    sender: Mutex<Option<oneshot::Sender<(String, Bytes)>>>,
+}
+
+/// A waiter's registration, undone on drop -- including when the client hangs up mid-wait and the
+/// future is dropped.  An element handed over but never received goes back on its list.
+struct Registration<'a> {
+    shared:   &'a Shared,
+    keys:     &'a [String],
+    waiter:   Arc<Waiter>,
+    receiver: oneshot::Receiver<(String, Bytes)>,
+}
+
+/// Which end of a list to work at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    /// The head (LPUSH, LPOP)
+    Left,
+    /// The tail (RPUSH, RPOP)
+    Right,
+}
+
+impl Db {
+    /// Push each of `elements`, in turn, onto `end` of the list at `key`, creating the list if need
+    /// be.  Returns its new length.
+    pub fn push(&self, key: &str, elements: Vec<Bytes>, end: End) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let list = self.shared.list_or_new(&mut entries, key)?;
+        for element in elements {
+            push_one(list, element, end);
+        }
+        let len = list.len();
+        self.shared.serve_waiters(&mut entries, key);
+        Ok(len)
+    }
+
+    /// Take up to `count` elements off `end` of the list at `key`.  `None` if there is no list.
+    pub fn pop(&self,
+               key: &str,
+               end: End,
+               count: usize)
+               -> Result<Option<Vec<Bytes>>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(list) = self.shared.live_list(&mut entries, key)? else {
+            return Ok(None);
+        };
+        let count = count.min(list.len());
+        let popped = match end {
+            End::Left => list.drain(..count).collect(),
+            End::Right => list.drain(list.len() - count..).rev().collect(),
+        };
+        if list.is_empty() {
+            self.shared.remove(&mut entries, key);
+        }
+        Ok(Some(popped))
+    }
+
+    /// Pop from `end` of the first non-empty list among `keys`, waiting up to `timeout` (for ever if
+    /// `None`) for one to be pushed.  Returns the key popped from, with the element.
+    ///
+    /// Clients waiting on the same key are served in the order they started waiting.
+    pub async fn blocking_pop(&self,
+                              keys: &[String],
+                              end: End,
+                              timeout: Option<Duration>)
+                              -> Result<Option<(String, Bytes)>, CommandError> {
+        let registration = {
+            let _gate = self.shared.gate();
+            let mut shards = self.shared.entries.lock_keys(keys);
+            for key in keys {
+                if let Some(list) = self.shared.live_list(shards.map_for(key), key)? {
+                    let element = pop_one(list, end);
+                    if list.is_empty() {
+                        self.shared.remove(shards.map_for(key), key);
+                    }
+                    self.shared.touch(key);
+                    return Ok(Some((key.clone(), element)));
+                }
+            }
+            // register while still holding the shards, so no push can slip in unseen
+            self.shared.register(keys, end)
+        };
+        Ok(registration.wait(timeout).await)
+    }
+
+    /// Move an element from `from` of the list at `source` to `to` of the list at `destination`,
+    /// atomically.  Returns the element; `None` if there is no source list.
+    pub fn lmove(&self,
+                 source: &str,
+                 destination: &str,
+                 from: End,
+                 to: End)
+                 -> Result<Option<Bytes>, CommandError> {
+        let mut shards = self.shared.entries.lock_keys([source, destination]);
+        let Some(list) = self.shared.live_list(shards.map_for(source), source)? else {
+            return Ok(None);
+        };
+        if list.len() == 1 && source == destination {
+            // rotating a single element is a no-op
+            return Ok(list.front().cloned());
+        }
+        self.shared
+            .live_list(shards.map_for(destination), destination)?;
+
+        let list = self.shared
+                       .live_list(shards.map_for(source), source)?
+                       .expect("Source checked above.");
+        let element = pop_one(list, from);
+        if list.is_empty() {
+            self.shared.remove(shards.map_for(source), source);
+        }
+        let entries = shards.map_for(destination);
+        push_one(self.shared.list_or_new(entries, destination)?,
+                 element.clone(),
+                 to);
+        self.shared.serve_waiters(entries, destination);
+        Ok(Some(element))
+    }
+
+    /// LMOVE, waiting up to `timeout` (for ever if `None`) for the source list to get an element.
+    ///
+    /// Should the destination turn out to hold something other than a list by the time an element
+    /// arrives, the element goes back where it came from.
+    pub async fn blocking_move(&self,
+                               source: &str,
+                               destination: &str,
+                               from: End,
+                               to: End,
+                               timeout: Option<Duration>)
+                               -> Result<Option<Bytes>, CommandError> {
+        let keys = [source.to_string()];
+        let registration = {
+            let _gate = self.shared.gate();
+            let mut entries = self.shared.entries.lock_key(source);
+            if self.shared.live_list(&mut entries, source)?.is_some() {
+                drop(entries);
+                let moved = self.lmove(source, destination, from, to);
+                self.shared.touch(source);
+                self.shared.touch(destination);
+                return moved;
+            }
+            self.shared.register(&keys, from)
+        };
+        let Some((_, element)) = registration.wait(timeout).await else {
+            return Ok(None);
+        };
+
+        // the source was changed (and touched) by whoever handed over the element
+        let _gate = self.shared.gate();
+        self.shared.touch(destination);
+        let mut shards = self.shared.entries.lock_keys([source, destination]);
+        if let Err(err) = self.shared
+                              .live_list(shards.map_for(destination), destination)
+        {
+            self.shared
+                .restore(shards.map_for(source), source, element, from);
+            return Err(err);
+        }
+        let entries = shards.map_for(destination);
+        push_one(self.shared.list_or_new(entries, destination)?,
+                 element.clone(),
+                 to);
+        self.shared.serve_waiters(entries, destination);
+        Ok(Some(element))
+    }
+
+    /// Elements `start..=stop` of the list at `key`.  Negative indices count back from the tail;
+    /// indices out of range are clamped.
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<Bytes>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(list) = self.shared.live_list(&mut entries, key)? else {
+            return Ok(Vec::new());
+        };
+        let len = list.len() as i64;
+        let start = if start < 0 { len + start } else { start }.max(0);
+        let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        Ok(list.range(start as usize..=stop as usize)
+               .cloned()
+               .collect())
+    }
+
+    /// Length of the list at `key`; `0` if there is none.
+    pub fn llen(&self, key: &str) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_list(&mut entries, key)?
+               .map_or(0, |list| list.len()))
+    }
+
+    /// Element `index` of the list at `key`, counting back from the tail if negative.
+    pub fn lindex(&self, key: &str, index: i64) -> Result<Option<Bytes>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(list) = self.shared.live_list(&mut entries, key)? else {
+            return Ok(None);
+        };
+        let index = if index < 0 {
+            list.len() as i64 + index
+        } else {
+            index
+        };
+        Ok(usize::try_from(index).ok()
+                                 .and_then(|index| list.get(index))
+                                 .cloned())
+    }
+}
+
+impl Shared {
+    /// The list at `key` in its (locked) shard, if there is one.  Any other type is an error.
+    fn live_list<'m>(&self,
+                     entries: &'m mut Entries,
+                     key: &str)
+                     -> Result<Option<&'m mut VecDeque<Bytes>>, CommandError> {
+        match self.live(entries, key) {
+            None => Ok(None),
+            Some(Entry { value: Value::List(list),
+                         .. }) => Ok(Some(list)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// The list at `key`, created empty if the key doesn't exist.  (The caller must fill it.)
+    fn list_or_new<'m>(&self,
+                       entries: &'m mut Entries,
+                       key: &str)
+                       -> Result<&'m mut VecDeque<Bytes>, CommandError> {
+        if self.live(entries, key).is_none() {
+            entries.insert(key.to_string(),
+                           Entry { value:      Value::List(VecDeque::new()),
+                                   expires_at: None, });
+        }
+        Ok(self.live_list(entries, key)?.expect("Key just made live."))
+    }
+}
+
+impl Shared {
+    /// Queue a waiter under each of `keys`.  Call with the keys' shards locked.
+    fn register<'a>(&'a self, keys: &'a [String], end: End) -> Registration<'a> {
+        let (sender, receiver) = oneshot::channel();
+        let waiter = Arc::new(Waiter { end,
+                                       sender: Mutex::new(Some(sender)) });
+        let mut blocked = self.blocked.lock().expect("Unpoisoned mutex.");
+        for key in keys {
+            blocked.entry(key.clone())
+                   .or_default()
+                   .push_back(waiter.clone());
+        }
+        Registration { shared: self,
+                       keys,
+                       waiter,
+                       receiver }
+    }
+
+    /// Hand elements of the list at `key` (in its locked shard) to the clients waiting on it, oldest
+    /// first, for as long as both last.  Call after anything that adds to a list.
+    fn serve_waiters(&self, entries: &mut Entries, key: &str) {
+        {
+            let mut blocked = self.blocked.lock().expect("Unpoisoned mutex.");
+            let Some(queue) = blocked.get_mut(key) else {
+                return;
+            };
+            let Some(Entry { value: Value::List(list),
+                             .. }) = entries.get_mut(key)
+            else {
+                return;
+            };
+            while !list.is_empty() {
+                let Some(waiter) = queue.pop_front() else {
+                    break;
+                };
+                // already served through another key, or given up
+                let Some(sender) = waiter.take_sender() else {
+                    continue;
+                };
+                let element = pop_one(list, waiter.end);
+                if let Err((_, element)) = sender.send((key.to_string(), element)) {
+                    push_one(list, element, waiter.end);
+                }
+            }
+            if queue.is_empty() {
+                blocked.remove(key);
+            }
+        }
+        if entries.get(key)
+                  .is_some_and(|entry| matches!(&entry.value, Value::List(list) if list.is_empty()))
+        {
+            self.remove(entries, key);
+        }
+    }
+
+    /// Put an element back on the end of the list it was popped from, then let waiters have it.
+    /// Call with shared access, like any other write.
+    fn restore(&self, entries: &mut Entries, key: &str, element: Bytes, end: End) {
+        match self.list_or_new(entries, key) {
+            Ok(list) => push_one(list, element, end),
+            // the key has since been given some other type; nowhere to put it
+            Err(_) => return,
+        }
+        self.touch(key);
+        self.serve_waiters(entries, key);
+    }
+}
+
+impl Waiter {
+    fn take_sender(&self) -> Option<oneshot::Sender<(String, Bytes)>> {
+        self.sender.lock().expect("Unpoisoned mutex.").take()
+    }
+}
+
+impl Registration<'_> {
+    /// Wait to be served, for up to `timeout` (for ever if `None`).
+    async fn wait(mut self, timeout: Option<Duration>) -> Option<(String, Bytes)> {
+        let served = match timeout {
+            Some(timeout) => time::timeout(timeout, &mut self.receiver).await.ok(),
+            None => Some((&mut self.receiver).await),
+        };
+        match served {
+            Some(served) => served.ok(),
+            // Timed out.  If someone took our sender in the meantime, an element is on its way.
+            None => match self.waiter.take_sender() {
+                Some(_) => None,
+                None => (&mut self.receiver).await.ok(),
+            },
+        }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let served = self.waiter.take_sender().is_none();
+        // putting back an element is a write like any other: not in the middle of a transaction
+        let _gate = self.shared.gate();
+        // With the shards locked, nobody is midway through serving us.
+        let mut shards = self.shared.entries.lock_keys(self.keys);
+        if served {
+            if let Ok((key, element)) = self.receiver.try_recv() {
+                self.shared
+                    .restore(shards.map_for(&key), &key, element, self.waiter.end);
+            }
+        }
+        let mut blocked = self.shared.blocked.lock().expect("Unpoisoned mutex.");
+        for key in self.keys {
+            if let Some(queue) = blocked.get_mut(key) {
+                queue.retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+                if queue.is_empty() {
+                    blocked.remove(key);
+                }
+            }
+        }
+    }
+}
+
+fn pop_one(list: &mut VecDeque<Bytes>, end: End) -> Bytes {
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }.expect("List is not empty.")
+}
+
+fn push_one(list: &mut VecDeque<Bytes>, element: Bytes, end: End) {
+    match end {
+        End::Left => list.push_front(element),
+        End::Right => list.push_back(element),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use futures::poll;
+
+    use super::*;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn waiters_are_served_first_come_first_served() {
+        let db = Db::with_shards(4);
+        let (first_keys, second_keys) = (keys(&["q"]), keys(&["other", "q"]));
+        let mut first = pin!(db.blocking_pop(&first_keys, End::Left, None));
+        let mut second = pin!(db.blocking_pop(&second_keys, End::Left, None));
+        assert!(poll!(first.as_mut()).is_pending());
+        assert!(poll!(second.as_mut()).is_pending());
+
+        let pushed = vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")];
+        assert_eq!(db.push("q", pushed, End::Right), Ok(3));
+        assert_eq!(first.await, Ok(Some(("q".to_string(), Bytes::from("a")))));
+        assert_eq!(second.await, Ok(Some(("q".to_string(), Bytes::from("b")))));
+        assert_eq!(db.lrange("q", 0, -1), Ok(vec![Bytes::from("c")]));
+        assert!(db.shared.blocked.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn timing_out_leaves_nothing_behind() {
+        let db = Db::with_shards(4);
+        let popped = db.blocking_pop(&keys(&["q"]), End::Left, Some(Duration::from_millis(20)))
+                       .await;
+        assert_eq!(popped, Ok(None));
+        assert!(db.shared.blocked.lock().unwrap().is_empty());
+        assert_eq!(db.push("q", vec![Bytes::from("a")], End::Left), Ok(1));
+        assert_eq!(db.llen("q"), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn abandoned_wait_returns_its_element() {
+        let db = Db::with_shards(4);
+        let waiting = keys(&["q"]);
+        let version = db.watch("q");
+        {
+            let mut abandoned = pin!(db.blocking_pop(&waiting, End::Left, None));
+            assert!(poll!(abandoned.as_mut()).is_pending());
+            // handed over, but never collected
+            db.push("q", vec![Bytes::from("a")], End::Left).unwrap();
+            assert_eq!(db.llen("q"), Ok(0));
+        }
+        assert_eq!(db.lrange("q", 0, -1), Ok(vec![Bytes::from("a")]));
+        assert!(db.shared.blocked.lock().unwrap().is_empty());
+
+        // putting it back is a change, for anyone WATCHing the list
+        assert!(!db.with_exclusive_access(|| db.unchanged(&[("q".to_string(), version)])));
+    }
+
+    #[tokio::test]
+    async fn blocking_move_lands_in_destination() {
+        let db = Db::with_shards(4);
+        let mut moving = pin!(db.blocking_move("src", "dst", End::Left, End::Right, None));
+        assert!(poll!(moving.as_mut()).is_pending());
+        db.push("src", vec![Bytes::from("a")], End::Left).unwrap();
+        assert_eq!(moving.await, Ok(Some(Bytes::from("a"))));
+        assert_eq!(db.lrange("dst", 0, -1), Ok(vec![Bytes::from("a")]));
+        assert_eq!(db.exists(&keys(&["src"])), 0);
+    }
+
+    fn elements(items: &[&str]) -> Vec<Bytes> {
+        items.iter()
+             .map(|item| Bytes::from(item.to_string()))
+             .collect()
+    }
+
+    #[tokio::test]
+    async fn push_to_either_end() {
+        let db = Db::with_shards(4);
+        // each element goes on in turn, so LPUSH reverses them
+        assert_eq!(db.push("l", elements(&["a", "b"]), End::Left), Ok(2));
+        assert_eq!(db.push("l", elements(&["c", "d"]), End::Right), Ok(4));
+        assert_eq!(db.lrange("l", 0, -1), Ok(elements(&["b", "a", "c", "d"])));
+        assert_eq!(db.llen("l"), Ok(4));
+        assert_eq!(db.llen("missing"), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn pop_with_a_count() {
+        let db = Db::with_shards(4);
+        db.push("l", elements(&["a", "b", "c", "d", "e"]), End::Right)
+          .unwrap();
+        assert_eq!(db.pop("l", End::Left, 2), Ok(Some(elements(&["a", "b"]))));
+        // from the tail, in the order they come off
+        assert_eq!(db.pop("l", End::Right, 2), Ok(Some(elements(&["e", "d"]))));
+        assert_eq!(db.pop("l", End::Right, 0), Ok(Some(Vec::new())));
+        // asking for more than there are takes the lot, and the key with them
+        assert_eq!(db.pop("l", End::Left, 10), Ok(Some(elements(&["c"]))));
+        assert_eq!(db.key_type("l"), None);
+        assert_eq!(db.pop("l", End::Left, 1), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn ranges_and_indices_count_back_from_the_tail() {
+        let db = Db::with_shards(4);
+        db.push("l", elements(&["a", "b", "c", "d"]), End::Right)
+          .unwrap();
+        for (start, stop, expected) in [(0, -1, &["a", "b", "c", "d"][..]),
+                                        (1, 2, &["b", "c"]),
+                                        (-2, -1, &["c", "d"]),
+                                        (-100, 1, &["a", "b"]),
+                                        (2, 100, &["c", "d"]),
+                                        (3, 1, &[]),
+                                        (5, 10, &[]),
+                                        (0, -100, &[])]
+        {
+            assert_eq!(db.lrange("l", start, stop),
+                       Ok(elements(expected)),
+                       "{start} {stop}");
+        }
+        assert_eq!(db.lrange("missing", 0, -1), Ok(Vec::new()));
+
+        assert_eq!(db.lindex("l", 0), Ok(Some(Bytes::from("a"))));
+        assert_eq!(db.lindex("l", -1), Ok(Some(Bytes::from("d"))));
+        assert_eq!(db.lindex("l", -4), Ok(Some(Bytes::from("a"))));
+        assert_eq!(db.lindex("l", -5), Ok(None));
+        assert_eq!(db.lindex("l", 4), Ok(None));
+        assert_eq!(db.lindex("missing", 0), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn wrong_type_is_an_error() {
+        let db = Db::with_shards(4);
+        db.set("s".to_string(), Bytes::from("string"), None);
+        assert_eq!(db.push("s", elements(&["a"]), End::Left),
+                   Err(CommandError::WrongType));
+        assert_eq!(db.pop("s", End::Right, 1), Err(CommandError::WrongType));
+        assert_eq!(db.lrange("s", 0, -1), Err(CommandError::WrongType));
+        assert_eq!(db.lindex("s", 0), Err(CommandError::WrongType));
+        assert_eq!(db.llen("s"), Err(CommandError::WrongType));
+        assert_eq!(db.get("s"), Ok(Some(Bytes::from("string"))));
+    }
+}
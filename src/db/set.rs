@@ -0,0 +1,269 @@
+//! Sets: an unordered collection of distinct strings per key
+//!
+//! The algebra commands lock every shard involved -- destination included, for the STORE variants --
+//! before reading anything, so they see (and write) a single moment of the store.
+
+use bytes::Bytes;
+
+use super::{Db, Entries, Entry, Shared, Value};
+use crate::{error::CommandError, glob::glob_match, scan_map::ScanSet, shard_hash::ShardGuards};
+
+/// How SINTER, SUNION & SDIFF combine their sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    /// Members of every set
+    Inter,
+    /// Members of any set
+    Union,
+    /// Members of the first set and none of the others
+    Diff,
+}
+
+impl Db {
+    /// Add `members` to the set at `key`, creating it if need be.  Returns how many were new.
+    pub fn sadd(&self, key: &str, members: Vec<Bytes>) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let set = self.shared.set_or_new(&mut entries, key)?;
+        Ok(members.into_iter()
+                  .filter(|member| set.insert(member.clone()))
+                  .count())
+    }
+
+    /// Remove `members` from the set at `key`.  Returns how many were there to remove.
+    pub fn srem(&self, key: &str, members: &[Bytes]) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(set) = self.shared.live_set(&mut entries, key)? else {
+            return Ok(0);
+        };
+        let removed = members.iter().filter(|member| set.remove(*member)).count();
+        if set.is_empty() {
+            self.shared.remove(&mut entries, key);
+        }
+        Ok(removed)
+    }
+
+    /// Every member of the set at `key`.
+    pub fn smembers(&self, key: &str) -> Result<Vec<Bytes>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_set(&mut entries, key)?
+               .map(|set| set.iter().cloned().collect())
+               .unwrap_or_default())
+    }
+
+    /// Whether each of `members` is in the set at `key`.
+    pub fn smismember(&self, key: &str, members: &[Bytes]) -> Result<Vec<bool>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let set = self.shared.live_set(&mut entries, key)?;
+        Ok(members.iter()
+                  .map(|member| set.as_ref().is_some_and(|set| set.contains(member)))
+                  .collect())
+    }
+
+    /// Number of members of the set at `key`.
+    pub fn scard(&self, key: &str) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_set(&mut entries, key)?
+               .map_or(0, |set| set.len()))
+    }
+
+    /// Combine the sets at `keys` (a missing key being an empty set).
+    pub fn set_op(&self, op: SetOp, keys: &[String]) -> Result<Vec<Bytes>, CommandError> {
+        let mut shards = self.shared.entries.lock_keys(keys);
+        let result = self.shared.combine(&mut shards, op, keys)?;
+        Ok(result.into_iter().collect())
+    }
+
+    /// Combine the sets at `keys` and store the result at `destination`, replacing whatever was there.
+    /// Returns the size of the result.
+    pub fn set_op_store(&self,
+                        op: SetOp,
+                        destination: &str,
+                        keys: &[String])
+                        -> Result<usize, CommandError> {
+        let mut shards = self.shared
+                             .entries
+                             .lock_keys(keys.iter().map(String::as_str).chain([destination]));
+        let result = self.shared.combine(&mut shards, op, keys)?;
+        let len = result.len();
+        let entries = shards.map_for(destination);
+        self.shared.remove(entries, destination);
+        if !result.is_empty() {
+            entries.insert(destination.to_string(),
+                           Entry { value:      Value::Set(result),
+                                   expires_at: None, });
+        }
+        Ok(len)
+    }
+
+    /// One step of a walk over the members of the set at `key`; see `scan` for how the cursor works.
+    pub fn sscan(&self,
+                 key: &str,
+                 cursor: u64,
+                 count: usize,
+                 pattern: Option<&[u8]>)
+                 -> Result<(u64, Vec<Bytes>), CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(set) = self.shared.live_set(&mut entries, key)? else {
+            return Ok((0, Vec::new()));
+        };
+        let (next, taken) = set.step(cursor, count).finish();
+        let members =
+            taken.into_iter()
+                 .filter(|member| pattern.is_none_or(|pattern| glob_match(pattern, member)))
+                 .cloned()
+                 .collect();
+        Ok((next, members))
+    }
+}
+
+impl Shared {
+    /// Combine the sets at `keys`, whose shards are all locked.  A missing key is an empty set; a key
+    /// of any other type is an error, even if the answer is already known without it.
+    fn combine(&self,
+               shards: &mut ShardGuards<'_, String, Entry>,
+               op: SetOp,
+               keys: &[String])
+               -> Result<ScanSet<Bytes>, CommandError> {
+        for key in keys {
+            self.live_set(shards.map_for(key), key)?;
+        }
+        let mut sets = keys.iter().map(|key| match shards.map(key).get(key) {
+                                      Some(Entry { value: Value::Set(set),
+                                                   .. }) => Some(set),
+                                      _ => None,
+                                  });
+        let first = sets.next().flatten().cloned().unwrap_or_default();
+        Ok(sets.fold(first, |acc, set| match (op, set) {
+                   (SetOp::Inter, None) => ScanSet::new(),
+                   (SetOp::Inter, Some(set)) => acc.into_iter()
+                                                   .filter(|member| set.contains(member))
+                                                   .collect(),
+                   (SetOp::Union, Some(set)) => {
+                       acc.into_iter().chain(set.iter().cloned()).collect()
+                   }
+                   (SetOp::Diff, Some(set)) => acc.into_iter()
+                                                  .filter(|member| !set.contains(member))
+                                                  .collect(),
+                   (SetOp::Union | SetOp::Diff, None) => acc,
+               }))
+    }
+
+    /// The set at `key` in its (locked) shard, if there is one.  Any other type is an error.
+    fn live_set<'m>(&self,
+                    entries: &'m mut Entries,
+                    key: &str)
+                    -> Result<Option<&'m mut ScanSet<Bytes>>, CommandError> {
+        match self.live(entries, key) {
+            None => Ok(None),
+            Some(Entry { value: Value::Set(set),
+                         .. }) => Ok(Some(set)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// The set at `key`, created empty if the key doesn't exist.  (The caller must fill it.)
+    fn set_or_new<'m>(&self,
+                      entries: &'m mut Entries,
+                      key: &str)
+                      -> Result<&'m mut ScanSet<Bytes>, CommandError> {
+        if self.live(entries, key).is_none() {
+            entries.insert(key.to_string(),
+                           Entry { value:      Value::Set(ScanSet::new()),
+                                   expires_at: None, });
+        }
+        Ok(self.live_set(entries, key)?.expect("Key just made live."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(items: &[&str]) -> Vec<Bytes> {
+        items.iter()
+             .map(|item| Bytes::from(item.to_string()))
+             .collect()
+    }
+
+    fn sorted(mut items: Vec<Bytes>) -> Vec<Bytes> {
+        items.sort();
+        items
+    }
+
+    /// Two keys that live in different shards.
+    fn apart(db: &Db) -> (String, String) {
+        let entries = &db.shared.entries;
+        let first = "set:0".to_string();
+        let second = (1..).map(|i| format!("set:{i}"))
+                          .find(|key| entries.divine_hashmap(key) != entries.divine_hashmap(&first))
+                          .unwrap();
+        (first, second)
+    }
+
+    #[tokio::test]
+    async fn algebra_across_shards() {
+        let db = Db::with_shards(8);
+        let (a, b) = apart(&db);
+        db.sadd(&a, members(&["1", "2", "3"])).unwrap();
+        db.sadd(&b, members(&["2", "3", "4"])).unwrap();
+        let keys = [a.clone(), b.clone()];
+        assert_eq!(sorted(db.set_op(SetOp::Inter, &keys).unwrap()),
+                   members(&["2", "3"]));
+        assert_eq!(sorted(db.set_op(SetOp::Union, &keys).unwrap()),
+                   members(&["1", "2", "3", "4"]));
+        assert_eq!(db.set_op(SetOp::Diff, &keys).unwrap(), members(&["1"]));
+        let with_missing = [a.clone(), "missing".to_string()];
+        assert!(db.set_op(SetOp::Inter, &with_missing).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn store_may_overwrite_a_source() {
+        let db = Db::with_shards(8);
+        let (a, b) = apart(&db);
+        db.sadd(&a, members(&["1", "2"])).unwrap();
+        db.sadd(&b, members(&["2", "3"])).unwrap();
+        assert_eq!(db.set_op_store(SetOp::Union, &b, &[a.clone(), b.clone()]),
+                   Ok(3));
+        assert_eq!(sorted(db.smembers(&b).unwrap()), members(&["1", "2", "3"]));
+        // an empty result leaves no key behind
+        assert_eq!(db.set_op_store(SetOp::Diff, &b, &[a.clone(), b.clone()]),
+                   Ok(0));
+        assert_eq!(db.key_type(&b), None);
+    }
+
+    #[tokio::test]
+    async fn wrong_type_anywhere_is_an_error() {
+        let db = Db::with_shards(8);
+        let (a, b) = apart(&db);
+        db.sadd(&a, members(&["1"])).unwrap();
+        db.set(b.clone(), Bytes::from("string"), None);
+        assert_eq!(db.set_op(SetOp::Union, &[a.clone(), b.clone()]),
+                   Err(CommandError::WrongType));
+        assert_eq!(db.set_op_store(SetOp::Inter, "dst", &["missing".to_string(), b]),
+                   Err(CommandError::WrongType));
+        assert_eq!(db.key_type("dst"), None);
+    }
+
+    #[tokio::test]
+    async fn scan_returns_every_member_once_in_small_steps() {
+        let db = Db::with_shards(4);
+        let all: Vec<String> = (0..500).map(|i| format!("member:{i}")).collect();
+        let all: Vec<&str> = all.iter().map(String::as_str).collect();
+        db.sadd("s", members(&all)).unwrap();
+
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, step) = db.sscan("s", cursor, 7, None).unwrap();
+            assert!(step.len() <= 7, "{} members in one step", step.len());
+            seen.extend(step);
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(sorted(seen), sorted(members(&all)));
+    }
+}
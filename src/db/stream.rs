@@ -0,0 +1,506 @@
+//! Streams: an append-only log of field-value entries per key, each under a unique, ever-growing ID
+//!
+//! An ID is a millisecond timestamp plus a sequence number to tell apart entries added in the same
+//! millisecond.  Unlike the other collections, a stream stays put when trimmed down to nothing.
+//!
+//! Clients blocked in XREAD subscribe to a watch channel per key, which XADD pokes.  They subscribe
+//! while holding the shards they just found empty, so no entry can slip in unnoticed.
+
+use std::{collections::{BTreeMap, HashMap},
+          fmt,
+          ops::Bound,
+          time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use bytes::Bytes;
+use tokio::{sync::watch, time};
+
+use super::{Db, Entries, Entry, Shared, Value};
+use crate::{error::CommandError, shard_hash::ShardGuards};
+
+mod group;
+
+pub use group::{ClaimOptions, GroupEntry, PendingInfo, PendingSummary};
+
+/// Blocked XREAD clients' wake-up channels, by the key they wait on.
+pub(super) type Readers = HashMap<String, watch::Sender<()>>;
+
+/// An entry: its ID and its fields, in the order given.
+pub type StreamEntry = (StreamId, Vec<(Bytes, Bytes)>);
+
+/// Identifies a stream entry: `<milliseconds>-<sequence>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms:  u64,
+    pub seq: u64,
+}
+
+/// The ID XADD is asked to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XAddId {
+    /// `*`: the current time, or just past the last entry if that's later.
+    Auto,
+    /// `<ms>-*`: the next sequence number within the given millisecond.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+/// How XADD and XTRIM cut a stream down, oldest entries first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trim {
+    /// Keep at most this many entries.
+    MaxLen(usize),
+    /// Drop entries with a smaller ID.
+    MinId(StreamId),
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Stream {
+    entries: BTreeMap<StreamId, Vec<(Bytes, Bytes)>>,
+    /// The greatest ID ever added, which new IDs must exceed even after trimming.
+    last_id: StreamId,
+    groups:  HashMap<Bytes, group::Group>,
+}
+
+/// A blocked reader's subscriptions, dropped -- and their channels cleared away, if no one else is
+/// waiting on them -- however the wait ends.
+struct Watch<'a> {
+    shared:    &'a Shared,
+    keys:      Vec<&'a str>,
+    receivers: Vec<watch::Receiver<()>>,
+}
+
+impl StreamId {
+    pub const MAX: StreamId = StreamId { ms:  u64::MAX,
+                                         seq: u64::MAX, };
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+
+    /// The ID just after this one, if any.
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => Some(StreamId { ms:  self.ms.checked_add(1)?,
+                                    seq: 0, }),
+        }
+    }
+
+    /// The ID just before this one, if any.
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => Some(StreamId { ms:  self.ms.checked_sub(1)?,
+                                    seq: u64::MAX, }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl Db {
+    /// Append an entry to the stream at `key` -- creating it, unless `create` is false -- and trim it
+    /// if asked.  Returns the new entry's ID; `None` if there was no stream and `create` was false.
+    pub fn xadd(&self,
+                key: &str,
+                id: XAddId,
+                fields: Vec<(Bytes, Bytes)>,
+                trim: Option<Trim>,
+                create: bool)
+                -> Result<Option<StreamId>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let stream = self.shared.live_stream(&mut entries, key)?;
+        if stream.is_none() && !create {
+            return Ok(None);
+        }
+        // settle the ID before creating anything, so a bad one leaves no empty stream behind
+        let id = next_id(stream.map_or(StreamId::MIN, |stream| stream.last_id), id)?;
+        let stream = self.shared.stream_or_new(&mut entries, key)?;
+        stream.entries.insert(id, fields);
+        stream.last_id = id;
+        if let Some(trim) = trim {
+            stream.trim(trim);
+        }
+        if let Some(readers) = self.shared
+                                   .readers
+                                   .lock()
+                                   .expect("Unpoisoned mutex.")
+                                   .get(key)
+        {
+            readers.send_replace(());
+        }
+        Ok(Some(id))
+    }
+
+    /// Entries of the stream at `key` with IDs from `start` to `end`, inclusive; newest first if
+    /// `rev`.  At most `count` of them.
+    pub fn xrange(&self,
+                  key: &str,
+                  start: StreamId,
+                  end: StreamId,
+                  rev: bool,
+                  count: Option<usize>)
+                  -> Result<Vec<StreamEntry>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(stream) = self.shared.live_stream(&mut entries, key)? else {
+            return Ok(Vec::new());
+        };
+        if start > end {
+            return Ok(Vec::new());
+        }
+        let range = stream.entries.range(start..=end);
+        let count = count.unwrap_or(usize::MAX);
+        Ok(match rev {
+            false => range.take(count).map(clone_entry).collect(),
+            true => range.rev().take(count).map(clone_entry).collect(),
+        })
+    }
+
+    /// Number of entries in the stream at `key`.
+    pub fn xlen(&self, key: &str) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_stream(&mut entries, key)?
+               .map_or(0, |stream| stream.entries.len()))
+    }
+
+    /// Cut the stream at `key` down.  Returns how many entries went.
+    pub fn xtrim(&self, key: &str, trim: Trim) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_stream(&mut entries, key)?
+               .map_or(0, |stream| stream.trim(trim)))
+    }
+
+    /// Up to `count` entries from each stream past the ID given for it.  (An ID of `None` stands for
+    /// "entries added from now on", so finds nothing here.)  Only streams with something to report
+    /// appear in the result.
+    pub fn xread(&self,
+                 streams: &[(String, Option<StreamId>)],
+                 count: Option<usize>)
+                 -> Result<Vec<(String, Vec<StreamEntry>)>, CommandError> {
+        let mut shards = self.shared
+                             .entries
+                             .lock_keys(streams.iter().map(|(key, _)| key.as_str()));
+        let after = self.shared.read_after(&mut shards, streams)?;
+        self.shared.read(&mut shards, streams, &after, count)
+    }
+
+    /// As `xread`, but if there is nothing to report yet, wait up to `timeout` (or for ever) for an
+    /// entry to be added to one of the streams.  An empty result means the wait timed out.
+    pub async fn blocking_xread(&self,
+                                streams: &[(String, Option<StreamId>)],
+                                count: Option<usize>,
+                                timeout: Option<Duration>)
+                                -> Result<Vec<(String, Vec<StreamEntry>)>, CommandError> {
+        // `$` means the last ID as of the first attempt
+        let mut after = None;
+        self.shared
+            .block_on_streams(streams, timeout, |shards| {
+                if after.is_none() {
+                    after = Some(self.shared.read_after(shards, streams)?);
+                }
+                let after = after.as_deref().expect("IDs just settled.");
+                self.shared.read(shards, streams, after, count)
+            })
+            .await
+    }
+}
+
+impl Shared {
+    /// The stream at `key` in its (locked) shard, if there is one.  Any other type is an error.
+    fn live_stream<'m>(&self,
+                       entries: &'m mut Entries,
+                       key: &str)
+                       -> Result<Option<&'m mut Stream>, CommandError> {
+        match self.live(entries, key) {
+            None => Ok(None),
+            Some(Entry { value: Value::Stream(stream),
+                         .. }) => Ok(Some(stream)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// The stream at `key`, created empty if the key doesn't exist.
+    fn stream_or_new<'m>(&self,
+                         entries: &'m mut Entries,
+                         key: &str)
+                         -> Result<&'m mut Stream, CommandError> {
+        if self.live(entries, key).is_none() {
+            entries.insert(key.to_string(),
+                           Entry { value:      Value::Stream(Stream::default()),
+                                   expires_at: None, });
+        }
+        Ok(self.live_stream(entries, key)?
+               .expect("Key just made live."))
+    }
+
+    /// The IDs to read past in each of `streams` (whose shards are locked), with `None` -- XREAD's
+    /// `$` -- standing for the stream's last ID.
+    fn read_after(&self,
+                  shards: &mut ShardGuards<'_, String, Entry>,
+                  streams: &[(String, Option<StreamId>)])
+                  -> Result<Vec<StreamId>, CommandError> {
+        streams.iter()
+               .map(|(key, id)| match id {
+                   Some(id) => Ok(*id),
+                   None => Ok(self.live_stream(shards.map_for(key), key)?
+                                  .map_or(StreamId::MIN, |stream| stream.last_id)),
+               })
+               .collect()
+    }
+
+    /// Up to `count` entries of each of `streams` (whose shards are locked) with IDs past the
+    /// matching one of `after`.
+    fn read(&self,
+            shards: &mut ShardGuards<'_, String, Entry>,
+            streams: &[(String, Option<StreamId>)],
+            after: &[StreamId],
+            count: Option<usize>)
+            -> Result<Vec<(String, Vec<StreamEntry>)>, CommandError> {
+        let mut found = Vec::new();
+        for ((key, _), after) in streams.iter().zip(after) {
+            let Some(stream) = self.live_stream(shards.map_for(key), key)? else {
+                continue;
+            };
+            let entries: Vec<_> = stream.entries
+                                        .range((Bound::Excluded(after), Bound::Unbounded))
+                                        .take(count.unwrap_or(usize::MAX))
+                                        .map(clone_entry)
+                                        .collect();
+            if !entries.is_empty() {
+                found.push((key.clone(), entries));
+            }
+        }
+        Ok(found)
+    }
+
+    /// Make `attempt` with the shards of `streams` locked, and again each time one of them gets a new
+    /// entry, until it finds something or `timeout` runs out.  Nothing found means it ran out.
+    async fn block_on_streams<T>(&self,
+                                 streams: &[(String, Option<StreamId>)],
+                                 timeout: Option<Duration>,
+                                 mut attempt: impl FnMut(&mut ShardGuards<'_, String, Entry>)
+                                       -> Result<Vec<T>, CommandError>)
+                                 -> Result<Vec<T>, CommandError> {
+        let deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+        let keys: Vec<&str> = streams.iter().map(|(key, _)| key.as_str()).collect();
+        let mut watch = {
+            let _gate = self.gate();
+            let mut shards = self.entries.lock_keys(keys.iter().copied());
+            let found = attempt(&mut shards)?;
+            if !found.is_empty() {
+                return Ok(found);
+            }
+            // subscribe while still holding the shards, so no XADD can slip in unseen
+            self.watch(keys.clone())
+        };
+        loop {
+            if !watch.changed(deadline).await {
+                return Ok(Vec::new());
+            }
+            let _gate = self.gate();
+            let found = attempt(&mut self.entries.lock_keys(keys.iter().copied()))?;
+            if !found.is_empty() {
+                return Ok(found);
+            }
+        }
+    }
+
+    /// Subscribe to additions to the streams at `keys`.  Call with their shards locked.
+    fn watch<'a>(&'a self, keys: Vec<&'a str>) -> Watch<'a> {
+        let mut readers = self.readers.lock().expect("Unpoisoned mutex.");
+        let receivers = keys.iter()
+                            .map(|key| {
+                                readers.entry(key.to_string())
+                                       .or_insert_with(|| watch::channel(()).0)
+                                       .subscribe()
+                            })
+                            .collect();
+        Watch { shared: self,
+                keys,
+                receivers }
+    }
+}
+
+impl Stream {
+    /// Returns how many entries went.
+    fn trim(&mut self, trim: Trim) -> usize {
+        match trim {
+            Trim::MaxLen(max) => {
+                let excess = self.entries.len().saturating_sub(max);
+                for _ in 0..excess {
+                    self.entries.pop_first();
+                }
+                excess
+            }
+            Trim::MinId(min) => {
+                let kept = self.entries.split_off(&min);
+                std::mem::replace(&mut self.entries, kept).len()
+            }
+        }
+    }
+}
+
+impl Watch<'_> {
+    /// Wait until one of the streams gets a new entry (`true`), or until `deadline` (`false`).
+    async fn changed(&mut self, deadline: Option<time::Instant>) -> bool {
+        let changes =
+            futures::future::select_all(self.receivers
+                                            .iter_mut()
+                                            .map(|receiver| Box::pin(receiver.changed())));
+        match deadline {
+            Some(deadline) => time::timeout_at(deadline, changes).await.is_ok(),
+            None => {
+                // (the senders outlive our receivers, so `changed` can't fail)
+                let _ = changes.await;
+                true
+            }
+        }
+    }
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        self.receivers.clear();
+        let mut readers = self.shared.readers.lock().expect("Unpoisoned mutex.");
+        for key in &self.keys {
+            if readers.get(*key)
+                      .is_some_and(|sender| sender.receiver_count() == 0)
+            {
+                readers.remove(*key);
+            }
+        }
+    }
+}
+
+/// The ID for a new entry, given the stream's last one.
+fn next_id(last: StreamId, id: XAddId) -> Result<StreamId, CommandError> {
+    let too_small = || {
+        CommandError::Other("The ID specified in XADD is equal or smaller than the target stream top \
+                             item"
+                                  .to_string())
+    };
+    match id {
+        XAddId::Auto => {
+            let now = unix_millis();
+            match now > last.ms {
+                true => Ok(StreamId { ms: now, seq: 0 }),
+                false => last.next().ok_or_else(too_small),
+            }
+        }
+        XAddId::AutoSeq(ms) if ms == last.ms => last.next()
+                                                    .filter(|id| id.ms == ms)
+                                                    .ok_or_else(too_small),
+        XAddId::AutoSeq(ms) if ms > last.ms => Ok(StreamId { ms, seq: 0 }),
+        XAddId::AutoSeq(_) => Err(too_small()),
+        XAddId::Explicit(StreamId::MIN) => {
+            Err(CommandError::Other("The ID specified in XADD must be greater than 0-0".to_string()))
+        }
+        XAddId::Explicit(id) if id > last => Ok(id),
+        XAddId::Explicit(_) => Err(too_small()),
+    }
+}
+
+/// Current unix time, in milliseconds.
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+                     .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn clone_entry((id, fields): (&StreamId, &Vec<(Bytes, Bytes)>)) -> StreamEntry {
+    (*id, fields.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use futures::poll;
+
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    fn fields() -> Vec<(Bytes, Bytes)> {
+        vec![(Bytes::from("f"), Bytes::from("v"))]
+    }
+
+    fn add(db: &Db, key: &str, new: XAddId) -> Result<Option<StreamId>, CommandError> {
+        db.xadd(key, new, fields(), None, true)
+    }
+
+    #[tokio::test]
+    async fn ids_only_go_up() {
+        let db = Db::new();
+        assert_eq!(add(&db, "s", XAddId::AutoSeq(0)), Ok(Some(id(0, 1))));
+        assert_eq!(add(&db, "s", XAddId::Explicit(id(5, 3))),
+                   Ok(Some(id(5, 3))));
+        assert_eq!(add(&db, "s", XAddId::AutoSeq(5)), Ok(Some(id(5, 4))));
+        assert_eq!(add(&db, "s", XAddId::AutoSeq(7)), Ok(Some(id(7, 0))));
+        assert!(add(&db, "s", XAddId::Explicit(id(7, 0))).is_err());
+        assert!(add(&db, "s", XAddId::AutoSeq(6)).is_err());
+        let auto = add(&db, "s", XAddId::Auto).unwrap().unwrap();
+        assert!(auto > id(7, 0));
+
+        // trimming doesn't let old IDs back in
+        assert_eq!(db.xtrim("s", Trim::MaxLen(0)), Ok(5));
+        assert_eq!(db.xlen("s"), Ok(0));
+        assert!(add(&db, "s", XAddId::Explicit(id(7, 1))).is_err());
+
+        assert!(add(&db, "fresh", XAddId::Explicit(StreamId::MIN)).is_err());
+        assert_eq!(db.key_type("fresh"), None);
+    }
+
+    #[tokio::test]
+    async fn trims_and_ranges() {
+        let db = Db::new();
+        for ms in 1..=5 {
+            db.xadd("s",
+                    XAddId::Explicit(id(ms, 0)),
+                    fields(),
+                    Some(Trim::MaxLen(3)),
+                    true)
+              .unwrap();
+        }
+        let ids =
+            |entries: Vec<StreamEntry>| entries.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(db.xrange("s", StreamId::MIN, StreamId::MAX, false, None)
+                         .unwrap()),
+                   vec![id(3, 0), id(4, 0), id(5, 0)]);
+        assert_eq!(ids(db.xrange("s", id(4, 0), StreamId::MAX, true, Some(1))
+                         .unwrap()),
+                   vec![id(5, 0)]);
+        assert_eq!(db.xtrim("s", Trim::MinId(id(5, 0))), Ok(2));
+        assert!(db.xrange("s", id(9, 0), id(1, 0), false, None)
+                  .unwrap()
+                  .is_empty());
+    }
+
+    #[tokio::test]
+    async fn blocked_reader_wakes_on_add() {
+        let db = Db::new();
+        let streams = vec![("a".to_string(), None),
+                           ("b".to_string(), Some(StreamId::MIN))];
+        let mut read = pin!(db.blocking_xread(&streams, None, None));
+        assert!(poll!(read.as_mut()).is_pending());
+        add(&db, "b", XAddId::Explicit(id(1, 0))).unwrap();
+        let found = read.await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, "b");
+        assert_eq!(found[0].1[0].0, id(1, 0));
+        assert!(db.shared.readers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn blocked_reader_times_out() {
+        let db = Db::new();
+        let streams = vec![("a".to_string(), None)];
+        let read = db.blocking_xread(&streams, None, Some(Duration::from_millis(20)));
+        assert_eq!(read.await, Ok(Vec::new()));
+        assert!(db.shared.readers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,531 @@
+//! Consumer groups: several clients sharing out a stream's entries, each entry to one of them
+//!
+//! A group remembers the last entry it handed out, and every entry handed out but not yet
+//! acknowledged -- its pending entries list -- along with who has it, since when, and how many times
+//! it has been delivered.  Entries a consumer never acknowledges (it crashed, say) stay pending
+//! until another consumer claims them.
+
+use std::{collections::{BTreeMap, BTreeSet, HashMap},
+          ops::Bound,
+          time::Duration};
+
+use bytes::Bytes;
+
+use super::{clone_entry, unix_millis, Stream, StreamEntry, StreamId};
+use crate::{db::{Db, Entries, Entry, Shared},
+            error::CommandError,
+            shard_hash::ShardGuards};
+
+/// An entry as read back from a pending entries list: without its fields if it has since been
+/// deleted from the stream.
+pub type GroupEntry = (StreamId, Option<Vec<(Bytes, Bytes)>>);
+
+/// XPENDING's summary of a group's pending entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingSummary {
+    pub count:     usize,
+    /// Lowest and highest pending IDs
+    pub range:     Option<(StreamId, StreamId)>,
+    /// Consumers with pending entries, and how many each has.
+    pub consumers: Vec<(Bytes, usize)>,
+}
+
+/// A pending entry, as the extended form of XPENDING lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingInfo {
+    pub id:         StreamId,
+    pub consumer:   Bytes,
+    /// Milliseconds since it was last delivered.
+    pub idle:       u64,
+    pub deliveries: u64,
+}
+
+/// XCLAIM's options.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClaimOptions {
+    /// Only claim entries idle for at least this many milliseconds.
+    pub min_idle:    u64,
+    /// IDLE & TIME: when to record the entry as delivered (unix milliseconds), rather than now.
+    pub delivered:   Option<u64>,
+    /// RETRYCOUNT: the delivery count to record, rather than one more.
+    pub retry_count: Option<u64>,
+    /// FORCE: claim entries that aren't pending at all, as long as they're in the stream.
+    pub force:       bool,
+    /// JUSTID: don't count this as a delivery.
+    pub just_id:     bool,
+    /// LASTID: move the group's last delivered entry up to this, if it's behind.
+    pub last_id:     Option<StreamId>,
+}
+
+#[derive(Debug, Default)]
+pub(in crate::db) struct Group {
+    last_delivered: StreamId,
+    pending:        BTreeMap<StreamId, Pending>,
+    consumers:      HashMap<Bytes, Consumer>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    consumer:     Bytes,
+    /// Unix milliseconds
+    delivered_at: u64,
+    deliveries:   u64,
+}
+
+#[derive(Debug, Default)]
+struct Consumer {
+    /// The consumer's share of the group's pending entries.
+    pending: BTreeSet<StreamId>,
+}
+
+impl Db {
+    /// Create a group on the stream at `key`, to deliver entries past `id` (`None`: past the last
+    /// one).  If there's no stream, `mkstream` says whether to create an empty one.
+    pub fn xgroup_create(&self,
+                         key: &str,
+                         group: Bytes,
+                         id: Option<StreamId>,
+                         mkstream: bool)
+                         -> Result<(), CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let stream = match mkstream {
+            true => self.shared.stream_or_new(&mut entries, key)?,
+            false => self.shared
+                         .live_stream(&mut entries, key)?
+                         .ok_or_else(no_stream)?,
+        };
+        if stream.groups.contains_key(&group) {
+            return Err(CommandError::BusyGroup);
+        }
+        let group = stream.groups.entry(group).or_default();
+        group.last_delivered = id.unwrap_or(stream.last_id);
+        Ok(())
+    }
+
+    /// Delete a group, pending entries and all.  Returns whether there was one.
+    pub fn xgroup_destroy(&self, key: &str, group: &[u8]) -> Result<bool, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let stream = self.shared
+                         .live_stream(&mut entries, key)?
+                         .ok_or_else(no_stream)?;
+        Ok(stream.groups.remove(group).is_some())
+    }
+
+    /// Move a group's last delivered entry to `id` (`None`: the stream's last one).
+    pub fn xgroup_set_id(&self,
+                         key: &str,
+                         group: &[u8],
+                         id: Option<StreamId>)
+                         -> Result<(), CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let stream = self.shared
+                         .live_stream(&mut entries, key)?
+                         .ok_or_else(no_stream)?;
+        let last_id = stream.last_id;
+        let group = stream.groups
+                          .get_mut(group)
+                          .ok_or_else(|| no_such_group(key, group))?;
+        group.last_delivered = id.unwrap_or(last_id);
+        Ok(())
+    }
+
+    /// Add a consumer to a group.  Returns whether it was new.
+    pub fn xgroup_create_consumer(&self,
+                                  key: &str,
+                                  group: &[u8],
+                                  consumer: Bytes)
+                                  -> Result<bool, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let group = self.shared.xgroup(&mut entries, key, group)?;
+        let new = !group.consumers.contains_key(&consumer);
+        group.consumers.entry(consumer).or_default();
+        Ok(new)
+    }
+
+    /// Remove a consumer from a group, with its pending entries.  Returns how many of those it had.
+    pub fn xgroup_del_consumer(&self,
+                               key: &str,
+                               group: &[u8],
+                               consumer: &[u8])
+                               -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let group = self.shared.xgroup(&mut entries, key, group)?;
+        let Some(consumer) = group.consumers.remove(consumer) else {
+            return Ok(0);
+        };
+        for id in &consumer.pending {
+            group.pending.remove(id);
+        }
+        Ok(consumer.pending.len())
+    }
+
+    /// Read from each stream on behalf of `consumer` in `group`.  With an ID of `None` (`>`): up to
+    /// `count` entries never delivered to anyone in the group, which become the consumer's pending
+    /// entries unless `noack`.  With an ID: the consumer's own pending entries past it.
+    ///
+    /// Streams read with `>` appear in the result only if there was something new.
+    pub fn xreadgroup(&self,
+                      group: &[u8],
+                      consumer: &Bytes,
+                      streams: &[(String, Option<StreamId>)],
+                      count: Option<usize>,
+                      noack: bool)
+                      -> Result<Vec<(String, Vec<GroupEntry>)>, CommandError> {
+        let mut shards = self.shared
+                             .entries
+                             .lock_keys(streams.iter().map(|(key, _)| key.as_str()));
+        self.shared
+            .read_group(&mut shards, group, consumer, streams, count, noack)
+    }
+
+    /// As `xreadgroup`, but if every stream is read with `>` and there is nothing new, wait up to
+    /// `timeout` (or for ever) for an entry to be added to one of them.  An empty result means the
+    /// wait timed out.
+    pub async fn blocking_xreadgroup(&self,
+                                     group: &[u8],
+                                     consumer: &Bytes,
+                                     streams: &[(String, Option<StreamId>)],
+                                     count: Option<usize>,
+                                     noack: bool,
+                                     timeout: Option<Duration>)
+                                     -> Result<Vec<(String, Vec<GroupEntry>)>, CommandError> {
+        self.shared
+            .block_on_streams(streams, timeout, |shards| {
+                let read = self.shared
+                               .read_group(shards, group, consumer, streams, count, noack)?;
+                // delivering entries changes the group's pending list
+                read.iter().for_each(|(key, _)| self.shared.touch(key));
+                Ok(read)
+            })
+            .await
+    }
+
+    /// Acknowledge entries: they're no longer pending.  Returns how many were.
+    pub fn xack(&self, key: &str, group: &[u8], ids: &[StreamId]) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(group) = self.shared
+                              .live_stream(&mut entries, key)?
+                              .and_then(|stream| stream.groups.get_mut(group))
+        else {
+            return Ok(0);
+        };
+        Ok(ids.iter().filter(|id| group.unassign(**id)).count())
+    }
+
+    /// Overview of a group's pending entries.
+    pub fn xpending_summary(&self,
+                            key: &str,
+                            group: &[u8])
+                            -> Result<PendingSummary, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let group = self.shared.xgroup(&mut entries, key, group)?;
+        let first = group.pending.keys().next();
+        let last = group.pending.keys().next_back();
+        let mut consumers: Vec<_> =
+            group.consumers
+                 .iter()
+                 .filter(|(_, consumer)| !consumer.pending.is_empty())
+                 .map(|(name, consumer)| (name.clone(), consumer.pending.len()))
+                 .collect();
+        consumers.sort();
+        Ok(PendingSummary { count: group.pending.len(),
+                            range: first.zip(last).map(|(first, last)| (*first, *last)),
+                            consumers })
+    }
+
+    /// Up to `count` of a group's pending entries with IDs from `start` to `end`, idle for at least
+    /// `min_idle` milliseconds and, if given, belonging to `consumer`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn xpending(&self,
+                    key: &str,
+                    group: &[u8],
+                    start: StreamId,
+                    end: StreamId,
+                    count: usize,
+                    consumer: Option<&[u8]>,
+                    min_idle: u64)
+                    -> Result<Vec<PendingInfo>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let group = self.shared.xgroup(&mut entries, key, group)?;
+        if start > end {
+            return Ok(Vec::new());
+        }
+        let now = unix_millis();
+        Ok(group.pending
+                .range(start..=end)
+                .map(|(id, pending)| PendingInfo { id:         *id,
+                                                   consumer:   pending.consumer.clone(),
+                                                   idle:
+                                                       now.saturating_sub(pending.delivered_at),
+                                                   deliveries: pending.deliveries, })
+                .filter(|info| consumer.is_none_or(|consumer| info.consumer == consumer))
+                .filter(|info| info.idle >= min_idle)
+                .take(count)
+                .collect())
+    }
+
+    /// Take over pending entries on behalf of `consumer`.  Returns those claimed; entries deleted from
+    /// the stream since they were delivered are dropped from the pending list instead.
+    pub fn xclaim(&self,
+                  key: &str,
+                  group: &[u8],
+                  consumer: Bytes,
+                  ids: &[StreamId],
+                  options: ClaimOptions)
+                  -> Result<Vec<StreamEntry>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let stream = self.shared
+                         .live_stream(&mut entries, key)?
+                         .ok_or_else(|| no_such_group(key, group))?;
+        let group = stream.groups
+                          .get_mut(group)
+                          .ok_or_else(|| no_such_group(key, group))?;
+        let now = unix_millis();
+        group.consumers.entry(consumer.clone()).or_default();
+        if let Some(last_id) = options.last_id {
+            group.last_delivered = group.last_delivered.max(last_id);
+        }
+
+        let mut claimed = Vec::new();
+        for &id in ids {
+            let deliveries = match group.pending.get(&id) {
+                None if !options.force => continue,
+                None => 0,
+                Some(pending) if now.saturating_sub(pending.delivered_at) < options.min_idle => {
+                    continue
+                }
+                Some(pending) => pending.deliveries,
+            };
+            let Some(fields) = stream.entries.get(&id) else {
+                group.unassign(id);
+                continue;
+            };
+            let deliveries = options.retry_count
+                                    .unwrap_or(deliveries + !options.just_id as u64);
+            group.assign(id, Pending { consumer: consumer.clone(),
+                                       delivered_at: options.delivered
+                                                            .unwrap_or(now),
+                                       deliveries });
+            claimed.push((id, fields.clone()));
+        }
+        Ok(claimed)
+    }
+}
+
+impl Shared {
+    /// The group named `group` on the stream at `key`.  Missing either is an error.
+    fn xgroup<'m>(&self,
+                  entries: &'m mut Entries,
+                  key: &str,
+                  group: &[u8])
+                  -> Result<&'m mut Group, CommandError> {
+        self.live_stream(entries, key)?
+            .and_then(|stream| stream.groups.get_mut(group))
+            .ok_or_else(|| no_such_group(key, group))
+    }
+
+    /// XREADGROUP, with the streams' shards locked.
+    fn read_group(&self,
+                  shards: &mut ShardGuards<'_, String, Entry>,
+                  group: &[u8],
+                  consumer: &Bytes,
+                  streams: &[(String, Option<StreamId>)],
+                  count: Option<usize>,
+                  noack: bool)
+                  -> Result<Vec<(String, Vec<GroupEntry>)>, CommandError> {
+        // check every stream and group before delivering anything
+        for (key, _) in streams {
+            self.live_stream(shards.map_for(key), key)?
+                .filter(|stream| stream.groups.contains_key(group))
+                .ok_or_else(|| {
+                    CommandError::NoGroup(format!("No such key '{}' or consumer group '{}' in XREADGROUP \
+                                                   with GROUP option",
+                                                  key,
+                                                  String::from_utf8_lossy(group)))
+                })?;
+        }
+
+        let count = count.unwrap_or(usize::MAX);
+        let now = unix_millis();
+        let mut found = Vec::new();
+        for (key, id) in streams {
+            let Some(Stream { entries, groups, .. }) = self.live_stream(shards.map_for(key), key)?
+            else {
+                continue;
+            };
+            let group = groups.get_mut(group).expect("Group checked above.");
+            let state = group.consumers.entry(consumer.clone()).or_default();
+            let read: Vec<_> = match id {
+                Some(after) => state.pending
+                                    .range((Bound::Excluded(after), Bound::Unbounded))
+                                    .take(count)
+                                    .map(|id| (*id, entries.get(id).cloned()))
+                                    .collect(),
+                None => {
+                    let new: Vec<_> = entries.range((Bound::Excluded(group.last_delivered),
+                                                     Bound::Unbounded))
+                                             .take(count)
+                                             .map(clone_entry)
+                                             .collect();
+                    if new.is_empty() {
+                        continue;
+                    }
+                    group.last_delivered = new[new.len() - 1].0;
+                    if !noack {
+                        for (id, _) in &new {
+                            group.assign(*id, Pending { consumer:     consumer.clone(),
+                                                        delivered_at: now,
+                                                        deliveries:   1, });
+                        }
+                    }
+                    new.into_iter()
+                       .map(|(id, fields)| (id, Some(fields)))
+                       .collect()
+                }
+            };
+            found.push((key.clone(), read));
+        }
+        Ok(found)
+    }
+}
+
+impl Group {
+    /// Make `id` pending with `pending.consumer`, taking it from whoever had it.
+    fn assign(&mut self, id: StreamId, pending: Pending) {
+        let consumer = pending.consumer.clone();
+        if let Some(previous) = self.pending.insert(id, pending) {
+            if let Some(previous) = self.consumers.get_mut(&previous.consumer) {
+                previous.pending.remove(&id);
+            }
+        }
+        self.consumers
+            .entry(consumer)
+            .or_default()
+            .pending
+            .insert(id);
+    }
+
+    /// Take `id` off the pending list.  Returns whether it was there.
+    fn unassign(&mut self, id: StreamId) -> bool {
+        let Some(pending) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(consumer) = self.consumers.get_mut(&pending.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+}
+
+fn no_stream() -> CommandError {
+    CommandError::Other("The XGROUP subcommand requires the key to exist. Note that for CREATE you may \
+                         want to use the MKSTREAM option to create an empty stream automatically."
+                                                                                                .to_string())
+}
+
+fn no_such_group(key: &str, group: &[u8]) -> CommandError {
+    CommandError::NoGroup(format!("No such key '{}' or consumer group '{}'",
+                                  key,
+                                  String::from_utf8_lossy(group)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::XAddId;
+
+    fn id(ms: u64) -> StreamId {
+        StreamId { ms, seq: 0 }
+    }
+
+    fn fill(db: &Db, key: &str, ids: impl IntoIterator<Item=u64>) {
+        for ms in ids {
+            db.xadd(key,
+                    XAddId::Explicit(id(ms)),
+                    vec![(Bytes::from("n"), Bytes::from(ms.to_string()))],
+                    None,
+                    true)
+              .unwrap();
+        }
+    }
+
+    fn ids(read: &[(String, Vec<GroupEntry>)]) -> Vec<StreamId> {
+        read.iter()
+            .flat_map(|(_, entries)| entries.iter().map(|(id, _)| *id))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn entries_are_shared_out_once() {
+        let db = Db::new();
+        fill(&db, "s", 1..=3);
+        db.xgroup_create("s", Bytes::from("g"), Some(StreamId::MIN), false)
+          .unwrap();
+        let streams = [("s".to_string(), None)];
+        let (alice, bob) = (Bytes::from("alice"), Bytes::from("bob"));
+        let read = db.xreadgroup(b"g", &alice, &streams, Some(2), false)
+                     .unwrap();
+        assert_eq!(ids(&read), vec![id(1), id(2)]);
+        let read = db.xreadgroup(b"g", &bob, &streams, None, false).unwrap();
+        assert_eq!(ids(&read), vec![id(3)]);
+        assert!(db.xreadgroup(b"g", &bob, &streams, None, false)
+                  .unwrap()
+                  .is_empty());
+
+        // history: alice's own pending entries
+        let history = [("s".to_string(), Some(StreamId::MIN))];
+        assert_eq!(ids(&db.xreadgroup(b"g", &alice, &history, None, false).unwrap()),
+                   vec![id(1), id(2)]);
+        assert_eq!(db.xack("s", b"g", &[id(1), id(1), id(9)]), Ok(1));
+        let summary = db.xpending_summary("s", b"g").unwrap();
+        assert_eq!(summary.count, 2);
+        assert_eq!(summary.range, Some((id(2), id(3))));
+        assert_eq!(summary.consumers, vec![(alice, 1), (bob, 1)]);
+    }
+
+    #[tokio::test]
+    async fn idle_entries_can_be_claimed() {
+        let db = Db::new();
+        fill(&db, "s", 1..=2);
+        db.xgroup_create("s", Bytes::from("g"), Some(StreamId::MIN), false)
+          .unwrap();
+        let streams = [("s".to_string(), None)];
+        db.xreadgroup(b"g", &Bytes::from("crashed"), &streams, None, false)
+          .unwrap();
+
+        let fresh = ClaimOptions { min_idle: 60_000,
+                                   ..Default::default() };
+        assert!(db.xclaim("s", b"g", Bytes::from("worker"), &[id(1)], fresh)
+                  .unwrap()
+                  .is_empty());
+        let stale = ClaimOptions { delivered: Some(0),
+                                   ..Default::default() };
+        let claimed = db.xclaim("s", b"g", Bytes::from("worker"), &[id(1)], stale)
+                        .unwrap();
+        assert_eq!(claimed.len(), 1);
+        let pending = db.xpending("s", b"g", StreamId::MIN, StreamId::MAX, 10, None, 0)
+                        .unwrap();
+        assert_eq!(pending[0].consumer, Bytes::from("worker"));
+        assert_eq!(pending[0].deliveries, 2);
+        assert!(pending[0].idle > 1_000_000);
+        assert_eq!(pending[1].consumer, Bytes::from("crashed"));
+
+        // the old owner's share went with it
+        assert_eq!(db.xgroup_del_consumer("s", b"g", b"crashed"), Ok(1));
+        assert_eq!(db.xpending_summary("s", b"g").unwrap().count, 1);
+    }
+
+    #[tokio::test]
+    async fn missing_groups_are_errors() {
+        let db = Db::new();
+        let streams = [("s".to_string(), None)];
+        assert!(matches!(db.xreadgroup(b"g", &Bytes::from("c"), &streams, None, false),
+                         Err(CommandError::NoGroup(_))));
+        assert!(db.xgroup_create("s", Bytes::from("g"), None, false)
+                  .is_err());
+        db.xgroup_create("s", Bytes::from("g"), None, true).unwrap();
+        assert_eq!(db.xgroup_create("s", Bytes::from("g"), None, true),
+                   Err(CommandError::BusyGroup));
+        assert_eq!(db.xlen("s"), Ok(0));
+    }
+}
@@ -0,0 +1,128 @@
+//! What MULTI/EXEC needs from the store: a way to run a batch of commands with nothing else going on,
+//! and versions of WATCHed keys, to tell whether they changed since
+//!
+//! Every command runs holding `Shared::gate` for reading; EXEC takes it for writing.  Versions are
+//! only kept for keys somebody watches: a command that changes a key bumps its version (if it has one)
+//! before letting go of the gate, as does the key expiring.
+
+use std::{collections::HashMap, sync::RwLockReadGuard};
+
+use super::{Db, Shared};
+
+/// Keys being watched, by name.
+pub(super) type Watched = HashMap<String, KeyVersion>;
+
+#[derive(Debug, Default)]
+pub(super) struct KeyVersion {
+    /// Connections watching the key
+    watchers: usize,
+    /// Changes since the first of them started
+    version:  u64,
+}
+
+impl Db {
+    /// Run `f` as an ordinary command: alongside any others, but never in the middle of a transaction.
+    pub fn with_shared_access<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _gate = self.shared.gate();
+        f()
+    }
+
+    /// Run `f` with the store to itself: no other command runs until it's done.  Meant for EXEC; `f`
+    /// mustn't ask for shared access.
+    pub fn with_exclusive_access<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _gate = self.shared.gate.write().expect("Unpoisoned lock.");
+        f()
+    }
+
+    /// Start watching `key`.  Returns its version, for `unchanged` to compare with.
+    pub fn watch(&self, key: &str) -> u64 {
+        let mut watched = self.shared.watched.lock().expect("Unpoisoned mutex.");
+        let entry = watched.entry(key.to_string()).or_default();
+        entry.watchers += 1;
+        entry.version
+    }
+
+    /// Stop watching `key` (once for each `watch`).
+    pub fn unwatch(&self, key: &str) {
+        let mut watched = self.shared.watched.lock().expect("Unpoisoned mutex.");
+        if let Some(entry) = watched.get_mut(key) {
+            entry.watchers -= 1;
+            if entry.watchers == 0 {
+                watched.remove(key);
+            }
+        }
+    }
+
+    /// Record that `keys` may have changed.  Call before giving up shared access.
+    pub fn touch<'k>(&self, keys: impl IntoIterator<Item=&'k str>) {
+        keys.into_iter().for_each(|key| self.shared.touch(key));
+    }
+
+    /// Whether every key is still at the version `watch` gave for it -- and hasn't expired since,
+    /// either.  Call with exclusive access.
+    pub fn unchanged(&self, keys: &[(String, u64)]) -> bool {
+        for (key, _) in keys {
+            // a key past its deadline is only removed (and touched) once looked at
+            let _ = self.key_type(key);
+        }
+        let watched = self.shared.watched.lock().expect("Unpoisoned mutex.");
+        keys.iter().all(|(key, version)| {
+                       watched.get(key)
+                              .is_some_and(|entry| entry.version == *version)
+                   })
+    }
+}
+
+impl Shared {
+    /// Shared access to the store, for as long as the guard is held.
+    pub(super) fn gate(&self) -> RwLockReadGuard<'_, ()> {
+        self.gate.read().expect("Unpoisoned lock.")
+    }
+
+    /// Bump the version of `key`, if anyone is watching it.
+    pub(super) fn touch(&self, key: &str) {
+        let mut watched = self.watched.lock().expect("Unpoisoned mutex.");
+        if let Some(entry) = watched.get_mut(key) {
+            entry.version += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn versions_follow_changes_to_watched_keys() {
+        let db = Db::new();
+        let version = db.watch("k");
+        let watched = [("k".to_string(), version)];
+        db.touch(["other"]);
+        assert!(db.unchanged(&watched));
+        db.touch(["k"]);
+        assert!(!db.unchanged(&watched));
+
+        // a second watcher starts from where the key is now
+        let again = [("k".to_string(), db.watch("k"))];
+        assert!(db.unchanged(&again));
+        db.unwatch("k");
+        db.unwatch("k");
+        assert!(db.shared.watched.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expiry_counts_as_a_change() {
+        let db = Db::new();
+        db.set("k".into(),
+               Bytes::from("v"),
+               Some(Duration::from_millis(20)));
+        let watched = [("k".to_string(), db.watch("k"))];
+        assert!(db.unchanged(&watched));
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert!(!db.unchanged(&watched));
+    }
+}
@@ -0,0 +1,508 @@
+//! Sorted sets: distinct strings per key, each with a score, kept in score order
+//!
+//! A member's score is looked up in a hash map; the members' order lives in a skip list, which
+//! answers rank queries and finds the ends of a range in logarithmic time.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use self::skiplist::{NodeId, SkipList};
+use super::{Db, Entries, Entry, SetCondition, Shared, Value};
+use crate::error::CommandError;
+
+mod skiplist;
+
+/// One end of a BYSCORE range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+/// One end of a BYLEX range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexBound {
+    /// `-`: before every member
+    Least,
+    /// `+`: after every member
+    Greatest,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+/// Which members ZRANGE wants, lowest first.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZRange {
+    /// By position, counting from the end if negative, as with LRANGE.
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    /// By member, for sets whose members all share a score.
+    Lex(LexBound, LexBound),
+}
+
+/// ZADD's GT and LT: only update a score if that moves it up (or down).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreCondition {
+    Gt,
+    Lt,
+}
+
+/// ZADD's flags, bar INCR.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZAddOptions {
+    /// NX: only add new members.  XX: only update existing ones.
+    pub condition: Option<SetCondition>,
+    /// New members are added regardless.
+    pub compare:   Option<ScoreCondition>,
+    /// CH: count members whose score changed, as well as new ones.
+    pub changed:   bool,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    order:  SkipList,
+}
+
+/// Whether a member lies outside one end of a range, given its score and itself.
+type Bound<'a> = Box<dyn Fn(f64, &[u8]) -> bool+'a>;
+
+/// What ZADD did to one member.
+enum Update {
+    Added(f64),
+    Changed(f64),
+    Unchanged(f64),
+    /// Refused by NX, XX, GT or LT.
+    Skipped,
+}
+
+impl Db {
+    /// Give each member its score in the sorted set at `key`, creating it if need be.  Returns how many
+    /// members were new (or, with `changed`, new or rescored).
+    pub fn zadd(&self,
+                key: &str,
+                members: Vec<(f64, Bytes)>,
+                options: ZAddOptions)
+                -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let zset = self.shared.zset_or_new(&mut entries, key)?;
+        let mut count = 0;
+        for (score, member) in members {
+            match zset.update(member, score, false, options)? {
+                Update::Added(_) => count += 1,
+                Update::Changed(_) if options.changed => count += 1,
+                _ => {}
+            }
+        }
+        if zset.len() == 0 {
+            self.shared.remove(&mut entries, key);
+        }
+        Ok(count)
+    }
+
+    /// Add `delta` to the score of `member` (0 if it's new), as ZINCRBY and ZADD's INCR do.  Returns
+    /// the new score, or `None` if the options ruled the change out.
+    pub fn zincr_by(&self,
+                    key: &str,
+                    member: Bytes,
+                    delta: f64,
+                    options: ZAddOptions)
+                    -> Result<Option<f64>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let zset = self.shared.zset_or_new(&mut entries, key)?;
+        let update = zset.update(member, delta, true, options);
+        if zset.len() == 0 {
+            self.shared.remove(&mut entries, key);
+        }
+        Ok(match update? {
+            Update::Added(score) | Update::Changed(score) | Update::Unchanged(score) => Some(score),
+            Update::Skipped => None,
+        })
+    }
+
+    /// Members of the sorted set at `key` in `range`, with their scores; highest first if `rev`.
+    /// Of those, `offset` are skipped and at most `count` returned.
+    pub fn zrange(&self,
+                  key: &str,
+                  range: &ZRange,
+                  rev: bool,
+                  offset: usize,
+                  count: Option<usize>)
+                  -> Result<Vec<(Bytes, f64)>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_zset(&mut entries, key)?
+               .map(|zset| zset.range(range, rev, offset, count))
+               .unwrap_or_default())
+    }
+
+    /// Position of `member` in the sorted set at `key` (from the highest if `rev`), and its score.
+    pub fn zrank(&self,
+                 key: &str,
+                 member: &[u8],
+                 rev: bool)
+                 -> Result<Option<(usize, f64)>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(zset) = self.shared.live_zset(&mut entries, key)? else {
+            return Ok(None);
+        };
+        let Some(&score) = zset.scores.get(member) else {
+            return Ok(None);
+        };
+        let rank = zset.order
+                       .rank(score, member)
+                       .expect("Scored member is in the order.");
+        Ok(Some((if rev { zset.len() - 1 - rank } else { rank }, score)))
+    }
+
+    /// Score of `member` in the sorted set at `key`.
+    pub fn zscore(&self, key: &str, member: &[u8]) -> Result<Option<f64>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_zset(&mut entries, key)?
+               .and_then(|zset| zset.scores.get(member).copied()))
+    }
+
+    /// Number of members of the sorted set at `key`.
+    pub fn zcard(&self, key: &str) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        Ok(self.shared
+               .live_zset(&mut entries, key)?
+               .map_or(0, |zset| zset.len()))
+    }
+
+    /// Remove `members` from the sorted set at `key`.  Returns how many were there to remove.
+    pub fn zrem(&self, key: &str, members: &[Bytes]) -> Result<usize, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(zset) = self.shared.live_zset(&mut entries, key)? else {
+            return Ok(0);
+        };
+        let removed = members.iter()
+                             .filter(|member| zset.remove(member).is_some())
+                             .count();
+        if zset.len() == 0 {
+            self.shared.remove(&mut entries, key);
+        }
+        Ok(removed)
+    }
+
+    /// Remove and return up to `count` of the lowest-scored members (highest, if `rev`), in order.
+    pub fn zpop(&self,
+                key: &str,
+                count: usize,
+                rev: bool)
+                -> Result<Vec<(Bytes, f64)>, CommandError> {
+        let mut entries = self.shared.entries.lock_key(key);
+        let Some(zset) = self.shared.live_zset(&mut entries, key)? else {
+            return Ok(Vec::new());
+        };
+        let start = if rev {
+            zset.order.last()
+        } else {
+            zset.order.first()
+        };
+        let popped: Vec<_> = zset.walk(start, rev).take(count).collect();
+        for (member, _) in &popped {
+            zset.remove(member);
+        }
+        if zset.len() == 0 {
+            self.shared.remove(&mut entries, key);
+        }
+        Ok(popped)
+    }
+}
+
+impl Shared {
+    /// The sorted set at `key` in its (locked) shard, if there is one.  Any other type is an error.
+    fn live_zset<'m>(&self,
+                     entries: &'m mut Entries,
+                     key: &str)
+                     -> Result<Option<&'m mut SortedSet>, CommandError> {
+        match self.live(entries, key) {
+            None => Ok(None),
+            Some(Entry { value: Value::SortedSet(zset),
+                         .. }) => Ok(Some(zset)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    /// The sorted set at `key`, created empty if the key doesn't exist.  (The caller must fill it, or
+    /// remove it again.)
+    fn zset_or_new<'m>(&self,
+                       entries: &'m mut Entries,
+                       key: &str)
+                       -> Result<&'m mut SortedSet, CommandError> {
+        if self.live(entries, key).is_none() {
+            entries.insert(key.to_string(), Entry { value:
+                                                        Value::SortedSet(SortedSet::default()),
+                                                    expires_at: None, });
+        }
+        Ok(self.live_zset(entries, key)?.expect("Key just made live."))
+    }
+}
+
+impl SortedSet {
+    pub(super) fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Set `member`'s score to `value` -- or, if `incr`, add `value` to it -- as far as `options`
+    /// allow.
+    fn update(&mut self,
+              member: Bytes,
+              value: f64,
+              incr: bool,
+              options: ZAddOptions)
+              -> Result<Update, CommandError> {
+        let Some(&old) = self.scores.get(&member) else {
+            if options.condition == Some(SetCondition::Xx) {
+                return Ok(Update::Skipped);
+            }
+            self.insert(member, value);
+            return Ok(Update::Added(value));
+        };
+        if options.condition == Some(SetCondition::Nx) {
+            return Ok(Update::Skipped);
+        }
+        let score = if incr { old + value } else { value };
+        if score.is_nan() {
+            return Err(CommandError::Other("resulting score is not a number (NaN)".to_string()));
+        }
+        match options.compare {
+            Some(ScoreCondition::Gt) if score <= old => return Ok(Update::Skipped),
+            Some(ScoreCondition::Lt) if score >= old => return Ok(Update::Skipped),
+            _ => {}
+        }
+        if score == old {
+            return Ok(Update::Unchanged(score));
+        }
+        self.order.remove(old, &member);
+        self.insert(member, score);
+        Ok(Update::Changed(score))
+    }
+
+    /// Add a member that isn't there yet.
+    fn insert(&mut self, member: Bytes, score: f64) {
+        self.scores.insert(member.clone(), score);
+        self.order.insert(score, member);
+    }
+
+    fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.order.remove(score, member);
+        Some(score)
+    }
+
+    fn range(&self,
+             range: &ZRange,
+             rev: bool,
+             offset: usize,
+             count: Option<usize>)
+             -> Vec<(Bytes, f64)> {
+        // `before` holds for members short of the range's start, `after` for those past its end
+        let (before, after): (Bound<'_>, Bound<'_>) = match range {
+            ZRange::Rank(start, stop) => {
+                let Some((first, len)) = self.ranks(*start, *stop, rev) else {
+                    return Vec::new();
+                };
+                let start = self.order.at_rank(first);
+                return self.walk(start, rev)
+                           .take(len)
+                           .skip(offset)
+                           .take(count.unwrap_or(usize::MAX))
+                           .collect();
+            }
+            ZRange::Score(min, max) => (Box::new(move |score, _| min.short_of(score)),
+                                        Box::new(move |score, _| max.beyond(score))),
+            ZRange::Lex(min, max) => (Box::new(move |_, member| min.short_of(member)),
+                                      Box::new(move |_, member| max.beyond(member))),
+        };
+        let (start, end) = match rev {
+            false => (self.order.first_past(&before), after),
+            true => (self.order.last_before(&after), before),
+        };
+        self.walk(start, rev)
+            .take_while(|(member, score)| !end(*score, member))
+            .skip(offset)
+            .take(count.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// Where a walk over ranks `start..=stop` (counted from the highest if `rev`) begins, counting
+    /// from the lowest, and how many members it covers.
+    fn ranks(&self, start: i64, stop: i64, rev: bool) -> Option<(usize, usize)> {
+        let len = self.len() as i64;
+        let start = if start < 0 {
+            (start + len).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            stop + len
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return None;
+        }
+        let first = if rev { len - 1 - start } else { start };
+        Some((first as usize, (stop - start + 1) as usize))
+    }
+
+    /// Members and scores from `start` on, going down if `rev`.
+    fn walk(&self, start: Option<NodeId>, rev: bool) -> impl Iterator<Item=(Bytes, f64)>+'_ {
+        std::iter::successors(start, move |&id| match rev {
+            false => self.order.next(id),
+            true => self.order.prev(id),
+        }).map(|id| (self.order.member(id).clone(), self.order.score(id)))
+    }
+}
+
+impl ScoreBound {
+    /// Whether `score` falls short of this, as a range's minimum.
+    fn short_of(&self, score: f64) -> bool {
+        match *self {
+            ScoreBound::Inclusive(min) => score < min,
+            ScoreBound::Exclusive(min) => score <= min,
+        }
+    }
+
+    /// Whether `score` lies beyond this, as a range's maximum.
+    fn beyond(&self, score: f64) -> bool {
+        match *self {
+            ScoreBound::Inclusive(max) => score > max,
+            ScoreBound::Exclusive(max) => score >= max,
+        }
+    }
+}
+
+impl LexBound {
+    /// Whether `member` falls short of this, as a range's minimum.
+    fn short_of(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Least => false,
+            LexBound::Greatest => true,
+            LexBound::Inclusive(min) => member < &min[..],
+            LexBound::Exclusive(min) => member <= &min[..],
+        }
+    }
+
+    /// Whether `member` lies beyond this, as a range's maximum.
+    fn beyond(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Least => true,
+            LexBound::Greatest => false,
+            LexBound::Inclusive(max) => member > &max[..],
+            LexBound::Exclusive(max) => member >= &max[..],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(db: &Db, key: &str, range: ZRange, rev: bool) -> Vec<(Bytes, f64)> {
+        db.zrange(key, &range, rev, 0, None).unwrap()
+    }
+
+    fn pairs(items: &[(&str, f64)]) -> Vec<(Bytes, f64)> {
+        items.iter()
+             .map(|(member, score)| (Bytes::from(member.to_string()), *score))
+             .collect()
+    }
+
+    fn scored(items: &[(f64, &str)]) -> Vec<(f64, Bytes)> {
+        items.iter()
+             .map(|(score, member)| (*score, Bytes::from(member.to_string())))
+             .collect()
+    }
+
+    #[tokio::test]
+    async fn add_flags() {
+        let db = Db::new();
+        let add = |items, options| db.zadd("z", scored(items), options).unwrap();
+        assert_eq!(add(&[(1.0, "a"), (2.0, "b")], ZAddOptions::default()), 2);
+        let xx = ZAddOptions { condition: Some(SetCondition::Xx),
+                               ..Default::default() };
+        assert_eq!(add(&[(5.0, "a"), (1.0, "new")], xx), 0);
+        let nx_ch = ZAddOptions { condition: Some(SetCondition::Nx),
+                                  changed: true,
+                                  ..Default::default() };
+        assert_eq!(add(&[(9.0, "a"), (3.0, "c")], nx_ch), 1);
+        let gt_ch = ZAddOptions { compare: Some(ScoreCondition::Gt),
+                                  changed: true,
+                                  ..Default::default() };
+        // a goes up; b would go down; d is new
+        assert_eq!(add(&[(6.0, "a"), (0.0, "b"), (4.0, "d")], gt_ch), 2);
+        assert_eq!(members(&db, "z", ZRange::Rank(0, -1), false),
+                   pairs(&[("b", 2.0), ("c", 3.0), ("d", 4.0), ("a", 6.0)]));
+
+        let lt = ZAddOptions { compare: Some(ScoreCondition::Lt),
+                               ..Default::default() };
+        assert_eq!(db.zincr_by("z", Bytes::from("a"), 1.0, lt), Ok(None));
+        assert_eq!(db.zincr_by("z", Bytes::from("a"), -1.5, lt), Ok(Some(4.5)));
+        assert_eq!(db.zadd("absent", scored(&[(1.0, "a")]), xx), Ok(0));
+        assert_eq!(db.key_type("absent"), None);
+    }
+
+    #[tokio::test]
+    async fn incr_to_nan_is_refused() {
+        let db = Db::new();
+        db.zadd("z", scored(&[(f64::INFINITY, "a")]), ZAddOptions::default())
+          .unwrap();
+        assert!(db.zincr_by("z",
+                            Bytes::from("a"),
+                            f64::NEG_INFINITY,
+                            ZAddOptions::default())
+                  .is_err());
+        assert_eq!(db.zscore("z", b"a"), Ok(Some(f64::INFINITY)));
+    }
+
+    #[tokio::test]
+    async fn ranges_and_ranks() {
+        let db = Db::new();
+        db.zadd("z",
+                scored(&[(1.0, "a"), (2.0, "b"), (2.0, "c"), (3.0, "d")]),
+                ZAddOptions::default())
+          .unwrap();
+        assert_eq!(members(&db, "z", ZRange::Rank(-2, 10), true),
+                   pairs(&[("b", 2.0), ("a", 1.0)]));
+        assert_eq!(members(&db,
+                           "z",
+                           ZRange::Score(ScoreBound::Exclusive(1.0), ScoreBound::Inclusive(3.0)),
+                           true),
+                   pairs(&[("d", 3.0), ("c", 2.0), ("b", 2.0)]));
+        let range = ZRange::Score(ScoreBound::Inclusive(f64::NEG_INFINITY),
+                                  ScoreBound::Exclusive(3.0));
+        assert_eq!(db.zrange("z", &range, false, 1, Some(1)),
+                   Ok(pairs(&[("b", 2.0)])));
+        assert_eq!(db.zrank("z", b"c", false), Ok(Some((2, 2.0))));
+        assert_eq!(db.zrank("z", b"c", true), Ok(Some((1, 2.0))));
+        assert_eq!(db.zrank("z", b"x", false), Ok(None));
+
+        db.zadd("lex",
+                scored(&[(0.0, "apple"), (0.0, "banana"), (0.0, "cherry")]),
+                ZAddOptions::default())
+          .unwrap();
+        let range = ZRange::Lex(LexBound::Exclusive(Bytes::from("apple")),
+                                LexBound::Greatest);
+        assert_eq!(members(&db, "lex", range, false),
+                   pairs(&[("banana", 0.0), ("cherry", 0.0)]));
+        let range = ZRange::Lex(LexBound::Greatest, LexBound::Least);
+        assert!(members(&db, "lex", range, false).is_empty());
+    }
+
+    #[tokio::test]
+    async fn popping_empties_the_key() {
+        let db = Db::new();
+        db.zadd("z",
+                scored(&[(1.0, "a"), (2.0, "b")]),
+                ZAddOptions::default())
+          .unwrap();
+        assert_eq!(db.zpop("z", 1, true), Ok(pairs(&[("b", 2.0)])));
+        assert_eq!(db.zpop("z", 5, false), Ok(pairs(&[("a", 1.0)])));
+        assert_eq!(db.key_type("z"), None);
+    }
+}
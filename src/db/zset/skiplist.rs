@@ -0,0 +1,379 @@
+//! The ordered half of a sorted set: a skip list of `(score, member)`, after Redis's `zskiplist`
+//!
+//! Each link records how many nodes it jumps over, so finding a member's rank -- or the member at a
+//! given rank -- takes O(log n), as do seeks to the start or end of a score (or lex) range.
+//!
+//! Nodes live in a `Vec` and refer to each other by index.  Index 0 is the head, which holds no
+//! element.
+
+use bytes::Bytes;
+
+/// Most levels a node can have; plenty for 2^64 elements at `P`.
+const MAX_LEVEL: usize = 32;
+
+/// Chance that a node reaching one level also reaches the next.
+const P: f64 = 0.25;
+
+const HEAD: usize = 0;
+
+/// Position of an element in the list.  Valid until the list is next changed.
+pub type NodeId = usize;
+
+#[derive(Debug)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    /// Slots in `nodes` freed by removals, for reuse.
+    free:  Vec<usize>,
+    /// Levels in use (at least 1).
+    level: usize,
+    len:   usize,
+    tail:  Option<NodeId>,
+}
+
+#[derive(Debug)]
+struct Node {
+    member:   Bytes,
+    score:    f64,
+    forward:  Vec<Link>,
+    backward: Option<NodeId>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    next: Option<NodeId>,
+    /// Nodes passed by following this link (counting the one it lands on).
+    span: usize,
+}
+
+impl SkipList {
+    pub fn new() -> SkipList {
+        let head = Node { member:   Bytes::new(),
+                          score:    0.0,
+                          forward:  vec![
+                              Link { next: None,
+                                     span: 0, };
+                              MAX_LEVEL
+                          ],
+                          backward: None, };
+        SkipList { nodes: vec![head],
+                   free:  Vec::new(),
+                   level: 1,
+                   len:   0,
+                   tail:  None, }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Add an element.  The caller makes sure `member` isn't already present.
+    pub fn insert(&mut self, score: f64, member: Bytes) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.before(x, i, score, &member) {
+                rank[i] += self.nodes[x].forward[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].forward[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node { member,
+                          score,
+                          forward: vec![
+                              Link { next: None,
+                                     span: 0, };
+                              level
+                          ],
+                          backward: (update[0] != HEAD).then_some(update[0]) };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            if i >= level {
+                self.nodes[prev].forward[i].span += 1;
+                continue;
+            }
+            let link = self.nodes[prev].forward[i];
+            self.nodes[id].forward[i] = Link { next: link.next,
+                                               span: link.span - (rank[0] - rank[i]), };
+            self.nodes[prev].forward[i] = Link { next: Some(id),
+                                                 span: rank[0] - rank[i] + 1, };
+        }
+        match self.nodes[id].forward[0].next {
+            Some(next) => self.nodes[next].backward = Some(id),
+            None => self.tail = Some(id),
+        }
+        self.len += 1;
+    }
+
+    /// Remove an element.  Returns whether it was there.
+    pub fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.before(x, i, score, member) {
+                x = next;
+            }
+            update[i] = x;
+        }
+        let Some(id) = self.nodes[x].forward[0].next else {
+            return false;
+        };
+        if self.nodes[id].score != score || self.nodes[id].member != member {
+            return false;
+        }
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            let skipped =
+                (self.nodes[prev].forward[i].next == Some(id)).then(|| self.nodes[id].forward[i]);
+            let link = &mut self.nodes[prev].forward[i];
+            match skipped {
+                Some(removed) => {
+                    *link = Link { next: removed.next,
+                                   span: link.span + removed.span - 1, }
+                }
+                None => link.span -= 1,
+            }
+        }
+        let backward = self.nodes[id].backward;
+        match self.nodes[id].forward[0].next {
+            Some(next) => self.nodes[next].backward = backward,
+            None => self.tail = backward,
+        }
+        while self.level > 1 && self.nodes[HEAD].forward[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+        self.nodes[id].member = Bytes::new();
+        self.nodes[id].forward = Vec::new();
+        self.free.push(id);
+        self.len -= 1;
+        true
+    }
+
+    /// 0-based rank of an element, if present.
+    pub fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut x = HEAD;
+        let mut rank = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].forward[i].next {
+                let node = &self.nodes[next];
+                if node.score > score || (node.score == score && &node.member[..] > member) {
+                    break;
+                }
+                rank += self.nodes[x].forward[i].span;
+                x = next;
+            }
+            if x != HEAD && self.nodes[x].score == score && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// The element at 0-based `rank`.
+    pub fn at_rank(&self, rank: usize) -> Option<NodeId> {
+        let target = rank + 1;
+        let mut x = HEAD;
+        let mut traversed = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].forward[i].next {
+                if traversed + self.nodes[x].forward[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].forward[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// The first element for which `before` is false.  `before` must hold for some prefix of the list
+    /// and for nothing after it.
+    pub fn first_past(&self, before: impl Fn(f64, &[u8]) -> bool) -> Option<NodeId> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].forward[i].next {
+                let node = &self.nodes[next];
+                if !before(node.score, &node.member) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        self.nodes[x].forward[0].next
+    }
+
+    /// The last element for which `after` is false.  `after` must hold for some suffix of the list and
+    /// for nothing before it.
+    pub fn last_before(&self, after: impl Fn(f64, &[u8]) -> bool) -> Option<NodeId> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].forward[i].next {
+                let node = &self.nodes[next];
+                if after(node.score, &node.member) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        (x != HEAD).then_some(x)
+    }
+
+    pub fn first(&self) -> Option<NodeId> {
+        self.nodes[HEAD].forward[0].next
+    }
+
+    pub fn last(&self) -> Option<NodeId> {
+        self.tail
+    }
+
+    pub fn next(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id].forward[0].next
+    }
+
+    pub fn prev(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id].backward
+    }
+
+    pub fn score(&self, id: NodeId) -> f64 {
+        self.nodes[id].score
+    }
+
+    pub fn member(&self, id: NodeId) -> &Bytes {
+        &self.nodes[id].member
+    }
+
+    /// The node after `x` at level `i`, if it sorts before `(score, member)`.
+    fn before(&self, x: usize, i: usize, score: f64, member: &[u8]) -> Option<NodeId> {
+        let next = self.nodes[x].forward[i].next?;
+        let node = &self.nodes[next];
+        (node.score < score || (node.score == score && &node.member[..] < member)).then_some(next)
+    }
+}
+
+impl Default for SkipList {
+    fn default() -> SkipList {
+        SkipList::new()
+    }
+}
+
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && rand::random::<f64>() < P {
+        level += 1;
+    }
+    level
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elements(list: &SkipList) -> Vec<(f64, Bytes)> {
+        std::iter::successors(list.first(), |&id| list.next(id)).map(|id| {
+                                                                    (list.score(id),
+                                                                     list.member(id).clone())
+                                                                })
+                                                                .collect()
+    }
+
+    fn member(i: usize) -> Bytes {
+        Bytes::from(format!("m{i:04}"))
+    }
+
+    #[test]
+    fn keeps_score_then_member_order() {
+        let mut list = SkipList::new();
+        for (score, name) in [(2.0, "b"), (1.0, "z"), (2.0, "a"), (-1.5, "q")] {
+            list.insert(score, Bytes::from(name));
+        }
+        assert_eq!(elements(&list), vec![(-1.5, Bytes::from("q")),
+                                         (1.0, Bytes::from("z")),
+                                         (2.0, Bytes::from("a")),
+                                         (2.0, Bytes::from("b"))]);
+        assert_eq!(list.member(list.last().unwrap()), &Bytes::from("b"));
+        assert_eq!(list.member(list.prev(list.last().unwrap()).unwrap()),
+                   &Bytes::from("a"));
+    }
+
+    #[test]
+    fn ranks_agree_with_positions() {
+        let mut list = SkipList::new();
+        // insert in a scrambled order
+        for i in (0..500).map(|i| (i * 7919) % 500) {
+            list.insert(i as f64, member(i));
+        }
+        for i in 0..500 {
+            assert_eq!(list.rank(i as f64, &member(i)), Some(i));
+            let id = list.at_rank(i).unwrap();
+            assert_eq!((list.score(id), list.member(id)), (i as f64, &member(i)));
+        }
+        assert_eq!(list.rank(1.0, b"nope"), None);
+        assert_eq!(list.at_rank(500), None);
+    }
+
+    #[test]
+    fn removal_keeps_ranks_and_links() {
+        let mut list = SkipList::new();
+        for i in 0..200 {
+            list.insert(i as f64, member(i));
+        }
+        for i in (0..200).filter(|i| i % 3 == 0) {
+            assert!(list.remove(i as f64, &member(i)));
+        }
+        assert!(!list.remove(0.0, &member(0)));
+        assert!(!list.remove(1.0, &member(2)));
+        let left: Vec<usize> = (0..200).filter(|i| i % 3 != 0).collect();
+        assert_eq!(list.len(), left.len());
+        for (rank, &i) in left.iter().enumerate() {
+            assert_eq!(list.rank(i as f64, &member(i)), Some(rank));
+            assert_eq!(list.at_rank(rank).map(|id| list.member(id).clone()),
+                       Some(member(i)));
+        }
+        let backwards: Vec<_> = std::iter::successors(list.last(), |&id| list.prev(id)).collect();
+        assert_eq!(backwards.len(), left.len());
+
+        // freed slots are reused
+        list.insert(1000.0, member(1000));
+        assert_eq!(list.rank(1000.0, &member(1000)), Some(left.len()));
+        assert!(list.nodes.len() <= 201);
+    }
+
+    #[test]
+    fn seeks_to_range_ends() {
+        let mut list = SkipList::new();
+        for i in 0..100 {
+            list.insert(i as f64, member(i));
+        }
+        let first = list.first_past(|score, _| score < 42.5).unwrap();
+        assert_eq!(list.score(first), 43.0);
+        let last = list.last_before(|score, _| score > 42.5).unwrap();
+        assert_eq!(list.score(last), 42.0);
+        assert_eq!(list.first_past(|score, _| score < 1000.0), None);
+        assert_eq!(list.last_before(|score, _| score > -1.0), None);
+    }
+}
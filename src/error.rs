@@ -0,0 +1,94 @@
+//! Error types
+//!
+//! `Error` is the catch-all used by examples and plumbing.  `CommandError` is what a client gets
+//! back when a request can't be served: each variant renders as the error reply Redis would send.
+
+use std::fmt;
+
+use crate::frame::Frame;
+
+pub type Result<T> = core::result::Result<T, Error>;
+pub type Error = Box<dyn std::error::Error+Send+Sync>;
+
+/// Why a command was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// No such command.  Carries the name and (up to a few of) the arguments, for the message.
+    UnknownCommand { name: String, args: Vec<String> },
+    /// Too few or too many arguments for the named command.
+    WrongArity(String),
+    /// Command applied to a key holding a different type of value.
+    WrongType,
+    /// Arguments present in the right number, but not in a form the command understands.
+    Syntax,
+    /// An argument that should be an integer isn't one (or doesn't fit in 64 bits).
+    NotInteger,
+    /// An argument (or stored value) that should be a float isn't one.
+    NotFloat,
+    /// No such stream consumer group (or no such stream).  The message follows `NOGROUP `.
+    NoGroup(String),
+    /// XGROUP CREATE for a group that already exists.
+    BusyGroup,
+    /// EXEC of a transaction in which a command was refused while queuing.
+    ExecAbort,
+    /// A HyperLogLog command found a string that isn't one.
+    InvalidHll,
+    /// HELLO asked for a version of the protocol we don't speak.
+    NoProto,
+    /// HELLO's AUTH named a user that doesn't exist.
+    WrongPass,
+    /// Malformed request at the protocol level.  The connection can't be trusted past this point.
+    Protocol(String),
+    /// Anything else; the message follows `ERR `.
+    Other(String),
+}
+
+impl CommandError {
+    /// The error reply to send the client.
+    pub fn to_frame(&self) -> Frame {
+        Frame::Error(self.to_string())
+    }
+
+    /// Whether the connection should be dropped after replying, as Redis does for protocol errors.
+    pub fn closes_connection(&self) -> bool {
+        matches!(self, CommandError::Protocol(_))
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownCommand { name, args } => {
+                write!(f,
+                       "ERR unknown command '{}', with args beginning with: ",
+                       name)?;
+                args.iter().try_for_each(|arg| write!(f, "'{}' ", arg))
+            }
+            CommandError::WrongArity(name) => {
+                write!(f, "ERR wrong number of arguments for '{}' command", name)
+            }
+            CommandError::WrongType => {
+                "WRONGTYPE Operation against a key holding the wrong kind of value".fmt(f)
+            }
+            CommandError::Syntax => "ERR syntax error".fmt(f),
+            CommandError::NotInteger => "ERR value is not an integer or out of range".fmt(f),
+            CommandError::NotFloat => "ERR value is not a valid float".fmt(f),
+            CommandError::NoGroup(msg) => write!(f, "NOGROUP {}", msg),
+            CommandError::BusyGroup => "BUSYGROUP Consumer Group name already exists".fmt(f),
+            CommandError::ExecAbort => {
+                "EXECABORT Transaction discarded because of previous errors.".fmt(f)
+            }
+            CommandError::InvalidHll => {
+                "WRONGTYPE Key is not a valid HyperLogLog string value.".fmt(f)
+            }
+            CommandError::NoProto => "NOPROTO unsupported protocol version".fmt(f),
+            CommandError::WrongPass => {
+                "WRONGPASS invalid username-password pair or user is disabled.".fmt(f)
+            }
+            CommandError::Protocol(msg) => write!(f, "ERR Protocol error: {}", msg),
+            CommandError::Other(msg) => write!(f, "ERR {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
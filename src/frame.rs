@@ -0,0 +1,739 @@
+//! Redis protocol (RESP) frames, and parsing them out of a byte buffer
+
+use std::{fmt, io::Cursor, num::TryFromIntError, string::FromUtf8Error};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// A frame in the Redis protocol.
+///
+/// The variants after `Array` are RESP3's.  A connection speaking RESP2 gets each of them as the
+/// nearest RESP2 type instead (see `encode`), so commands can reply in RESP3 terms regardless.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    /// An integer of any size, in decimal.
+    BigNumber(String),
+    /// Text along with its three letter format: `txt` or `mkd`.
+    Verbatim {
+        format: String,
+        text:   Bytes,
+    },
+    /// Data the client didn't ask for (published messages, say).
+    Push(Vec<Frame>),
+    /// A frame, along with extra information about it.
+    Attribute {
+        attributes: Vec<(Frame, Frame)>,
+        frame:      Box<Frame>,
+    },
+}
+
+/// Which version of the protocol a connection speaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// Longest inline command, or line of a RESP frame, accepted: past this, a line with no end in sight
+/// is refused rather than searched again on every read (as redis does).
+pub const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// Bounds on what a peer may send, so that a hostile one can't have us buffer (or recurse) without
+/// end.  The defaults are redis's, where it has one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest bulk string accepted, in bytes
+    pub max_bulk_len:      usize,
+    /// Most entries in one aggregate (pairs, for a map)
+    pub max_multibulk_len: usize,
+    /// How deep aggregates may nest
+    pub max_depth:         usize,
+    /// Most bytes held for a connection while waiting for the rest of a frame
+    pub max_buffer:        usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { max_bulk_len:      512 * 1024 * 1024,
+                 max_multibulk_len: 1024 * 1024,
+                 max_depth:         32,
+                 max_buffer:        1024 * 1024 * 1024, }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Not enough data is available to parse a message
+    Incomplete,
+    /// Invalid message encoding
+    Other(crate::error::Error),
+}
+
+impl Frame {
+    /// Bulk frame holding a copy of `s`.
+    pub fn bulk(s: impl AsRef<[u8]>) -> Frame {
+        Frame::Bulk(Bytes::copy_from_slice(s.as_ref()))
+    }
+
+    /// The usual `+OK`.
+    pub fn ok() -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+
+    /// Check whether an entire message can be decoded from `src`, within `limits`.
+    ///
+    /// Advances the cursor past the message, so `src.position()` afterwards is the frame's length.
+    /// A length over the limits is an error as soon as it's read, without waiting for what follows.
+    pub fn check(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), Error> {
+        check_nested(src, limits, 0)
+    }
+
+    /// Parse a message that has already been validated with `check`.
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        match get_u8(src)? {
+            b'+' => {
+                let line = get_line(src)?.to_vec();
+                Ok(Frame::Simple(String::from_utf8(line)?))
+            }
+            b'-' => {
+                let line = get_line(src)?.to_vec();
+                Ok(Frame::Error(String::from_utf8(line)?))
+            }
+            b':' => Ok(Frame::Integer(get_integer(src)?)),
+            b'$' => {
+                if b'-' == peek_u8(src)? {
+                    if get_line(src)? != b"-1" {
+                        return Err("invalid frame format".into());
+                    }
+                    Ok(Frame::Null)
+                } else {
+                    let len: usize = get_integer(src)?.try_into()?;
+                    let n = len + 2;
+                    if src.remaining() < n {
+                        return Err(Error::Incomplete);
+                    }
+                    let data = Bytes::copy_from_slice(&src.chunk()[..len]);
+                    // skip that number of bytes + 2 (\r\n).
+                    skip(src, n)?;
+                    Ok(Frame::Bulk(data))
+                }
+            }
+            b'*' if b'-' == peek_u8(src)? => {
+                get_integer(src)?;
+                Ok(Frame::Null)
+            }
+            b'*' => Ok(Frame::Array(get_frames(src)?)),
+            b'_' => match get_line(src)? {
+                b"" => Ok(Frame::Null),
+                _ => Err("invalid frame format".into()),
+            },
+            b',' => {
+                let line = std::str::from_utf8(get_line(src)?).map_err(|_| "invalid frame format")?;
+                let double = line.parse().map_err(|_| "invalid frame format")?;
+                Ok(Frame::Double(double))
+            }
+            b'#' => match get_line(src)? {
+                b"t" => Ok(Frame::Boolean(true)),
+                b"f" => Ok(Frame::Boolean(false)),
+                _ => Err("invalid frame format".into()),
+            },
+            b'(' => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let digits = line.strip_prefix('-').unwrap_or(&line);
+                if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err("invalid frame format".into());
+                }
+                Ok(Frame::BigNumber(line))
+            }
+            b'=' => {
+                let len: usize = get_integer(src)?.try_into()?;
+                if src.remaining() < len + 2 {
+                    return Err(Error::Incomplete);
+                }
+                let data = &src.chunk()[..len];
+                if len < 4 || data[3] != b':' {
+                    return Err("invalid frame format".into());
+                }
+                let format = String::from_utf8(data[..3].to_vec())?;
+                let text = Bytes::copy_from_slice(&data[4..]);
+                skip(src, len + 2)?;
+                Ok(Frame::Verbatim { format, text })
+            }
+            b'%' => Ok(Frame::Map(get_pairs(src)?)),
+            b'~' => Ok(Frame::Set(get_frames(src)?)),
+            b'>' => Ok(Frame::Push(get_frames(src)?)),
+            b'|' => {
+                let attributes = get_pairs(src)?;
+                let frame = Box::new(Frame::parse(src)?);
+                Ok(Frame::Attribute { attributes, frame })
+            }
+            actual => Err(format!("invalid frame type byte `{}`", actual).into()),
+        }
+    }
+
+    /// Whether a message starting with `byte` is an inline command -- words on a line, as typed into
+    /// telnet -- rather than a RESP frame.
+    pub fn starts_inline(byte: u8) -> bool {
+        !matches!(byte,
+                  b'+'
+                  | b'-'
+                  | b':'
+                  | b'$'
+                  | b'*'
+                  | b'_'
+                  | b','
+                  | b'#'
+                  | b'('
+                  | b'='
+                  | b'%'
+                  | b'~'
+                  | b'>'
+                  | b'|')
+    }
+
+    /// Parse an inline command into an array of bulk strings, moving the cursor past its line.
+    ///
+    /// Words are separated by whitespace, and may be quoted as `redis-cli` does: "double quotes"
+    /// allowing escapes (`\n`, `\"`, `\x41`, ...), 'single quotes' only `\'`.  A blank line gives an
+    /// empty array.  The line ends with `\n`; a `\r` before it is dropped.  It may be at most
+    /// `MAX_INLINE_LEN` long, and hold at most `max_multibulk_len` words.
+    pub fn parse_inline(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<Frame, Error> {
+        let start = src.position() as usize;
+        let data = *src.get_ref();
+        let rest = &data[start..];
+        // a line of the longest length allowed has its `\n` just past it
+        let window = &rest[..rest.len().min(MAX_INLINE_LEN + 1)];
+        let Some(end) = window.iter().position(|&b| b == b'\n') else {
+            return match rest.len() > MAX_INLINE_LEN {
+                true => Err("too big inline request".into()),
+                false => Err(Error::Incomplete),
+            };
+        };
+        src.set_position((start + end + 1) as u64);
+        let line = &data[start..start + end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let words = split_words(line).ok_or("unbalanced quotes in request")?;
+        if words.len() > limits.max_multibulk_len {
+            return Err("invalid multibulk length".into());
+        }
+        Ok(Frame::Array(words.into_iter().map(Frame::Bulk).collect()))
+    }
+
+    /// Append the frame's encoding to `dst`, in the given version of the protocol.
+    ///
+    /// For RESP2, RESP3's types become what Redis sends RESP2 clients instead: maps flattened into
+    /// arrays of keys and values, sets and pushes as arrays, doubles, big numbers and verbatim text as
+    /// bulk strings, booleans as 1 or 0.  Attributes are left out.
+    pub fn encode(&self, protocol: Protocol, dst: &mut BytesMut) {
+        let resp3 = protocol == Protocol::Resp3;
+        match self {
+            Frame::Simple(val) => put_line(dst, b'+', val.as_bytes()),
+            Frame::Error(val) => put_line(dst, b'-', val.as_bytes()),
+            Frame::Integer(val) => put_line(dst, b':', val.to_string().as_bytes()),
+            Frame::Bulk(val) => put_bulk(dst, b'$', val),
+            Frame::Null if resp3 => dst.put_slice(b"_\r\n"),
+            Frame::Null => dst.put_slice(b"$-1\r\n"),
+            Frame::Array(items) => put_aggregate(dst, b'*', items, protocol),
+            Frame::Map(pairs) if resp3 => {
+                put_line(dst, b'%', pairs.len().to_string().as_bytes());
+                put_pairs(dst, pairs, protocol);
+            }
+            Frame::Map(pairs) => {
+                put_line(dst, b'*', (pairs.len() * 2).to_string().as_bytes());
+                put_pairs(dst, pairs, protocol);
+            }
+            Frame::Set(items) if resp3 => put_aggregate(dst, b'~', items, protocol),
+            Frame::Push(items) if resp3 => put_aggregate(dst, b'>', items, protocol),
+            Frame::Set(items) | Frame::Push(items) => put_aggregate(dst, b'*', items, protocol),
+            Frame::Double(val) if resp3 => put_line(dst, b',', format_double(*val).as_bytes()),
+            Frame::Double(val) => put_bulk(dst, b'$', format_double(*val).as_bytes()),
+            Frame::Boolean(val) if resp3 => put_line(dst, b'#', if *val { b"t" } else { b"f" }),
+            Frame::Boolean(val) => put_line(dst, b':', if *val { b"1" } else { b"0" }),
+            Frame::BigNumber(val) if resp3 => put_line(dst, b'(', val.as_bytes()),
+            Frame::BigNumber(val) => put_bulk(dst, b'$', val.as_bytes()),
+            Frame::Verbatim { format, text } if resp3 => {
+                put_line(dst, b'=', (text.len() + 4).to_string().as_bytes());
+                dst.put_slice(format.as_bytes());
+                dst.put_u8(b':');
+                dst.put_slice(text);
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Verbatim { text, .. } => put_bulk(dst, b'$', text),
+            Frame::Attribute { attributes, frame } => {
+                if resp3 {
+                    put_line(dst, b'|', attributes.len().to_string().as_bytes());
+                    put_pairs(dst, attributes, protocol);
+                }
+                frame.encode(protocol, dst);
+            }
+        }
+    }
+}
+
+/// Split an inline command into words, unquoting them.  `None` if the quotes don't match up.
+fn split_words(line: &[u8]) -> Option<Vec<Bytes>> {
+    let mut words = Vec::new();
+    let mut i = 0;
+    loop {
+        while line.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        if i == line.len() {
+            return Some(words);
+        }
+        let mut word = Vec::new();
+        let mut quote = None;
+        loop {
+            match (quote, line.get(i).copied()) {
+                (None, None) => break,
+                (None, Some(b)) if b.is_ascii_whitespace() => break,
+                (None, Some(b @ (b'"' | b'\''))) => quote = Some(b),
+                (None, Some(b)) => word.push(b),
+                (Some(_), None) => return None,
+                (Some(q), Some(b)) if b == q => {
+                    // a closing quote has to end the word
+                    if line.get(i + 1).is_some_and(|b| !b.is_ascii_whitespace()) {
+                        return None;
+                    }
+                    i += 1;
+                    break;
+                }
+                (Some(b'"'), Some(b'\\')) if line.get(i + 1).is_some() => {
+                    i += 1;
+                    let hex = line.get(i + 1..i + 3)
+                                  .and_then(|hex| std::str::from_utf8(hex).ok())
+                                  .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    match (line[i], hex) {
+                        (b'x', Some(b)) => {
+                            word.push(b);
+                            i += 2;
+                        }
+                        (b'n', _) => word.push(b'\n'),
+                        (b'r', _) => word.push(b'\r'),
+                        (b't', _) => word.push(b'\t'),
+                        (b'b', _) => word.push(0x08),
+                        (b'a', _) => word.push(0x07),
+                        (b, _) => word.push(b),
+                    }
+                }
+                (Some(b'\''), Some(b'\\')) if line.get(i + 1) == Some(&b'\'') => {
+                    i += 1;
+                    word.push(b'\'');
+                }
+                (Some(_), Some(b)) => word.push(b),
+            }
+            i += 1;
+        }
+        words.push(Bytes::from(word));
+    }
+}
+
+/// A double as the protocol spells it: `inf`, `-inf` and `nan` included.
+fn format_double(val: f64) -> String {
+    match val.is_nan() {
+        true => "nan".to_string(),
+        false => val.to_string(),
+    }
+}
+
+fn put_line(dst: &mut BytesMut, kind: u8, line: &[u8]) {
+    dst.put_u8(kind);
+    dst.put_slice(line);
+    dst.put_slice(b"\r\n");
+}
+
+fn put_bulk(dst: &mut BytesMut, kind: u8, data: &[u8]) {
+    put_line(dst, kind, data.len().to_string().as_bytes());
+    dst.put_slice(data);
+    dst.put_slice(b"\r\n");
+}
+
+fn put_aggregate(dst: &mut BytesMut, kind: u8, items: &[Frame], protocol: Protocol) {
+    put_line(dst, kind, items.len().to_string().as_bytes());
+    for item in items {
+        item.encode(protocol, dst);
+    }
+}
+
+fn put_pairs(dst: &mut BytesMut, pairs: &[(Frame, Frame)], protocol: Protocol) {
+    for (key, value) in pairs {
+        key.encode(protocol, dst);
+        value.encode(protocol, dst);
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Simple(response) => response.fmt(fmt),
+            Frame::Error(msg) => write!(fmt, "error: {}", msg),
+            Frame::Integer(num) => num.fmt(fmt),
+            Frame::Bulk(msg) => match std::str::from_utf8(msg) {
+                Ok(string) => string.fmt(fmt),
+                Err(_) => write!(fmt, "{:?}", msg),
+            },
+            Frame::Null => "(nil)".fmt(fmt),
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    part.fmt(fmt)?;
+                }
+                Ok(())
+            }
+            Frame::Map(pairs) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{}: {}", key, value)?;
+                }
+                Ok(())
+            }
+            Frame::Double(num) => format_double(*num).fmt(fmt),
+            Frame::Boolean(val) => val.fmt(fmt),
+            Frame::BigNumber(num) => num.fmt(fmt),
+            Frame::Verbatim { text, .. } => Frame::Bulk(text.clone()).fmt(fmt),
+            Frame::Attribute { frame, .. } => frame.fmt(fmt),
+        }
+    }
+}
+
+/// `Frame::check`, for a frame inside `depth` aggregates.
+fn check_nested(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<(), Error> {
+    match get_u8(src)? {
+        b'+' | b'-' | b'_' | b',' | b'#' | b'(' => {
+            get_line(src)?;
+            Ok(())
+        }
+        b':' => {
+            let _ = get_integer(src)?;
+            Ok(())
+        }
+        // a null bulk string (`$-1`, and nothing else negative), with nothing to follow
+        b'$' if b'-' == peek_u8(src)? => match get_line(src)? {
+            b"-1" => Ok(()),
+            _ => Err("invalid bulk length".into()),
+        },
+        b'$' | b'=' => {
+            let len = usize::try_from(get_integer(src)?).ok()
+                                                        .filter(|&len| len <= limits.max_bulk_len)
+                                                        .ok_or("invalid bulk length")?;
+            // skip that number of bytes + 2 (\r\n).
+            skip(src, len + 2)
+        }
+        // a null array (`*-1`, or any negative length, as redis reads it), with nothing to follow
+        b'*' if b'-' == peek_u8(src)? => get_integer(src).map(drop),
+        b'*' | b'~' | b'>' => {
+            let len = aggregate_len(src, limits, depth)?;
+            for _ in 0..len {
+                check_nested(src, limits, depth + 1)?;
+            }
+            Ok(())
+        }
+        b'%' => {
+            let len = aggregate_len(src, limits, depth)?;
+            for _ in 0..len {
+                check_nested(src, limits, depth + 1)?;
+                check_nested(src, limits, depth + 1)?;
+            }
+            Ok(())
+        }
+        b'|' => {
+            let len = aggregate_len(src, limits, depth)?;
+            for _ in 0..len {
+                check_nested(src, limits, depth + 1)?;
+                check_nested(src, limits, depth + 1)?;
+            }
+            // the frame the attributes are about; counted as nested, so a chain of them is bounded too
+            check_nested(src, limits, depth + 1)
+        }
+        actual => Err(format!("invalid frame type byte `{}`", actual).into()),
+    }
+}
+
+/// The length of an aggregate found inside `depth` others, if it's within `limits`.
+fn aggregate_len(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<usize, Error> {
+    if depth >= limits.max_depth {
+        return Err("too many nested aggregates".into());
+    }
+    let len = usize::try_from(get_integer(src)?).ok()
+                                                .filter(|&len| len <= limits.max_multibulk_len)
+                                                .ok_or("invalid multibulk length")?;
+    Ok(len)
+}
+
+fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.chunk()[0])
+}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.get_u8())
+}
+
+fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+    src.advance(n);
+    Ok(())
+}
+
+/// The entries of an aggregate, its length first.
+fn get_frames(src: &mut Cursor<&[u8]>) -> Result<Vec<Frame>, Error> {
+    let len: usize = get_integer(src)?.try_into()?;
+    let mut out = Vec::with_capacity(len);
+    for _ in 0..len {
+        out.push(Frame::parse(src)?);
+    }
+    Ok(out)
+}
+
+/// The entries of a map (or attribute), its number of pairs first.
+fn get_pairs(src: &mut Cursor<&[u8]>) -> Result<Vec<(Frame, Frame)>, Error> {
+    let len: usize = get_integer(src)?.try_into()?;
+    let mut out = Vec::with_capacity(len);
+    for _ in 0..len {
+        out.push((Frame::parse(src)?, Frame::parse(src)?));
+    }
+    Ok(out)
+}
+
+/// Read a (possibly negative) decimal integer line.
+fn get_integer(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    let line = get_line(src)?;
+    std::str::from_utf8(line).ok()
+                             .and_then(|s| s.parse().ok())
+                             .ok_or_else(|| "invalid frame format".into())
+}
+
+/// Find a line, returning it without its `\r\n` and moving the cursor past it.
+///
+/// A line may be at most `MAX_INLINE_LEN` long, so a peer can't have us search an ever longer one.
+fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let data = *src.get_ref();
+    let rest = &data[start..];
+    // a line of the longest length allowed has its `\r\n` just past it
+    let window = &rest[..rest.len().min(MAX_INLINE_LEN + 2)];
+    match window.windows(2).position(|pair| pair == b"\r\n") {
+        Some(end) => {
+            src.set_position((start + end + 2) as u64);
+            Ok(&data[start..start + end])
+        }
+        None if rest.len() >= MAX_INLINE_LEN + 2 => Err("too big line".into()),
+        None => Err(Error::Incomplete),
+    }
+}
+
+impl From<String> for Error {
+    fn from(src: String) -> Error {
+        Error::Other(src.into())
+    }
+}
+
+impl From<&str> for Error {
+    fn from(src: &str) -> Error {
+        src.to_string().into()
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(_src: FromUtf8Error) -> Error {
+        "invalid frame format".into()
+    }
+}
+
+impl From<TryFromIntError> for Error {
+    fn from(_src: TryFromIntError) -> Error {
+        "invalid frame format".into()
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(fmt),
+            Error::Other(err) => err.fmt(fmt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(frame: &Frame, protocol: Protocol) -> BytesMut {
+        let mut dst = BytesMut::new();
+        frame.encode(protocol, &mut dst);
+        dst
+    }
+
+    fn parse(data: &[u8]) -> Frame {
+        let mut src = Cursor::new(data);
+        Frame::check(&mut src, &Limits::default()).unwrap();
+        assert_eq!(src.position() as usize, data.len());
+        src.set_position(0);
+        Frame::parse(&mut src).unwrap()
+    }
+
+    #[test]
+    fn resp3_round_trips() {
+        let verbatim = Frame::Verbatim { format: "txt".into(),
+                                         text:   Bytes::from("some text"), };
+        let map =
+            Frame::Map(vec![(Frame::bulk("set"), Frame::Set(vec![Frame::bulk("a"), Frame::Null])),
+                            (Frame::Simple("double".into()), Frame::Double(-1.5)),
+                            (Frame::Boolean(true), Frame::Push(vec![Frame::Boolean(false)])),
+                            (Frame::BigNumber("-123456789012345678901234567890".into()),
+                             verbatim)]);
+        let frame = Frame::Attribute { attributes: vec![(Frame::bulk("ttl"), Frame::Integer(3))],
+                                       frame:      Box::new(map), };
+        let encoded = encode(&frame, Protocol::Resp3);
+        assert_eq!(parse(&encoded), frame);
+        assert_eq!(&encode(&Frame::Double(f64::NEG_INFINITY), Protocol::Resp3)[..],
+                   b",-inf\r\n");
+        assert_eq!(&encode(&Frame::Null, Protocol::Resp3)[..], b"_\r\n");
+        assert_eq!(&encode(&Frame::Verbatim { format: "txt".into(),
+                                              text:   Bytes::from("hi"), },
+                           Protocol::Resp3)[..],
+                   b"=6\r\ntxt:hi\r\n");
+    }
+
+    #[test]
+    fn resp2_gets_the_nearest_types() {
+        let map = Frame::Map(vec![(Frame::bulk("a"), Frame::Double(1.5)),
+                                  (Frame::bulk("b"),
+                                   Frame::Set(vec![Frame::Boolean(true), Frame::Null])),
+                                  (Frame::bulk("c"), Frame::BigNumber("12".into()))]);
+        let frame = Frame::Attribute { attributes: vec![(Frame::bulk("ignored"), Frame::Null)],
+                                       frame:      Box::new(map), };
+        assert_eq!(parse(&encode(&frame, Protocol::Resp2)),
+                   Frame::Array(vec![Frame::bulk("a"),
+                                     Frame::bulk("1.5"),
+                                     Frame::bulk("b"),
+                                     Frame::Array(vec![Frame::Integer(1), Frame::Null]),
+                                     Frame::bulk("c"),
+                                     Frame::bulk("12")]));
+        assert_eq!(&encode(&Frame::Null, Protocol::Resp2)[..], b"$-1\r\n");
+    }
+
+    #[test]
+    fn inline_commands_split_like_redis_cli() {
+        let inline = |data: &[u8]| Frame::parse_inline(&mut Cursor::new(data), &Limits::default());
+        let words = |words: &[&str]| Frame::Array(words.iter().map(Frame::bulk).collect());
+        assert_eq!(inline(b"SET  key \"a \\\"b\\\"\\x41\\n\" 'it\\'s'\r\n").unwrap(),
+                   words(&["SET", "key", "a \"b\"A\n", "it's"]));
+        assert_eq!(inline(b"ping\n").unwrap(), words(&["ping"]));
+        assert_eq!(inline(b"  \r\n").unwrap(), words(&[]));
+        assert_eq!(inline(b"get 'a b'").unwrap_err().to_string(),
+                   Error::Incomplete.to_string());
+        for unbalanced in [&b"get \"a\r\n"[..], b"get 'a'b\r\n", b"get \"a\"\"\r\n"] {
+            assert_eq!(inline(unbalanced).unwrap_err().to_string(),
+                       "unbalanced quotes in request");
+        }
+
+        let mut src = Cursor::new(&b"a b\r\nc\r\n"[..]);
+        let limits = Limits::default();
+        assert_eq!(Frame::parse_inline(&mut src, &limits).unwrap(),
+                   words(&["a", "b"]));
+        assert_eq!(Frame::parse_inline(&mut src, &limits).unwrap(),
+                   words(&["c"]));
+    }
+
+    #[test]
+    fn malformed_resp3_is_refused() {
+        for data in [&b"#x\r\n"[..],
+                     b",one\r\n",
+                     b"(12a\r\n",
+                     b"=2\r\nab\r\n",
+                     b"_x\r\n"]
+        {
+            let mut src = Cursor::new(data);
+            assert!(matches!(Frame::parse(&mut src), Err(Error::Other(_))),
+                    "{:?}",
+                    String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn hostile_frames_are_refused_early() {
+        let limits = Limits { max_bulk_len: 16,
+                              max_multibulk_len: 4,
+                              max_depth: 3,
+                              ..Limits::default() };
+        let check = |data: &[u8]| Frame::check(&mut Cursor::new(data), &limits);
+
+        // refused on the length alone, before any of what it promises has arrived
+        for (data, err) in [(&b"$17\r\n"[..], "invalid bulk length"),
+                            (b"*1\r\n=4294967296\r\n", "invalid bulk length"),
+                            (b"*5\r\n", "invalid multibulk length"),
+                            (b"%5\r\n", "invalid multibulk length"),
+                            (b"~-1\r\n", "invalid multibulk length"),
+                            (b"*1\r\n*1\r\n*1\r\n*1\r\n", "too many nested aggregates"),
+                            (b"|0\r\n|0\r\n|0\r\n|0\r\n", "too many nested aggregates")]
+        {
+            assert_eq!(check(data).unwrap_err().to_string(),
+                       err,
+                       "{:?}",
+                       String::from_utf8_lossy(data));
+        }
+        // a length that isn't even an integer
+        assert_eq!(check(b"=99999999999999999999\r\n").unwrap_err().to_string(),
+                   "invalid frame format");
+
+        // a null array is no length at all
+        assert_eq!(parse(b"*-1\r\n"), Frame::Null);
+        assert!(check(b"*-2\r\n").is_ok());
+        // but a null bulk string is spelled one way only
+        assert_eq!(parse(b"$-1\r\n"), Frame::Null);
+        for data in [&b"$-5\r\n"[..], b"$-\r\n", b"$-10\r\n", b"=-1\r\n"] {
+            assert_eq!(check(data).unwrap_err().to_string(),
+                       "invalid bulk length",
+                       "{:?}",
+                       String::from_utf8_lossy(data));
+        }
+        assert!(matches!(check(b"$-"), Err(Error::Incomplete)));
+
+        // no line may run on: one that has yet to end is refused once it's past the limit
+        for kind in *b"+-:$*%," {
+            let mut line = vec![kind];
+            line.resize(MAX_INLINE_LEN + 1, b'1');
+            assert!(matches!(check(&line), Err(Error::Incomplete)));
+            line.extend_from_slice(b"1\r");
+            assert_eq!(check(&line).unwrap_err().to_string(), "too big line");
+        }
+        // right at it, it's just a (long) line
+        let mut line = vec![b'+'];
+        line.resize(MAX_INLINE_LEN + 1, b'x');
+        line.extend_from_slice(b"\r\n");
+        assert!(check(&line).is_ok());
+
+        // right at the limits is fine
+        assert!(check(b"*4\r\n*1\r\n*0\r\n$16\r\n0123456789abcdef\r\n:1\r\n_\r\n").is_ok());
+        // and nesting too deep to recurse through is caught at the default limits as well
+        let deep = b"*1\r\n".repeat(1_000_000);
+        assert_eq!(Frame::check(&mut Cursor::new(&deep[..]), &Limits::default()).unwrap_err()
+                                                                                .to_string(),
+                   "too many nested aggregates");
+    }
+}
@@ -0,0 +1,125 @@
+//! Redis-style glob patterns, as used by KEYS and SCAN's MATCH option
+//!
+//! - `*` any run of bytes (including none)
+//! - `?` exactly one byte
+//! - `[abc]`, `[a-z]`, `[^abc]` one byte from (or, with `^`, not from) a set
+//! - `\x` a literal `x`
+
+/// Does `string` match `pattern`?
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => string.is_empty(),
+        Some((b'*', rest)) => {
+            // collapse runs of stars; then try every possible length for this one
+            let rest = trim_stars(rest);
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=string.len()).any(|skip| glob_match(rest, &string[skip..]))
+        }
+        Some((b'?', rest)) => !string.is_empty() && glob_match(rest, &string[1..]),
+        Some((b'[', rest)) => {
+            let Some((&c, string_rest)) = string.split_first() else {
+                return false;
+            };
+            let (matched, rest) = match_class(rest, c);
+            matched && glob_match(rest, string_rest)
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            string.first() == Some(&rest[0]) && glob_match(&rest[1..], &string[1..])
+        }
+        Some((&p, rest)) => string.first() == Some(&p) && glob_match(rest, &string[1..]),
+    }
+}
+
+fn trim_stars(mut pattern: &[u8]) -> &[u8] {
+    while let Some((b'*', rest)) = pattern.split_first() {
+        pattern = rest;
+    }
+    pattern
+}
+
+/// Match `c` against the set that opens `pattern` (just past its `[`).
+///
+/// Returns whether it matched, and the pattern left after the closing `]`.
+/// An unterminated set runs to the end of the pattern, as in Redis.
+fn match_class(pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let (negate, mut pattern) = match pattern.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == c;
+                pattern = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (lo, hi) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= (lo..=hi).contains(&c);
+                pattern = rest;
+            }
+            [literal, rest @ ..] => {
+                matched |= *literal == c;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negate, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn literals_and_wildcards() {
+        assert!(matches("hello", "hello"));
+        assert!(!matches("hello", "hell"));
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("h*o", "hello"));
+        assert!(matches("h*o", "ho"));
+        assert!(!matches("h*o", "help"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("user:*:name", "user:42:name"));
+        assert!(matches("**a**", "bab"));
+    }
+
+    #[test]
+    fn character_sets() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+        assert!(matches("[z-a]", "m"));
+        assert!(matches("[a-]", "-"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(r"h\*llo", "h*llo"));
+        assert!(!matches(r"h\*llo", "hello"));
+        assert!(matches(r"[\]]", "]"));
+        assert!(matches(r"a\?", "a?"));
+        assert!(!matches(r"a\?", "ab"));
+    }
+}
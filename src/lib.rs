@@ -1,6 +1,18 @@
 //! Lib
 
-mod connection;
+pub mod client;
+pub mod codec;
+pub mod cmd;
+pub mod connection;
+pub mod db;
+pub mod error;
+pub mod frame;
+pub mod glob;
+pub mod hyperloglog;
+pub mod parse;
+pub mod scan_map;
+pub mod server;
+pub mod shard_hash;
 pub mod boilerplate {
     use console_subscriber;
     use tracing_subscriber::EnvFilter;
@@ -43,51 +55,3 @@ pub mod boilerplate {
         Console,
     }
 }
-
-pub mod error {
-    pub type Result<T> = core::result::Result<T, Error>;
-    pub type Error = Box<dyn std::error::Error>;
-}
-
-#[allow(dead_code)]
-mod shard_hash {
-    use std::{collections::HashMap,
-              hash::{DefaultHasher, Hash, Hasher},
-              sync::{Arc, Mutex}};
-
-    type ShardedDb<K, V> = Arc<Vec<Mutex<HashMap<K, V>>>>;
-
-    /// Hash a thing
-    /// (paritcularly a string)
-    fn hash<T: Hash>(t: &T) -> usize {
-        let mut hasher = DefaultHasher::new();
-        t.hash(&mut hasher);
-        hasher.finish() as usize
-    }
-
-    /// Create an Arc-wrapped vector of Mutexed Hashmaps.
-    ///
-    /// An attempt to decrease contention for HashMap functionality.
-    ///
-    /// Warn: I'm not clear on how we determine which Hashmap we belong to without going through them all, as written.  Which would seem to defeat the point -- unless reading + locking is that much speedier a process...
-    fn new_sharded_db<K, V>(num_shards: usize) -> ShardedDb<K, V>
-        where K: Eq+Hash,
-              V: Default
-    {
-        let mut db = Vec::with_capacity(num_shards);
-        for _ in 0..num_shards {
-            db.push(Mutex::new(HashMap::new()));
-        }
-        Arc::new(db)
-    }
-
-    /// Determine which element of a sharded hashmap to use
-    /// before making any requests or shard collection
-    ///
-    pub fn divine_hashmap<K, V>(db: &ShardedDb<K, V>, key: &K) -> usize
-        where K: Eq+Hash,
-              V: Default
-    {
-        hash(&key).rem_euclid(db.len())
-    }
-}
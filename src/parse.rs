@@ -0,0 +1,197 @@
+//! Walk the parts of a command frame
+//!
+//! Commands arrive as an array of bulk strings: `["SET", "key", "value"]`.
+//! `Parse` hands those parts out one at a time, converting as it goes.
+
+use std::{fmt, vec};
+
+use bytes::Bytes;
+
+use crate::{error::CommandError, frame::Frame};
+
+/// Cursor over the entries of an array frame.
+#[derive(Debug)]
+pub struct Parse {
+    parts: vec::IntoIter<Frame>,
+}
+
+/// Failure to pull the next argument out of a command frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Asked for another argument, but the frame has been used up.
+    EndOfStream,
+    /// Arguments left over once the command had all it wanted.
+    Trailing,
+    /// Argument should have been an integer.
+    NotInteger,
+    /// Argument should have been a (finite) float.
+    NotFloat,
+    /// Argument not one of the options the command accepts.
+    Syntax,
+    /// No command goes by that name.
+    UnknownCommand,
+    /// Frame isn't shaped like a command at all.
+    Protocol(String),
+    /// Argument present, but unusable for some other reason; message follows `ERR `.
+    Other(String),
+}
+
+impl Parse {
+    /// Start walking a frame.  Only arrays carry commands.
+    pub fn new(frame: Frame) -> Result<Parse, ParseError> {
+        match frame {
+            Frame::Array(array) => Ok(Parse { parts: array.into_iter(), }),
+            frame => Err(ParseError::Protocol(format!("expected array, got {:?}", frame))),
+        }
+    }
+
+    /// Next argument, as raw bytes.
+    pub fn next_bytes(&mut self) -> Result<Bytes, ParseError> {
+        match self.parts.next().ok_or(ParseError::EndOfStream)? {
+            Frame::Bulk(data) => Ok(data),
+            Frame::Simple(s) => Ok(Bytes::from(s.into_bytes())),
+            frame => Err(ParseError::Protocol(format!("expected bulk frame, got {:?}", frame))),
+        }
+    }
+
+    /// Next argument, as a (utf-8) string.
+    pub fn next_string(&mut self) -> Result<String, ParseError> {
+        let data = self.next_bytes()?;
+        String::from_utf8(data.to_vec()).map_err(|_| "invalid UTF-8 in argument".into())
+    }
+
+    /// Next argument, as a (signed, 64 bit) integer.
+    pub fn next_int(&mut self) -> Result<i64, ParseError> {
+        match self.parts.next().ok_or(ParseError::EndOfStream)? {
+            Frame::Integer(v) => Ok(v),
+            Frame::Bulk(data) => parse_int(&data).ok_or(ParseError::NotInteger),
+            _ => Err(ParseError::NotInteger),
+        }
+    }
+
+    /// Next argument, as a finite float.
+    pub fn next_float(&mut self) -> Result<f64, ParseError> {
+        let data = self.next_bytes()?;
+        parse_float(&data).ok_or(ParseError::NotFloat)
+    }
+
+    /// The next argument as a sorted-set score (see `parse_score`).
+    pub fn next_score(&mut self) -> Result<f64, ParseError> {
+        let data = self.next_bytes()?;
+        parse_score(&data).ok_or(ParseError::NotFloat)
+    }
+
+    /// Number of arguments left.
+    pub fn remaining(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// Whether any arguments remain.
+    pub fn is_empty(&self) -> bool {
+        self.parts.len() == 0
+    }
+
+    /// Confirm every argument was consumed.
+    pub fn finish(&mut self) -> Result<(), ParseError> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(ParseError::Trailing),
+        }
+    }
+}
+
+/// Read bytes as a 64 bit integer, as strictly as Redis does: no sign but `-`, no padding.
+pub fn parse_int(data: &[u8]) -> Option<i64> {
+    if data.first() == Some(&b'+') {
+        return None;
+    }
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+/// Read bytes as a finite float.  (`inf` and `nan` are not numbers one can count with.)
+pub fn parse_float(data: &[u8]) -> Option<f64> {
+    let s = std::str::from_utf8(data).ok()?;
+    if s.starts_with(char::is_whitespace) || s.ends_with(char::is_whitespace) {
+        return None;
+    }
+    s.parse::<f64>().ok().filter(|f| f.is_finite())
+}
+
+/// Write a float the way INCRBYFLOAT stores it: plain decimal notation, never an exponent, with no
+/// trailing zeros (so `3.0e3` is `3000`).
+///
+/// Redis adds in `long double` and prints 17 significant digits of that; an `f64` only carries
+/// `f64::DIGITS` of them reliably, so that's as many as are written -- `0.1 + 0.2` is `0.3` either
+/// way.
+pub fn format_float(val: f64) -> String {
+    // the exponent after rounding to that many digits decides how many decimals they need
+    let sci = format!("{:.*e}", f64::DIGITS as usize - 1, val);
+    let exp: i32 = sci.rsplit('e')
+                      .next()
+                      .and_then(|exp| exp.parse().ok())
+                      .unwrap_or(0);
+    let decimals = (f64::DIGITS as i32 - 1 - exp).max(0) as usize;
+    let mut out = format!("{:.*}", decimals, val);
+    if out.contains('.') {
+        out.truncate(out.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    out
+}
+
+/// Read bytes as a sorted-set score: any float but NaN, with `inf`, `+inf` and `-inf` allowed.
+pub fn parse_score(data: &[u8]) -> Option<f64> {
+    match &data.to_ascii_lowercase()[..] {
+        b"inf" | b"+inf" => Some(f64::INFINITY),
+        b"-inf" => Some(f64::NEG_INFINITY),
+        _ => parse_float(data),
+    }
+}
+
+impl ParseError {
+    /// The reply for a failure while parsing command `name`.
+    pub fn for_command(self, name: &str) -> CommandError {
+        match self {
+            ParseError::EndOfStream | ParseError::Trailing => {
+                CommandError::WrongArity(name.to_string())
+            }
+            ParseError::NotInteger => CommandError::NotInteger,
+            ParseError::NotFloat => CommandError::NotFloat,
+            ParseError::UnknownCommand => CommandError::UnknownCommand { name: name.to_string(),
+                                                                         args: Vec::new(), },
+            ParseError::Syntax => CommandError::Syntax,
+            ParseError::Protocol(msg) => CommandError::Protocol(msg),
+            ParseError::Other(msg) => CommandError::Other(msg),
+        }
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(src: String) -> ParseError {
+        ParseError::Other(src)
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(src: &str) -> ParseError {
+        src.to_string().into()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::EndOfStream => "protocol error; unexpected end of stream".fmt(f),
+            ParseError::Trailing => {
+                "protocol error; expected end of frame, but there was more".fmt(f)
+            }
+            ParseError::NotInteger => "value is not an integer or out of range".fmt(f),
+            ParseError::NotFloat => "value is not a valid float".fmt(f),
+            ParseError::Syntax => "syntax error".fmt(f),
+            ParseError::UnknownCommand => "unknown command".fmt(f),
+            ParseError::Protocol(msg) => write!(f, "protocol error; {}", msg),
+            ParseError::Other(msg) => msg.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
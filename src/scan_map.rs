@@ -0,0 +1,321 @@
+//! A HashMap that can also be walked in hash order
+//!
+//! SCAN and its per-key cousins hand out a cursor between calls, and resume from it: the cursor is
+//! the hash of the next key to visit.  Hashes are fixed for the life of the process, so the walk
+//! never misses or repeats a key that stays put, however much the map changes in between.  Each
+//! map keeps its keys indexed by hash alongside the usual table, so a step only looks at the keys it
+//! returns.
+
+use std::{borrow::Borrow,
+          collections::{BTreeMap, HashMap},
+          hash::Hash,
+          ops::Deref};
+
+use crate::shard_hash;
+
+/// A HashMap whose keys are also kept in order of their hash.
+///
+/// Reads go straight to the inner map; anything that adds or removes a key goes through here, to
+/// keep the index in step.
+#[derive(Debug, Clone)]
+pub struct ScanMap<K, V> {
+    map:   HashMap<K, V>,
+    /// Keys by hash.  (Nearly always one per hash.)
+    order: BTreeMap<u64, Vec<K>>,
+}
+
+/// A HashSet that can also be walked in hash order.
+#[derive(Debug, Clone)]
+pub struct ScanSet<K> {
+    map: ScanMap<K, ()>,
+}
+
+/// The next few entries of a walk, each tagged with its hash.
+#[derive(Debug)]
+pub struct Step<T> {
+    items: Vec<(u64, T)>,
+    /// Whether anything comes after `items`.
+    more:  bool,
+}
+
+impl<K, V> ScanMap<K, V> where K: Eq+Hash+Clone
+{
+    /// An empty map.
+    pub fn new() -> ScanMap<K, V> {
+        ScanMap { map:   HashMap::new(),
+                  order: BTreeMap::new(), }
+    }
+
+    /// Set `key` to `value`.  Returns the value it replaced, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.map.get_mut(&key) {
+            Some(old) => Some(std::mem::replace(old, value)),
+            None => {
+                self.order
+                    .entry(shard_hash::hash(&key))
+                    .or_default()
+                    .push(key.clone());
+                self.map.insert(key, value)
+            }
+        }
+    }
+
+    /// Remove `key`.  Returns its value, if it was there.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+        where K: Borrow<Q>,
+              Q: Eq+Hash+?Sized
+    {
+        let value = self.map.remove(key)?;
+        let h = shard_hash::hash(key);
+        let keys = self.order.get_mut(&h).expect("Indexed key.");
+        keys.retain(|k| k.borrow() != key);
+        if keys.is_empty() {
+            self.order.remove(&h);
+        }
+        Some(value)
+    }
+
+    /// The value at `key`, to change in place.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+        where K: Borrow<Q>,
+              Q: Eq+Hash+?Sized
+    {
+        self.map.get_mut(key)
+    }
+
+    /// The first `count` entries (at least one) whose hash is `cursor` or more, in hash order, plus
+    /// any sharing the last one's hash: the next cursor couldn't point between them.
+    pub fn step(&self, cursor: u64, count: usize) -> Step<(&K, &V)> {
+        let count = count.max(1);
+        let mut items = Vec::new();
+        let mut runs = self.order.range(cursor..);
+        for (&h, keys) in runs.by_ref() {
+            items.extend(keys.iter().map(|key| (h, (key, &self.map[key]))));
+            if items.len() >= count {
+                break;
+            }
+        }
+        Step { items,
+               more: runs.next().is_some() }
+    }
+}
+
+impl<K, V> Deref for ScanMap<K, V> {
+    type Target = HashMap<K, V>;
+
+    fn deref(&self) -> &HashMap<K, V> {
+        &self.map
+    }
+}
+
+impl<K, V> Default for ScanMap<K, V> where K: Eq+Hash+Clone
+{
+    fn default() -> ScanMap<K, V> {
+        ScanMap::new()
+    }
+}
+
+impl<K> Default for ScanSet<K> where K: Eq+Hash+Clone
+{
+    fn default() -> ScanSet<K> {
+        ScanSet::new()
+    }
+}
+
+impl<K> ScanSet<K> where K: Eq+Hash+Clone
+{
+    /// An empty set.
+    pub fn new() -> ScanSet<K> {
+        ScanSet { map: ScanMap::new(), }
+    }
+
+    /// Add `member`.  Returns whether it is new.
+    pub fn insert(&mut self, member: K) -> bool {
+        self.map.insert(member, ()).is_none()
+    }
+
+    /// Remove `member`.  Returns whether it was there.
+    pub fn remove<Q>(&mut self, member: &Q) -> bool
+        where K: Borrow<Q>,
+              Q: Eq+Hash+?Sized
+    {
+        self.map.remove(member).is_some()
+    }
+
+    /// Whether `member` is in the set.
+    pub fn contains<Q>(&self, member: &Q) -> bool
+        where K: Borrow<Q>,
+              Q: Eq+Hash+?Sized
+    {
+        self.map.contains_key(member)
+    }
+
+    /// Every member, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item=&K> {
+        self.map.keys()
+    }
+
+    /// Number of members.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether the set has no members.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// As `ScanMap::step`.
+    pub fn step(&self, cursor: u64, count: usize) -> Step<&K> {
+        self.map.step(cursor, count).map(|(member, ())| member)
+    }
+}
+
+impl<K> FromIterator<K> for ScanSet<K> where K: Eq+Hash+Clone
+{
+    fn from_iter<I: IntoIterator<Item=K>>(members: I) -> ScanSet<K> {
+        let mut set = ScanSet::new();
+        for member in members {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+impl<K> IntoIterator for ScanSet<K> {
+    type IntoIter = std::collections::hash_map::IntoKeys<K, ()>;
+    type Item = K;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.map.into_keys()
+    }
+}
+
+impl<T> Step<T> {
+    /// One step over several maps at once, from the steps each took from the same cursor: the first
+    /// `count` of all their entries, plus any sharing the last one's hash.
+    ///
+    /// Each map offered at least `count` entries if it had more, so whatever they left out comes
+    /// after everything kept here.
+    pub fn merge(steps: impl IntoIterator<Item=Step<T>>, count: usize) -> Step<T> {
+        let count = count.max(1);
+        let mut items = Vec::new();
+        let mut more = false;
+        for step in steps {
+            items.extend(step.items);
+            more |= step.more;
+        }
+        items.sort_by_key(|(h, _)| *h);
+
+        let mut take = count.min(items.len());
+        if let Some(&(last, _)) = take.checked_sub(1).and_then(|i| items.get(i)) {
+            take += items[take..].iter().take_while(|(h, _)| *h == last).count();
+        }
+        more |= take < items.len();
+        items.truncate(take);
+        Step { items, more }
+    }
+
+    /// Transform each entry, keeping its place in the walk.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> Step<U> {
+        Step { items: self.items
+                          .into_iter()
+                          .map(|(h, item)| (h, f(item)))
+                          .collect(),
+               more:  self.more, }
+    }
+
+    /// The entries, and the cursor for the next step: the hash right after the last one taken, or
+    /// `0` if that was the lot.
+    pub fn finish(self) -> (u64, Vec<T>) {
+        let next = match (self.more, self.items.last()) {
+            (true, Some(&(last, _))) => last + 1,
+            _ => 0,
+        };
+        (next, self.items.into_iter().map(|(_, item)| item).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Every key, walking `count` at a time.
+    fn walk(map: &ScanMap<String, u32>, count: usize) -> Vec<String> {
+        let (mut cursor, mut seen) = (0, Vec::new());
+        loop {
+            let (next, keys) = map.step(cursor, count).finish();
+            assert!(keys.len() <= count);
+            seen.extend(keys.into_iter().map(|(key, _)| key.clone()));
+            match next {
+                0 => return seen,
+                next => cursor = next,
+            }
+        }
+    }
+
+    #[test]
+    fn walk_visits_every_key_once() {
+        let mut map = ScanMap::new();
+        for i in 0..500 {
+            map.insert(format!("key:{i}"), i);
+        }
+        let seen = walk(&map, 7);
+        assert_eq!(seen.len(), 500);
+        assert_eq!(seen.iter().collect::<HashSet<_>>().len(), 500);
+    }
+
+    #[test]
+    fn index_follows_inserts_and_removes() {
+        let mut map = ScanMap::new();
+        assert_eq!(map.insert("a".to_string(), 1), None);
+        assert_eq!(map.insert("a".to_string(), 2), Some(1));
+        assert_eq!(map.insert("b".to_string(), 3), None);
+        assert_eq!(map.remove("a"), Some(2));
+        assert_eq!(map.remove("a"), None);
+        assert_eq!(walk(&map, 10), ["b"]);
+        assert_eq!(map.get("b"), Some(&3));
+        map.remove("b");
+        assert_eq!(map.step(0, 10).finish().1.len(), 0);
+    }
+
+    #[test]
+    fn steps_from_several_maps_merge_in_hash_order() {
+        let mut maps = [ScanMap::new(), ScanMap::new(), ScanMap::new()];
+        for i in 0..300 {
+            maps[i % 3].insert(format!("key:{i}"), i as u32);
+        }
+        let (mut cursor, mut seen) = (0, HashSet::new());
+        loop {
+            let steps = maps.iter().map(|map| map.step(cursor, 5));
+            let (next, keys) = Step::merge(steps, 5).finish();
+            assert!(keys.len() <= 5);
+            for (key, _) in keys {
+                assert!(seen.insert(key.clone()), "{key} returned twice");
+            }
+            match next {
+                0 => break,
+                next => cursor = next,
+            }
+        }
+        assert_eq!(seen.len(), 300);
+    }
+
+    #[test]
+    fn merge_keeps_ties_together() {
+        let step = |items: Vec<(u64, char)>, more| Step { items, more };
+        let merged = Step::merge([step(vec![(1, 'a'), (5, 'b')], true),
+                                  step(vec![(3, 'c'), (5, 'd')], false)],
+                                 3);
+        assert_eq!(merged.finish(), (6, vec!['a', 'c', 'b', 'd']));
+
+        let merged = Step::merge([step(vec![(1, 'a')], false), step(vec![(2, 'b')], false)],
+                                 1);
+        assert_eq!(merged.finish(), (2, vec!['a']));
+
+        let merged = Step::merge([step(vec![(1, 'a')], false), step(vec![], false)], 1);
+        assert_eq!(merged.finish(), (0, vec!['a']));
+    }
+}
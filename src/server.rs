@@ -0,0 +1,365 @@
+//! The server side of a connection: read requests, carry them out, write replies
+//!
+//! `run` accepts connections for as long as the listener lasts, serving each on a task of its own.
+//! The commands understood are those in the `Registry` it's given.
+
+use std::{pin::Pin,
+          sync::{atomic::{AtomicU64, Ordering},
+                 Arc}};
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::{net::{TcpListener, TcpStream},
+            sync::broadcast::{self, error::RecvError}};
+use tokio_stream::{StreamExt, StreamMap};
+
+use crate::{cmd::{Command, CommandFlags, Registry, Transaction},
+            connection::Connection,
+            db::Db,
+            error::{CommandError, Result},
+            frame::{self, Frame, Limits, Protocol}};
+
+/// Messages arriving on a subscribed channel.
+type Messages = Pin<Box<dyn Stream<Item=Bytes>+Send>>;
+
+/// Id for the next connection, as HELLO reports it.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What the server keeps track of for a connection, between requests.
+#[derive(Debug)]
+struct Session {
+    id:          u64,
+    transaction: Transaction,
+}
+
+/// Serve clients connecting to `listener`, against `db`, with the commands in `registry`.
+///
+/// Only returns if accepting a connection fails.
+pub async fn run(listener: TcpListener, db: Db, registry: Registry) -> Result<()> {
+    run_with_limits(listener, db, registry, Limits::default()).await
+}
+
+/// `run`, hanging up on clients that send frames beyond `limits` (after a protocol error reply).
+pub async fn run_with_limits(listener: TcpListener,
+                             db: Db,
+                             registry: Registry,
+                             limits: Limits)
+                             -> Result<()> {
+    let registry = Arc::new(registry);
+    loop {
+        // The Second item contains the IP and port of the new connection.
+        // -- presumably "accept" is "accept if asked, wait otherwise"
+        tracing::debug!("Awaiting socket receipt...");
+        let (socket, _) = listener.accept().await?;
+        tracing::debug!("'Cloning' Arcs.");
+        let db = db.clone();
+        let registry = registry.clone();
+        tracing::debug!("Socket accepted; Spawning thread to process...");
+        tokio::spawn(async move {
+            tracing::debug!("Thread for socket processing spawned.");
+            tracing::debug!("Processing socket...");
+            match process(socket, db, &registry, limits).await {
+                Ok(()) => tracing::debug!("Socket processed."),
+                Err(err) => tracing::debug!(%err, "Socket dropped."),
+            }
+        });
+    }
+}
+
+/// Process commands from a TcpStream, translate into 'frames', and manage comms with database.
+///
+/// A bad request gets an error reply and the connection carries on -- unless the request was so
+/// malformed that the rest of the stream can't be trusted, in which case we reply and hang up.
+async fn process(socket: TcpStream, db: Db, registry: &Registry, limits: Limits) -> Result<()> {
+    // replies go out as soon as they're flushed, not when the client's acknowledged the last ones
+    socket.set_nodelay(true)?;
+    // Read&Write "frames" instead of working with byte streams
+    let mut connection = Connection::with_limits(socket, limits);
+    let mut session = Session { id:          NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+                                transaction: Transaction::new(db.clone()), };
+
+    while let Some(frame) = read_frame(&mut connection).await? {
+        serve(&mut connection, &db, registry, &mut session, frame).await?;
+        // a client pipelining requests has sent more already: answer those too, then send every
+        // reply at once
+        while let Some(frame) = buffered_frame(&mut connection).await? {
+            serve(&mut connection, &db, registry, &mut session, frame).await?;
+        }
+        connection.flush().await?;
+    }
+    Ok(())
+}
+
+/// Carry out one request, queuing the reply on the connection.
+async fn serve(connection: &mut Connection,
+               db: &Db,
+               registry: &Registry,
+               session: &mut Session,
+               frame: Frame)
+               -> Result<()> {
+    use Command::{Describe, Hello, Subscribe, Unsubscribe};
+
+    tracing::info!("GOT: {:?}", frame);
+    let response = match registry.parse(frame) {
+        // MULTI & co., and whatever is queued after MULTI
+        command if session.transaction.takes(&command) => session.transaction.handle(command),
+        Ok(Subscribe { channels }) => {
+            // connection is now dedicated to receiving messages, until it unsubscribes from everything
+            return subscribe_mode(connection, db, registry, channels).await;
+        }
+        Ok(Unsubscribe { channels }) => {
+            // Not subscribed to anything, so nothing to remove; acknowledge anyway (as redis does).
+            for frame in unsubscribe_replies(channels, 0) {
+                connection.queue_frame(&frame).await?;
+            }
+            return Ok(());
+        }
+        Ok(Hello { protocol, auth, .. }) => hello(connection, session.id, protocol, auth),
+        Ok(Describe(query)) => Ok(registry.describe(&query)),
+        Ok(cmd) => {
+            if registry.get(cmd.name())
+                       .is_some_and(|spec| spec.flags.contains(CommandFlags::BLOCKING))
+            {
+                // the replies so far shouldn't wait along with this one
+                connection.flush().await?;
+            }
+            tokio::select! {
+                // most commands finish at once; only watch for a hang-up while one is waiting
+                biased;
+                response = cmd.execute(db) => response,
+                closed = connection.closed() => {
+                    // client hung up (or sent too much) while we waited; dropping the command
+                    // abandons the wait
+                    return reply_if_malformed(connection, closed.map(|()| None)).await.map(drop);
+                }
+            }
+        }
+        Err(err) => Err(err),
+    };
+    match response {
+        Ok(frame) => connection.queue_frame(&frame).await?,
+        Err(err) => {
+            connection.queue_frame(&err.to_frame()).await?;
+            if err.closes_connection() {
+                connection.flush().await?;
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Switch the connection to the protocol asked for, if any, and describe the server.
+fn hello(connection: &mut Connection,
+         id: u64,
+         protocol: Option<i64>,
+         auth: Option<(String, String)>)
+         -> std::result::Result<Frame, CommandError> {
+    let protocol = match protocol {
+        None => connection.protocol(),
+        Some(2) => Protocol::Resp2,
+        Some(3) => Protocol::Resp3,
+        Some(_) => return Err(CommandError::NoProto),
+    };
+    // there are no passwords: the default user is all there is, and needs none
+    if auth.is_some_and(|(user, _)| user != "default") {
+        return Err(CommandError::WrongPass);
+    }
+    connection.set_protocol(protocol);
+
+    let field = |name: &str, value: Frame| (Frame::bulk(name), value);
+    let proto = match protocol {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+    Ok(Frame::Map(vec![field("server", Frame::bulk("my-redis")),
+                       field("version", Frame::bulk(env!("CARGO_PKG_VERSION"))),
+                       field("proto", Frame::Integer(proto)),
+                       field("id", Frame::Integer(id as i64)),
+                       field("mode", Frame::bulk("standalone")),
+                       field("role", Frame::bulk("master")),
+                       field("modules", Frame::Array(Vec::new()))]))
+}
+
+/// Read the next request, waiting for one if need be.  Empty ones are skipped, as redis does.
+async fn read_frame(connection: &mut Connection) -> Result<Option<Frame>> {
+    loop {
+        let read = connection.read_frame().await;
+        match reply_if_malformed(connection, read).await? {
+            Some(frame) if is_empty_request(&frame) => continue,
+            frame => return Ok(frame),
+        }
+    }
+}
+
+/// The next request, if the client has sent a whole one already.  Empty ones are skipped.
+async fn buffered_frame(connection: &mut Connection) -> Result<Option<Frame>> {
+    loop {
+        let read = connection.buffered_frame();
+        match reply_if_malformed(connection, read).await? {
+            Some(frame) if is_empty_request(&frame) => continue,
+            frame => return Ok(frame),
+        }
+    }
+}
+
+/// An empty (`*0`) or null (`*-1`) array: a request for nothing, which gets no reply.
+fn is_empty_request(frame: &Frame) -> bool {
+    match frame {
+        Frame::Null => true,
+        Frame::Array(items) => items.is_empty(),
+        _ => false,
+    }
+}
+
+/// Pass on what was read; a frame that couldn't be parsed gets a protocol error reply first.
+async fn reply_if_malformed(connection: &mut Connection,
+                            read: Result<Option<Frame>>)
+                            -> Result<Option<Frame>> {
+    match read {
+        Err(err) if err.is::<frame::Error>() => {
+            let reply = CommandError::Protocol(err.to_string()).to_frame();
+            // the connection is being dropped anyway; a failed goodbye changes nothing
+            let _ = connection.write_frame(&reply).await;
+            Err(err)
+        }
+        read => read,
+    }
+}
+
+/// Serve a subscribed connection: forward published messages, and accept further (un)subscribe requests.
+///
+/// Returns once the client has no subscriptions left (or has hung up).
+async fn subscribe_mode(connection: &mut Connection,
+                        db: &Db,
+                        registry: &Registry,
+                        channels: Vec<String>)
+                        -> Result<()> {
+    let mut subscriptions: StreamMap<String, Messages> = StreamMap::new();
+    let served = serve_subscriptions(connection, db, registry, &mut subscriptions, channels).await;
+
+    // however we got here, drop our receivers and let the registry forget channels nobody listens to
+    let channels: Vec<String> = subscriptions.keys().cloned().collect();
+    drop(subscriptions);
+    channels.iter()
+            .for_each(|channel| db.prune_channel(channel));
+    served
+}
+
+async fn serve_subscriptions(connection: &mut Connection,
+                             db: &Db,
+                             registry: &Registry,
+                             subscriptions: &mut StreamMap<String, Messages>,
+                             channels: Vec<String>)
+                             -> Result<()> {
+    use Command::{Ping, Subscribe, Unsubscribe};
+
+    let mut to_subscribe = channels;
+
+    loop {
+        for channel in to_subscribe.drain(..) {
+            if !subscriptions.contains_key(&channel) {
+                subscriptions.insert(channel.clone(), messages(db.subscribe(channel.clone())));
+            }
+            let reply = reply_array(["subscribe", &channel], subscriptions.len());
+            connection.write_frame(&reply).await?;
+        }
+
+        tokio::select! {
+            Some((channel, message)) = subscriptions.next() => {
+                let reply = Frame::Push(vec![Frame::Bulk(Bytes::from_static(b"message")),
+                                              Frame::Bulk(Bytes::from(channel)),
+                                              Frame::Bulk(message)]);
+                connection.write_frame(&reply).await?;
+            }
+            frame = read_frame(connection) => {
+                let Some(frame) = frame? else {
+                    // client hung up
+                    return Ok(());
+                };
+                match registry.parse(frame) {
+                    Ok(Subscribe { channels }) => to_subscribe.extend(channels),
+                    Ok(Unsubscribe { mut channels }) => {
+                        if channels.is_empty() {
+                            channels = subscriptions.keys().cloned().collect();
+                        }
+                        let mut replies = Vec::with_capacity(channels.len());
+                        for channel in channels {
+                            subscriptions.remove(&channel);
+                            db.prune_channel(&channel);
+                            replies.push(reply_array(["unsubscribe", &channel], subscriptions.len()));
+                        }
+                        for reply in replies {
+                            connection.write_frame(&reply).await?;
+                        }
+                        if subscriptions.is_empty() {
+                            return Ok(());
+                        }
+                    }
+                    Ok(Ping { message }) => {
+                        // a RESP3 client can tell replies from pushes, so gets the usual reply
+                        let reply = match (connection.protocol(), message) {
+                            (Protocol::Resp3, None) => Frame::Simple("PONG".to_string()),
+                            (Protocol::Resp3, Some(message)) => Frame::Bulk(message),
+                            (Protocol::Resp2, message) => {
+                                Frame::Array(vec![Frame::Bulk(Bytes::from_static(b"pong")),
+                                                  Frame::Bulk(message.unwrap_or_default())])
+                            }
+                        };
+                        connection.write_frame(&reply).await?;
+                    }
+                    Ok(cmd) => {
+                        let err = CommandError::Other(format!("Can't execute '{}': only (P)SUBSCRIBE / \
+                                                               (P)UNSUBSCRIBE / PING / QUIT / RESET are \
+                                                               allowed in this context",
+                                                              cmd.name()));
+                        connection.write_frame(&err.to_frame()).await?;
+                    }
+                    Err(err) => {
+                        connection.write_frame(&err.to_frame()).await?;
+                        if err.closes_connection() {
+                            return Err(err.into());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Turn a broadcast receiver into a stream of messages.
+///
+/// A subscriber that falls too far behind skips the messages it missed, rather than erroring out.
+fn messages(rx: broadcast::Receiver<Bytes>) -> Messages {
+    Box::pin(futures::stream::unfold(rx, |mut rx| async move {
+                 loop {
+                     match rx.recv().await {
+                         Ok(msg) => return Some((msg, rx)),
+                         Err(RecvError::Lagged(n)) => {
+                             tracing::warn!(n, "subscriber lagged; messages skipped")
+                         }
+                         Err(RecvError::Closed) => return None,
+                     }
+                 }
+             }))
+}
+
+/// `[kind, channel, count]` -- the shape of every (un)subscribe acknowledgement.  Like messages,
+/// these are pushes, for a RESP3 client.
+fn reply_array([kind, channel]: [&str; 2], count: usize) -> Frame {
+    Frame::Push(vec![Frame::Bulk(Bytes::copy_from_slice(kind.as_bytes())),
+                      Frame::Bulk(Bytes::copy_from_slice(channel.as_bytes())),
+                      Frame::Integer(count as i64)])
+}
+
+/// Acknowledgements for an UNSUBSCRIBE that leaves `count` subscriptions behind.
+fn unsubscribe_replies(channels: Vec<String>, count: usize) -> Vec<Frame> {
+    if channels.is_empty() {
+        return vec![Frame::Push(vec![Frame::Bulk(Bytes::from_static(b"unsubscribe")),
+                                     Frame::Null,
+                                     Frame::Integer(count as i64)])];
+    }
+    channels.iter()
+            .map(|channel| reply_array(["unsubscribe", channel], count))
+            .collect()
+}
@@ -0,0 +1,330 @@
+//! A HashMap split into independently locked shards
+//!
+//! An attempt to decrease contention for HashMap functionality: each key hashes to exactly one
+//! shard, so requests for keys in different shards never wait on each other.
+//!
+//! Work that spans several keys takes every shard it needs up front, always in ascending shard
+//! order, so two such requests can never each hold a lock the other is waiting for.
+//!
+//! Each shard is a `ScanMap`, so SCAN can walk the keys in hash order.
+
+use std::{hash::{DefaultHasher, Hash, Hasher},
+          sync::{atomic::{AtomicU64, Ordering},
+                 Mutex, MutexGuard, TryLockError}};
+
+use crate::scan_map::ScanMap;
+
+/// Shard count used when none is asked for.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Vector of Mutexed maps, with a count of how often each lock was fought over.
+#[derive(Debug)]
+pub struct ShardedDb<K, V> {
+    shards: Vec<Shard<K, V>>,
+}
+
+#[derive(Debug)]
+struct Shard<K, V> {
+    map:          Mutex<ScanMap<K, V>>,
+    acquisitions: AtomicU64,
+    contended:    AtomicU64,
+}
+
+/// Several shards, locked together.  Hands out the map for any key whose shard it holds.
+#[derive(Debug)]
+pub struct ShardGuards<'a, K, V> {
+    db:     &'a ShardedDb<K, V>,
+    /// Sorted by shard index.
+    guards: Vec<(usize, MutexGuard<'a, ScanMap<K, V>>)>,
+}
+
+/// Lock statistics for one shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShardStats {
+    /// Times the shard's lock has been taken.
+    pub acquisitions: u64,
+    /// Of those, how many had to wait for another holder to let go.
+    pub contended:    u64,
+}
+
+/// Hash a thing
+/// (paritcularly a string)
+///
+/// Stable for the life of the process, so it can also be used to put keys in a fixed order.
+pub fn hash<T: Hash+?Sized>(t: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    t.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<K, V> ShardedDb<K, V> where K: Eq+Hash+Clone
+{
+    /// Create a store of `num_shards` empty maps.
+    ///
+    /// # Panics
+    /// if `num_shards` is zero.
+    pub fn new(num_shards: usize) -> ShardedDb<K, V> {
+        assert!(num_shards > 0, "A sharded db needs at least one shard.");
+        let shards = (0..num_shards).map(|_| Shard { map:          Mutex::new(ScanMap::new()),
+                                                     acquisitions: AtomicU64::new(0),
+                                                     contended:    AtomicU64::new(0), })
+                                    .collect();
+        ShardedDb { shards }
+    }
+
+    /// Number of shards.
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Determine which element of a sharded hashmap to use
+    /// before making any requests or shard collection
+    pub fn divine_hashmap<Q>(&self, key: &Q) -> usize
+        where K: std::borrow::Borrow<Q>,
+              Q: Hash+?Sized
+    {
+        hash(key).rem_euclid(self.shards.len() as u64) as usize
+    }
+
+    /// Lock the shard holding `key`.
+    pub fn lock_key<Q>(&self, key: &Q) -> MutexGuard<'_, ScanMap<K, V>>
+        where K: std::borrow::Borrow<Q>,
+              Q: Hash+?Sized
+    {
+        self.lock(self.divine_hashmap(key))
+    }
+
+    /// Lock shard number `index`, noting whether we had to wait for it.
+    ///
+    /// # Panics
+    /// if `index` is out of range, or the shard's mutex is poisoned.
+    pub fn lock(&self, index: usize) -> MutexGuard<'_, ScanMap<K, V>> {
+        let shard = &self.shards[index];
+        shard.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match shard.map.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => {
+                shard.contended.fetch_add(1, Ordering::Relaxed);
+                shard.map.lock().expect("Unpoisoned mutex.")
+            }
+            Err(TryLockError::Poisoned(_)) => panic!("Poisoned shard mutex."),
+        }
+    }
+
+    /// Lock every shard holding one of `keys`, in ascending shard order.
+    ///
+    /// Each shard is locked once, however many of the keys it holds.
+    pub fn lock_keys<'k, Q>(&self, keys: impl IntoIterator<Item=&'k Q>) -> ShardGuards<'_, K, V>
+        where K: std::borrow::Borrow<Q>,
+              Q: Hash+?Sized+'k
+    {
+        let mut indices: Vec<usize> = keys.into_iter()
+                                          .map(|key| self.divine_hashmap(key))
+                                          .collect();
+        indices.sort_unstable();
+        indices.dedup();
+        let guards = indices.into_iter()
+                            .map(|index| (index, self.lock(index)))
+                            .collect();
+        ShardGuards { db: self, guards }
+    }
+
+    /// Lock statistics, one entry per shard.
+    pub fn stats(&self) -> Vec<ShardStats> {
+        self.shards
+            .iter()
+            .map(|shard| ShardStats { acquisitions: shard.acquisitions.load(Ordering::Relaxed),
+                                      contended:    shard.contended.load(Ordering::Relaxed), })
+            .collect()
+    }
+}
+
+impl<K, V> ShardGuards<'_, K, V> where K: Eq+Hash+Clone
+{
+    /// The (locked) map holding `key`.
+    ///
+    /// # Panics
+    /// if `key`'s shard isn't among those locked.
+    pub fn map_for<Q>(&mut self, key: &Q) -> &mut ScanMap<K, V>
+        where K: std::borrow::Borrow<Q>,
+              Q: Hash+?Sized
+    {
+        let at = self.position(key);
+        &mut self.guards[at].1
+    }
+
+    /// Read-only `map_for`, so that several maps can be looked at together.
+    pub fn map<Q>(&self, key: &Q) -> &ScanMap<K, V>
+        where K: std::borrow::Borrow<Q>,
+              Q: Hash+?Sized
+    {
+        &self.guards[self.position(key)].1
+    }
+
+    fn position<Q>(&self, key: &Q) -> usize
+        where K: std::borrow::Borrow<Q>,
+              Q: Hash+?Sized
+    {
+        let index = self.db.divine_hashmap(key);
+        self.guards
+            .binary_search_by_key(&index, |(i, _)| *i)
+            .expect("Key's shard is locked.")
+    }
+}
+
+impl<K, V> Default for ShardedDb<K, V> where K: Eq+Hash+Clone
+{
+    fn default() -> ShardedDb<K, V> {
+        ShardedDb::new(DEFAULT_SHARDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{Arc, Barrier},
+              thread,
+              time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn key_always_lands_in_same_shard() {
+        let db: ShardedDb<String, u32> = ShardedDb::new(8);
+        for key in ["a", "hello", "user:1234", ""] {
+            let first = db.divine_hashmap(key);
+            assert!(first < 8);
+            assert_eq!(first, db.divine_hashmap(key));
+            assert_eq!(first, db.divine_hashmap(&key.to_string()));
+        }
+    }
+
+    #[test]
+    fn keys_spread_over_shards() {
+        let db: ShardedDb<String, u32> = ShardedDb::new(8);
+        let mut used = [false; 8];
+        for i in 0..1000 {
+            used[db.divine_hashmap(&format!("key:{i}"))] = true;
+        }
+        assert!(used.iter().all(|&u| u), "some shard never used: {used:?}");
+    }
+
+    #[test]
+    fn values_are_found_through_their_shard() {
+        let db: ShardedDb<String, u32> = ShardedDb::new(4);
+        for i in 0..100 {
+            db.lock_key(&format!("key:{i}"))
+              .insert(format!("key:{i}"), i);
+        }
+        for i in 0..100 {
+            assert_eq!(db.lock_key(&format!("key:{i}")).get(&format!("key:{i}")),
+                       Some(&i));
+        }
+        let total: usize = (0..4).map(|shard| db.lock(shard).len()).sum();
+        assert_eq!(total, 100);
+    }
+
+    #[test]
+    fn single_shard_holds_everything() {
+        let db: ShardedDb<&str, ()> = ShardedDb::new(1);
+        assert_eq!(db.divine_hashmap("x"), 0);
+        assert_eq!(db.divine_hashmap("y"), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_shards_is_refused() {
+        let _: ShardedDb<String, ()> = ShardedDb::new(0);
+    }
+
+    #[test]
+    fn lock_keys_takes_each_shard_once() {
+        let db: ShardedDb<String, u32> = ShardedDb::new(4);
+        let keys: Vec<String> = (0..50).map(|i| format!("key:{i}")).collect();
+        let mut guards = db.lock_keys(&keys);
+        for (i, key) in keys.iter().enumerate() {
+            guards.map_for(key).insert(key.clone(), i as u32);
+        }
+        drop(guards);
+        assert_eq!(db.stats().iter().map(|s| s.acquisitions).sum::<u64>(), 4);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(db.lock_key(key).get(key), Some(&(i as u32)));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn map_for_refuses_unlocked_shard() {
+        let db: ShardedDb<String, u32> = ShardedDb::new(64);
+        let a = "a".to_string();
+        let other = (0..).map(|i| format!("k{i}"))
+                         .find(|k| db.divine_hashmap(k) != db.divine_hashmap(&a))
+                         .unwrap();
+        db.lock_keys([&a]).map_for(&other);
+    }
+
+    #[test]
+    fn opposite_orders_do_not_deadlock() {
+        let db: Arc<ShardedDb<String, u32>> = Arc::new(ShardedDb::new(8));
+        let keys: Vec<String> = (0..16).map(|i| format!("key:{i}")).collect();
+        for key in &keys {
+            db.lock_key(key).insert(key.clone(), 0);
+        }
+        let workers: Vec<_> =
+            (0..4).map(|n| {
+                      let (db, mut keys) = (db.clone(), keys.clone());
+                      if n % 2 == 1 {
+                          keys.reverse();
+                      }
+                      thread::spawn(move || {
+                          for _ in 0..1000 {
+                              let mut guards = db.lock_keys(&keys);
+                              for key in &keys {
+                                  *guards.map_for(key).get_mut(key).unwrap() += 1;
+                              }
+                          }
+                      })
+                  })
+                  .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        for key in &keys {
+            assert_eq!(db.lock_key(key).get(key), Some(&4000));
+        }
+    }
+
+    #[test]
+    fn uncontended_locks_are_counted() {
+        let db: ShardedDb<String, u32> = ShardedDb::new(2);
+        drop(db.lock(0));
+        drop(db.lock(0));
+        drop(db.lock(1));
+        assert_eq!(db.stats(), vec![ShardStats { acquisitions: 2,
+                                                 contended:    0, },
+                                    ShardStats { acquisitions: 1,
+                                                 contended:    0, }]);
+    }
+
+    #[test]
+    fn waiting_for_a_held_lock_counts_as_contention() {
+        let db: Arc<ShardedDb<String, u32>> = Arc::new(ShardedDb::new(2));
+        let held = Arc::new(Barrier::new(2));
+
+        let holder = {
+            let (db, held) = (db.clone(), held.clone());
+            thread::spawn(move || {
+                let _guard = db.lock(0);
+                held.wait();
+                thread::sleep(Duration::from_millis(100));
+            })
+        };
+        held.wait();
+        drop(db.lock(0));
+        holder.join().unwrap();
+
+        let stats = db.stats();
+        assert_eq!(stats[0], ShardStats { acquisitions: 2,
+                                          contended:    1, });
+        assert_eq!(stats[1], ShardStats::default());
+    }
+}